@@ -0,0 +1,95 @@
+//! Measures echo round trips of the echo binary's node through `gossip_glomers::run`,
+//! the runner every node uses, over an in-memory duplex instead of stdio, with and
+//! without padded replies. It covers line parsing, dispatch to a handler task and
+//! Message::send, and leaves out the pipes to Maelstrom.
+//!
+//! Run with `cargo bench --bench echo`. Criterion reports the time per echo and the
+//! echos per second; a change it marks as a regression is worth a look before merging.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gossip_glomers::echo::{EchoNode, Payload};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines};
+
+/// A running echo node and the client ends of its input and output.
+struct Running {
+    input: Option<DuplexStream>,
+    output: Lines<BufReader<DuplexStream>>,
+}
+
+impl Running {
+    /// Starts a node padding its replies with `padding` bytes. It reads
+    /// `ECHO_PADDING_BYTES` before answering the init, so nodes are started one at a
+    /// time.
+    async fn start(padding: usize) -> Self {
+        std::env::set_var("ECHO_PADDING_BYTES", padding.to_string());
+        let (mut input, node_input) = tokio::io::duplex(1 << 20);
+        let (node_output, output) = tokio::io::duplex(1 << 20);
+        tokio::spawn(gossip_glomers::run::<EchoNode, Payload, ()>(
+            node_input,
+            node_output,
+        ));
+        let init = r#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":0,"node_id":"n0","node_ids":["n0"]}}"#;
+        input
+            .write_all(format!("{}\n", init).as_bytes())
+            .await
+            .expect("send init");
+        let mut output = BufReader::new(output).lines();
+        output.next_line().await.expect("read init_ok");
+        std::env::remove_var("ECHO_PADDING_BYTES");
+        Self {
+            input: Some(input),
+            output,
+        }
+    }
+
+    /// Sends `count` echos and waits for all of their replies.
+    async fn echo(&mut self, count: u64) {
+        let mut input = self.input.take().expect("input");
+        // Written from another task, so neither side blocks on a full duplex.
+        let writer = tokio::spawn(async move {
+            for id in 1..=count {
+                let line = format!(
+                    r#"{{"src":"c0","dest":"n0","body":{{"type":"echo","msg_id":{},"echo":"hello"}}}}"#,
+                    id
+                );
+                input
+                    .write_all(format!("{}\n", line).as_bytes())
+                    .await
+                    .expect("send echo");
+            }
+            input
+        });
+        for _ in 0..count {
+            self.output
+                .next_line()
+                .await
+                .expect("read echo_ok")
+                .expect("node stopped");
+        }
+        self.input = Some(writer.await.expect("writer"));
+    }
+}
+
+fn echo(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .build()
+        .expect("build runtime");
+    let mut group = c.benchmark_group("echo");
+    group.throughput(Throughput::Elements(1));
+    for padding in [0, 1024, 16 * 1024] {
+        let mut node = runtime.block_on(Running::start(padding));
+        group.bench_function(BenchmarkId::new("padding", padding), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let started = std::time::Instant::now();
+                    node.echo(iters).await;
+                    started.elapsed()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, echo);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+//! Measures the per-tick work of the broadcast nodes once they know 100k values, on
+//! virtual time so nothing sleeps. Dense values make a single range; sparse ones, every
+//! other value, make 50k ranges, the worst case for a RangeSet.
+//!
+//! - `learn`: adding one new value to the known set and finding it was new, as every
+//!   incoming broadcast does in broadcast_efficient.
+//! - `tick, peer down`: queueing one new value for a peer that acks nothing and
+//!   polling its outbox, which resends all 100k values whenever the retry is due.
+//! - `merkle digest`: building the tree over the known set and its digest, as every
+//!   gossip round of broadcast with BROADCAST_ANTI_ENTROPY=merkle does.
+//!
+//! Run with `cargo bench --bench gossip`. Criterion reports the time per operation.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use gossip_glomers::{
+    gossip::{Outbox, RangeSet, Timing},
+    merkle::MerkleTree,
+};
+
+const VALUES: u64 = 100_000;
+
+/// Interval between two ticks of broadcast_efficient.
+const TICK: Duration = Duration::from_millis(5);
+
+fn gossip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gossip");
+    for (shape, step) in [("dense", 1), ("sparse", 2)] {
+        let known: RangeSet = (0..VALUES).map(|value| value * step).collect();
+        let next = VALUES * step;
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("learn", shape), |b| {
+            b.iter(|| {
+                i += 1;
+                let new = RangeSet::from_iter([next + i * step]);
+                let new = new.difference(&known);
+                black_box(!new.is_empty())
+            })
+        });
+
+        let timing = Timing {
+            debounce: Duration::from_millis(20),
+            min_retry: Duration::from_millis(400),
+            max_retry: Duration::from_millis(3200),
+        };
+        let start = Instant::now();
+        let mut outbox = Outbox::new(timing);
+        outbox.push(&known, start);
+        outbox.poll(start + timing.debounce);
+        let mut i: u32 = 0;
+        group.bench_function(BenchmarkId::new("tick, peer down", shape), |b| {
+            b.iter(|| {
+                i += 1;
+                let now = start + timing.debounce + TICK * i;
+                outbox.push(&RangeSet::from_iter([next + u64::from(i) * step]), now);
+                black_box(outbox.poll(now))
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("merkle digest", shape), |b| {
+            b.iter(|| {
+                let tree = MerkleTree::new(6, known.iter()).expect("merkle tree");
+                black_box(tree.digest())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, gossip);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+//! Measures how fast each id format turns counter values into ids on a single
+//! thread. Run with `cargo bench --bench ids`.
+
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use gossip_glomers::ids::{self, UuidV7};
+
+const IDS: u64 = 2_000_000;
+
+fn main() {
+    let next = AtomicU64::new(0);
+    bench("node-seq", || {
+        ids::node_seq(3, next.fetch_add(1, Ordering::Relaxed))
+    });
+    let uuid = UuidV7::new(3);
+    let next = AtomicU64::new(0);
+    bench("uuidv7", || {
+        uuid.format(next.fetch_add(1, Ordering::Relaxed))
+    });
+}
+
+/// Generates IDS ids with `generate` and prints the rate.
+fn bench(name: &str, mut generate: impl FnMut() -> String) {
+    // Warm up allocator and caches before timing.
+    for _ in 0..IDS / 10 {
+        black_box(generate());
+    }
+    let started = Instant::now();
+    for _ in 0..IDS {
+        black_box(generate());
+    }
+    let elapsed = started.elapsed().max(Duration::from_nanos(1));
+    println!(
+        "{:<10} {:>12.0} ids/sec ({:.1} ns/id)",
+        name,
+        IDS as f64 / elapsed.as_secs_f64(),
+        elapsed.as_nanos() as f64 / IDS as f64
+    );
+}
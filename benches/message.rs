@@ -0,0 +1,38 @@
+//! Measures Message::send, serialization and writes included, for an echo-sized
+//! payload and a 64KB one. It writes to a sink instead of stdout, so the numbers leave
+//! out the pipe to Maelstrom.
+//!
+//! Run with `cargo bench --bench message`. Criterion reports the time per message and
+//! the bytes per second of payload.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gossip_glomers::{Body, Message};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+fn send(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("build runtime");
+    let mut group = c.benchmark_group("send");
+    for size in [16, 64 * 1024] {
+        let message = Message {
+            src: "n0".to_string(),
+            dest: "c1".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: Some(1),
+                payload: json!({"type": "echo_ok", "echo": "x".repeat(size)}),
+            },
+        };
+        let out = Mutex::new(tokio::io::sink());
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::new("payload", size), |b| {
+            b.iter(|| runtime.block_on(message.send(&out)).expect("send message"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, send);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+//! Feeds arbitrary bytes, after a valid init line, to `gossip_glomers::run`, the
+//! runner every node reads its input with. No line may panic the runner or the node:
+//! lines that are not messages are dropped, and messages whose payload does not parse
+//! go to `Node::malformed`.
+//!
+//! Run with `cargo fuzz run line` from the repository root. The lines of
+//! `tests/fixtures` make a good seed corpus.
+
+#![no_main]
+
+use std::sync::atomic::AtomicUsize;
+
+use async_trait::async_trait;
+use gossip_glomers::{kv_service::Payload, ErrorCode, Event, Init, Message, Node, Output};
+use libfuzzer_sys::fuzz_target;
+use tokio::sync::Mutex;
+
+const INIT: &[u8] =
+    br#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}
+"#;
+
+/// Answers every key/value request as if the store were empty, and malformed
+/// requests with a malformed-request error, as the nodes of the binaries do.
+struct LineNode {
+    id: AtomicUsize,
+    stdout: Mutex<Output>,
+}
+
+#[async_trait]
+impl Node<Payload> for LineNode {
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: AtomicUsize::new(1),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        let reply = match &message.body.payload {
+            Payload::Read { .. } | Payload::Cas { .. } => Payload::Error {
+                code: ErrorCode::KeyDoesNotExist.code(),
+                text: "not found".to_string(),
+            },
+            Payload::Write { .. } => Payload::WriteOk,
+            _ => return Ok(()),
+        };
+        message
+            .into_reply(Some(&self.id))
+            .map_payload(|_| reply)
+            .send(&self.stdout)
+            .await
+    }
+
+    async fn malformed(
+        &self,
+        message: Message<serde_json::Value>,
+        err: serde_json::Error,
+    ) -> anyhow::Result<()> {
+        if message.body.id.is_none() {
+            return Ok(());
+        }
+        message
+            .into_reply(Some(&self.id))
+            .map_payload(|_| Payload::Error {
+                code: ErrorCode::MalformedRequest.code(),
+                text: err.to_string(),
+            })
+            .send(&self.stdout)
+            .await
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let input = [INIT, data].concat();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build runtime");
+    // Input that is not UTF-8 ends the run with an error, which is not a crash.
+    let _ = runtime.block_on(gossip_glomers::run::<LineNode, Payload, ()>(
+        std::io::Cursor::new(input),
+        tokio::io::sink(),
+    ));
+});
@@ -1,15 +1,22 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    str::FromStr,
     sync::atomic::AtomicUsize,
     time::Duration,
 };
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::{
+    event_loop,
+    merkle::{Digest, MerkleTree},
+    Event, Init, Node, Output,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
+/// Sets of messages and the topology are ordered, so what a node sends does not
+/// depend on hash order and runs with the same seed send the same bytes.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -22,16 +29,81 @@ enum Payload {
     Read,
     ReadOk {
         #[serde(rename = "messages")]
-        msgs: HashSet<usize>,
+        msgs: BTreeSet<usize>,
     },
     Topology {
         #[serde(rename = "topology")]
-        topo: HashMap<String, Vec<String>>,
+        topo: BTreeMap<String, Vec<String>>,
     },
     TopologyOk,
     Gossip {
-        seen: HashSet<usize>,
+        seen: BTreeSet<usize>,
+    },
+    /// Opens a merkle exchange with the digest of the sender's messages.
+    MerkleDigest {
+        digest: Digest,
     },
+    /// Answers a digest with the buckets that differ and the messages the answering
+    /// node has in them. The opener sends the messages it has there that were not
+    /// among them as a `Gossip`.
+    MerkleDiff {
+        buckets: Vec<usize>,
+        seen: BTreeSet<usize>,
+    },
+}
+
+/// How a node brings its neighbors up to date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AntiEntropy {
+    /// Every neighbor gets every round the messages it is not known to have, which
+    /// are only the ones it gossiped back. Most messages are sent over and over.
+    Delta,
+    /// Every neighbor gets a digest of all messages and the two nodes only swap the
+    /// messages in buckets that differ. Sets that agree cost a digest per round, sets
+    /// that differ two more messages.
+    Merkle,
+}
+
+impl FromStr for AntiEntropy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delta" => std::result::Result::Ok(Self::Delta),
+            "merkle" => std::result::Result::Ok(Self::Merkle),
+            _ => anyhow::bail!("unknown anti-entropy {:?}, expected delta or merkle", s),
+        }
+    }
+}
+
+/// Default depth of the merkle tree, for 64 buckets.
+const DEFAULT_MERKLE_DEPTH: u32 = 6;
+
+#[derive(Debug, Clone)]
+struct BroadcastConfig {
+    anti_entropy: AntiEntropy,
+    merkle_depth: u32,
+}
+
+impl BroadcastConfig {
+    /// Reads the configuration from the environment:
+    /// - `BROADCAST_ANTI_ENTROPY`: `delta` to send neighbors the messages they are not
+    ///   known to have, or `merkle` to exchange digests (default delta)
+    /// - `BROADCAST_MERKLE_DEPTH`: depth of the merkle tree, for `2^depth` buckets, at
+    ///   most 16 (default 6)
+    fn from_env() -> anyhow::Result<Self> {
+        let merkle_depth = gossip_glomers::env_or("BROADCAST_MERKLE_DEPTH", DEFAULT_MERKLE_DEPTH)?;
+        if merkle_depth > gossip_glomers::merkle::MAX_DEPTH {
+            anyhow::bail!(
+                "BROADCAST_MERKLE_DEPTH must be at most {}",
+                gossip_glomers::merkle::MAX_DEPTH
+            );
+        }
+        Ok(Self {
+            anti_entropy: gossip_glomers::env_or("BROADCAST_ANTI_ENTROPY", AntiEntropy::Delta)?,
+            merkle_depth,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -43,44 +115,45 @@ enum InjectedPayload {
 
 struct BroadcastNode {
     node: String,
-    msgs: Mutex<HashSet<usize>>,
+    config: BroadcastConfig,
+    msgs: Mutex<BTreeSet<usize>>,
     neighbors: Mutex<Vec<String>>,
-    known: Mutex<HashMap<String, HashSet<usize>>>,
-    stdout: Mutex<tokio::io::Stdout>,
+    known: Mutex<HashMap<String, BTreeSet<usize>>>,
+    stdout: Mutex<Output>,
     id: AtomicUsize,
 }
 
+impl BroadcastNode {
+    async fn merkle_tree(&self) -> anyhow::Result<MerkleTree<usize>> {
+        let msgs = self.msgs.lock().await;
+        MerkleTree::new(self.config.merkle_depth, msgs.iter().copied())
+    }
+}
+
 #[async_trait]
 impl Node<Payload, InjectedPayload> for BroadcastNode {
     fn from_init(
         init: Init,
         tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Mutex<Output>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // Generate a Gossip injection event every 500ms
-        // TODO: handle EOF (AtomicBool?)
-        tokio::spawn(async move {
-            loop {
-                std::thread::sleep(Duration::from_millis(500));
-                if let Err(_) = tx
-                    .send(gossip_glomers::Event::Injected(InjectedPayload::Gossip))
-                    .await
-                {
-                    break;
-                }
-            }
-        });
+        let config = BroadcastConfig::from_env()?;
+        eprintln!("broadcast config: {:?}", config);
+        // Generate a Gossip injection event every 500ms. The timer must not block a
+        // runtime thread, or a node with a single one never handles a message again.
+        gossip_glomers::spawn_timer(tx, Duration::from_millis(500), InjectedPayload::Gossip);
         Ok(Self {
             node: init.node_id,
-            msgs: Mutex::new(HashSet::new()),
+            config,
+            msgs: Mutex::new(BTreeSet::new()),
             neighbors: Mutex::new(Vec::new()),
             known: Mutex::new(
                 init.node_ids
                     .into_iter()
-                    .map(|id| (id, HashSet::new()))
+                    .map(|id| (id, BTreeSet::new()))
                     .collect(),
             ),
             id: 1.into(),
@@ -128,7 +201,7 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
                     Payload::Topology { mut topo } => {
                         *self.neighbors.lock().await = topo
                             .remove(&self.node)
-                            .unwrap_or_else(|| panic!("node {} not found in topology", self.node));
+                            .with_context(|| format!("node {} not found in topology", self.node))?;
                         reply.body.payload = Payload::TopologyOk;
                         reply
                             .send(&self.stdout)
@@ -136,6 +209,55 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
                             .context("send response message")?;
                     }
                     Payload::TopologyOk => {}
+                    Payload::MerkleDigest { digest } => {
+                        let tree = self.merkle_tree().await?;
+                        let buckets = tree.diff(&digest).context("diff merkle digest")?;
+                        if buckets.is_empty() {
+                            return Ok(());
+                        }
+                        let seen = tree.elements_in(&buckets).copied().collect();
+                        reply.body.payload = Payload::MerkleDiff { buckets, seen };
+                        reply.send(&self.stdout).await.context("send merkle diff")?;
+                    }
+                    Payload::MerkleDiff { buckets, seen } => {
+                        let tree = self.merkle_tree().await?;
+                        let missing: BTreeSet<usize> = tree
+                            .elements_in(&buckets)
+                            .filter(|msg| !seen.contains(msg))
+                            .copied()
+                            .collect();
+                        self.msgs.lock().await.extend(seen);
+                        if missing.is_empty() {
+                            return Ok(());
+                        }
+                        reply.body.payload = Payload::Gossip { seen: missing };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send gossip message")?;
+                    }
+                }
+            }
+            gossip_glomers::Event::Injected(_)
+                if self.config.anti_entropy == AntiEntropy::Merkle =>
+            {
+                let digest = self.merkle_tree().await?.digest();
+                for neighbor in self.neighbors.lock().await.iter() {
+                    let to_send = gossip_glomers::Message {
+                        src: self.node.clone(),
+                        dest: neighbor.clone(),
+                        body: gossip_glomers::Body {
+                            id: None,
+                            in_reply_to: None,
+                            payload: Payload::MerkleDigest {
+                                digest: digest.clone(),
+                            },
+                        },
+                    };
+                    to_send
+                        .send(&self.stdout)
+                        .await
+                        .context("send merkle digest")?;
                 }
             }
             gossip_glomers::Event::Injected(_) => {
@@ -145,7 +267,7 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
                         .msgs
                         .lock()
                         .await
-                        .difference(&known_to_n)
+                        .difference(known_to_n)
                         .copied()
                         .collect();
                     let to_send = gossip_glomers::Message {
@@ -172,3 +294,386 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
 async fn main() -> anyhow::Result<()> {
     event_loop::<BroadcastNode, _, _>().await
 }
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::{
+        testkit::{wire, Cluster, Delivery, Fate, Rng},
+        Message,
+    };
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const NODES: [&str; 5] = ["n0", "n1", "n2", "n3", "n4"];
+
+    /// A cluster of NODES where every node neighbors every other.
+    async fn cluster() -> Cluster {
+        seeded_cluster(0).await
+    }
+
+    /// A cluster of NODES where every node neighbors every other, its faults drawn
+    /// from `seed`.
+    async fn seeded_cluster(seed: u64) -> Cluster {
+        let mut cluster = Cluster::builder()
+            .nodes::<BroadcastNode, Payload, InjectedPayload>(&NODES)
+            .seed(seed)
+            .start()
+            .await;
+        let topo: BTreeMap<String, Vec<String>> = NODES
+            .iter()
+            .map(|node| {
+                let others = NODES.iter().filter(|other| *other != node);
+                (
+                    node.to_string(),
+                    others.map(|other| other.to_string()).collect(),
+                )
+            })
+            .collect();
+        for node in NODES {
+            let id = cluster.send("c0", node, Payload::Topology { topo: topo.clone() });
+            let _: Message<Payload> = cluster.expect_reply_to(id).await;
+        }
+        cluster
+    }
+
+    async fn broadcast(cluster: &mut Cluster, node: &str, msg: usize) {
+        let id = cluster.send("c1", node, Payload::Broadcast { msg });
+        let reply: Message<Payload> = cluster.expect_reply_to(id).await;
+        assert!(matches!(reply.body.payload, Payload::BroadcastOk));
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str) -> BTreeSet<usize> {
+        let id = cluster.send("c1", node, Payload::Read);
+        match cluster.expect_reply_to(id).await.body.payload {
+            Payload::ReadOk { msgs } => msgs,
+            payload => panic!("expected read_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcasts_during_a_partition_converge_after_it_heals() {
+        let mut cluster = cluster().await;
+        cluster.partition(&["n0", "n1"], &["n2", "n3", "n4"]);
+        for msg in 0..10 {
+            broadcast(&mut cluster, NODES[msg % NODES.len()], msg).await;
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        let left: BTreeSet<usize> = (0..10).filter(|msg| msg % 5 < 2).collect();
+        assert_eq!(read(&mut cluster, "n1").await, left);
+        assert!(read(&mut cluster, "n4").await.is_disjoint(&left));
+
+        cluster.heal();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        for node in NODES {
+            assert_eq!(
+                read(&mut cluster, node).await,
+                (0..10).collect(),
+                "at {}",
+                node
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcasts_converge_over_lossy_slow_links() {
+        let mut cluster = cluster().await;
+        cluster.set_drop_rate(0.3);
+        for from in NODES {
+            for to in NODES {
+                cluster.set_latency(from, to, Duration::from_millis(100));
+            }
+        }
+        for msg in 0..20 {
+            broadcast(&mut cluster, NODES[msg % NODES.len()], msg).await;
+        }
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        for node in NODES {
+            assert_eq!(
+                read(&mut cluster, node).await,
+                (0..20).collect(),
+                "at {}",
+                node
+            );
+        }
+        let trace = cluster.trace();
+        assert!(trace.iter().any(|delivery| delivery.fate == Fate::Dropped));
+    }
+
+    /// How often nodes gossip, which bounds how fast a message spreads.
+    const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+
+    type Topology = BTreeMap<String, Vec<String>>;
+
+    /// A connected topology of `nodes`: a line, a tree or a random graph, the first two
+    /// as Maelstrom computes them.
+    fn random_topology(rng: &mut Rng, nodes: &[String]) -> Topology {
+        let mut topo: Topology = nodes
+            .iter()
+            .map(|node| (node.clone(), Vec::new()))
+            .collect();
+        let mut link = |a: &String, b: &String| {
+            if a != b && !topo[a].contains(b) {
+                topo.get_mut(a).expect("a node").push(b.clone());
+                topo.get_mut(b).expect("a node").push(a.clone());
+            }
+        };
+        match rng.below(3) {
+            0 => {
+                for pair in nodes.windows(2) {
+                    link(&pair[0], &pair[1]);
+                }
+            }
+            1 => {
+                for (i, node) in nodes.iter().enumerate().skip(1) {
+                    link(&nodes[(i - 1) / 2], node);
+                }
+            }
+            _ => {
+                for (i, node) in nodes.iter().enumerate().skip(1) {
+                    link(&nodes[rng.below(i as u64) as usize], node);
+                }
+                for _ in 0..nodes.len() {
+                    let a = &nodes[rng.below(nodes.len() as u64) as usize];
+                    let b = &nodes[rng.below(nodes.len() as u64) as usize];
+                    link(a, b);
+                }
+            }
+        }
+        topo
+    }
+
+    /// The number of gossip rounds a message needs to get from any node to any other
+    /// in `topo`.
+    fn diameter(topo: &Topology) -> usize {
+        let eccentricity = |from: &String| {
+            let mut seen = BTreeSet::from([from]);
+            let mut frontier = vec![from];
+            let mut hops = 0;
+            while !frontier.is_empty() {
+                frontier = frontier
+                    .iter()
+                    .flat_map(|node| &topo[*node])
+                    .filter(|node| seen.insert(*node))
+                    .collect();
+                hops += 1;
+            }
+            hops - 1
+        };
+        topo.keys().map(eccentricity).max().unwrap_or_default()
+    }
+
+    /// Messages between nodes allowed per broadcast, from BROADCAST_TEST_BUDGET
+    /// (default 20).
+    fn budget() -> f64 {
+        std::env::var("BROADCAST_TEST_BUDGET")
+            .map(|budget| budget.parse().expect("BROADCAST_TEST_BUDGET is a number"))
+            .unwrap_or(20.0)
+    }
+
+    /// What a random run did, dumped when it breaks a property.
+    struct Run {
+        seed: u64,
+        anti_entropy: &'static str,
+        topo: Topology,
+        trace: Vec<Delivery>,
+    }
+
+    impl Run {
+        fn fail(&self, err: String) -> ! {
+            let trace: Vec<String> = self
+                .trace
+                .iter()
+                .map(|delivery| format!("{:?} {:?} {}", delivery.at, delivery.fate, delivery.line))
+                .collect();
+            panic!(
+                "seed {} ({} anti-entropy): {}\ntopology: {:?}\ntrace:\n{}",
+                self.seed,
+                self.anti_entropy,
+                err,
+                self.topo,
+                trace.join("\n")
+            );
+        }
+    }
+
+    /// Broadcasts at random nodes of a random topology, cutting it in two for a
+    /// random window, then checks that every node reads every message within a gossip
+    /// round per hop of the healed topology and that gossip stayed within budget.
+    async fn random_run(seed: u64) {
+        let mut rng = Rng::new(seed);
+        let nodes: Vec<String> = (0..3 + rng.below(4)).map(|i| format!("n{}", i)).collect();
+        let ids: Vec<&str> = nodes.iter().map(String::as_str).collect();
+        let anti_entropy = ["delta", "merkle"][rng.below(2) as usize];
+        let mut cluster = Cluster::builder()
+            .nodes::<BroadcastNode, Payload, InjectedPayload>(&ids)
+            .env(&[("BROADCAST_ANTI_ENTROPY", Some(anti_entropy))])
+            .seed(seed)
+            .start()
+            .await;
+        let topo = random_topology(&mut rng, &nodes);
+        for node in &ids {
+            let id = cluster.send("c0", node, Payload::Topology { topo: topo.clone() });
+            let _: Message<Payload> = cluster.expect_reply_to(id).await;
+        }
+
+        let start = tokio::time::Instant::now();
+        let split = 1 + rng.below(ids.len() as u64 - 1) as usize;
+        let (cut_at, heal_at) = (rng.below(10), 10 + rng.below(10));
+        let msgs = 20;
+        for msg in 0..msgs {
+            if msg == cut_at {
+                cluster.partition(&ids[..split], &ids[split..]);
+            }
+            if msg == heal_at {
+                cluster.heal();
+            }
+            broadcast(
+                &mut cluster,
+                ids[rng.below(ids.len() as u64) as usize],
+                msg as usize,
+            )
+            .await;
+            tokio::time::sleep(Duration::from_millis(rng.below(300))).await;
+        }
+        cluster.heal();
+        let rounds = diameter(&topo) as u32 + 1;
+        tokio::time::sleep(GOSSIP_INTERVAL * rounds).await;
+        let end = tokio::time::Instant::now();
+
+        let mut reads = Vec::new();
+        for node in &ids {
+            reads.push((node, read(&mut cluster, node).await));
+        }
+        let run = Run {
+            seed,
+            anti_entropy,
+            topo,
+            trace: cluster.trace(),
+        };
+        let all: BTreeSet<usize> = (0..msgs as usize).collect();
+        for (node, read) in reads {
+            if read != all {
+                run.fail(format!(
+                    "{} misses {:?} {} rounds after healing",
+                    node,
+                    all.difference(&read).collect::<Vec<_>>(),
+                    rounds
+                ));
+            }
+        }
+        let gossip = run
+            .trace
+            .iter()
+            .filter(|delivery| delivery.at >= start && delivery.at <= end)
+            .filter(|delivery| nodes.contains(&delivery.from) && nodes.contains(&delivery.to))
+            .count();
+        let per_broadcast = gossip as f64 / msgs as f64;
+        if per_broadcast > budget() {
+            run.fail(format!(
+                "{} messages between nodes per broadcast, over the budget of {}",
+                per_broadcast,
+                budget()
+            ));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_runs_converge_within_budget() {
+        for seed in 0..20 {
+            random_run(seed).await;
+        }
+    }
+
+    /// Runs broadcasts on a lossy, jittery network that is cut in two for a while, on
+    /// a runtime of its own, and returns the trace with times relative to the start.
+    fn seeded_trace(seed: u64) -> Vec<String> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .expect("build runtime");
+        runtime.block_on(async {
+            let start = tokio::time::Instant::now();
+            let mut cluster = seeded_cluster(seed).await;
+            cluster.set_drop_rate(0.2);
+            cluster.set_jitter(Duration::from_millis(50));
+            cluster.partition(&["n0", "n1"], &["n2", "n3", "n4"]);
+            for msg in 0..10 {
+                broadcast(&mut cluster, NODES[msg % NODES.len()], msg).await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            cluster.heal();
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            for node in NODES {
+                assert_eq!(read(&mut cluster, node).await.len(), 10, "seed {}", seed);
+            }
+            cluster
+                .trace()
+                .iter()
+                .map(|delivery| {
+                    format!(
+                        "{:?} {:?} {}",
+                        delivery.at - start,
+                        delivery.fate,
+                        delivery.line
+                    )
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_trace() {
+        for seed in 0..3 {
+            let trace = seeded_trace(seed);
+            let again = seeded_trace(seed);
+            if let Some(i) =
+                (0..trace.len().max(again.len())).find(|&i| trace.get(i) != again.get(i))
+            {
+                panic!(
+                    "seed {}: the traces differ at message {}:\n{:?}\n{:?}",
+                    seed,
+                    i,
+                    trace.get(i),
+                    again.get(i)
+                );
+            }
+        }
+        assert_ne!(seeded_trace(0), seeded_trace(1));
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let seen = || prop::collection::btree_set(any::<usize>(), 0..8);
+        prop_oneof![
+            any::<usize>().prop_map(|msg| Payload::Broadcast { msg }),
+            Just(Payload::BroadcastOk),
+            Just(Payload::Read),
+            seen().prop_map(|msgs| Payload::ReadOk { msgs }),
+            prop::collection::btree_map(
+                wire::node_id(),
+                prop::collection::vec(wire::node_id(), 0..4),
+                0..4
+            )
+            .prop_map(|topo| Payload::Topology { topo }),
+            Just(Payload::TopologyOk),
+            seen().prop_map(|seen| Payload::Gossip { seen }),
+            wire::digest().prop_map(|digest| Payload::MerkleDigest { digest }),
+            (prop::collection::vec(any::<usize>(), 0..4), seen())
+                .prop_map(|(buckets, seen)| Payload::MerkleDiff { buckets, seen }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("broadcast");
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop,
+    kv_service::{self, Store},
+    rpc::Rpc,
+    Body, ErrorCode, Event, Init, Message, Node, Output,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{watch, Mutex};
+
+/// Messages between the nodes of the chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum ChainPayload {
+    /// Passes a write down the chain.
+    Propagate(Entry),
+    /// Answers a Propagate once the tail applied the write.
+    PropagateOk { seq: u64 },
+}
+
+/// Either a client request or reply, which may also be forwarded between nodes, or a
+/// message of the chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Payload {
+    Kv(kv_service::Payload),
+    Chain(ChainPayload),
+}
+
+/// A write as the head ordered it. Cas is decided at the head and travels as the
+/// write it turned into.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    seq: u64,
+    key: Value,
+    value: Value,
+}
+
+/// Default time a node waits for its successor to acknowledge a write before it sends
+/// the write again.
+const DEFAULT_HOP_TIMEOUT_MS: u64 = 500;
+
+/// Default time a node waits for the head or tail to answer a forwarded request.
+const DEFAULT_FORWARD_TIMEOUT_MS: u64 = 3000;
+
+#[derive(Debug, Clone)]
+struct ChainKVConfig {
+    hop_timeout: Duration,
+    forward_timeout: Duration,
+}
+
+impl ChainKVConfig {
+    /// Reads the configuration from the environment:
+    /// - `CHAIN_KV_HOP_TIMEOUT_MS`: time a node waits for its successor to acknowledge
+    ///   a write before sending it again in milliseconds (default 500)
+    /// - `CHAIN_KV_FORWARD_TIMEOUT_MS`: time a node waits for the head or the tail to
+    ///   answer a forwarded client request in milliseconds, after which the client
+    ///   gets a timeout (default 3000)
+    fn from_env() -> anyhow::Result<Self> {
+        let hop_timeout_ms =
+            gossip_glomers::env_or("CHAIN_KV_HOP_TIMEOUT_MS", DEFAULT_HOP_TIMEOUT_MS)?;
+        if hop_timeout_ms == 0 {
+            anyhow::bail!("CHAIN_KV_HOP_TIMEOUT_MS must be greater than 0");
+        }
+        let forward_timeout_ms =
+            gossip_glomers::env_or("CHAIN_KV_FORWARD_TIMEOUT_MS", DEFAULT_FORWARD_TIMEOUT_MS)?;
+        if forward_timeout_ms == 0 {
+            anyhow::bail!("CHAIN_KV_FORWARD_TIMEOUT_MS must be greater than 0");
+        }
+        Ok(Self {
+            hop_timeout: Duration::from_millis(hop_timeout_ms),
+            forward_timeout: Duration::from_millis(forward_timeout_ms),
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    store: Store,
+    /// Writes that arrived ahead of one before them, by seq.
+    buffer: BTreeMap<u64, Entry>,
+}
+
+/// A linearizable key/value store on a chain of all nodes, in the order of their ids.
+/// Writes and cas go to the head, which decides them against its store, numbers them
+/// and passes them down the chain node by node. Every node applies them in that
+/// order, buffering the ones that overtook an earlier write, and the client is answered
+/// once the tail applied its write. Reads go to the tail, which only ever holds writes
+/// every node before it has, so a read never sees a write that could still be lost.
+/// Other nodes forward the requests they get to the head or tail and pass the answer
+/// back.
+///
+/// The chain never changes. A node whose successor does not acknowledge a write in
+/// time sends it again, and keeps doing so, so a slow or partitioned node only delays
+/// the writes behind it and loses none. A node that crashes stalls every write until
+/// it is back. Replacing it would take a configuration service that removes it from
+/// the chain and tells its neighbors, which this node does not have.
+struct ChainKVNode {
+    id: AtomicUsize,
+    node: String,
+    head: String,
+    tail: String,
+    /// The next node down the chain, or None on the tail.
+    successor: Option<String>,
+    config: ChainKVConfig,
+    state: Mutex<State>,
+    /// The seq of the last write applied here. Writes apply in order, so all writes
+    /// up to it are.
+    applied: watch::Sender<u64>,
+    /// The seq of the last write the tail applied, as far as this node knows.
+    committed: watch::Sender<u64>,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+impl ChainKVNode {
+    fn next_id(&self) -> usize {
+        self.id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Applies a write or cas on the head and returns the reply once the tail applied
+    /// it. A failed request is answered once the writes it was decided against are
+    /// committed, so a read at the tail right after it sees what it saw.
+    async fn write(&self, request: kv_service::Payload) -> anyhow::Result<kv_service::Payload> {
+        let key = match &request {
+            kv_service::Payload::Write { key, .. } | kv_service::Payload::Cas { key, .. } => {
+                key.clone()
+            }
+            request => anyhow::bail!("not a write: {:?}", request),
+        };
+        let mut state = self.state.lock().await;
+        let reply = kv_service::apply(&mut state.store, request);
+        let seq = *self.applied.borrow();
+        let entry = match reply {
+            kv_service::Payload::WriteOk | kv_service::Payload::CasOk => {
+                let value = state.store[&key.to_string()].clone();
+                self.applied.send_replace(seq + 1);
+                Some(Entry {
+                    seq: seq + 1,
+                    key,
+                    value,
+                })
+            }
+            _ => None,
+        };
+        drop(state);
+        match entry {
+            Some(entry) => self.replicate(entry).await?,
+            None => self.wait_committed(seq).await?,
+        }
+        Ok(reply)
+    }
+
+    /// Buffers a write from the predecessor, applies every write that is next in
+    /// order, and returns once the tail applied it. A write sent again by a predecessor
+    /// that gave up waiting is already on its way down the chain, and is only waited for.
+    async fn propagate(&self, entry: Entry) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        let seq = entry.seq;
+        if seq <= *self.applied.borrow() || state.buffer.contains_key(&seq) {
+            drop(state);
+            return self.wait_committed(seq).await;
+        }
+        state.buffer.insert(seq, entry.clone());
+        let mut applied = *self.applied.borrow();
+        while let Some(next) = state.buffer.remove(&(applied + 1)) {
+            state.store.insert(next.key.to_string(), next.value);
+            applied += 1;
+        }
+        self.applied.send_replace(applied);
+        drop(state);
+        self.replicate(entry).await
+    }
+
+    /// Passes a write applied or buffered here down the chain and returns once the tail
+    /// applied it. Only the tail has nowhere to pass it, and waits until it applied
+    /// every write before it as well.
+    async fn replicate(&self, entry: Entry) -> anyhow::Result<()> {
+        let seq = entry.seq;
+        let Some(successor) = &self.successor else {
+            let mut applied = self.applied.subscribe();
+            applied
+                .wait_for(|applied| *applied >= seq)
+                .await
+                .context("wait for write to apply")?;
+            self.commit(seq);
+            return Ok(());
+        };
+        loop {
+            let message = Message {
+                src: self.node.clone(),
+                dest: successor.clone(),
+                body: Body {
+                    id: Some(self.next_id()),
+                    in_reply_to: None,
+                    payload: Payload::Chain(ChainPayload::Propagate(entry.clone())),
+                },
+            };
+            match self
+                .rpc
+                .call(message, self.config.hop_timeout, &self.stdout)
+                .await
+            {
+                std::result::Result::Ok(_) => break,
+                // The successor is slow or cut off: keep the write and try again, the
+                // successor ignores it if it got it already.
+                Err(err) if ErrorCode::of(&err) == Some(ErrorCode::Timeout) => {
+                    eprintln!("propagate {} to {}: {:#}", seq, successor, err);
+                }
+                Err(err) => return Err(err).context("propagate write"),
+            }
+        }
+        // The tail applies writes in order, so it applied every write before this one.
+        self.commit(seq);
+        Ok(())
+    }
+
+    /// Records that the tail applied the write `seq`.
+    fn commit(&self, seq: u64) {
+        self.committed.send_if_modified(|committed| {
+            let advanced = seq > *committed;
+            *committed = (*committed).max(seq);
+            advanced
+        });
+    }
+
+    /// Waits until the tail applied the write `seq`, which is immediate for seq 0.
+    async fn wait_committed(&self, seq: u64) -> anyhow::Result<()> {
+        if self.successor.is_none() {
+            let mut applied = self.applied.subscribe();
+            applied
+                .wait_for(|applied| *applied >= seq)
+                .await
+                .context("wait for writes to apply")?;
+            return Ok(());
+        }
+        let mut committed = self.committed.subscribe();
+        committed
+            .wait_for(|committed| *committed >= seq)
+            .await
+            .context("wait for writes to commit")?;
+        Ok(())
+    }
+
+    /// Answers a client request here if this node is where it belongs, or forwards it
+    /// to the head or tail.
+    async fn serve(&self, request: Message<kv_service::Payload>) -> anyhow::Result<()> {
+        let target = match request.body.payload {
+            kv_service::Payload::Read { .. } => &self.tail,
+            _ => &self.head,
+        };
+        if *target != self.node {
+            let id = self.next_id();
+            let mut reply = match self
+                .rpc
+                .forward(
+                    request.clone().map_payload(Payload::Kv),
+                    target,
+                    id,
+                    self.config.forward_timeout,
+                    &self.stdout,
+                )
+                .await
+            {
+                std::result::Result::Ok(reply) => reply,
+                Err(err) => {
+                    let code = match ErrorCode::of(&err) {
+                        Some(ErrorCode::Timeout) => ErrorCode::Timeout,
+                        _ => ErrorCode::Crash,
+                    };
+                    let mut reply = request.into_reply(None).map_payload(Payload::Kv);
+                    reply.body.payload = Payload::Kv(kv_service::Payload::Error {
+                        code: code.code(),
+                        text: format!("forward to {}: {:#}", target, err),
+                    });
+                    reply
+                }
+            };
+            reply.body.id = Some(self.next_id());
+            reply.send(&self.stdout).await.context("send reply")?;
+            return Ok(());
+        }
+        let mut reply = request.into_reply(Some(&self.id));
+        reply.body.payload = match reply.body.payload {
+            kv_service::Payload::Read { key } => {
+                let mut state = self.state.lock().await;
+                kv_service::apply(&mut state.store, kv_service::Payload::Read { key })
+            }
+            request => self.write(request).await?,
+        };
+        reply
+            .map_payload(Payload::Kv)
+            .send(&self.stdout)
+            .await
+            .context("send reply")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Node<Payload> for ChainKVNode {
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = ChainKVConfig::from_env()?;
+        eprintln!("chain_kv config: {:?}", config);
+        let mut chain = init.node_ids.clone();
+        chain.sort();
+        let position = chain
+            .iter()
+            .position(|node| *node == init.node_id)
+            .with_context(|| format!("node {} not in node ids", init.node_id))?;
+        Ok(Self {
+            id: 1.into(),
+            head: chain[0].clone(),
+            tail: chain[chain.len() - 1].clone(),
+            successor: chain.get(position + 1).cloned(),
+            node: init.node_id,
+            config,
+            state: Mutex::default(),
+            applied: watch::channel(0).0,
+            committed: watch::channel(0).0,
+            rpc: Rpc::new(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        // Answers to propagated writes and forwarded requests go to whoever waits for them.
+        let Some(message) = self.rpc.resolve(message).await else {
+            return Ok(());
+        };
+        match message.body.payload {
+            Payload::Chain(ChainPayload::Propagate(entry)) => {
+                let seq = entry.seq;
+                self.propagate(entry).await?;
+                let reply = Message {
+                    src: message.dest,
+                    dest: message.src,
+                    body: Body {
+                        id: Some(self.next_id()),
+                        in_reply_to: message.body.id,
+                        payload: Payload::Chain(ChainPayload::PropagateOk { seq }),
+                    },
+                };
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send propagate_ok")?;
+            }
+            Payload::Chain(ChainPayload::PropagateOk { .. }) => {}
+            Payload::Kv(payload) if payload.is_reply() => {}
+            Payload::Kv(request) => {
+                let request = Message {
+                    src: message.src,
+                    dest: message.dest,
+                    body: Body {
+                        id: message.body.id,
+                        in_reply_to: message.body.in_reply_to,
+                        payload: request,
+                    },
+                };
+                self.serve(request).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<ChainKVNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Cluster, Fate};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    async fn cluster() -> Cluster {
+        Cluster::builder()
+            .nodes::<ChainKVNode, Payload, ()>(&NODES)
+            .start()
+            .await
+    }
+
+    async fn request(
+        cluster: &mut Cluster,
+        node: &str,
+        request: kv_service::Payload,
+    ) -> kv_service::Payload {
+        let id = cluster.send("c1", node, Payload::Kv(request));
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            Payload::Kv(reply) => reply,
+            payload => panic!("expected a kv reply, got {:?}", payload),
+        }
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str) -> Value {
+        match request(cluster, node, kv_service::Payload::Read { key: json!("x") }).await {
+            kv_service::Payload::ReadOk { value } => value,
+            reply => panic!("expected read_ok, got {:?}", reply),
+        }
+    }
+
+    fn write(value: i64) -> kv_service::Payload {
+        kv_service::Payload::Write {
+            key: json!("x"),
+            value: json!(value),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_anywhere_see_writes_acknowledged_anywhere() {
+        let mut cluster = cluster().await;
+        for value in 0..9 {
+            let writer = NODES[value as usize % NODES.len()];
+            let reader = NODES[(value as usize + 1) % NODES.len()];
+            let reply = request(&mut cluster, writer, write(value)).await;
+            assert!(matches!(reply, kv_service::Payload::WriteOk));
+            assert_eq!(read(&mut cluster, reader).await, json!(value));
+        }
+        let cas = kv_service::Payload::Cas {
+            key: json!("x"),
+            from: json!(8),
+            to: json!(9),
+            create_if_not_exists: false,
+        };
+        assert!(matches!(
+            request(&mut cluster, "n2", cas).await,
+            kv_service::Payload::CasOk
+        ));
+        assert_eq!(read(&mut cluster, "n1").await, json!(9));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_survive_a_cut_off_middle_hop() {
+        let mut cluster = cluster().await;
+        cluster.partition(&["n1"], &["n0", "n2"]);
+        let id = cluster.send("c1", "n0", Payload::Kv(write(1)));
+        tokio::time::sleep(Duration::from_millis(DEFAULT_HOP_TIMEOUT_MS * 3)).await;
+        cluster.heal();
+        let reply = cluster.expect_reply_to::<Payload>(id).await;
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Kv(kv_service::Payload::WriteOk)
+        ));
+        assert_eq!(read(&mut cluster, "n2").await, json!(1));
+
+        let lost = cluster
+            .trace()
+            .into_iter()
+            .filter(|delivery| delivery.to == "n1" && delivery.fate == Fate::Dropped)
+            .filter(|delivery| delivery.line.contains(r#""type":"propagate""#))
+            .count();
+        assert!(lost >= 2, "the head sent the write again, {} lost", lost);
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let entry = (any::<u64>(), wire::json(), wire::json())
+            .prop_map(|(seq, key, value)| Entry { seq, key, value });
+        prop_oneof![
+            wire::service_payload().prop_map(Payload::Kv),
+            entry.prop_map(|entry| Payload::Chain(ChainPayload::Propagate(entry))),
+            any::<u64>().prop_map(|seq| Payload::Chain(ChainPayload::PropagateOk { seq })),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("lin_kv");
+    }
+}
@@ -1,8 +1,13 @@
-use std::{cmp, collections::HashMap, sync::atomic::AtomicUsize, time::Duration};
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::{event_loop, rpc::Rpc, Event, Init, Message, Node, Output};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
@@ -10,11 +15,26 @@ use tokio::sync::Mutex;
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Add { delta: u64 },
+    Add {
+        delta: u64,
+    },
     AddOk,
     Read,
-    ReadOk { value: u64 },
-    Sync { value: u64 },
+    ReadOk {
+        value: u64,
+        /// Per-node contributions to `value`, only sent in debug mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        breakdown: Option<HashMap<String, u64>>,
+    },
+    Sync {
+        counters: HashMap<String, u64>,
+        /// Sum of `counters`. Entries only ever grow, so this is monotone per sender.
+        version: u64,
+    },
+    StateRequest,
+    StateReply {
+        counters: HashMap<String, u64>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -24,12 +44,180 @@ enum InjectedPayload {
     Sync,
 }
 
+/// Default interval between two rounds of Sync messages.
+const DEFAULT_SYNC_MS: u64 = 500;
+
+/// Default time a quorum read waits for peers before falling back to the local value.
+const DEFAULT_QUORUM_TIMEOUT_MS: u64 = 200;
+
+/// Sync intervals below this flood the network and are most likely a typo.
+const MIN_RECOMMENDED_SYNC_MS: u64 = 10;
+
+#[derive(Debug, Clone)]
+struct CounterConfig {
+    sync_period: Duration,
+    quorum_read: bool,
+    quorum_timeout: Duration,
+    debug_read: bool,
+}
+
+impl CounterConfig {
+    /// Reads the configuration from the environment:
+    /// - `COUNTER_SYNC_MS`: interval between Sync rounds in milliseconds (default 500)
+    /// - `COUNTER_QUORUM_READ`: answer reads from a majority of nodes (default false)
+    /// - `COUNTER_QUORUM_TIMEOUT_MS`: how long a quorum read waits for peers (default 200)
+    /// - `COUNTER_DEBUG_READ`: include the per-node breakdown in read_ok (default false)
+    fn from_env() -> anyhow::Result<Self> {
+        let sync_ms = gossip_glomers::env_or("COUNTER_SYNC_MS", DEFAULT_SYNC_MS)?;
+        if let Some(warning) = check_sync_ms(sync_ms)? {
+            eprintln!("warning: {}", warning);
+        }
+        let quorum_timeout_ms =
+            gossip_glomers::env_or("COUNTER_QUORUM_TIMEOUT_MS", DEFAULT_QUORUM_TIMEOUT_MS)?;
+        Ok(Self {
+            sync_period: Duration::from_millis(sync_ms),
+            quorum_read: gossip_glomers::env_or("COUNTER_QUORUM_READ", false)?,
+            quorum_timeout: Duration::from_millis(quorum_timeout_ms),
+            debug_read: gossip_glomers::env_or("COUNTER_DEBUG_READ", false)?,
+        })
+    }
+}
+
+/// Rejects a sync interval of 0, and returns a warning for intervals that are allowed
+/// but most likely a typo.
+fn check_sync_ms(sync_ms: u64) -> anyhow::Result<Option<String>> {
+    if sync_ms == 0 {
+        anyhow::bail!("COUNTER_SYNC_MS must be greater than 0");
+    }
+    Ok((sync_ms < MIN_RECOMMENDED_SYNC_MS).then(|| {
+        format!(
+            "COUNTER_SYNC_MS={} is below {}ms",
+            sync_ms, MIN_RECOMMENDED_SYNC_MS
+        )
+    }))
+}
+
+/// Returns how many peers a quorum read waits for: with this node, a majority.
+fn peers_needed(nodes: usize) -> usize {
+    nodes / 2
+}
+
+/// Merges the counters of the replies to a quorum read into `counter`, keeping the
+/// highest value of every node.
+fn merge_state_replies(counter: &mut HashMap<String, u64>, replies: &[Message<Payload>]) {
+    for reply in replies {
+        if let Payload::StateReply { counters } = &reply.body.payload {
+            for (node, value) in counters {
+                if let Some(current) = counter.get_mut(node) {
+                    *current = cmp::max(*current, *value);
+                }
+            }
+        }
+    }
+}
+
+/// Records the version of a Sync from `from` and returns whether it is newer than every
+/// earlier one, which is when it may carry something new.
+fn accept_sync(synced: &mut HashMap<String, u64>, from: &str, version: u64) -> bool {
+    let last = synced.entry(from.to_string()).or_default();
+    if version <= *last {
+        return false;
+    }
+    *last = version;
+    true
+}
+
+/// Merges the counters of a Sync into `counter` and returns how many entries grew.
+fn merge_sync(counter: &mut HashMap<String, u64>, counters: &HashMap<String, u64>) -> u64 {
+    let mut changed = 0;
+    for (node, value) in counters {
+        if let Some(current) = counter.get_mut(node) {
+            if *value > *current {
+                *current = *value;
+                changed += 1;
+            }
+        }
+    }
+    changed
+}
+
 struct CounterNode {
     id: AtomicUsize,
     node: String,
     nodes: Vec<String>,
     counter: Mutex<HashMap<String, u64>>,
-    stdout: Mutex<tokio::io::Stdout>,
+    stdout: Mutex<Output>,
+    rpc: Rpc<Payload>,
+    config: CounterConfig,
+    /// Highest Sync version merged so far, per sender.
+    synced: Mutex<HashMap<String, u64>>,
+    stats: SyncStats,
+}
+
+/// Counters describing how much of the Sync traffic carried new information.
+#[derive(Default)]
+struct SyncStats {
+    received: AtomicU64,
+    skipped: AtomicU64,
+    entries_merged: AtomicU64,
+    entries_changed: AtomicU64,
+}
+
+impl SyncStats {
+    fn report(&self) {
+        let received = self.received.load(Ordering::Relaxed);
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        let merged = self.entries_merged.load(Ordering::Relaxed);
+        let changed = self.entries_changed.load(Ordering::Relaxed);
+        eprintln!(
+            "sync stats: received={} skipped={} entries_merged={} entries_changed={} wasted={:.2}",
+            received,
+            skipped,
+            merged,
+            changed,
+            1.0 - changed as f64 / merged.max(1) as f64,
+        );
+    }
+}
+
+impl CounterNode {
+    /// Asks every peer for its view of the counters and merges the replies once a
+    /// majority of the cluster (counting this node) has answered. Falls back to the
+    /// local view if the quorum is not reached before the configured timeout. Returns
+    /// the merged counters.
+    async fn quorum_read(&self) -> anyhow::Result<HashMap<String, u64>> {
+        let requests = self
+            .nodes
+            .iter()
+            .filter(|node| *node != &self.node)
+            .map(|node| gossip_glomers::Message {
+                src: self.node.clone(),
+                dest: node.clone(),
+                body: gossip_glomers::Body {
+                    id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                    in_reply_to: None,
+                    payload: Payload::StateRequest,
+                },
+            })
+            .collect();
+        let needed = peers_needed(self.nodes.len());
+        let replies = self
+            .rpc
+            .quorum(requests, needed, self.config.quorum_timeout, &self.stdout)
+            .await
+            .context("state request quorum")?;
+
+        let mut counter = self.counter.lock().await;
+        merge_state_replies(&mut counter, &replies);
+        if replies.len() < needed {
+            eprintln!(
+                "quorum read got {}/{} replies, serving local value",
+                replies.len(),
+                needed
+            );
+        }
+        Ok(counter.clone())
+    }
 }
 
 #[async_trait]
@@ -37,24 +225,14 @@ impl Node<Payload, InjectedPayload> for CounterNode {
     fn from_init(
         init: Init,
         tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Mutex<Output>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // Generate a Gossip injection event every 500ms
-        // TODO: handle EOF (AtomicBool?)
-        tokio::spawn(async move {
-            loop {
-                std::thread::sleep(Duration::from_millis(500));
-                if let Err(_) = tx
-                    .send(gossip_glomers::Event::Injected(InjectedPayload::Sync))
-                    .await
-                {
-                    break;
-                }
-            }
-        });
+        let config = CounterConfig::from_env()?;
+        eprintln!("counter config: {:?}", config);
+        gossip_glomers::spawn_timer(tx, config.sync_period, InjectedPayload::Sync);
 
         Ok(Self {
             id: 1.into(),
@@ -62,6 +240,10 @@ impl Node<Payload, InjectedPayload> for CounterNode {
             nodes: init.node_ids.clone(),
             counter: Mutex::new(init.node_ids.into_iter().map(|id| (id, 0)).collect()),
             stdout,
+            rpc: Rpc::new(),
+            config,
+            synced: Mutex::new(HashMap::new()),
+            stats: SyncStats::default(),
         })
     }
 
@@ -70,8 +252,11 @@ impl Node<Payload, InjectedPayload> for CounterNode {
         event: gossip_glomers::Event<Payload, InjectedPayload>,
     ) -> anyhow::Result<()> {
         match event {
-            gossip_glomers::Event::EOF => {}
+            gossip_glomers::Event::EOF => self.stats.report(),
             gossip_glomers::Event::Message(message) => {
+                let Some(message) = self.rpc.resolve(message).await else {
+                    return Ok(());
+                };
                 let mut reply = message.into_reply(Some(&self.id));
                 match reply.body.payload {
                     Payload::Add { delta } => {
@@ -86,22 +271,47 @@ impl Node<Payload, InjectedPayload> for CounterNode {
                     }
                     Payload::AddOk => {}
                     Payload::Read => {
-                        let value = self.counter.lock().await.iter().map(|(_, v)| v).sum();
-                        reply.body.payload = Payload::ReadOk { value };
+                        // One snapshot, so the breakdown always sums to the value.
+                        let counters = if self.config.quorum_read {
+                            self.quorum_read().await?
+                        } else {
+                            self.counter.lock().await.clone()
+                        };
+                        let value = counters.values().sum();
+                        let breakdown = self.config.debug_read.then_some(counters);
+                        reply.body.payload = Payload::ReadOk { value, breakdown };
                         reply
                             .send(&self.stdout)
                             .await
                             .context("send read response")?;
                     }
                     Payload::ReadOk { .. } => {}
-                    Payload::Sync { value } => {
-                        if let Some(current) = self.counter.lock().await.get_mut(&reply.dest) {
-                            *current = cmp::max(*current, value);
+                    Payload::Sync { counters, version } => {
+                        self.stats.received.fetch_add(1, Ordering::Relaxed);
+                        if !accept_sync(&mut *self.synced.lock().await, &reply.dest, version) {
+                            self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+                            return Ok(());
                         }
+                        let changed = merge_sync(&mut *self.counter.lock().await, &counters);
+                        self.stats
+                            .entries_merged
+                            .fetch_add(counters.len() as u64, Ordering::Relaxed);
+                        self.stats
+                            .entries_changed
+                            .fetch_add(changed, Ordering::Relaxed);
                     }
+                    Payload::StateRequest => {
+                        reply.body.payload = Payload::StateReply {
+                            counters: self.counter.lock().await.clone(),
+                        };
+                        reply.send(&self.stdout).await.context("send state reply")?;
+                    }
+                    Payload::StateReply { .. } => {}
                 }
             }
             gossip_glomers::Event::Injected(_) => {
+                let counters = self.counter.lock().await.clone();
+                let version = counters.values().sum();
                 for node in &self.nodes {
                     if node != &self.node {
                         let sync_msg = gossip_glomers::Message {
@@ -111,7 +321,8 @@ impl Node<Payload, InjectedPayload> for CounterNode {
                                 id: None,
                                 in_reply_to: None,
                                 payload: Payload::Sync {
-                                    value: self.counter.lock().await[&self.node],
+                                    counters: counters.clone(),
+                                    version,
                                 },
                             },
                         };
@@ -131,3 +342,280 @@ impl Node<Payload, InjectedPayload> for CounterNode {
 async fn main() -> anyhow::Result<()> {
     event_loop::<CounterNode, _, _>().await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gossip_glomers::testkit::{assert_golden, wire, with_env, Cluster, Harness};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn sync_ms_of_zero_is_rejected() {
+        assert!(check_sync_ms(0).is_err());
+    }
+
+    #[test]
+    fn low_sync_ms_is_warned_about() {
+        let warning = check_sync_ms(MIN_RECOMMENDED_SYNC_MS - 1).unwrap();
+        assert!(warning.is_some_and(|warning| warning.contains("COUNTER_SYNC_MS=9")));
+        assert_eq!(check_sync_ms(MIN_RECOMMENDED_SYNC_MS).unwrap(), None);
+        assert_eq!(check_sync_ms(DEFAULT_SYNC_MS).unwrap(), None);
+    }
+
+    fn counters(values: &[(&str, u64)]) -> HashMap<String, u64> {
+        values
+            .iter()
+            .map(|(node, value)| (node.to_string(), *value))
+            .collect()
+    }
+
+    fn state_request(id: usize, to: &str) -> Message<Payload> {
+        Message {
+            src: "n0".to_string(),
+            dest: to.to_string(),
+            body: gossip_glomers::Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload: Payload::StateRequest,
+            },
+        }
+    }
+
+    /// Answers state request `id` from `from` after `delay`.
+    fn answer_later(
+        rpc: &Arc<Rpc<Payload>>,
+        delay: Duration,
+        from: &str,
+        id: usize,
+        values: &[(&str, u64)],
+    ) {
+        let rpc = rpc.clone();
+        let reply = Message {
+            src: from.to_string(),
+            dest: "n0".to_string(),
+            body: gossip_glomers::Body {
+                id: None,
+                in_reply_to: Some(id),
+                payload: Payload::StateReply {
+                    counters: counters(values),
+                },
+            },
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            assert!(rpc.resolve(reply).await.is_none(), "reply was not awaited");
+        });
+    }
+
+    #[test]
+    fn quorum_counts_this_node() {
+        assert_eq!(peers_needed(1), 0);
+        assert_eq!(peers_needed(3), 1);
+        assert_eq!(peers_needed(4), 2);
+        assert_eq!(peers_needed(5), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quorum_read_met() {
+        let rpc = Arc::new(Rpc::new());
+        let out = Mutex::new(tokio::io::sink());
+        let timeout = Duration::from_millis(DEFAULT_QUORUM_TIMEOUT_MS);
+        answer_later(&rpc, Duration::from_millis(10), "n1", 1, &[("n1", 5)]);
+        answer_later(&rpc, Duration::from_millis(20), "n2", 2, &[("n2", 7)]);
+
+        let started = tokio::time::Instant::now();
+        let requests = vec![state_request(1, "n1"), state_request(2, "n2")];
+        let replies = rpc.quorum(requests, 2, timeout, &out).await.unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(
+            tokio::time::Instant::now() - started,
+            Duration::from_millis(20)
+        );
+
+        let mut counter = counters(&[("n0", 1), ("n1", 2), ("n2", 0)]);
+        merge_state_replies(&mut counter, &replies);
+        assert_eq!(counter, counters(&[("n0", 1), ("n1", 5), ("n2", 7)]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quorum_read_timed_out_serves_what_arrived() {
+        let rpc = Arc::new(Rpc::new());
+        let out = Mutex::new(tokio::io::sink());
+        let timeout = Duration::from_millis(DEFAULT_QUORUM_TIMEOUT_MS);
+        answer_later(&rpc, Duration::from_millis(10), "n1", 1, &[("n1", 5)]);
+
+        let started = tokio::time::Instant::now();
+        let requests = vec![state_request(1, "n1"), state_request(2, "n2")];
+        let replies = rpc.quorum(requests, 2, timeout, &out).await.unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(tokio::time::Instant::now() - started, timeout);
+
+        // Lower values never win, and the value of the silent peer stays local.
+        let mut counter = counters(&[("n0", 1), ("n1", 9), ("n2", 3)]);
+        merge_state_replies(&mut counter, &replies);
+        assert_eq!(counter, counters(&[("n0", 1), ("n1", 9), ("n2", 3)]));
+    }
+
+    #[test]
+    fn sync_not_newer_than_the_last_is_skipped() {
+        let mut synced = HashMap::new();
+        assert!(accept_sync(&mut synced, "n1", 3));
+        assert!(!accept_sync(&mut synced, "n1", 3));
+        assert!(!accept_sync(&mut synced, "n1", 2));
+        assert!(accept_sync(&mut synced, "n1", 4));
+        // Versions are per sender.
+        assert!(accept_sync(&mut synced, "n2", 1));
+        // A version of 0 carries nothing, not even from a new sender.
+        assert!(!accept_sync(&mut synced, "n3", 0));
+    }
+
+    #[test]
+    fn sync_merge_counts_grown_entries() {
+        let mut counter = counters(&[("n0", 4), ("n1", 2), ("n2", 0)]);
+        let changed = merge_sync(
+            &mut counter,
+            &counters(&[("n0", 1), ("n1", 6), ("n2", 0), ("n9", 8)]),
+        );
+        assert_eq!(changed, 1);
+        assert_eq!(counter, counters(&[("n0", 4), ("n1", 6), ("n2", 0)]));
+    }
+
+    #[test]
+    fn config_from_env() {
+        let unset = [("COUNTER_SYNC_MS", None), ("COUNTER_QUORUM_READ", None)];
+        let config = with_env(&unset, CounterConfig::from_env).unwrap();
+        assert_eq!(config.sync_period, Duration::from_millis(DEFAULT_SYNC_MS));
+        assert!(!config.quorum_read);
+
+        let set = [
+            ("COUNTER_SYNC_MS", Some("250")),
+            ("COUNTER_QUORUM_READ", Some("true")),
+        ];
+        let config = with_env(&set, CounterConfig::from_env).unwrap();
+        assert_eq!(config.sync_period, Duration::from_millis(250));
+        assert!(config.quorum_read);
+
+        for sync_ms in ["0", "soon"] {
+            let vars = [("COUNTER_SYNC_MS", Some(sync_ms))];
+            assert!(with_env(&vars, CounterConfig::from_env).is_err());
+        }
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str) -> u64 {
+        let id = cluster.send("c1", node, Payload::Read);
+        match cluster.expect_reply_to(id).await.body.payload {
+            Payload::ReadOk { value, .. } => value,
+            payload => panic!("expected read_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adds_at_every_node_converge() {
+        let mut cluster = Cluster::builder()
+            .nodes::<CounterNode, Payload, InjectedPayload>(&["n0", "n1", "n2"])
+            .start()
+            .await;
+        for (node, delta) in [("n0", 1), ("n1", 10), ("n2", 100), ("n0", 1000)] {
+            let id = cluster.send("c1", node, Payload::Add { delta });
+            let reply: Message<Payload> = cluster.expect_reply_to(id).await;
+            assert!(matches!(reply.body.payload, Payload::AddOk));
+        }
+
+        tokio::time::sleep(Duration::from_millis(2 * DEFAULT_SYNC_MS)).await;
+        for node in ["n0", "n1", "n2"] {
+            assert_eq!(read(&mut cluster, node).await, 1111, "read at {}", node);
+        }
+        let syncs = cluster
+            .trace()
+            .iter()
+            .filter(|delivery| delivery.line.contains(r#""type":"sync""#))
+            .count();
+        assert!(syncs >= 6, "only {} syncs", syncs);
+    }
+
+    /// Counts the Syncs a cluster of three sends in a second with `COUNTER_SYNC_MS` set
+    /// to `sync_ms`.
+    async fn syncs_per_second(sync_ms: &str) -> usize {
+        let mut cluster = Cluster::builder()
+            .nodes::<CounterNode, Payload, InjectedPayload>(&["n0", "n1", "n2"])
+            .env(&[("COUNTER_SYNC_MS", Some(sync_ms))])
+            .start()
+            .await;
+        cluster
+            .advance::<Payload>(Duration::from_millis(1050))
+            .await;
+        cluster
+            .trace()
+            .iter()
+            .filter(|delivery| delivery.line.contains(r#""type":"sync""#))
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_ms_sets_how_often_syncs_are_sent() {
+        // Every round, each node syncs with the two others.
+        assert_eq!(syncs_per_second("100").await, 10 * 6);
+        assert_eq!(syncs_per_second("250").await, 4 * 6);
+    }
+
+    /// Adds at a node and reads it back, with `COUNTER_DEBUG_READ` set to `debug_read`.
+    async fn read_transcript(debug_read: Option<&str>) -> Vec<String> {
+        let mut node =
+            Harness::<CounterNode, Payload, InjectedPayload>::builder("n0", &["n0", "n1"])
+                .env(&[("COUNTER_DEBUG_READ", debug_read)])
+                .start()
+                .await;
+        for body in [
+            serde_json::json!({"type": "add", "delta": 5}),
+            serde_json::json!({"type": "read"}),
+        ] {
+            let id = node.send_json("c1", body).await;
+            node.expect_reply_to(id).await;
+        }
+        node.transcript().to_vec()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_ok_matches_its_golden_file() {
+        assert_golden("counter_read", &read_transcript(None).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_ok_with_a_breakdown_matches_its_golden_file() {
+        assert_golden(
+            "counter_read_breakdown",
+            &read_transcript(Some("true")).await,
+        );
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let counters = || prop::collection::hash_map(wire::node_id(), any::<u64>(), 0..4);
+        prop_oneof![
+            any::<u64>().prop_map(|delta| Payload::Add { delta }),
+            Just(Payload::AddOk),
+            Just(Payload::Read),
+            (any::<u64>(), proptest::option::of(counters()))
+                .prop_map(|(value, breakdown)| Payload::ReadOk { value, breakdown }),
+            (counters(), any::<u64>())
+                .prop_map(|(counters, version)| Payload::Sync { counters, version }),
+            Just(Payload::StateRequest),
+            counters().prop_map(|counters| Payload::StateReply { counters }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("g_counter");
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop, ids, rpc::Rpc, Body, ErrorCode, Event, Init, KVPayload, MaelstromError, Message,
+    Node, Output, WithKV, KV,
+};
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Read { key: u64, value: Option<Vec<i64>> },
+    Append { key: u64, element: i64 },
+}
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Read { key, value } => ("r", key, value).serialize(serializer),
+            Self::Append { key, element } => ("append", key, element).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (op, key, value) = <(String, u64, serde_json::Value)>::deserialize(deserializer)?;
+        match op.as_str() {
+            "r" => serde_json::from_value(value)
+                .map(|value| Self::Read { key, value })
+                .map_err(de::Error::custom),
+            "append" => serde_json::from_value(value)
+                .map(|element| Self::Append { key, element })
+                .map_err(de::Error::custom),
+            op => Err(de::Error::unknown_variant(op, &["r", "append"])),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum DatomicPayload {
+    Txn { txn: Vec<Op> },
+    TxnOk { txn: Vec<Op> },
+    Error { code: usize, text: String },
+}
+
+type Payload = WithKV<DatomicPayload>;
+
+const STORAGE: &str = "lin-kv";
+
+/// Key of the id of the current map, the only key ever overwritten.
+const ROOT_KEY: &str = "root";
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default number of times a txn is run before it is given up on.
+const DEFAULT_ATTEMPTS: u32 = 10;
+
+/// Default pause after the first conflict, doubled after each one.
+const DEFAULT_BACKOFF_MS: u64 = 5;
+
+/// The database: list ids by key. Maps and lists are never changed once written, so a
+/// map id pins down a snapshot of the whole database.
+type Map = BTreeMap<u64, String>;
+
+#[derive(Debug, Clone)]
+struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads the policy from the environment:
+    /// - `DATOMIC_ATTEMPTS`: times a txn is run before it fails with txn_conflict
+    ///   (default 10)
+    /// - `DATOMIC_BACKOFF_MS`: pause after the first conflict in milliseconds, doubled
+    ///   after each further one (default 5)
+    fn from_env() -> anyhow::Result<Self> {
+        let attempts = gossip_glomers::env_or("DATOMIC_ATTEMPTS", DEFAULT_ATTEMPTS)?;
+        if attempts == 0 {
+            anyhow::bail!("DATOMIC_ATTEMPTS must be greater than 0");
+        }
+        let backoff_ms = gossip_glomers::env_or("DATOMIC_BACKOFF_MS", DEFAULT_BACKOFF_MS)?;
+        Ok(Self {
+            attempts,
+            backoff: Duration::from_millis(backoff_ms),
+        })
+    }
+
+    /// Returns the pause before attempt `attempt`, counting from 0.
+    fn backoff(&self, attempt: u32) -> Duration {
+        match attempt {
+            0 => Duration::ZERO,
+            attempt => self.backoff * 2u32.saturating_pow(attempt - 1),
+        }
+    }
+}
+
+/// Runs txns against an immutable database in lin-kv: every txn reads the root, works
+/// on the snapshot it points to, writes the maps and lists it changed under fresh ids
+/// and swaps the root to its new map. The swap fails if another txn committed in
+/// between, and the txn runs again on the newer snapshot.
+struct DatomicNode {
+    node: String,
+    /// Index of the node in the cluster, which new ids carry.
+    index: usize,
+    id: AtomicUsize,
+    /// Counter new ids are unique by within this node.
+    next_id: AtomicU64,
+    retry: RetryPolicy,
+    /// Maps and lists read or written so far by id. They never change, so they can be
+    /// kept for as long as there is memory.
+    maps: Mutex<HashMap<String, Map>>,
+    lists: Mutex<HashMap<String, Vec<i64>>>,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+/// A txn under way: the snapshot it reads and the lists it changed.
+struct Snapshot {
+    root: Option<String>,
+    map: Map,
+    written: BTreeMap<u64, Vec<i64>>,
+}
+
+impl DatomicNode {
+    /// Runs `txn` until it commits or the retry policy gives up.
+    async fn transact(&self, txn: Vec<Op>) -> DatomicPayload {
+        for attempt in 0..self.retry.attempts {
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            match self.attempt(txn.clone()).await {
+                std::result::Result::Ok(Some(txn)) => return DatomicPayload::TxnOk { txn },
+                std::result::Result::Ok(None) => {}
+                // Only a failed root swap may have committed, anything else happened on
+                // objects no one reads before the root points to them.
+                Err(err) => {
+                    let code = match ErrorCode::of(&err) {
+                        Some(ErrorCode::Crash) => ErrorCode::Crash,
+                        _ => ErrorCode::TemporarilyUnavailable,
+                    };
+                    return DatomicPayload::Error {
+                        code: code.code(),
+                        text: format!("{:#}", err),
+                    };
+                }
+            }
+        }
+        DatomicPayload::Error {
+            code: ErrorCode::TxnConflict.code(),
+            text: format!("txn conflicted {} times", self.retry.attempts),
+        }
+    }
+
+    /// Runs `txn` once on the current snapshot. Returns its result if it committed and
+    /// None if another txn committed first.
+    async fn attempt(&self, mut txn: Vec<Op>) -> anyhow::Result<Option<Vec<Op>>> {
+        let root: Option<String> = match self.read(STORAGE, ROOT_KEY.to_string()).await {
+            std::result::Result::Ok(root) => Some(root),
+            Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => None,
+            Err(err) => return Err(err).context("read root"),
+        };
+        let map = match &root {
+            Some(root) => self.map(root).await?,
+            None => Map::new(),
+        };
+        let mut snapshot = Snapshot {
+            root,
+            map,
+            written: BTreeMap::new(),
+        };
+        for op in &mut txn {
+            match op {
+                Op::Read { key, value } => *value = self.list(&snapshot, *key).await?,
+                Op::Append { key, element } => {
+                    let mut list = self.list(&snapshot, *key).await?.unwrap_or_default();
+                    list.push(*element);
+                    snapshot.written.insert(*key, list);
+                }
+            }
+        }
+        if snapshot.written.is_empty() {
+            // The root read fixes the point in time a read-only txn happened at.
+            return Ok(Some(txn));
+        }
+        Ok(self.commit(snapshot).await?.then_some(txn))
+    }
+
+    /// Writes the changed lists and the new map, and swaps the root to it. Returns
+    /// false if the root moved since the snapshot was taken.
+    async fn commit(&self, snapshot: Snapshot) -> anyhow::Result<bool> {
+        let Snapshot {
+            root,
+            mut map,
+            written,
+        } = snapshot;
+        for (key, list) in written {
+            let id = self.new_id();
+            self.write(STORAGE, format!("list:{}", id), list.clone())
+                .await
+                .context("write list")?;
+            self.lists.lock().await.insert(id.clone(), list);
+            map.insert(key, id);
+        }
+        let id = self.new_id();
+        self.write(STORAGE, format!("map:{}", id), map.clone())
+            .await
+            .context("write map")?;
+        self.maps.lock().await.insert(id.clone(), map);
+        // Null never matches a root, so creating the root fails once someone else has.
+        let from = root.map_or(serde_json::Value::Null, serde_json::Value::String);
+        match self
+            .cas(STORAGE, ROOT_KEY.to_string(), from, id.into(), true)
+            .await
+        {
+            std::result::Result::Ok(()) => Ok(true),
+            Err(err) if ErrorCode::of(&err) == Some(ErrorCode::PreconditionFailed) => Ok(false),
+            Err(err) => Err(MaelstromError::new(
+                ErrorCode::Crash,
+                format!("swap root, the txn may have committed: {:#}", err),
+            )
+            .into()),
+        }
+    }
+
+    /// Returns the map with id `id`, from the cache if it was seen before.
+    async fn map(&self, id: &str) -> anyhow::Result<Map> {
+        if let Some(map) = self.maps.lock().await.get(id) {
+            return Ok(map.clone());
+        }
+        let map: Map = self
+            .read(STORAGE, format!("map:{}", id))
+            .await
+            .context("read map")?;
+        self.maps.lock().await.insert(id.to_string(), map.clone());
+        Ok(map)
+    }
+
+    /// Returns the list of `key` in `snapshot`, including the txn's own appends.
+    async fn list(&self, snapshot: &Snapshot, key: u64) -> anyhow::Result<Option<Vec<i64>>> {
+        if let Some(list) = snapshot.written.get(&key) {
+            return Ok(Some(list.clone()));
+        }
+        let Some(id) = snapshot.map.get(&key) else {
+            return Ok(None);
+        };
+        if let Some(list) = self.lists.lock().await.get(id) {
+            return Ok(Some(list.clone()));
+        }
+        let list: Vec<i64> = self
+            .read(STORAGE, format!("list:{}", id))
+            .await
+            .context("read list")?;
+        self.lists.lock().await.insert(id.clone(), list.clone());
+        Ok(Some(list))
+    }
+
+    /// Returns an id no node has used before, see ids::node_seq.
+    fn new_id(&self) -> String {
+        ids::node_seq(self.index, self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    async fn rpc(
+        &self,
+        to: &str,
+        payload: KVPayload<serde_json::Value>,
+    ) -> anyhow::Result<Message<Payload>> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::Relaxed)),
+                in_reply_to: None,
+                payload: WithKV::KV(payload),
+            },
+        };
+        self.rpc.call(msg, RPC_TIMEOUT, &self.stdout).await
+    }
+}
+
+#[async_trait]
+impl<T> KV<T> for DatomicNode
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T> {
+        let payload = KVPayload::Read { key };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("read from storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::ReadOk { value }) => {
+                serde_json::from_value(value).context("deserialize stored value")
+            }
+            WithKV::Workload(DatomicPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write(&self, storage: &str, key: String, value: T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value).context("serialize value")?;
+        let payload = KVPayload::Write { key, value };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("write to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::WriteOk {}) => Ok(()),
+            WithKV::Workload(DatomicPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        let from = serde_json::to_value(from).context("serialize from value")?;
+        let to = serde_json::to_value(to).context("serialize to value")?;
+        let payload = KVPayload::Cas { key, from, to, put };
+        let result = self.rpc(storage, payload).await.context("cas to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::CasOk {}) => Ok(()),
+            WithKV::Workload(DatomicPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload> for DatomicNode {
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let retry = RetryPolicy::from_env()?;
+        eprintln!("datomic retry policy: {:?}", retry);
+        Ok(Self {
+            index: init.node_index()?,
+            node: init.node_id,
+            id: 1.into(),
+            next_id: 0.into(),
+            retry,
+            maps: Mutex::default(),
+            lists: Mutex::default(),
+            rpc: Rpc::new(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        // Storage replies go to the RPC waiting for them.
+        let Some(message) = self.rpc.resolve(message).await else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(&self.id));
+        match reply.body.payload {
+            WithKV::Workload(DatomicPayload::Txn { txn }) => {
+                reply.body.payload = WithKV::Workload(self.transact(txn).await);
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send txn response")?;
+            }
+            WithKV::Workload(DatomicPayload::TxnOk { .. } | DatomicPayload::Error { .. }) => {}
+            WithKV::KV(payload) => {
+                eprintln!("unexpected storage message: {:?}", payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<DatomicNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Harness, KvOp, MockKvService};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    async fn node(
+        kv: &MockKvService,
+        vars: &[(&str, Option<&str>)],
+    ) -> Harness<DatomicNode, Payload> {
+        Harness::builder("n0", &["n0", "n1"])
+            .env(vars)
+            .service(kv)
+            .start()
+            .await
+    }
+
+    async fn transact(node: &mut Harness<DatomicNode, Payload>, txn: Vec<Op>) -> DatomicPayload {
+        let id = node
+            .send("c1", WithKV::Workload(DatomicPayload::Txn { txn }))
+            .await;
+        match node.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(payload) => payload,
+            payload => panic!("expected a txn reply, got {:?}", payload),
+        }
+    }
+
+    fn reads(txn: &DatomicPayload) -> Vec<Option<Vec<i64>>> {
+        let DatomicPayload::TxnOk { txn } = txn else {
+            panic!("expected txn_ok, got {:?}", txn);
+        };
+        txn.iter()
+            .filter_map(|op| match op {
+                Op::Read { value, .. } => Some(value.clone()),
+                Op::Append { .. } => None,
+            })
+            .collect()
+    }
+
+    fn append(key: u64, element: i64) -> Op {
+        Op::Append { key, element }
+    }
+
+    fn read(key: u64) -> Op {
+        Op::Read { key, value: None }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn txns_commit_new_snapshots_and_leave_old_ones_alone() {
+        let kv = MockKvService::lin(STORAGE);
+        let mut node = node(&kv, &[]).await;
+        let txn = transact(&mut node, vec![read(1), append(1, 10), append(2, 20)]).await;
+        assert_eq!(reads(&txn), [None]);
+        // Two lists, their map and the root.
+        assert_eq!(kv.key_count(), 4);
+        let first = kv.get(ROOT_KEY).expect("a root");
+
+        let txn = transact(&mut node, vec![append(1, 11), read(1), read(2)]).await;
+        assert_eq!(reads(&txn), [Some(vec![10, 11]), Some(vec![20])]);
+        assert_ne!(kv.get(ROOT_KEY), Some(first.clone()));
+        // The first snapshot is still there as it was.
+        let map = kv.get(format!("map:{}", first.as_str().expect("a map id")));
+        let map: Map = serde_json::from_value(map.expect("the first map")).expect("a map");
+        let list = kv.get(format!("list:{}", map[&1])).expect("a list");
+        assert_eq!(list, serde_json::json!([10]));
+
+        // Read-only txns only read.
+        let (writes, cas) = (kv.count(KvOp::Write), kv.count(KvOp::Cas));
+        let txn = transact(&mut node, vec![read(1), read(3)]).await;
+        assert_eq!(reads(&txn), [Some(vec![10, 11]), None]);
+        assert_eq!((kv.count(KvOp::Write), kv.count(KvOp::Cas)), (writes, cas));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn conflicting_txns_run_again_until_the_policy_gives_up() {
+        let kv = MockKvService::lin(STORAGE);
+        let mut node = node(&kv, &[("DATOMIC_ATTEMPTS", Some("3"))]).await;
+        kv.fail_next(KvOp::Cas, 2);
+        let txn = transact(&mut node, vec![append(1, 10), read(1)]).await;
+        assert_eq!(reads(&txn), [Some(vec![10])]);
+        assert_eq!(kv.count(KvOp::Cas), 3);
+
+        kv.fail_next(KvOp::Cas, 3);
+        let txn = transact(&mut node, vec![append(1, 11)]).await;
+        assert!(matches!(
+            txn,
+            DatomicPayload::Error { code, .. } if code == ErrorCode::TxnConflict.code()
+        ));
+        let txn = transact(&mut node, vec![read(1)]).await;
+        assert_eq!(reads(&txn), [Some(vec![10])]);
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (
+                any::<u64>(),
+                proptest::option::of(prop::collection::vec(any::<i64>(), 0..4))
+            )
+                .prop_map(|(key, value)| Op::Read { key, value }),
+            (any::<u64>(), any::<i64>()).prop_map(|(key, element)| Op::Append { key, element }),
+        ]
+    }
+
+    /// Nodes that number themselves by their place among the node ids refuse to start
+    /// without one.
+    #[tokio::test(start_paused = true)]
+    #[should_panic(expected = "node n3 is not among the node ids")]
+    async fn a_node_missing_from_node_ids_refuses_to_start() {
+        Harness::<DatomicNode, Payload>::new("n3", &["n0", "n1", "n2"]).await;
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let txn = || prop::collection::vec(op(), 0..4);
+        wire::with_kv(prop_oneof![
+            txn().prop_map(|txn| DatomicPayload::Txn { txn }),
+            txn().prop_map(|txn| DatomicPayload::TxnOk { txn }),
+            wire::error().prop_map(|(code, text)| DatomicPayload::Error { code, text }),
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("txn_list_append");
+        wire::assert_fixture_parses::<Payload>("kv_service");
+    }
+}
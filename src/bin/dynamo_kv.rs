@@ -0,0 +1,782 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    clock::TokioClock,
+    crdt::{LwwEntry, LwwMap, Stamp},
+    event_loop,
+    kv_service::{self, key_does_not_exist},
+    rpc::Rpc,
+    sharding, Body, ErrorCode, Event, HybridClock, Init, MaelstromError, Message, Node, Output,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+/// Messages between the replicas. Keys are the JSON encoding of the client's key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum DynamoPayload {
+    /// Stores a write, or keeps it as a hint for `hint_for` if that preferred replica
+    /// could not be reached. A node whose hints are full refuses hints.
+    Replicate {
+        key: String,
+        entry: LwwEntry<Value>,
+        hint_for: Option<String>,
+    },
+    ReplicateOk,
+    /// Asks a replica for its latest write to a key, hints included.
+    Fetch {
+        key: String,
+    },
+    FetchOk {
+        entry: Option<LwwEntry<Value>>,
+    },
+    /// Hands the hints kept for the receiver over to it.
+    Handoff {
+        entries: LwwMap<String, Value>,
+    },
+    HandoffOk,
+}
+
+/// Either a client request or reply, or a message between the replicas.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Payload {
+    Kv(kv_service::Payload),
+    Dynamo(DynamoPayload),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InjectedPayload {
+    Handoff,
+}
+
+/// Default number of replicas of every key.
+const DEFAULT_REPLICAS: usize = 3;
+
+/// Default number of replicas that must store a write before it is acknowledged.
+const DEFAULT_WRITE_QUORUM: usize = 2;
+
+/// Default number of replicas a read waits for.
+const DEFAULT_READ_QUORUM: usize = 2;
+
+/// Default time a replica gets to answer before it counts as unreachable.
+const DEFAULT_TIMEOUT_MS: u64 = 300;
+
+/// Default interval between two rounds of hint handoff.
+const DEFAULT_HANDOFF_MS: u64 = 500;
+
+/// Default number of hints a node keeps for others, summed over all of them.
+const DEFAULT_MAX_HINTS: usize = 10_000;
+
+/// Default distance a peer's clock may run ahead before observing it warns.
+const DEFAULT_MAX_DRIFT_MS: u64 = 1000;
+
+#[derive(Debug, Clone)]
+struct DynamoKVConfig {
+    replicas: usize,
+    write_quorum: usize,
+    read_quorum: usize,
+    timeout: Duration,
+    handoff_period: Duration,
+    max_hints: usize,
+    max_drift: Duration,
+}
+
+impl DynamoKVConfig {
+    /// Reads the configuration from the environment:
+    /// - `DYNAMO_KV_N`: number of replicas of every key, capped at the number of nodes
+    ///   (default 3)
+    /// - `DYNAMO_KV_W`: replicas, preferred or standing in, that must store a write
+    ///   before it is acknowledged (default 2)
+    /// - `DYNAMO_KV_R`: replicas a read waits for (default 2)
+    /// - `DYNAMO_KV_TIMEOUT_MS`: time a replica gets to answer before the next node
+    ///   stands in for it, in milliseconds (default 300)
+    /// - `DYNAMO_KV_HANDOFF_MS`: interval between rounds of handing hints over to
+    ///   their replicas in milliseconds, which is also how often an unreachable replica
+    ///   is probed (default 500)
+    /// - `DYNAMO_KV_MAX_HINTS`: hints a node keeps for others, after which it refuses
+    ///   more (default 10000)
+    /// - `DYNAMO_KV_MAX_DRIFT_MS`: how far ahead of the local wall clock a write from a
+    ///   peer may be stamped before it is warned about, in milliseconds (default 1000)
+    fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            replicas: gossip_glomers::env_or("DYNAMO_KV_N", DEFAULT_REPLICAS)?,
+            write_quorum: gossip_glomers::env_or("DYNAMO_KV_W", DEFAULT_WRITE_QUORUM)?,
+            read_quorum: gossip_glomers::env_or("DYNAMO_KV_R", DEFAULT_READ_QUORUM)?,
+            timeout: Duration::from_millis(gossip_glomers::env_or(
+                "DYNAMO_KV_TIMEOUT_MS",
+                DEFAULT_TIMEOUT_MS,
+            )?),
+            handoff_period: Duration::from_millis(gossip_glomers::env_or(
+                "DYNAMO_KV_HANDOFF_MS",
+                DEFAULT_HANDOFF_MS,
+            )?),
+            max_hints: gossip_glomers::env_or("DYNAMO_KV_MAX_HINTS", DEFAULT_MAX_HINTS)?,
+            max_drift: Duration::from_millis(gossip_glomers::env_or(
+                "DYNAMO_KV_MAX_DRIFT_MS",
+                DEFAULT_MAX_DRIFT_MS,
+            )?),
+        };
+        if config.replicas == 0 {
+            anyhow::bail!("DYNAMO_KV_N must be greater than 0");
+        }
+        if config.write_quorum == 0 || config.write_quorum > config.replicas {
+            anyhow::bail!("DYNAMO_KV_W must be between 1 and DYNAMO_KV_N");
+        }
+        if config.read_quorum == 0 || config.read_quorum > config.replicas {
+            anyhow::bail!("DYNAMO_KV_R must be between 1 and DYNAMO_KV_N");
+        }
+        if config.timeout.is_zero() || config.handoff_period.is_zero() {
+            anyhow::bail!("DYNAMO_KV_TIMEOUT_MS and DYNAMO_KV_HANDOFF_MS must be greater than 0");
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    store: LwwMap<String, Value>,
+    /// Writes kept for preferred replicas that could not be reached, by replica. Only
+    /// the latest write of every key is kept. Hints live in memory only, so a node that
+    /// crashes loses the hints it kept.
+    hints: HashMap<String, LwwMap<String, Value>>,
+}
+
+impl State {
+    fn hint_count(&self) -> usize {
+        self.hints.values().map(LwwMap::len).sum()
+    }
+
+    /// Returns the latest write to `key` this node knows of, hints included.
+    fn latest(&self, key: &String) -> Option<LwwEntry<Value>> {
+        self.hints
+            .values()
+            .filter_map(|hints| hints.entry(key))
+            .chain(self.store.entry(key))
+            .max_by(|a, b| a.stamp.cmp(&b.stamp))
+            .cloned()
+    }
+}
+
+/// A key/value store that stays available while nodes are cut off, in the manner of
+/// Dynamo. Every key has N preferred replicas, the first nodes of its rendezvous
+/// preference list. A write is stamped with a hybrid clock by the node it arrives at
+/// and sent to the preferred replicas. For every one that does not answer, the next
+/// node on the list stores the write as a hint for it, and the write is acknowledged
+/// once W nodes stored it. Reads ask the preferred replicas, and the nodes after them
+/// if fewer than R answer, and return the write with the highest stamp. Preferred
+/// replicas that answered with an older write are sent the newest one: read repair.
+///
+/// Hints are handed over to their replica on a timer, which doubles as the probe of
+/// whether it is reachable again, and dropped once it took them. Writes only ever
+/// resolve by stamp: concurrent writes to a key keep the one with the highest stamp,
+/// and cas is not supported.
+struct DynamoKVNode {
+    id: AtomicUsize,
+    node: String,
+    node_ids: Vec<String>,
+    config: DynamoKVConfig,
+    clock: HybridClock<TokioClock>,
+    state: Mutex<State>,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+impl DynamoKVNode {
+    fn message(&self, to: &str, id: Option<usize>, payload: DynamoPayload) -> Message<Payload> {
+        Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id,
+                in_reply_to: None,
+                payload: Payload::Dynamo(payload),
+            },
+        }
+    }
+
+    /// Sends `payload` to `to` and returns the answer, or the error it carried.
+    async fn rpc(&self, to: &str, payload: DynamoPayload) -> anyhow::Result<Payload> {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        let msg = self.message(to, Some(id), payload);
+        match self
+            .rpc
+            .call(msg, self.config.timeout, &self.stdout)
+            .await?
+            .body
+            .payload
+        {
+            Payload::Kv(kv_service::Payload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            payload => Ok(payload),
+        }
+    }
+
+    /// Stores a write here, as a hint for `hint_for` if given. Fails if the hints are
+    /// full.
+    async fn store(
+        &self,
+        key: String,
+        entry: LwwEntry<Value>,
+        hint_for: Option<String>,
+    ) -> Result<(), MaelstromError> {
+        self.clock.observe(entry.stamp.time);
+        let mut state = self.state.lock().await;
+        match hint_for {
+            None => {
+                state.store.apply(key, entry);
+            }
+            Some(replica) => {
+                let known = state
+                    .hints
+                    .get(&replica)
+                    .is_some_and(|hints| hints.entry(&key).is_some());
+                if !known && state.hint_count() >= self.config.max_hints {
+                    return Err(MaelstromError::new(
+                        ErrorCode::TemporarilyUnavailable,
+                        format!("hints for other nodes are full, not keeping {}", key),
+                    ));
+                }
+                state.hints.entry(replica).or_default().apply(key, entry);
+            }
+        }
+        std::result::Result::Ok(())
+    }
+
+    /// Stores a write on `node`, here or on another node. Returns whether it took.
+    async fn replicate(
+        &self,
+        node: &str,
+        key: &str,
+        entry: &LwwEntry<Value>,
+        hint_for: Option<&str>,
+    ) -> bool {
+        if node == self.node {
+            return self
+                .store(key.to_string(), entry.clone(), hint_for.map(str::to_string))
+                .await
+                .is_ok();
+        }
+        let payload = DynamoPayload::Replicate {
+            key: key.to_string(),
+            entry: entry.clone(),
+            hint_for: hint_for.map(str::to_string),
+        };
+        match self.rpc(node, payload).await {
+            std::result::Result::Ok(_) => true,
+            Err(err) => {
+                eprintln!("replicate {} on {}: {:#}", key, node, err);
+                false
+            }
+        }
+    }
+
+    /// Stamps a client write and stores it on N nodes, standing in for the preferred
+    /// replicas that are out of reach. Succeeds once W stored it.
+    async fn write(&self, key: &Value, value: Value) -> Result<(), MaelstromError> {
+        let key = key.to_string();
+        let entry = LwwEntry {
+            stamp: Stamp {
+                time: self.clock.now(),
+                node: self.node.clone(),
+            },
+            value: Some(value),
+        };
+        let preference = sharding::preference_list(&key, &self.node_ids);
+        let replicas = self.config.replicas.min(preference.len());
+        let (preferred, mut standins) = (&preference[..replicas], preference[replicas..].iter());
+        let stored = futures::future::join_all(
+            preferred
+                .iter()
+                .map(|node| self.replicate(node, &key, &entry, None)),
+        )
+        .await;
+        let mut acks = stored.iter().filter(|stored| **stored).count();
+        for (replica, _) in preferred
+            .iter()
+            .zip(&stored)
+            .filter(|(_, stored)| !**stored)
+        {
+            for standin in standins.by_ref() {
+                if self.replicate(standin, &key, &entry, Some(replica)).await {
+                    acks += 1;
+                    break;
+                }
+            }
+        }
+        if acks < self.config.write_quorum {
+            // The nodes that did store it keep it, so the write may still show up.
+            return Err(MaelstromError::new(
+                ErrorCode::Timeout,
+                format!(
+                    "write of {} stored on {} nodes, {} needed",
+                    key, acks, self.config.write_quorum
+                ),
+            ));
+        }
+        std::result::Result::Ok(())
+    }
+
+    /// Asks `nodes` for their latest write to `key` and returns the answers of the
+    /// first `needed` that answer in time.
+    async fn fetch(
+        &self,
+        nodes: &[&str],
+        key: &str,
+        needed: usize,
+    ) -> anyhow::Result<Vec<(String, Option<LwwEntry<Value>>)>> {
+        let mut answers = Vec::new();
+        let mut requests = Vec::new();
+        for node in nodes {
+            if *node == self.node {
+                answers.push((
+                    self.node.clone(),
+                    self.state.lock().await.latest(&key.to_string()),
+                ));
+                continue;
+            }
+            let id = self.id.fetch_add(1, Ordering::Relaxed);
+            requests.push(self.message(
+                node,
+                Some(id),
+                DynamoPayload::Fetch {
+                    key: key.to_string(),
+                },
+            ));
+        }
+        let replies = self
+            .rpc
+            .quorum(
+                requests,
+                needed.saturating_sub(answers.len()),
+                self.config.timeout,
+                &self.stdout,
+            )
+            .await?;
+        for reply in replies {
+            if let Payload::Dynamo(DynamoPayload::FetchOk { entry }) = reply.body.payload {
+                answers.push((reply.src, entry));
+            }
+        }
+        Ok(answers)
+    }
+
+    /// Reads the latest write to a key from R nodes, preferred replicas first, and
+    /// repairs the preferred replicas that answered with an older one.
+    async fn read(&self, key: &Value) -> Result<Value, MaelstromError> {
+        let key = key.to_string();
+        let preference = sharding::preference_list(&key, &self.node_ids);
+        let replicas = self.config.replicas.min(preference.len());
+        let needed = self.config.read_quorum;
+        let unavailable = |err: anyhow::Error| {
+            MaelstromError::new(ErrorCode::TemporarilyUnavailable, format!("{:#}", err))
+        };
+        let mut answers = self
+            .fetch(&preference[..replicas], &key, needed)
+            .await
+            .map_err(unavailable)?;
+        if answers.len() < needed {
+            let more = self
+                .fetch(&preference[replicas..], &key, needed - answers.len())
+                .await
+                .map_err(unavailable)?;
+            answers.extend(more);
+        }
+        if answers.len() < needed {
+            return Err(MaelstromError::new(
+                ErrorCode::TemporarilyUnavailable,
+                format!(
+                    "read of {} got {} answers, {} needed",
+                    key,
+                    answers.len(),
+                    needed
+                ),
+            ));
+        }
+        let latest = answers
+            .iter()
+            .filter_map(|(_, entry)| entry.as_ref())
+            .max_by(|a, b| a.stamp.cmp(&b.stamp))
+            .cloned();
+        let Some(latest) = latest else {
+            return Err(MaelstromError::new(
+                ErrorCode::KeyDoesNotExist,
+                format!("key {} does not exist", key),
+            ));
+        };
+        self.clock.observe(latest.stamp.time);
+        for (node, entry) in &answers {
+            let stale = entry
+                .as_ref()
+                .is_none_or(|entry| entry.stamp < latest.stamp);
+            if stale && preference[..replicas].contains(&node.as_str()) {
+                self.repair(node, &key, &latest).await;
+            }
+        }
+        latest.value.ok_or_else(|| {
+            MaelstromError::new(
+                ErrorCode::KeyDoesNotExist,
+                format!("key {} does not exist", key),
+            )
+        })
+    }
+
+    /// Sends a preferred replica the latest write, without waiting for it to answer.
+    async fn repair(&self, node: &str, key: &str, entry: &LwwEntry<Value>) {
+        if node == self.node {
+            let _ = self.store(key.to_string(), entry.clone(), None).await;
+            return;
+        }
+        let payload = DynamoPayload::Replicate {
+            key: key.to_string(),
+            entry: entry.clone(),
+            hint_for: None,
+        };
+        if let Err(err) = self.message(node, None, payload).send(&self.stdout).await {
+            eprintln!("read repair of {} on {}: {:#}", key, node, err);
+        }
+    }
+
+    /// Hands the hints of every replica over to it, and drops them once it took them.
+    /// Hints that changed in the meantime are kept for the next round.
+    async fn handoff(&self) {
+        let hints: Vec<(String, LwwMap<String, Value>)> = self
+            .state
+            .lock()
+            .await
+            .hints
+            .iter()
+            .filter(|(_, hints)| !hints.is_empty())
+            .map(|(replica, hints)| (replica.clone(), hints.clone()))
+            .collect();
+        for (replica, sent) in hints {
+            let payload = DynamoPayload::Handoff {
+                entries: sent.clone(),
+            };
+            if let Err(err) = self.rpc(&replica, payload).await {
+                eprintln!("hand off {} hints to {}: {:#}", sent.len(), replica, err);
+                continue;
+            }
+            let mut state = self.state.lock().await;
+            let Some(hints) = state.hints.get_mut(&replica) else {
+                continue;
+            };
+            let mut kept = LwwMap::new();
+            for (key, _) in hints.iter() {
+                let entry = hints.entry(key).expect("iterated key has an entry");
+                if sent.stamp(key) != Some(&entry.stamp) {
+                    kept.apply(key.clone(), entry.clone());
+                }
+            }
+            eprintln!(
+                "handed {} hints to {}, {} came in meanwhile",
+                sent.len(),
+                replica,
+                kept.len()
+            );
+            if kept.is_empty() {
+                state.hints.remove(&replica);
+            } else {
+                *hints = kept;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for DynamoKVNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = DynamoKVConfig::from_env()?;
+        eprintln!("dynamo_kv config: {:?}", config);
+        gossip_glomers::spawn_timer(tx, config.handoff_period, InjectedPayload::Handoff);
+        Ok(Self {
+            id: 1.into(),
+            node: init.node_id,
+            node_ids: init.node_ids,
+            clock: HybridClock::with_clock(config.max_drift, TokioClock::new()),
+            config,
+            state: Mutex::default(),
+            rpc: Rpc::new(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        let message = match event {
+            Event::EOF => return Ok(()),
+            Event::Injected(InjectedPayload::Handoff) => {
+                self.handoff().await;
+                return Ok(());
+            }
+            Event::Message(message) => message,
+        };
+        // Answers to writes, fetches and handoffs go to whoever waits for them.
+        let Some(message) = self.rpc.resolve(message).await else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(&self.id));
+        let error = |err: MaelstromError| {
+            Payload::Kv(kv_service::Payload::Error {
+                code: err.code.code(),
+                text: err.text,
+            })
+        };
+        reply.body.payload = match reply.body.payload {
+            Payload::Kv(kv_service::Payload::Read { key }) => match self.read(&key).await {
+                std::result::Result::Ok(value) => {
+                    Payload::Kv(kv_service::Payload::ReadOk { value })
+                }
+                Err(err) if err.code == ErrorCode::KeyDoesNotExist => {
+                    Payload::Kv(key_does_not_exist(&key))
+                }
+                Err(err) => error(err),
+            },
+            Payload::Kv(kv_service::Payload::Write { key, value }) => {
+                match self.write(&key, value).await {
+                    std::result::Result::Ok(()) => Payload::Kv(kv_service::Payload::WriteOk),
+                    Err(err) => error(err),
+                }
+            }
+            Payload::Kv(kv_service::Payload::Cas { .. }) => error(MaelstromError::new(
+                ErrorCode::NotSupported,
+                "cas is not supported: writes only resolve by stamp",
+            )),
+            Payload::Dynamo(DynamoPayload::Replicate {
+                key,
+                entry,
+                hint_for,
+            }) => match self.store(key, entry, hint_for).await {
+                std::result::Result::Ok(()) => Payload::Dynamo(DynamoPayload::ReplicateOk),
+                Err(err) => error(err),
+            },
+            Payload::Dynamo(DynamoPayload::Fetch { key }) => {
+                Payload::Dynamo(DynamoPayload::FetchOk {
+                    entry: self.state.lock().await.latest(&key),
+                })
+            }
+            Payload::Dynamo(DynamoPayload::Handoff { entries }) => {
+                self.clock.observe(entries.max_time());
+                self.state.lock().await.store.merge(entries);
+                Payload::Dynamo(DynamoPayload::HandoffOk)
+            }
+            // Answers nobody waits for anymore, and answers to read repairs.
+            Payload::Kv(_)
+            | Payload::Dynamo(
+                DynamoPayload::ReplicateOk
+                | DynamoPayload::FetchOk { .. }
+                | DynamoPayload::HandoffOk,
+            ) => return Ok(()),
+        };
+        reply.send(&self.stdout).await.context("send reply")?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<DynamoKVNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Cluster, Fate};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+
+    const NODES: [&str; 4] = ["n0", "n1", "n2", "n3"];
+
+    /// Returns the preference list of the key `"x"` the tests write to.
+    fn preference() -> Vec<String> {
+        let ids: Vec<String> = NODES.iter().map(|id| id.to_string()).collect();
+        sharding::preference_list(&json!("x").to_string(), &ids)
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    }
+
+    async fn cluster(vars: &[(&str, Option<&str>)]) -> Cluster {
+        Cluster::builder()
+            .nodes::<DynamoKVNode, Payload, InjectedPayload>(&NODES)
+            .env(vars)
+            .start()
+            .await
+    }
+
+    async fn write(cluster: &mut Cluster, node: &str, value: i64) {
+        let write = kv_service::Payload::Write {
+            key: json!("x"),
+            value: json!(value),
+        };
+        let id = cluster.send("c1", node, Payload::Kv(write));
+        let reply = cluster.expect_reply_to::<Payload>(id).await;
+        assert!(
+            matches!(
+                reply.body.payload,
+                Payload::Kv(kv_service::Payload::WriteOk)
+            ),
+            "write to {}: {:?}",
+            node,
+            reply.body.payload
+        );
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str) -> Value {
+        let id = cluster.send(
+            "c1",
+            node,
+            Payload::Kv(kv_service::Payload::Read { key: json!("x") }),
+        );
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            Payload::Kv(kv_service::Payload::ReadOk { value }) => value,
+            payload => panic!("read from {}: {:?}", node, payload),
+        }
+    }
+
+    /// Returns the value `node` itself keeps for the key, hints included.
+    async fn fetch(cluster: &mut Cluster, node: &str) -> Option<Value> {
+        let fetch = DynamoPayload::Fetch {
+            key: json!("x").to_string(),
+        };
+        let id = cluster.send("c1", node, Payload::Dynamo(fetch));
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            Payload::Dynamo(DynamoPayload::FetchOk { entry }) => {
+                entry.and_then(|entry| entry.value)
+            }
+            payload => panic!("fetch from {}: {:?}", node, payload),
+        }
+    }
+
+    fn handoffs_to(cluster: &Cluster, node: &str) -> usize {
+        cluster
+            .trace()
+            .into_iter()
+            .filter(|delivery| delivery.to == node && delivery.fate == Fate::Delivered)
+            .filter(|delivery| delivery.line.contains(r#""type":"handoff""#))
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_and_reads_go_on_while_a_replica_is_cut_off() {
+        let preference = preference();
+        let (owner, standin) = (&preference[0], &preference[3]);
+        let others: Vec<&str> = preference[1..].iter().map(String::as_str).collect();
+        let mut cluster = cluster(&[]).await;
+        cluster.partition(&[owner], &others);
+
+        for value in 0..3 {
+            write(&mut cluster, others[value as usize], value).await;
+            for reader in &others {
+                assert_eq!(read(&mut cluster, reader).await, json!(value));
+            }
+        }
+        assert_eq!(fetch(&mut cluster, owner).await, None);
+        assert_eq!(fetch(&mut cluster, standin).await, Some(json!(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hints_reach_their_replica_once_it_is_back() {
+        let preference = preference();
+        let owner = &preference[0];
+        let others: Vec<&str> = preference[1..].iter().map(String::as_str).collect();
+        let mut cluster = cluster(&[]).await;
+        cluster.partition(&[owner], &others);
+        write(&mut cluster, &preference[1], 7).await;
+
+        cluster
+            .advance::<Payload>(Duration::from_millis(DEFAULT_HANDOFF_MS * 2))
+            .await;
+        assert_eq!(handoffs_to(&cluster, owner), 0);
+        assert_eq!(fetch(&mut cluster, owner).await, None);
+
+        cluster.heal();
+        cluster
+            .advance::<Payload>(Duration::from_millis(DEFAULT_HANDOFF_MS * 2))
+            .await;
+        assert_eq!(handoffs_to(&cluster, owner), 1);
+        assert_eq!(fetch(&mut cluster, owner).await, Some(json!(7)));
+
+        // The stand-in dropped the hint once the replica took it.
+        cluster
+            .advance::<Payload>(Duration::from_millis(DEFAULT_HANDOFF_MS * 2))
+            .await;
+        assert_eq!(handoffs_to(&cluster, owner), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_repair_a_stale_replica() {
+        let preference = preference();
+        let (owner, coordinator, slow) = (&preference[0], &preference[1], &preference[2]);
+        let others: Vec<&str> = preference[1..].iter().map(String::as_str).collect();
+        // Hints are never handed over, so only read repair can bring the owner up to date.
+        let mut cluster = cluster(&[("DYNAMO_KV_HANDOFF_MS", Some("3600000"))]).await;
+        write(&mut cluster, coordinator, 1).await;
+        cluster.partition(&[owner], &others);
+        write(&mut cluster, coordinator, 2).await;
+        cluster.heal();
+        assert_eq!(fetch(&mut cluster, owner).await, Some(json!(1)));
+
+        // The owner answers the read before the other preferred replica.
+        cluster.set_latency(slow, coordinator, Duration::from_millis(100));
+        assert_eq!(read(&mut cluster, coordinator).await, json!(2));
+        cluster.advance::<Payload>(Duration::from_millis(10)).await;
+        assert_eq!(fetch(&mut cluster, owner).await, Some(json!(2)));
+        assert_eq!(handoffs_to(&cluster, owner), 0);
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let dynamo = prop_oneof![
+            (
+                any::<String>(),
+                wire::lww_entry(),
+                proptest::option::of(wire::node_id())
+            )
+                .prop_map(|(key, entry, hint_for)| DynamoPayload::Replicate {
+                    key,
+                    entry,
+                    hint_for
+                }),
+            Just(DynamoPayload::ReplicateOk),
+            any::<String>().prop_map(|key| DynamoPayload::Fetch { key }),
+            proptest::option::of(wire::lww_entry())
+                .prop_map(|entry| DynamoPayload::FetchOk { entry }),
+            wire::lww_map().prop_map(|entries| DynamoPayload::Handoff { entries }),
+            Just(DynamoPayload::HandoffOk),
+        ];
+        prop_oneof![
+            wire::service_payload().prop_map(Payload::Kv),
+            dynamo.prop_map(Payload::Dynamo),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("lin_kv");
+    }
+}
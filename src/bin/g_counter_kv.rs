@@ -0,0 +1,546 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop, rpc::Rpc, Body, ErrorCode, Event, Init, KVPayload, MaelstromError, Message, Node,
+    Output, WithKV, KV,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum GCounterPayload {
+    Add { delta: u64 },
+    AddOk,
+    Read,
+    ReadOk { value: u64 },
+    Error { code: usize, text: String },
+}
+
+type Payload = WithKV<GCounterPayload>;
+
+const STORAGE: &str = "seq-kv";
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default number of times an add is tried before it is given up on.
+const DEFAULT_ATTEMPTS: u32 = 10;
+
+/// Default pause after the first failed try, doubled after each one.
+const DEFAULT_BACKOFF_MS: u64 = 5;
+
+#[derive(Debug, Clone)]
+struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads the policy from the environment:
+    /// - `G_COUNTER_KV_ATTEMPTS`: times an add is tried before it fails (default 10)
+    /// - `G_COUNTER_KV_BACKOFF_MS`: pause after the first failed try in milliseconds,
+    ///   doubled after each further one (default 5)
+    fn from_env() -> anyhow::Result<Self> {
+        let attempts = gossip_glomers::env_or("G_COUNTER_KV_ATTEMPTS", DEFAULT_ATTEMPTS)?;
+        if attempts == 0 {
+            anyhow::bail!("G_COUNTER_KV_ATTEMPTS must be greater than 0");
+        }
+        let backoff_ms = gossip_glomers::env_or("G_COUNTER_KV_BACKOFF_MS", DEFAULT_BACKOFF_MS)?;
+        Ok(Self {
+            attempts,
+            backoff: Duration::from_millis(backoff_ms),
+        })
+    }
+
+    /// Returns the pause before attempt `attempt`, counting from 0.
+    fn backoff(&self, attempt: u32) -> Duration {
+        match attempt {
+            0 => Duration::ZERO,
+            attempt => self.backoff * 2u32.saturating_pow(attempt - 1),
+        }
+    }
+}
+
+/// A grow-only counter kept entirely in seq-kv, without any message between the nodes:
+/// every node adds to a key of its own, which only it writes, and reads sum the keys of
+/// all nodes. seq-kv may serve reads from the past, so a read first writes a fresh
+/// nonce, after which its reads see at least every add acknowledged before.
+struct GCounterKvNode {
+    node: String,
+    nodes: Vec<String>,
+    id: AtomicUsize,
+    retry: RetryPolicy,
+    /// The value of this node's key, if known. Adds hold the lock while they update
+    /// the key, so they never race each other.
+    own: Mutex<Option<u64>>,
+    /// Counter the nonces written before reads are unique by.
+    next_nonce: AtomicU64,
+    /// Highest value of every node's key read so far. Keys only grow, so a lower value
+    /// is a stale read and reading it would make the counter go back.
+    seen: Mutex<HashMap<String, u64>>,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+impl GCounterKvNode {
+    fn key(node: &str) -> String {
+        format!("g_counter:{}", node)
+    }
+
+    /// Adds `delta` to this node's key. The node is its only writer, so a cas from the
+    /// value it last wrote can only fail if an earlier cas timed out: the key is then
+    /// read again, which tells whether that cas went through. Reads see every write of
+    /// the same client in seq-kv, so no nonce is needed.
+    async fn add(&self, delta: u64) -> anyhow::Result<()> {
+        let key = Self::key(&self.node);
+        let mut own = self.own.lock().await;
+        // The value the first cas of this add started from.
+        let mut start = None;
+        for attempt in 0..self.retry.attempts {
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            let current = match *own {
+                Some(current) => current,
+                None => match self.read(STORAGE, key.clone()).await {
+                    std::result::Result::Ok(current) => current,
+                    Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => 0,
+                    Err(err) => {
+                        eprintln!("read {}: {:#}", key, err);
+                        continue;
+                    }
+                },
+            };
+            match start {
+                Some(start) if current == start + delta => {
+                    *own = Some(current);
+                    return Ok(());
+                }
+                Some(start) if current != start => {
+                    anyhow::bail!(
+                        "{} moved from {} to {} under its only writer",
+                        key,
+                        start,
+                        current
+                    );
+                }
+                _ => start = Some(current),
+            }
+            match self
+                .cas(STORAGE, key.clone(), current, current + delta, true)
+                .await
+            {
+                std::result::Result::Ok(()) => {
+                    *own = Some(current + delta);
+                    return Ok(());
+                }
+                Err(err) if ErrorCode::of(&err) == Some(ErrorCode::PreconditionFailed) => {
+                    *own = None;
+                    return Err(err).context(format!("{} changed under its only writer", key));
+                }
+                Err(err) => {
+                    *own = None;
+                    eprintln!("cas {}: {:#}", key, err);
+                }
+            }
+        }
+        Err(MaelstromError::new(
+            ErrorCode::Timeout,
+            format!("add did not go through in {} attempts", self.retry.attempts),
+        )
+        .into())
+    }
+
+    /// Sums the keys of all nodes, after writing a nonce so that the reads are at
+    /// least as recent as the moment of the read request.
+    async fn value(&self) -> anyhow::Result<u64> {
+        let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed);
+        self.write(STORAGE, format!("{}:nonce", Self::key(&self.node)), nonce)
+            .await
+            .context("write nonce")?;
+        let mut values = HashMap::new();
+        for node in &self.nodes {
+            let value = match self.read(STORAGE, Self::key(node)).await {
+                std::result::Result::Ok(value) => value,
+                Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => 0,
+                Err(err) => return Err(err).with_context(|| format!("read {}", node)),
+            };
+            values.insert(node.clone(), value);
+        }
+        let mut seen = self.seen.lock().await;
+        for (node, value) in values {
+            let highest = seen.entry(node).or_default();
+            *highest = cmp::max(*highest, value);
+        }
+        Ok(seen.values().sum())
+    }
+
+    async fn rpc(
+        &self,
+        to: &str,
+        payload: KVPayload<serde_json::Value>,
+    ) -> anyhow::Result<Message<Payload>> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::Relaxed)),
+                in_reply_to: None,
+                payload: WithKV::KV(payload),
+            },
+        };
+        self.rpc.call(msg, RPC_TIMEOUT, &self.stdout).await
+    }
+}
+
+#[async_trait]
+impl<T> KV<T> for GCounterKvNode
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T> {
+        let payload = KVPayload::Read { key };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("read from storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::ReadOk { value }) => {
+                serde_json::from_value(value).context("deserialize stored value")
+            }
+            // The workload's read_ok looks the same, and is tried first.
+            WithKV::Workload(GCounterPayload::ReadOk { value }) => {
+                serde_json::from_value(value.into()).context("deserialize stored value")
+            }
+            WithKV::Workload(GCounterPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write(&self, storage: &str, key: String, value: T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value).context("serialize value")?;
+        let payload = KVPayload::Write { key, value };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("write to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::WriteOk {}) => Ok(()),
+            WithKV::Workload(GCounterPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        let from = serde_json::to_value(from).context("serialize from value")?;
+        let to = serde_json::to_value(to).context("serialize to value")?;
+        let payload = KVPayload::Cas { key, from, to, put };
+        let result = self.rpc(storage, payload).await.context("cas to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::CasOk {}) => Ok(()),
+            WithKV::Workload(GCounterPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+}
+
+fn error(code: ErrorCode, err: anyhow::Error) -> GCounterPayload {
+    GCounterPayload::Error {
+        code: code.code(),
+        text: format!("{:#}", err),
+    }
+}
+
+#[async_trait]
+impl Node<Payload> for GCounterKvNode {
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let retry = RetryPolicy::from_env()?;
+        eprintln!("g_counter_kv retry policy: {:?}", retry);
+        Ok(Self {
+            node: init.node_id,
+            nodes: init.node_ids,
+            id: 1.into(),
+            retry,
+            own: Mutex::default(),
+            next_nonce: 0.into(),
+            seen: Mutex::default(),
+            rpc: Rpc::new(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        // Storage replies go to the RPC waiting for them.
+        let Some(message) = self.rpc.resolve(message).await else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(&self.id));
+        let payload = match reply.body.payload {
+            WithKV::Workload(GCounterPayload::Add { delta }) => match self.add(delta).await {
+                std::result::Result::Ok(()) => GCounterPayload::AddOk,
+                // Whatever went wrong, the add may have gone through.
+                Err(err) => error(
+                    ErrorCode::of(&err)
+                        .filter(|code| *code == ErrorCode::Timeout)
+                        .unwrap_or(ErrorCode::Crash),
+                    err,
+                ),
+            },
+            WithKV::Workload(GCounterPayload::Read) => match self.value().await {
+                std::result::Result::Ok(value) => GCounterPayload::ReadOk { value },
+                Err(err) => error(ErrorCode::TemporarilyUnavailable, err),
+            },
+            WithKV::Workload(
+                GCounterPayload::AddOk
+                | GCounterPayload::ReadOk { .. }
+                | GCounterPayload::Error { .. },
+            ) => return Ok(()),
+            WithKV::KV(payload) => {
+                eprintln!("unexpected storage message: {:?}", payload);
+                return Ok(());
+            }
+        };
+        reply.body.payload = WithKV::Workload(payload);
+        reply.send(&self.stdout).await.context("send reply")?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<GCounterKvNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{
+        check_counter, client_operations, wire, Cluster, CounterOk, CounterOp, Harness, KvOp,
+        MockKvService, Operation, Rng,
+    };
+    use serde_json::Value;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    /// The operation of a client request and its reply, None if the request definitely
+    /// failed. Any other error leaves the outcome unknown.
+    fn counter_operation(
+        operation: Operation<Value, Value>,
+    ) -> Option<Operation<CounterOp, CounterOk>> {
+        let payload = |body: Value| -> GCounterPayload {
+            serde_json::from_value(body).expect("parse counter message")
+        };
+        let input = match payload(operation.input) {
+            GCounterPayload::Add { delta } => CounterOp::Add(delta),
+            GCounterPayload::Read => CounterOp::Read,
+            request => panic!("unexpected request {:?}", request),
+        };
+        let output = match operation.output.map(payload) {
+            Some(GCounterPayload::AddOk) => Some(CounterOk::Added),
+            Some(GCounterPayload::ReadOk { value }) => Some(CounterOk::Value(value)),
+            Some(GCounterPayload::Error { code, .. })
+                if ErrorCode::from_code(code) == ErrorCode::TemporarilyUnavailable =>
+            {
+                return None;
+            }
+            _ => None,
+        };
+        Some(Operation {
+            client: operation.client,
+            input,
+            output,
+            invoked: operation.invoked,
+            returned: operation.returned,
+        })
+    }
+
+    /// Has three clients add and read at random nodes over a seq-kv that serves stale
+    /// reads and loses messages, then reads once more at every node after all adds are
+    /// done. Returns the history of it all.
+    async fn random_history(seed: u64) -> Vec<Operation<CounterOp, CounterOk>> {
+        let mut rng = Rng::new(seed);
+        let kv = MockKvService::seq("seq-kv", Duration::from_millis(50));
+        let mut cluster = Cluster::builder()
+            .nodes::<GCounterKvNode, Payload, ()>(&NODES)
+            .service(&kv)
+            .seed(seed)
+            .start()
+            .await;
+        cluster.set_drop_rate(0.05);
+        for _ in 0..20 {
+            let client = format!("c{}", rng.below(3));
+            let node = NODES[rng.below(NODES.len() as u64) as usize];
+            let request = match rng.below(2) {
+                0 => GCounterPayload::Add {
+                    delta: rng.below(5) + 1,
+                },
+                _ => GCounterPayload::Read,
+            };
+            cluster.send(&client, node, WithKV::<_, Value>::Workload(request));
+            tokio::time::sleep(Duration::from_millis(rng.below(20))).await;
+        }
+        // Long enough for every add to be retried until it gives up.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        cluster.set_drop_rate(0.0);
+        for node in NODES {
+            cluster.send(
+                "c9",
+                node,
+                WithKV::<_, Value>::Workload(GCounterPayload::Read),
+            );
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        client_operations(&cluster.trace())
+            .into_iter()
+            .filter_map(counter_operation)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_histories_read_some_serialization_of_the_adds() {
+        for seed in 0..10 {
+            let history = random_history(seed).await;
+            let finals = history
+                .iter()
+                .filter(|operation| operation.client == "c9" && operation.output.is_some())
+                .count();
+            assert_eq!(finals, NODES.len(), "seed {}: final reads failed", seed);
+            if let Err(err) = check_counter(&history) {
+                panic!("seed {}: {}", seed, err);
+            }
+        }
+    }
+
+    type GCounterHarness = Harness<GCounterKvNode, Payload>;
+
+    const OWN_KEY: &str = "g_counter:n0";
+
+    async fn harness(kv: &MockKvService) -> GCounterHarness {
+        GCounterHarness::builder("n0", &NODES)
+            .service(kv)
+            .start()
+            .await
+    }
+
+    async fn add(harness: &mut GCounterHarness, delta: u64) -> GCounterPayload {
+        let id = harness
+            .send("c1", WithKV::Workload(GCounterPayload::Add { delta }))
+            .await;
+        match harness.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(payload) => payload,
+            payload => panic!("expected an add reply, got {:?}", payload),
+        }
+    }
+
+    /// Asserts that `reply` is the error an add gets when its key changed behind it,
+    /// with `text` in its message.
+    fn assert_foreign_write(reply: GCounterPayload, text: &str) {
+        match reply {
+            GCounterPayload::Error { code, text: err } => {
+                assert_eq!(code, ErrorCode::Crash.code());
+                assert!(err.contains(text), "unexpected error {:?}", err);
+            }
+            reply => panic!("expected an error, got {:?}", reply),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_first_add_creates_the_key() {
+        let kv = MockKvService::seq(STORAGE, Duration::ZERO);
+        let mut harness = harness(&kv).await;
+        assert!(matches!(add(&mut harness, 3).await, GCounterPayload::AddOk));
+        assert_eq!(kv.get(OWN_KEY), Some(Value::from(3)));
+        assert_eq!(kv.count_key(KvOp::Read, OWN_KEY), 1);
+
+        // The node knows its key from then on, and adds to it without reading it.
+        assert!(matches!(add(&mut harness, 2).await, GCounterPayload::AddOk));
+        assert_eq!(kv.get(OWN_KEY), Some(Value::from(5)));
+        assert_eq!(kv.count_key(KvOp::Read, OWN_KEY), 1);
+        assert_eq!(kv.count_key(KvOp::Cas, OWN_KEY), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_foreign_write_to_the_own_key_fails_the_add() {
+        let kv = MockKvService::seq(STORAGE, Duration::ZERO);
+        let mut harness = harness(&kv).await;
+        assert!(matches!(add(&mut harness, 3).await, GCounterPayload::AddOk));
+
+        kv.put(OWN_KEY, 10);
+        let reply = add(&mut harness, 1).await;
+        assert_foreign_write(reply, "changed under its only writer");
+        assert_eq!(kv.get(OWN_KEY), Some(Value::from(10)));
+
+        // A cas that times out is followed by a read, which finds the foreign write.
+        kv.drop_next(KvOp::Cas, 1);
+        let id = harness
+            .send("c1", WithKV::Workload(GCounterPayload::Add { delta: 1 }))
+            .await;
+        harness.advance(RPC_TIMEOUT / 2).await;
+        kv.put(OWN_KEY, 20);
+        match harness.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(reply) => assert_foreign_write(reply, "moved from 10 to 20"),
+            payload => panic!("expected an add reply, got {:?}", payload),
+        }
+        assert_eq!(kv.get(OWN_KEY), Some(Value::from(20)));
+    }
+
+    /// Leaves out the lin-kv `read` request, which shares its type with the
+    /// workload's and parses as that, since the node only ever sends it.
+    fn payload() -> impl Strategy<Value = Payload> {
+        wire::with_kv(prop_oneof![
+            any::<u64>().prop_map(|delta| GCounterPayload::Add { delta }),
+            Just(GCounterPayload::AddOk),
+            Just(GCounterPayload::Read),
+            any::<u64>().prop_map(|value| GCounterPayload::ReadOk { value }),
+            wire::error().prop_map(|(code, text)| GCounterPayload::Error { code, text }),
+        ])
+        .prop_filter("lin-kv read", |payload| {
+            !matches!(payload, WithKV::KV(KVPayload::Read { .. }))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("g_counter");
+        wire::assert_fixture_parses::<Payload>("kv_service");
+    }
+}
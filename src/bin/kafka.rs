@@ -1,20 +1,180 @@
 use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicUsize, Ordering},
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Body, Event, Init, Message, Node, KV};
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use gossip_glomers::{
+    event_loop,
+    rpc::Rpc,
+    sharding::{self, owner},
+    Body, ErrorCode, Event, Init, KVPayload, MaelstromError, Message, Node, Output, WithKV, KV,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{oneshot, Mutex, Semaphore};
+
+/// Default maximum number of messages returned per key in a single poll.
+const DEFAULT_POLL_KEY_LIMIT: i64 = 100;
+
+/// Default maximum number of messages returned across all keys in a single poll.
+const DEFAULT_POLL_TOTAL_LIMIT: i64 = 1000;
+
+/// Default number of messages kept in the poll cache across all logs.
+const DEFAULT_CACHE_ENTRIES: usize = 100_000;
+
+/// Default number of storage RPCs a node has in flight at once.
+const DEFAULT_STORAGE_CONCURRENCY: usize = 32;
+
+/// Default number of messages stored in one segment value with the `vec` layout.
+const DEFAULT_SEGMENT_SIZE: i64 = 1000;
+
+/// How long to wait for storage or another node before giving up on a request.
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of recent Sends remembered to answer client retries.
+const SERVED_SENDS_CAPACITY: usize = 4096;
+
+/// How often nodes exchange their view of the committed offsets.
+const COMMITTED_GOSSIP_PERIOD: Duration = Duration::from_millis(200);
+
+/// How often owned logs are compacted, when compaction is enabled.
+const COMPACTION_PERIOD: Duration = Duration::from_secs(1);
+
+/// Most offsets of one log compacted in a single round, so a round never floods
+/// the storage service.
+const COMPACTION_BATCH: i64 = 100;
+
+/// Default number of messages below the committed offset that compaction keeps.
+const DEFAULT_COMPACTION_MARGIN: i64 = 1000;
+
+/// How long a poll waits for the storage reads of a single key.
+const POLL_KEY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often the latest offset hints are persisted.
+const HINT_FLUSH_PERIOD: Duration = Duration::from_millis(100);
+
+/// Whether `err` is a failed CAS, meaning the value changed under us and the
+/// operation should be retried against the new value.
+fn is_conflict(err: &anyhow::Error) -> bool {
+    ErrorCode::of(err) == Some(ErrorCode::PreconditionFailed)
+}
+
+/// How the messages of a log are laid out in storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogLayout {
+    /// One storage key per message: `{key}:{offset} -> msg`, plus `latest:{key}`.
+    PerOffset,
+    /// Messages are appended to segment values holding up to `segment_size` of them:
+    /// `log:{key}:{segment} -> [msg, ...]`. An append is a read plus a CAS, shared by
+    /// the appends to the same key that queue up meanwhile, and a poll is a single
+    /// read.
+    Vec,
+}
+
+impl FromStr for LogLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-offset" => Ok(Self::PerOffset),
+            "vec" => Ok(Self::Vec),
+            _ => anyhow::bail!("unknown log layout {:?}, expected per-offset or vec", s),
+        }
+    }
+}
+
+/// Key/value services provided by Maelstrom.
+const KV_SERVICES: [&str; 3] = ["lin-kv", "seq-kv", "lww-kv"];
+
+/// Settings read from the environment at startup, each falling back to a default.
+#[derive(Debug)]
+struct KafkaConfig {
+    log_storage: String,
+    offset_storage: String,
+    storage_concurrency: usize,
+    layout: LogLayout,
+    segment_size: i64,
+    offset_block: i64,
+    cache_entries: usize,
+    poll_key_limit: i64,
+    poll_total_limit: i64,
+    strict_commits: bool,
+    compaction: bool,
+    compaction_margin: i64,
+}
+
+impl KafkaConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            log_storage: kv_service("KAFKA_LOG_STORAGE", "lin-kv")?,
+            offset_storage: kv_service("KAFKA_OFFSET_STORAGE", "seq-kv")?,
+            storage_concurrency: gossip_glomers::env_or(
+                "KAFKA_STORAGE_CONCURRENCY",
+                DEFAULT_STORAGE_CONCURRENCY,
+            )?,
+            layout: gossip_glomers::env_or("KAFKA_LOG_LAYOUT", LogLayout::PerOffset)?,
+            segment_size: gossip_glomers::env_or("KAFKA_SEGMENT_SIZE", DEFAULT_SEGMENT_SIZE)?,
+            offset_block: gossip_glomers::env_or("KAFKA_OFFSET_BLOCK", 1)?,
+            cache_entries: gossip_glomers::env_or("KAFKA_CACHE_ENTRIES", DEFAULT_CACHE_ENTRIES)?,
+            poll_key_limit: gossip_glomers::env_or("KAFKA_POLL_KEY_LIMIT", DEFAULT_POLL_KEY_LIMIT)?,
+            poll_total_limit: gossip_glomers::env_or(
+                "KAFKA_POLL_TOTAL_LIMIT",
+                DEFAULT_POLL_TOTAL_LIMIT,
+            )?,
+            strict_commits: gossip_glomers::env_or("KAFKA_STRICT_COMMITS", false)?,
+            compaction: gossip_glomers::env_or("KAFKA_COMPACTION", false)?,
+            compaction_margin: gossip_glomers::env_or(
+                "KAFKA_COMPACTION_MARGIN",
+                DEFAULT_COMPACTION_MARGIN,
+            )?,
+        };
+        if config.storage_concurrency == 0 {
+            anyhow::bail!("KAFKA_STORAGE_CONCURRENCY must be greater than 0");
+        }
+        if config.segment_size <= 0 {
+            anyhow::bail!("KAFKA_SEGMENT_SIZE must be greater than 0");
+        }
+        if config.offset_block <= 0 {
+            anyhow::bail!("KAFKA_OFFSET_BLOCK must be greater than 0");
+        }
+        if config.offset_block > 1 && config.layout == LogLayout::Vec {
+            anyhow::bail!("KAFKA_OFFSET_BLOCK only applies to the per-offset layout");
+        }
+        if config.compaction_margin < 0 {
+            anyhow::bail!("KAFKA_COMPACTION_MARGIN must not be negative");
+        }
+        if config.poll_key_limit <= 0 || config.poll_total_limit <= 0 {
+            anyhow::bail!("KAFKA_POLL_KEY_LIMIT and KAFKA_POLL_TOTAL_LIMIT must be greater than 0");
+        }
+        Ok(config)
+    }
+}
 
-const MSG_SIZE: i64 = 5;
+/// Reads the storage service named by `name`, which has to be one of KV_SERVICES.
+fn kv_service(name: &str, default: &str) -> anyhow::Result<String> {
+    let service = gossip_glomers::env_or(name, default.to_string())?;
+    if !KV_SERVICES.contains(&service.as_str()) {
+        anyhow::bail!(
+            "unknown storage service {:?} for {}, expected one of {:?}",
+            service,
+            name,
+            KV_SERVICES
+        );
+    }
+    Ok(service)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+enum KafkaPayload {
     Send {
         key: String,
         msg: i64,
@@ -38,270 +198,3100 @@ enum Payload {
     ListCommittedOffsetsOk {
         offsets: HashMap<String, i64>,
     },
-    Read {
-        key: String,
-    },
-    ReadOk {
-        value: i64,
+    /// A node's view of the committed offsets, exchanged periodically between nodes,
+    /// along with the first and last offset of every log the node owns.
+    GossipCommitted {
+        offsets: HashMap<String, i64>,
+        #[serde(default)]
+        tails: HashMap<String, i64>,
+        #[serde(default)]
+        starts: HashMap<String, i64>,
     },
     Error {
         code: usize,
         text: String,
     },
-    Write {
-        key: String,
-        value: i64,
-    },
-    WriteOk {},
-    Cas {
-        key: String,
-        from: i64,
-        to: i64,
-        #[serde(default, rename = "create_if_not_exists")]
-        put: bool,
-    },
-    CasOk {},
 }
 
+impl KafkaPayload {
+    /// The message type, as it appears on the wire.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Send { .. } => "send",
+            Self::SendOk { .. } => "send_ok",
+            Self::Poll { .. } => "poll",
+            Self::PollOk { .. } => "poll_ok",
+            Self::CommitOffsets { .. } => "commit_offsets",
+            Self::CommitOffsetsOk => "commit_offsets_ok",
+            Self::ListCommittedOffsets { .. } => "list_committed_offsets",
+            Self::ListCommittedOffsetsOk { .. } => "list_committed_offsets_ok",
+            Self::GossipCommitted { .. } => "gossip_committed",
+            Self::Error { .. } => "error",
+        }
+    }
+
+    /// Whether a client request only reads, None for anything that is not a client
+    /// request.
+    fn reads_only(&self) -> Option<bool> {
+        match self {
+            Self::Poll { .. } | Self::ListCommittedOffsets { .. } => Some(true),
+            Self::Send { .. } | Self::CommitOffsets { .. } => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Everything a kafka node receives: the workload and the replies of its storage.
+type Payload = WithKV<KafkaPayload>;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum InjectedPayload {}
+enum InjectedPayload {
+    /// Sent once at startup.
+    WarmCaches,
+    FlushHints,
+    GossipCommitted,
+    Compact,
+}
 
 struct KafkaNode {
     id: AtomicUsize,
     node: String,
-    stdout: Mutex<tokio::io::Stdout>,
-    storage_lin: String,
-    storage_seq: String,
-    rpc: Mutex<HashMap<usize, tokio::sync::oneshot::Sender<Message<Payload>>>>,
+    stdout: Mutex<Output>,
+    /// Service holding the logs and `latest:` hints, which need linearizability.
+    log_storage: String,
+    /// Service holding `committed:` offsets. Slightly stale offsets are fine, and
+    /// keeping them apart means commits never queue behind appends.
+    offset_storage: String,
+    node_ids: Vec<String>,
+    rpc: Rpc<Payload>,
+    /// Bounds the storage RPCs in flight, so bursts queue in the node instead of
+    /// flooding the storage service. A permit is only held for a single RPC and
+    /// never while waiting for a lock, so it composes with the per-key locks.
+    storage_permits: Semaphore,
+    storage_stats: StorageStats,
+    layout: LogLayout,
+    segment_size: i64,
+    /// Last segment known to have room, per key. Only a hint: appends move past
+    /// segments that turn out to be full.
+    tail_segments: Mutex<HashMap<String, i64>>,
+    cache: Mutex<LogCache>,
+    /// Next offset to hand out, per owned key.
+    offsets: Mutex<HashMap<String, Arc<AtomicI64>>>,
+    /// Number of offsets reserved at a time. With 1, offsets are handed out from
+    /// `offsets` as they follow from the log itself.
+    offset_block: i64,
+    /// Next offset to hand out and last reserved offset, per key, when offsets are
+    /// reserved in blocks.
+    offset_blocks: Mutex<HashMap<String, (i64, i64)>>,
+    /// Highest offset per key known to be in storage: exact for owned keys, which
+    /// only this node writes, and learned from the owners' gossip for the others.
+    /// Polls never answer with less than that.
+    known_tails: Mutex<HashMap<String, i64>>,
+    /// Keys this node knows to be in the `keys` index.
+    indexed_keys: Mutex<HashSet<String>>,
+    /// Latest offset per key that has not been persisted to `latest:{key}` yet.
+    dirty_hints: Mutex<HashMap<String, i64>>,
+    poll_key_limit: i64,
+    poll_total_limit: i64,
+    poll_stats: PollStats,
+    append_locks: KeyLocks,
+    /// Messages waiting to be appended with the vec layout, per key, along with
+    /// where to send their offsets.
+    pending_appends: Mutex<HashMap<String, Vec<PendingAppend>>>,
+    served_sends: Mutex<ServedSends>,
+    /// Reject commits past the end of a log instead of clamping them.
+    strict_commits: bool,
+    /// Number of messages below the committed offset kept by compaction, which only
+    /// runs when enabled with KAFKA_COMPACTION.
+    compaction_margin: i64,
+    /// Earliest offset per key not compacted away. Exact for owned keys and learned
+    /// from the owners' gossip for the others; polls start no earlier than that.
+    log_starts: Mutex<HashMap<String, i64>>,
+    /// Highest committed offset per key known to this node, from its own commits
+    /// and from other nodes' gossip.
+    committed: Mutex<HashMap<String, i64>>,
+}
+
+/// Offsets handed out for recent Sends, keyed by client and msg_id, so retried
+/// requests can be answered without appending again. Holds at most `capacity`
+/// entries and forgets the oldest first; forgotten requests are simply appended
+/// again if retried.
+struct ServedSends {
+    offsets: HashMap<(String, usize), i64>,
+    order: VecDeque<(String, usize)>,
+    capacity: usize,
+}
+
+impl ServedSends {
+    fn new(capacity: usize) -> Self {
+        Self {
+            offsets: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn get(&self, client: &str, id: usize) -> Option<i64> {
+        self.offsets.get(&(client.to_string(), id)).copied()
+    }
+
+    fn insert(&mut self, client: String, id: usize, offset: i64) {
+        let request = (client, id);
+        if self.offsets.insert(request.clone(), offset).is_none() {
+            self.order.push_back(request);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.offsets.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A message waiting to be appended, and where to send its offset or the error.
+type PendingAppend = (i64, oneshot::Sender<Result<i64, String>>);
+
+/// Per-key async locks: at most one holder per key at a time, while different keys
+/// proceed in parallel. A key's lock is dropped as soon as nobody holds or waits for it.
+#[derive(Default)]
+struct KeyLocks {
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl KeyLocks {
+    async fn lock(&self, key: &str) -> KeyGuard<'_> {
+        let lock = self
+            .locks
+            .lock()
+            .expect("key locks poisoned")
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let guard = lock.clone().lock_owned().await;
+        KeyGuard {
+            locks: self,
+            key: key.to_string(),
+            lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+struct KeyGuard<'a> {
+    locks: &'a KeyLocks,
+    key: String,
+    lock: Arc<Mutex<()>>,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.locks.locks.lock().expect("key locks poisoned");
+        // One reference is held by the map and one by us; anything more is a waiter.
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// Counts how often poll replies were cut short by the configured limits.
+#[derive(Default)]
+struct PollStats {
+    keys: AtomicU64,
+    truncated: AtomicU64,
+    starved: AtomicU64,
+}
+
+impl PollStats {
+    fn record(&self, returned: i64, limit: i64) {
+        self.keys.fetch_add(1, Ordering::Relaxed);
+        if limit == 0 {
+            self.starved.fetch_add(1, Ordering::Relaxed);
+        } else if returned == limit {
+            self.truncated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn report(&self) {
+        eprintln!(
+            "poll stats: keys={} truncated={} starved={}",
+            self.keys.load(Ordering::Relaxed),
+            self.truncated.load(Ordering::Relaxed),
+            self.starved.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Client operation on whose behalf storage is asked, for the round-trip counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StorageOp {
+    Send,
+    Poll,
+    Commit,
+    List,
+    /// Hint flushes, gossip and anything else no client is waiting for.
+    Background,
+}
+
+impl StorageOp {
+    const ALL: [Self; 5] = [
+        Self::Send,
+        Self::Poll,
+        Self::Commit,
+        Self::List,
+        Self::Background,
+    ];
+
+    fn of(payload: &KafkaPayload) -> Self {
+        match payload {
+            KafkaPayload::Send { .. } => Self::Send,
+            KafkaPayload::Poll { .. } => Self::Poll,
+            KafkaPayload::CommitOffsets { .. } => Self::Commit,
+            KafkaPayload::ListCommittedOffsets { .. } => Self::List,
+            _ => Self::Background,
+        }
+    }
+
+    /// The operation the current task is serving.
+    fn current() -> Self {
+        STORAGE_OP.try_with(|op| *op).unwrap_or(Self::Background)
+    }
+}
+
+tokio::task_local! {
+    /// Set while a message is handled, so storage round-trips deep down the call
+    /// chain are counted against the client operation that caused them.
+    static STORAGE_OP: StorageOp;
+}
+
+/// Counts client requests and the storage round-trips made for them, per operation.
+#[derive(Default)]
+struct StorageStats {
+    requests: [AtomicU64; StorageOp::ALL.len()],
+    round_trips: [AtomicU64; StorageOp::ALL.len()],
+}
+
+impl StorageStats {
+    fn request(&self) {
+        self.requests[StorageOp::current() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn round_trip(&self) {
+        self.round_trips[StorageOp::current() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Requests served and storage round-trips made so far for `op`.
+    fn counts(&self, op: StorageOp) -> (u64, u64) {
+        (
+            self.requests[op as usize].load(Ordering::Relaxed),
+            self.round_trips[op as usize].load(Ordering::Relaxed),
+        )
+    }
+
+    fn report(&self) {
+        let ops: Vec<String> = StorageOp::ALL
+            .into_iter()
+            .map(|op| {
+                let (requests, round_trips) = self.counts(op);
+                let avg = round_trips as f64 / cmp::max(requests, 1) as f64;
+                format!("{:?}={}/{} ({:.2})", op, round_trips, requests, avg)
+            })
+            .collect();
+        eprintln!("storage round-trips per request: {}", ops.join(" "));
+    }
+}
+
+/// Contiguous run of messages of one log, starting at offset `base`.
+struct CachedLog {
+    base: i64,
+    msgs: Vec<i64>,
+    last_used: u64,
+}
+
+/// In-memory copy of the parts of each log this node has seen, so polls can be
+/// served without a storage round-trip. Holds at most `capacity` messages in total
+/// and evicts whole logs, least recently used first.
+struct LogCache {
+    logs: HashMap<String, CachedLog>,
+    capacity: usize,
+    len: usize,
+    clock: u64,
+}
+
+impl LogCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            logs: HashMap::new(),
+            capacity,
+            len: 0,
+            clock: 0,
+        }
+    }
+
+    /// Returns the last cached offset of `key`.
+    fn tail(&self, key: &str) -> Option<i64> {
+        let log = self.logs.get(key)?;
+        Some(log.base + log.msgs.len() as i64 - 1)
+    }
+
+    /// Returns the cached messages of `key` from `offset` onwards, at most `limit`.
+    fn get(&mut self, key: &str, offset: i64, limit: i64) -> Vec<Vec<i64>> {
+        self.clock += 1;
+        let Some(log) = self.logs.get_mut(key) else {
+            return Vec::new();
+        };
+        log.last_used = self.clock;
+        if offset < log.base {
+            return Vec::new();
+        }
+        log.msgs
+            .iter()
+            .enumerate()
+            .skip((offset - log.base) as usize)
+            .take(limit as usize)
+            .map(|(i, msg)| vec![log.base + i as i64, *msg])
+            .collect()
+    }
+
+    /// Records the messages of `key` starting at `offset`. A run starting inside or
+    /// right after the cached one extends it; any other run replaces it.
+    fn insert(&mut self, key: &str, offset: i64, msgs: impl IntoIterator<Item = i64>) {
+        let msgs: Vec<i64> = msgs.into_iter().collect();
+        if msgs.is_empty() {
+            return;
+        }
+        self.clock += 1;
+        let log = self
+            .logs
+            .entry(key.to_string())
+            .or_insert_with(|| CachedLog {
+                base: offset,
+                msgs: Vec::new(),
+                last_used: 0,
+            });
+        log.last_used = self.clock;
+
+        let end = log.base + log.msgs.len() as i64;
+        if offset >= log.base && offset <= end {
+            let skip = (end - offset) as usize;
+            let before = log.msgs.len();
+            log.msgs.extend(msgs.into_iter().skip(skip));
+            self.len += log.msgs.len() - before;
+        } else {
+            self.len = self.len - log.msgs.len() + msgs.len();
+            log.base = offset;
+            log.msgs = msgs;
+        }
+        self.evict();
+    }
+
+    /// Drops the cached messages of `key` below `offset`.
+    fn trim(&mut self, key: &str, offset: i64) {
+        let Some(log) = self.logs.get_mut(key) else {
+            return;
+        };
+        let drop = cmp::min(cmp::max(offset - log.base, 0) as usize, log.msgs.len());
+        log.msgs.drain(..drop);
+        log.base += drop as i64;
+        self.len -= drop;
+    }
+
+    fn evict(&mut self) {
+        while self.len > self.capacity {
+            let Some(key) = self
+                .logs
+                .iter()
+                .min_by_key(|(_, log)| log.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                return;
+            };
+            if let Some(log) = self.logs.remove(&key) {
+                self.len -= log.msgs.len();
+            }
+        }
+    }
 }
 
 impl KafkaNode {
-    async fn rpc(&self, to: &String, payload: Payload) -> anyhow::Result<Message<Payload>> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
+    /// Sends `payload` to `to` and waits for the reply. Only used for storage, so
+    /// every call counts as a storage round-trip.
+    async fn rpc(
+        &self,
+        to: &str,
+        payload: KVPayload<serde_json::Value>,
+    ) -> anyhow::Result<Message<Payload>> {
+        self.storage_stats.round_trip();
+        let _permit = self
+            .storage_permits
+            .acquire()
+            .await
+            .context("storage permits closed")?;
         let msg = Message {
             src: self.node.clone(),
-            dest: to.clone(),
+            dest: to.to_string(),
             body: Body {
-                id: self.id.fetch_add(1, Ordering::SeqCst).into(),
+                id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
                 in_reply_to: None,
-                payload,
+                payload: WithKV::KV(payload),
             },
         };
-        self.rpc.lock().await.insert(msg.body.id.unwrap(), tx);
-        msg.send(&self.stdout).await.context("send rpc message")?;
-        rx.await.context("receive rpc response")
+        self.rpc.call(msg, RPC_TIMEOUT, &self.stdout).await
     }
-}
 
-#[async_trait]
-impl KV<i64> for KafkaNode {
-    async fn read(&self, storage: &String, key: String) -> anyhow::Result<i64> {
-        let payload = Payload::Read { key };
-        let result = self
-            .rpc(storage, payload)
+    /// Hands a Send for a key we do not own to its owner and relays the answer to
+    /// the client. If the owner does not answer, the message may still have been
+    /// appended, so the client is told the outcome is unknown rather than to retry.
+    async fn forward_send(
+        &self,
+        message: Message<KafkaPayload>,
+        owner: &str,
+    ) -> anyhow::Result<()> {
+        let (client, request_id) = (message.src.clone(), message.body.id);
+        let id = self.id.fetch_add(1, Ordering::SeqCst);
+        let request = message.clone().map_payload(WithKV::Workload);
+        let reply = match self
+            .rpc
+            .forward(request, owner, id, RPC_TIMEOUT, &self.stdout)
             .await
-            .context("read from storage")?;
-        match result.body.payload {
-            Payload::ReadOk { value } => Ok(value),
-            _ => anyhow::bail!("unexpected payload"),
-        }
-    }
-
-    async fn write(&self, storage: &String, key: String, value: i64) -> anyhow::Result<()> {
-        let payload = Payload::Write { key, value };
-        let _result = self.rpc(storage, payload).await.context("write to storage");
-        Ok(())
+        {
+            Ok(reply) => {
+                if let (WithKV::Workload(KafkaPayload::SendOk { offset }), Some(request_id)) =
+                    (&reply.body.payload, request_id)
+                {
+                    self.served_sends
+                        .lock()
+                        .await
+                        .insert(client, request_id, *offset);
+                }
+                reply
+            }
+            Err(err) => {
+                let code = if ErrorCode::of(&err) == Some(ErrorCode::Timeout) {
+                    ErrorCode::Timeout
+                } else {
+                    ErrorCode::Crash
+                };
+                let mut reply = message.into_reply(Some(&self.id));
+                reply.body.payload = KafkaPayload::Error {
+                    code: code.code(),
+                    text: format!("owner {} did not answer: {:#}", owner, err),
+                };
+                reply.map_payload(WithKV::Workload)
+            }
+        };
+        reply
+            .send(&self.stdout)
+            .await
+            .context("send forwarded send response")
     }
 
-    async fn cas(
+    /// Tells `client` that its request `id` failed, rather than leaving it waiting. A
+    /// failed read definitely did not happen, but a write may have gone through before
+    /// storage stopped answering.
+    async fn reply_failure(
         &self,
-        storage: &String,
-        key: String,
-        from: i64,
-        to: i64,
-        put: bool,
+        client: &str,
+        id: usize,
+        reads_only: bool,
+        err: &anyhow::Error,
     ) -> anyhow::Result<()> {
-        let payload = Payload::Cas { key, from, to, put };
-        let result = self.rpc(storage, payload).await.context("cas to storage")?;
-        match result.body.payload {
-            Payload::CasOk {} => Ok(()),
-            _ => anyhow::bail!("unexpected payload"),
-        }
+        let code = if reads_only {
+            ErrorCode::TemporarilyUnavailable
+        } else if ErrorCode::of(err) == Some(ErrorCode::Timeout) {
+            ErrorCode::Timeout
+        } else {
+            ErrorCode::Crash
+        };
+        let reply = Message {
+            src: self.node.clone(),
+            dest: client.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                in_reply_to: Some(id),
+                payload: KafkaPayload::Error {
+                    code: code.code(),
+                    text: format!("{:#}", err),
+                },
+            },
+        };
+        reply
+            .send(&self.stdout)
+            .await
+            .context("send failure response")
     }
-}
 
-#[async_trait]
-impl Node<Payload, InjectedPayload> for KafkaNode {
-    fn from_init(
-        init: Init,
-        _tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
-    ) -> anyhow::Result<Self>
+    /// Like `read`, but a key that does not exist yet is `None` rather than an error.
+    async fn read_opt<T>(&self, storage: &str, key: String) -> anyhow::Result<Option<T>>
     where
-        Self: Sized,
+        Self: KV<T>,
+        T: Deserialize<'static> + Send,
     {
-        let id = AtomicUsize::new(1);
-        let storage_lin = "lin-kv".to_string();
-        let storage_seq = "seq-kv".to_string();
+        match self.read(storage, key).await {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-        Ok(Self {
-            id,
-            node: init.node_id,
-            stdout,
-            storage_lin,
-            storage_seq,
-            rpc: Mutex::new(HashMap::new()),
-        })
+    /// Appends `msg` to the log of `key` and returns its offset. Appends to one key
+    /// are serialized within the node, so offsets follow the order of the appends.
+    async fn append(&self, key: &str, msg: i64) -> anyhow::Result<i64> {
+        if self.layout == LogLayout::Vec {
+            return self.append_batched(key, msg).await;
+        }
+        let _guard = self.append_locks.lock(key).await;
+        self.index_key(key).await.context("index key")?;
+        let offset = self.next_offset(key).await.context("allocate offset")?;
+        self.write(&self.log_storage, format!("{}:{}", key, offset), msg)
+            .await
+            .context("write message")?;
+        self.cache.lock().await.insert(key, offset, [msg]);
+        self.merge_tails([(key.to_string(), offset)]).await;
+        let mut hints = self.dirty_hints.lock().await;
+        let hint = hints.entry(key.to_string()).or_insert(offset);
+        *hint = cmp::max(*hint, offset);
+        Ok(offset)
     }
 
-    /// # Handle incoming messages
-    ///
-    /// We will store the messages and offsets in the following format in the KV store:
-    /// - {key}:{offset} -> {msg}
-    /// - latest:{key} -> {offset}
-    /// - committed:{key} -> {offset}
-    async fn handle(
-        &self,
-        event: gossip_glomers::Event<Payload, InjectedPayload>,
-    ) -> anyhow::Result<()> {
-        match event {
-            gossip_glomers::Event::EOF => {}
-            gossip_glomers::Event::Message(message) => {
-                // Handle RPC responses
-                if message.body.in_reply_to.is_some() {
-                    let id = message.body.in_reply_to.unwrap();
-                    let tx = self.rpc.lock().await.remove(&id).unwrap();
-                    if let Err(_) = tx.send(message) {
-                        anyhow::bail!("rpc response channel closed");
-                    }
-                    return Ok(());
+    /// Appends `msg` with the vec layout. Appends to a key that queue up behind the
+    /// append lock are written together: whoever gets the lock next appends every
+    /// queued message with one CAS per segment and hands out the offsets.
+    async fn append_batched(&self, key: &str, msg: i64) -> anyhow::Result<i64> {
+        let (tx, rx) = oneshot::channel();
+        self.pending_appends
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_default()
+            .push((msg, tx));
+        {
+            let _guard = self.append_locks.lock(key).await;
+            // Empty if an earlier holder of the lock took our message along.
+            let batch = self
+                .pending_appends
+                .lock()
+                .await
+                .remove(key)
+                .unwrap_or_default();
+            if !batch.is_empty() {
+                let msgs: Vec<i64> = batch.iter().map(|(msg, _)| *msg).collect();
+                let offsets = match self.index_key(key).await.context("index key") {
+                    Ok(()) => self.append_segment(key, &msgs).await,
+                    Err(err) => Err(err),
+                };
+                for (i, (_, tx)) in batch.into_iter().enumerate() {
+                    let result = match &offsets {
+                        Ok(offsets) => Ok(offsets[i]),
+                        Err(err) => Err(format!("{:#}", err)),
+                    };
+                    let _ = tx.send(result);
                 }
+            }
+        }
+        rx.await
+            .context("append batch dropped")?
+            .map_err(anyhow::Error::msg)
+    }
 
-                let mut reply = message.into_reply(Some(&self.id));
-                match reply.body.payload {
-                    Payload::Send { key, msg } => {
-                        // Find the offset
-                        let latest_key = format!("latest:{}", key);
-                        let mut start = match self
-                            .read(&self.storage_lin, latest_key.clone())
-                            .await
-                            .context("read latest offset")
-                        {
-                            Ok(offset) => offset,
-                            Err(_) => 0,
-                        };
+    /// Appends `msgs` to the tail segment of `key` with a read-append-CAS, moving on
+    /// to the next segment once the current one holds `segment_size` messages, and
+    /// returns their offsets.
+    async fn append_segment(&self, key: &str, msgs: &[i64]) -> anyhow::Result<Vec<i64>> {
+        let mut segment = *self.tail_segments.lock().await.get(key).unwrap_or(&0);
+        let mut offsets = Vec::with_capacity(msgs.len());
+        while offsets.len() < msgs.len() {
+            let segment_key = format!("log:{}:{}", key, segment);
+            let (current, put): (Vec<i64>, bool) = match self
+                .read_opt::<Option<Vec<i64>>>(&self.log_storage, segment_key.clone())
+                .await
+                .context("read tail segment")?
+            {
+                Some(Some(current)) => (current, false),
+                // Only full segments get compacted.
+                Some(None) => {
+                    segment += 1;
+                    continue;
+                }
+                None => (Vec::new(), true),
+            };
+            let room = self.segment_size - current.len() as i64;
+            if room <= 0 {
+                segment += 1;
+                continue;
+            }
 
-                        loop {
-                            let curr = start.clone();
-                            let (prev, now) = (curr.clone() - 1, curr);
-                            let res = self
-                                .cas(&self.storage_lin, latest_key.clone(), prev, now, true)
-                                .await
-                                .context("cas to find offset");
-                            match res {
-                                Ok(_) => break,
-                                Err(_) => start += 1,
-                            }
-                        }
+            let start = segment * self.segment_size + current.len() as i64;
+            let chunk = &msgs[offsets.len()..cmp::min(msgs.len(), offsets.len() + room as usize)];
+            let mut next = current.clone();
+            next.extend_from_slice(chunk);
+            match self
+                .cas(&self.log_storage, segment_key, current, next, put)
+                .await
+            {
+                Ok(()) => {
+                    self.tail_segments
+                        .lock()
+                        .await
+                        .insert(key.to_string(), segment);
+                    self.cache
+                        .lock()
+                        .await
+                        .insert(key, start, chunk.iter().copied());
+                    let last = start + chunk.len() as i64 - 1;
+                    self.merge_tails([(key.to_string(), last)]).await;
+                    offsets.extend(start..=last);
+                }
+                Err(err) if is_conflict(&err) => continue,
+                Err(err) => return Err(err.context("append to tail segment")),
+            }
+        }
+        Ok(offsets)
+    }
 
-                        let msg_key = format!("{}:{}", key, start);
-                        let _ = self
-                            .write(&self.storage_seq, msg_key.clone(), msg)
-                            .await
-                            .context("write message");
+    /// Splits the poll budget between `keys`: every key gets an equal share of the
+    /// total limit, capped at the per-key limit. Clients poll again from where the
+    /// reply left off, so keys that get nothing this time catch up on the next poll.
+    fn poll_limits<'a>(&self, keys: impl Iterator<Item = &'a String>) -> HashMap<String, i64> {
+        let mut keys: Vec<&String> = keys.collect();
+        keys.sort();
+        let n = cmp::max(keys.len() as i64, 1);
+        let (share, extra) = (self.poll_total_limit / n, self.poll_total_limit % n);
+        keys.into_iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let share = share + i64::from((i as i64) < extra);
+                (key.clone(), cmp::min(share, self.poll_key_limit))
+            })
+            .collect()
+    }
 
-                        let _ = self
-                            .write(&self.storage_seq, latest_key, start)
-                            .await
-                            .context("write latest offset");
+    /// Like `poll_key`, but gives up on storage after POLL_KEY_TIMEOUT and returns
+    /// whatever the cache holds instead, so one slow key does not hold up the others.
+    /// Messages known to be in storage are always waited for.
+    async fn poll_key_within(
+        &self,
+        key: &str,
+        offset: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Vec<i64>>> {
+        let poll = self.poll_key(key, offset, limit);
+        tokio::pin!(poll);
+        if let Ok(msgs) = tokio::time::timeout(POLL_KEY_TIMEOUT, &mut poll).await {
+            return msgs.context("poll key");
+        }
+        let cached = self.cache.lock().await.get(key, cmp::max(offset, 0), limit);
+        if self.covers_known_tail(key, offset, limit, &cached).await {
+            return Ok(cached);
+        }
+        // The cache is missing messages known to be there, possibly ones a client
+        // was just told about, so keep waiting for storage rather than hide them.
+        poll.await.context("poll key")
+    }
 
-                        reply.body.payload = Payload::SendOk { offset: start };
-                        reply
-                            .send(&self.stdout)
-                            .await
-                            .context("send send ok response")?;
-                    }
-                    Payload::Poll { offsets } => {
-                        let mut msgs = HashMap::new();
-                        for (key, offset) in offsets {
-                            let mut msg = Vec::new();
-                            for id in offset..(offset + MSG_SIZE) {
-                                let msg_key = format!("{}:{}", key, id);
-                                let res = self
-                                    .read(&self.storage_seq, msg_key)
-                                    .await
-                                    .context("read message");
-                                match res {
-                                    Ok(value) => msg.push(vec![id, value]),
-                                    Err(_) => continue,
-                                };
-                            }
-                            msgs.insert(key, msg);
-                        }
-                        reply.body.payload = Payload::PollOk { msgs };
-                        reply
-                            .send(&self.stdout)
-                            .await
-                            .context("send poll ok response")?;
-                    }
-                    Payload::CommitOffsets { offsets } => {
-                        for (key, offset) in offsets {
-                            let committed_key = format!("committed:{}", key);
-                            let _ = self
-                                .write(&self.storage_seq, committed_key, offset)
-                                .await
-                                .context("write committed offset");
-                        }
-                        reply.body.payload = Payload::CommitOffsetsOk;
-                        reply
-                            .send(&self.stdout)
-                            .await
-                            .context("send commit offsets ok response")?;
-                    }
-                    Payload::ListCommittedOffsets { keys } => {
-                        let mut offsets = HashMap::new();
-                        for key in keys {
-                            let committed_key = format!("committed:{}", key);
-                            let res = self
-                                .read(&self.storage_seq, committed_key)
-                                .await
-                                .context("read committed offset");
-                            let offset = match res {
-                                Ok(offset) => offset,
-                                Err(_) => 0,
-                            };
-                            offsets.insert(key, offset);
-                        }
-                        reply.body.payload = Payload::ListCommittedOffsetsOk { offsets };
-                        reply
-                            .send(&self.stdout)
-                            .await
-                            .context("send list commit offsets ok response")?;
-                    }
-                    Payload::Error { code, text } => {
-                        eprintln!("Error {}: {}", code, text);
-                    }
-                    Payload::ListCommittedOffsetsOk { .. }
-                    | Payload::CommitOffsetsOk
-                    | Payload::PollOk { .. }
-                    | Payload::SendOk { .. }
-                    | Payload::Read { .. }
-                    | Payload::ReadOk { .. }
-                    | Payload::Write { .. }
-                    | Payload::WriteOk {}
-                    | Payload::Cas { .. }
-                    | Payload::CasOk {} => {}
-                }
+    /// Raises the known tail of each key to the given offset.
+    async fn merge_tails(&self, tails: impl IntoIterator<Item = (String, i64)>) {
+        let mut known = self.known_tails.lock().await;
+        for (key, offset) in tails {
+            let current = known.entry(key).or_insert(offset);
+            *current = cmp::max(*current, offset);
+        }
+    }
+
+    /// Whether `msgs`, polled from `offset` with `limit`, reach every message in
+    /// that range known to be in storage.
+    async fn covers_known_tail(
+        &self,
+        key: &str,
+        offset: i64,
+        limit: i64,
+        msgs: &[Vec<i64>],
+    ) -> bool {
+        let Some(&tail) = self.known_tails.lock().await.get(key) else {
+            return true;
+        };
+        let offset = cmp::max(offset, 0);
+        let needed = cmp::min(tail, offset + limit - 1);
+        needed < offset || msgs.last().is_some_and(|last| last[0] >= needed)
+    }
+
+    /// Returns up to `limit` messages of `key` starting at `offset`. Messages
+    /// never change once written, so whatever the cache holds is served as is and only
+    /// the part of the batch past the cached range is fetched from storage.
+    async fn poll_key(&self, key: &str, offset: i64, limit: i64) -> anyhow::Result<Vec<Vec<i64>>> {
+        if limit <= 0 {
+            return Ok(Vec::new());
+        }
+        // Compacted offsets are skipped, the poll starts at the earliest one kept.
+        let start = self.log_starts.lock().await.get(key).copied().unwrap_or(0);
+        let offset = cmp::max(offset, start);
+        let mut msgs = self.cache.lock().await.get(key, offset, limit);
+        if msgs.len() as i64 == limit {
+            return Ok(msgs);
+        }
+
+        let from = offset + msgs.len() as i64;
+        // The owner knows exactly where its logs end, so it only asks storage for
+        // messages it knows are there.
+        if owner(key, &self.node_ids) == self.node {
+            let tail = self.known_tails.lock().await.get(key).copied();
+            if tail.is_some_and(|tail| from > tail) {
+                return Ok(msgs);
             }
-            gossip_glomers::Event::Injected(_) => {}
         }
-        Ok(())
+        let fetched = self
+            .fetch_log(key, from, limit - msgs.len() as i64)
+            .await
+            .context("fetch log tail")?;
+        // Only the run up to the first tombstone is cached, as the cache holds
+        // contiguous offsets.
+        let contiguous = fetched
+            .iter()
+            .zip(from..)
+            .take_while(|(entry, id)| entry[0] == *id)
+            .map(|(entry, _)| entry[1]);
+        self.cache.lock().await.insert(key, from, contiguous);
+        msgs.extend(fetched);
+        Ok(msgs)
     }
-}
+
+    /// Reads up to `limit` messages of `key` starting at `offset` from storage. The
+    /// scan stops at the first missing entry, which is either the end of the log or
+    /// an append still in flight, so a poll never skips over a hole in the log.
+    /// Tombstones are skipped.
+    async fn fetch_log(&self, key: &str, offset: i64, limit: i64) -> anyhow::Result<Vec<Vec<i64>>> {
+        if self.layout == LogLayout::Vec {
+            return self.fetch_segment(key, offset, limit).await;
+        }
+
+        let mut msgs = Vec::new();
+        let mut id = offset;
+        while (msgs.len() as i64) < limit {
+            match self
+                .read_opt(&self.log_storage, format!("{}:{}", key, id))
+                .await
+                .context("read message")?
+            {
+                Some(Some(value)) => msgs.push(vec![id, value]),
+                // A tombstone fills an offset that was reserved but never used.
+                Some(None) => {}
+                None => break,
+            }
+            id += 1;
+        }
+        Ok(msgs)
+    }
+
+    /// Reads the messages of `key` starting at `offset` from the segment holding that
+    /// offset. A poll never crosses a segment boundary; clients simply poll again.
+    async fn fetch_segment(
+        &self,
+        key: &str,
+        offset: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Vec<i64>>> {
+        let mut segment = offset / self.segment_size;
+        let msgs = loop {
+            match self
+                .read_opt::<Option<Vec<i64>>>(&self.log_storage, format!("log:{}:{}", key, segment))
+                .await
+                .context("read segment")?
+            {
+                Some(Some(msgs)) => break msgs,
+                // A compacted segment, the log goes on with the next one.
+                Some(None) => segment += 1,
+                None => return Ok(Vec::new()),
+            }
+        };
+        let start = segment * self.segment_size;
+        let offset = cmp::max(offset, start);
+        Ok(msgs
+            .into_iter()
+            .enumerate()
+            .map(|(i, msg)| vec![start + i as i64, msg])
+            .skip((offset - start) as usize)
+            .take(limit as usize)
+            .collect())
+    }
+
+    /// Hands out the next offset of an owned key. As the owner is the only writer of
+    /// the key, offsets come from an in-memory counter, seeded from storage the first
+    /// time the key is touched.
+    async fn next_offset(&self, key: &str) -> anyhow::Result<i64> {
+        if self.offset_block > 1 {
+            return self.next_block_offset(key).await;
+        }
+        let counter = self.offset_counter(key).await?;
+        Ok(counter.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Returns the offset counter of `key`, seeding it from the log tail first.
+    async fn offset_counter(&self, key: &str) -> anyhow::Result<Arc<AtomicI64>> {
+        if let Some(counter) = self.offsets.lock().await.get(key) {
+            return Ok(counter.clone());
+        }
+        let next = self.find_tail(key).await.context("find log tail")? + 1;
+        Ok(self
+            .offsets
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(AtomicI64::new(next)))
+            .clone())
+    }
+
+    /// Hands out the next offset of the block reserved for `key`, reserving a new
+    /// block once it is used up. Callers hold the append lock of the key.
+    async fn next_block_offset(&self, key: &str) -> anyhow::Result<i64> {
+        let block = self.offset_blocks.lock().await.get(key).copied();
+        let (next, last) = match block {
+            Some((next, last)) if next <= last => (next, last),
+            _ => self
+                .reserve_block(key)
+                .await
+                .context("reserve offset block")?,
+        };
+        self.offset_blocks
+            .lock()
+            .await
+            .insert(key.to_string(), (next + 1, last));
+        Ok(next)
+    }
+
+    /// Reserves the next `offset_block` offsets of `key` by moving `reserved:{key}`
+    /// forward with a CAS, and returns the first and last of them. The first
+    /// reservation of a key starts after the end of its log.
+    async fn reserve_block(&self, key: &str) -> anyhow::Result<(i64, i64)> {
+        let reserved_key = format!("reserved:{}", key);
+        loop {
+            let current = self
+                .read_opt(&self.log_storage, reserved_key.clone())
+                .await
+                .context("read reserved offsets")?;
+            let (from, put) = match current {
+                Some(current) => {
+                    self.fill_reserved(key, current)
+                        .await
+                        .context("fill unused reserved offsets")?;
+                    (current, false)
+                }
+                None => (self.find_tail(key).await.context("find log tail")?, true),
+            };
+            let to = from + self.offset_block;
+            match self
+                .cas(&self.log_storage, reserved_key.clone(), from, to, put)
+                .await
+            {
+                Ok(()) => return Ok((from + 1, to)),
+                Err(err) if is_conflict(&err) => continue,
+                Err(err) => return Err(err.context("cas reserved offsets")),
+            }
+        }
+    }
+
+    /// Fills the offsets of `key` between the end of its log and `reserved`, the last
+    /// one reserved, with tombstones. Within a run the node uses up every block before
+    /// reserving the next, so those offsets were left unused by a run that stopped,
+    /// and polls would never get past the hole they leave once the next block is
+    /// written. Callers hold the append lock of the key.
+    async fn fill_reserved(&self, key: &str, reserved: i64) -> anyhow::Result<()> {
+        let tail = self.find_tail(key).await.context("find log tail")?;
+        for offset in tail + 1..=reserved {
+            self.write(
+                &self.log_storage,
+                format!("{}:{}", key, offset),
+                None::<i64>,
+            )
+            .await
+            .context("write tombstone")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the last offset of `key` known to exist, or -1 for an empty log. What
+    /// the node knows locally is tried first, and storage is only asked when that does
+    /// not reach `at_least`.
+    async fn log_tail(&self, key: &str, at_least: i64) -> anyhow::Result<i64> {
+        let mut tail = -1;
+        if let Some(next) = self.offsets.lock().await.get(key) {
+            tail = next.load(Ordering::SeqCst) - 1;
+        }
+        if let Some((next, _)) = self.offset_blocks.lock().await.get(key) {
+            tail = cmp::max(tail, next - 1);
+        }
+        if let Some(cached) = self.cache.lock().await.tail(key) {
+            tail = cmp::max(tail, cached);
+        }
+        if tail >= at_least {
+            return Ok(tail);
+        }
+        let stored = match self.layout {
+            LogLayout::PerOffset => self.find_tail(key).await?,
+            LogLayout::Vec => self.find_segment_tail(key).await?,
+        };
+        Ok(cmp::max(tail, stored))
+    }
+
+    /// Finds the last written offset of `key` with the vec layout, starting from the
+    /// tail segment hint and moving on while segments are full.
+    async fn find_segment_tail(&self, key: &str) -> anyhow::Result<i64> {
+        let mut segment = *self.tail_segments.lock().await.get(key).unwrap_or(&0);
+        loop {
+            let msgs: Option<Option<Vec<i64>>> = self
+                .read_opt(&self.log_storage, format!("log:{}:{}", key, segment))
+                .await
+                .context("read segment")?;
+            match msgs {
+                None => return Ok(segment * self.segment_size - 1),
+                Some(Some(msgs)) if (msgs.len() as i64) < self.segment_size => {
+                    return Ok(segment * self.segment_size + msgs.len() as i64 - 1)
+                }
+                // Full, or compacted, which only happens to full segments.
+                Some(_) => segment += 1,
+            }
+        }
+    }
+
+    /// Finds the last written offset of `key`, or -1 for an empty log. `latest:{key}`
+    /// is only a hint persisted in the background, so the log is scanned forward from
+    /// it until the first missing entry.
+    async fn find_tail(&self, key: &str) -> anyhow::Result<i64> {
+        let mut tail = self
+            .read_opt(&self.log_storage, format!("latest:{}", key))
+            .await
+            .context("read latest offset hint")?
+            .unwrap_or(-1);
+        loop {
+            let entry: Option<Option<i64>> = self
+                .read_opt(&self.log_storage, format!("{}:{}", key, tail + 1))
+                .await
+                .context("read message")?;
+            if entry.is_none() {
+                return Ok(tail);
+            }
+            tail += 1;
+        }
+    }
+
+    /// Persists the latest offset of every key appended to since the last flush.
+    async fn flush_hints(&self) -> anyhow::Result<()> {
+        let hints = std::mem::take(&mut *self.dirty_hints.lock().await);
+        for (key, offset) in hints {
+            self.write(&self.log_storage, format!("latest:{}", key), offset)
+                .await
+                .context("write latest offset hint")?;
+        }
+        Ok(())
+    }
+
+    /// Adds `key` to the `keys` index in the log storage, unless this node already
+    /// knows it is there.
+    async fn index_key(&self, key: &str) -> anyhow::Result<()> {
+        if self.indexed_keys.lock().await.contains(key) {
+            return Ok(());
+        }
+        loop {
+            let current: Option<Vec<String>> = self
+                .read_opt(&self.log_storage, "keys".to_string())
+                .await
+                .context("read key index")?;
+            let put = current.is_none();
+            let current = current.unwrap_or_default();
+            if current.iter().any(|indexed| indexed == key) {
+                break;
+            }
+            let mut next = current.clone();
+            next.push(key.to_string());
+            match self
+                .cas(&self.log_storage, "keys".to_string(), current, next, put)
+                .await
+            {
+                Ok(()) => break,
+                Err(err) if is_conflict(&err) => continue,
+                Err(err) => return Err(err.context("cas key index")),
+            }
+        }
+        self.indexed_keys.lock().await.insert(key.to_string());
+        Ok(())
+    }
+
+    /// Loads what storage knows about every indexed key, so the first requests after
+    /// a (re)start do not have to: the committed offsets, and for owned keys where
+    /// their log ends.
+    async fn warm_caches(&self) -> anyhow::Result<()> {
+        let keys: Vec<String> = self
+            .read_opt(&self.log_storage, "keys".to_string())
+            .await
+            .context("read key index")?
+            .unwrap_or_default();
+        self.indexed_keys.lock().await.extend(keys.iter().cloned());
+        for key in &keys {
+            let committed = self
+                .read_opt(&self.offset_storage, format!("committed:{}", key))
+                .await
+                .context("read committed offset")?;
+            if let Some(offset) = committed {
+                self.merge_committed([(key.clone(), offset)]).await;
+            }
+        }
+
+        for key in sharding::owned_keys(&self.node, &self.node_ids, keys) {
+            match self.layout {
+                LogLayout::PerOffset if self.offset_block == 1 => {
+                    self.offset_counter(&key).await?;
+                }
+                LogLayout::PerOffset => {}
+                LogLayout::Vec => {
+                    let tail = self.find_segment_tail(&key).await?;
+                    self.tail_segments
+                        .lock()
+                        .await
+                        .entry(key)
+                        .or_insert((tail + 1) / self.segment_size);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Raises the known start of each key to the given offset.
+    async fn merge_starts(&self, starts: impl IntoIterator<Item = (String, i64)>) {
+        let mut known = self.log_starts.lock().await;
+        for (key, offset) in starts {
+            let current = known.entry(key).or_insert(offset);
+            *current = cmp::max(*current, offset);
+        }
+    }
+
+    /// Compacts every owned log below its committed offset, less the margin.
+    async fn compact(&self) -> anyhow::Result<()> {
+        let committed = self.committed.lock().await.clone();
+        for (key, offset) in committed {
+            if owner(&key, &self.node_ids) != self.node {
+                continue;
+            }
+            let _guard = self.append_locks.lock(&key).await;
+            self.compact_log(&key, offset - self.compaction_margin)
+                .await
+                .with_context(|| format!("compact {}", key))?;
+        }
+        Ok(())
+    }
+
+    /// Overwrites the entries of `key` below `until` with tombstones, at most
+    /// COMPACTION_BATCH entries or segments of them, and then moves `start:{key}` past
+    /// them. Readers that still go by the old start skip the tombstones, so polls never
+    /// see a gap. With the vec layout only whole segments are compacted.
+    async fn compact_log(&self, key: &str, until: i64) -> anyhow::Result<()> {
+        let start = match self.log_starts.lock().await.get(key).copied() {
+            Some(start) => start,
+            None => self
+                .read_opt(&self.log_storage, format!("start:{}", key))
+                .await
+                .context("read log start")?
+                .unwrap_or(0),
+        };
+        let until = match self.layout {
+            LogLayout::PerOffset => cmp::min(until, start + COMPACTION_BATCH),
+            LogLayout::Vec => {
+                let segments = cmp::min(
+                    until / self.segment_size,
+                    start / self.segment_size + COMPACTION_BATCH,
+                );
+                segments * self.segment_size
+            }
+        };
+        if until <= start {
+            self.merge_starts([(key.to_string(), start)]).await;
+            return Ok(());
+        }
+
+        match self.layout {
+            LogLayout::PerOffset => {
+                for offset in start..until {
+                    self.write(
+                        &self.log_storage,
+                        format!("{}:{}", key, offset),
+                        None::<i64>,
+                    )
+                    .await
+                    .context("write tombstone")?;
+                }
+            }
+            LogLayout::Vec => {
+                for segment in start / self.segment_size..until / self.segment_size {
+                    self.write(
+                        &self.log_storage,
+                        format!("log:{}:{}", key, segment),
+                        None::<Vec<i64>>,
+                    )
+                    .await
+                    .context("write segment tombstone")?;
+                }
+            }
+        }
+        self.write(&self.log_storage, format!("start:{}", key), until)
+            .await
+            .context("write log start")?;
+        self.merge_starts([(key.to_string(), until)]).await;
+        self.cache.lock().await.trim(key, until);
+        Ok(())
+    }
+
+    /// Folds `offsets` into the local view of the committed offsets, keeping the
+    /// highest offset per key.
+    async fn merge_committed(&self, offsets: impl IntoIterator<Item = (String, i64)>) {
+        let mut committed = self.committed.lock().await;
+        for (key, offset) in offsets {
+            let current = committed.entry(key).or_insert(offset);
+            *current = cmp::max(*current, offset);
+        }
+    }
+
+    /// Sends the local view of the committed offsets, and the starts and tails of
+    /// the logs this node owns, to every other node.
+    async fn gossip_committed(&self) -> anyhow::Result<()> {
+        let offsets = self.committed.lock().await.clone();
+        let tails: HashMap<String, i64> = self
+            .known_tails
+            .lock()
+            .await
+            .iter()
+            .filter(|(key, _)| owner(key, &self.node_ids) == self.node)
+            .map(|(key, tail)| (key.clone(), *tail))
+            .collect();
+        let starts: HashMap<String, i64> = self
+            .log_starts
+            .lock()
+            .await
+            .iter()
+            .filter(|(key, _)| owner(key, &self.node_ids) == self.node)
+            .map(|(key, start)| (key.clone(), *start))
+            .collect();
+        if offsets.is_empty() && tails.is_empty() && starts.is_empty() {
+            return Ok(());
+        }
+        for node in self.node_ids.iter().filter(|node| **node != self.node) {
+            let msg = Message {
+                src: self.node.clone(),
+                dest: node.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: KafkaPayload::GossipCommitted {
+                        offsets: offsets.clone(),
+                        tails: tails.clone(),
+                        starts: starts.clone(),
+                    },
+                },
+            };
+            msg.send(&self.stdout)
+                .await
+                .context("send committed offsets gossip")?;
+        }
+        Ok(())
+    }
+
+    /// Raises `committed:{key}` to `offset`. The stored offset never moves backwards,
+    /// so commits that arrive late or out of order are no-ops. The local view is only
+    /// updated once the commit is durable.
+    async fn commit_offset(&self, key: &str, offset: i64) -> anyhow::Result<()> {
+        self.store_committed(key, offset).await?;
+        self.merge_committed([(key.to_string(), offset)]).await;
+        Ok(())
+    }
+
+    async fn store_committed(&self, key: &str, offset: i64) -> anyhow::Result<()> {
+        let committed_key = format!("committed:{}", key);
+        loop {
+            let current = self
+                .read_opt(&self.offset_storage, committed_key.clone())
+                .await
+                .context("read committed offset")?;
+            let res = match current {
+                Some(current) if current >= offset => return Ok(()),
+                Some(current) => {
+                    self.cas(
+                        &self.offset_storage,
+                        committed_key.clone(),
+                        current,
+                        offset,
+                        false,
+                    )
+                    .await
+                }
+                // -1 never gets stored, so this only succeeds by creating the key.
+                None => {
+                    self.cas(
+                        &self.offset_storage,
+                        committed_key.clone(),
+                        -1,
+                        offset,
+                        true,
+                    )
+                    .await
+                }
+            };
+            match res {
+                Ok(()) => return Ok(()),
+                Err(err) if is_conflict(&err) => continue,
+                Err(err) => return Err(err.context("cas committed offset")),
+            }
+        }
+    }
+
+    /// Handles a message from a client or another node. Runs within the STORAGE_OP
+    /// scope of the message, so storage round-trips are counted against it.
+    async fn serve(&self, message: Message<KafkaPayload>) -> anyhow::Result<()> {
+        if let KafkaPayload::Send { key, .. } = &message.body.payload {
+            // A retried Send gets the offset of the first attempt, so the message
+            // is not appended twice.
+            let served = match message.body.id {
+                Some(id) => self.served_sends.lock().await.get(&message.src, id),
+                None => None,
+            };
+            if let Some(offset) = served {
+                let mut reply = message.into_reply(Some(&self.id));
+                reply.body.payload = KafkaPayload::SendOk { offset };
+                return reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send duplicate send ok response");
+            }
+
+            // Every key has a single writer, so Sends are handled by the key's owner.
+            let owner = owner(key, &self.node_ids);
+            if owner != self.node {
+                let owner = owner.to_string();
+                return self.forward_send(message, &owner).await;
+            }
+        }
+
+        // Retried and forwarded Sends are answered without touching storage here and
+        // would only water down the averages.
+        self.storage_stats.request();
+        let mut reply = message.into_reply(Some(&self.id));
+        match reply.body.payload {
+            KafkaPayload::Send { key, msg } => {
+                let offset = self.append(&key, msg).await.context("append message")?;
+                if let Some(id) = reply.body.in_reply_to {
+                    self.served_sends
+                        .lock()
+                        .await
+                        .insert(reply.dest.clone(), id, offset);
+                }
+
+                reply.body.payload = KafkaPayload::SendOk { offset };
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send send ok response")?;
+            }
+            KafkaPayload::Poll { offsets } => {
+                // Every requested key gets an entry, keys without messages
+                // (including keys never appended to) an empty one.
+                let limits = self.poll_limits(offsets.keys());
+                let polls = offsets.into_iter().map(|(key, offset)| {
+                    let limit = limits.get(&key).copied().unwrap_or_default();
+                    async move {
+                        let msg = self.poll_key_within(&key, offset, limit).await?;
+                        self.poll_stats.record(msg.len() as i64, limit);
+                        anyhow::Ok((key, msg))
+                    }
+                });
+                let msgs = futures::future::try_join_all(polls)
+                    .await
+                    .context("poll keys")?
+                    .into_iter()
+                    .collect();
+                reply.body.payload = KafkaPayload::PollOk { msgs };
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send poll ok response")?;
+            }
+            KafkaPayload::CommitOffsets { offsets } => {
+                // Offsets past the end of a log are rejected in strict mode and
+                // clamped to the end of the log otherwise.
+                let mut commits = Vec::with_capacity(offsets.len());
+                for (key, offset) in offsets {
+                    let tail = self.log_tail(&key, offset).await.context("find log tail")?;
+                    if offset <= tail {
+                        commits.push((key, offset));
+                    } else if self.strict_commits {
+                        reply.body.payload = KafkaPayload::Error {
+                            code: ErrorCode::PreconditionFailed.code(),
+                            text: format!(
+                                "offset {} of {} is past the end of the log at {}",
+                                offset, key, tail
+                            ),
+                        };
+                        return reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send commit offsets error response");
+                    } else if tail >= 0 {
+                        commits.push((key, tail));
+                    }
+                }
+                for (key, offset) in commits {
+                    self.commit_offset(&key, offset)
+                        .await
+                        .context("commit offset")?;
+                }
+                reply.body.payload = KafkaPayload::CommitOffsetsOk;
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send commit offsets ok response")?;
+            }
+            KafkaPayload::ListCommittedOffsets { keys } => {
+                // Answered from the gossiped view where possible; storage is only
+                // asked about keys this node has not heard of yet.
+                let mut offsets = HashMap::new();
+                let mut unknown = Vec::new();
+                {
+                    let committed = self.committed.lock().await;
+                    for key in keys {
+                        match committed.get(&key) {
+                            Some(offset) => {
+                                offsets.insert(key, *offset);
+                            }
+                            None => unknown.push(key),
+                        }
+                    }
+                }
+                for key in unknown {
+                    let committed_key = format!("committed:{}", key);
+                    // Keys that were never committed are left out of the reply,
+                    // any other storage error fails the request.
+                    let committed = self
+                        .read_opt(&self.offset_storage, committed_key)
+                        .await
+                        .context("read committed offset")?;
+                    if let Some(offset) = committed {
+                        self.merge_committed([(key.clone(), offset)]).await;
+                        offsets.insert(key, offset);
+                    }
+                }
+                reply.body.payload = KafkaPayload::ListCommittedOffsetsOk { offsets };
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send list commit offsets ok response")?;
+            }
+            KafkaPayload::GossipCommitted {
+                offsets,
+                tails,
+                starts,
+            } => {
+                self.merge_committed(offsets).await;
+                self.merge_tails(tails).await;
+                self.merge_starts(starts).await;
+            }
+            KafkaPayload::Error { code, text } => {
+                eprintln!("Error {}: {}", code, text);
+            }
+            KafkaPayload::ListCommittedOffsetsOk { .. }
+            | KafkaPayload::CommitOffsetsOk
+            | KafkaPayload::PollOk { .. }
+            | KafkaPayload::SendOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Storage values travel as JSON, so any serializable type can be stored.
+#[async_trait]
+impl<T> KV<T> for KafkaNode
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T> {
+        let payload = KVPayload::Read { key };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("read from storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::ReadOk { value }) => {
+                serde_json::from_value(value).context("deserialize stored value")
+            }
+            WithKV::Workload(KafkaPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write(&self, storage: &str, key: String, value: T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value).context("serialize value")?;
+        let payload = KVPayload::Write { key, value };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("write to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::WriteOk {}) => Ok(()),
+            WithKV::Workload(KafkaPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        let from = serde_json::to_value(from).context("serialize from value")?;
+        let to = serde_json::to_value(to).context("serialize to value")?;
+        let payload = KVPayload::Cas { key, from, to, put };
+        let result = self.rpc(storage, payload).await.context("cas to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::CasOk {}) => Ok(()),
+            WithKV::Workload(KafkaPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for KafkaNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let id = AtomicUsize::new(1);
+        let config = KafkaConfig::from_env()?;
+        eprintln!("kafka config: {:?}", config);
+
+        let warm = tx.clone();
+        tokio::spawn(async move {
+            let _ = warm
+                .send(Event::Injected(InjectedPayload::WarmCaches))
+                .await;
+        });
+        if config.compaction {
+            gossip_glomers::spawn_timer(tx.clone(), COMPACTION_PERIOD, InjectedPayload::Compact);
+        }
+        gossip_glomers::spawn_timer(tx.clone(), HINT_FLUSH_PERIOD, InjectedPayload::FlushHints);
+        gossip_glomers::spawn_timer(
+            tx,
+            COMMITTED_GOSSIP_PERIOD,
+            InjectedPayload::GossipCommitted,
+        );
+
+        Ok(Self {
+            id,
+            node: init.node_id,
+            stdout,
+            log_storage: config.log_storage,
+            offset_storage: config.offset_storage,
+            node_ids: init.node_ids,
+            rpc: Rpc::new(),
+            storage_permits: Semaphore::new(config.storage_concurrency),
+            storage_stats: StorageStats::default(),
+            layout: config.layout,
+            segment_size: config.segment_size,
+            tail_segments: Mutex::new(HashMap::new()),
+            cache: Mutex::new(LogCache::new(config.cache_entries)),
+            offsets: Mutex::new(HashMap::new()),
+            offset_block: config.offset_block,
+            offset_blocks: Mutex::new(HashMap::new()),
+            known_tails: Mutex::new(HashMap::new()),
+            indexed_keys: Mutex::new(HashSet::new()),
+            dirty_hints: Mutex::new(HashMap::new()),
+            poll_key_limit: config.poll_key_limit,
+            poll_total_limit: config.poll_total_limit,
+            poll_stats: PollStats::default(),
+            append_locks: KeyLocks::default(),
+            pending_appends: Mutex::new(HashMap::new()),
+            served_sends: Mutex::new(ServedSends::new(SERVED_SENDS_CAPACITY)),
+            strict_commits: config.strict_commits,
+            compaction_margin: config.compaction_margin,
+            log_starts: Mutex::new(HashMap::new()),
+            committed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// # Handle incoming messages
+    ///
+    /// We will store the messages and offsets in the following format in the KV store:
+    /// - {key}:{offset} -> {msg} or null for a tombstone
+    ///   (or log:{key}:{segment} -> [{msg}, ...] with the vec layout)
+    /// - latest:{key} -> {offset}, a hint for where the log ends
+    /// - reserved:{key} -> {offset}, the last offset reserved when reserving in blocks
+    /// - keys -> [{key}, ...], every key appended to, to warm the caches on startup
+    /// - start:{key} -> {offset}, the earliest offset not compacted away
+    /// - committed:{key} -> {offset}, in the offset storage rather than the log storage
+    async fn handle(
+        &self,
+        event: gossip_glomers::Event<Payload, InjectedPayload>,
+    ) -> anyhow::Result<()> {
+        match event {
+            gossip_glomers::Event::EOF => {
+                self.poll_stats.report();
+                self.storage_stats.report();
+            }
+            gossip_glomers::Event::Message(message) => {
+                // Storage replies go to the RPC waiting for them, so only the
+                // workload is left to serve.
+                let Some(message) = self.rpc.resolve(message).await else {
+                    return Ok(());
+                };
+                let Message { src, dest, body } = message;
+                let payload = match body.payload {
+                    WithKV::Workload(payload) => payload,
+                    WithKV::KV(payload) => {
+                        eprintln!("unexpected storage message from {}: {:?}", src, payload);
+                        return Ok(());
+                    }
+                };
+                let message = Message {
+                    src,
+                    dest,
+                    body: Body {
+                        id: body.id,
+                        in_reply_to: body.in_reply_to,
+                        payload,
+                    },
+                };
+                let op = StorageOp::of(&message.body.payload);
+                let (src, id, kind) = (
+                    message.src.clone(),
+                    message.body.id,
+                    message.body.payload.kind(),
+                );
+                let reads_only = message.body.payload.reads_only();
+                let result = STORAGE_OP
+                    .scope(op, self.serve(message))
+                    .await
+                    .with_context(|| format!("serve {} from {} (msg_id {:?})", kind, src, id));
+                if let (Err(err), Some(reads_only), Some(id)) = (&result, reads_only, id) {
+                    self.reply_failure(&src, id, reads_only, err).await?;
+                }
+                return result;
+            }
+            gossip_glomers::Event::Injected(InjectedPayload::WarmCaches) => {
+                self.warm_caches().await.context("warm caches")?;
+            }
+            gossip_glomers::Event::Injected(InjectedPayload::FlushHints) => {
+                self.flush_hints().await.context("flush offset hints")?;
+            }
+            gossip_glomers::Event::Injected(InjectedPayload::Compact) => {
+                self.compact().await.context("compact logs")?;
+            }
+            gossip_glomers::Event::Injected(InjectedPayload::GossipCommitted) => {
+                self.gossip_committed()
+                    .await
+                    .context("gossip committed offsets")?;
+            }
+        }
+        Ok(())
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     event_loop::<KafkaNode, _, _>().await
 }
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{
+        client_operations, wire, with_env, Cluster, Fate, Harness, KvOp, MockKvService, Operation,
+        Rng,
+    };
+    use proptest::prelude::*;
+    use serde_json::Value;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    type Kafka = Harness<KafkaNode, Payload, InjectedPayload>;
+
+    #[test]
+    fn storage_services_come_from_the_environment() {
+        let unset = [("KAFKA_LOG_STORAGE", None), ("KAFKA_OFFSET_STORAGE", None)];
+        let config = with_env(&unset, KafkaConfig::from_env).unwrap();
+        assert_eq!(config.log_storage, "lin-kv");
+        assert_eq!(config.offset_storage, "seq-kv");
+
+        let set = [
+            ("KAFKA_LOG_STORAGE", Some("seq-kv")),
+            ("KAFKA_OFFSET_STORAGE", Some("lww-kv")),
+        ];
+        let config = with_env(&set, KafkaConfig::from_env).unwrap();
+        assert_eq!(config.log_storage, "seq-kv");
+        assert_eq!(config.offset_storage, "lww-kv");
+
+        for name in ["KAFKA_LOG_STORAGE", "KAFKA_OFFSET_STORAGE"] {
+            for service in ["", "redis", "LIN-KV"] {
+                let vars = [(name, Some(service))];
+                assert!(
+                    with_env(&vars, KafkaConfig::from_env).is_err(),
+                    "{}={:?}",
+                    name,
+                    service
+                );
+            }
+        }
+    }
+
+    /// A single node on `lin` and `seq` for the logs and committed offsets, with the
+    /// variables `vars` set while it reads its configuration.
+    async fn kafka(
+        lin: &MockKvService,
+        seq: &MockKvService,
+        vars: &[(&str, Option<&str>)],
+    ) -> Kafka {
+        Kafka::builder("n0", &["n0"])
+            .env(vars)
+            .service(lin)
+            .service(seq)
+            .start()
+            .await
+    }
+
+    fn storage() -> (MockKvService, MockKvService) {
+        (MockKvService::lin("lin-kv"), MockKvService::lin("seq-kv"))
+    }
+
+    async fn send(node: &mut Kafka, key: &str, msg: i64) -> i64 {
+        let payload = KafkaPayload::Send {
+            key: key.to_string(),
+            msg,
+        };
+        let id = node.send("c1", WithKV::Workload(payload)).await;
+        match node.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(KafkaPayload::SendOk { offset }) => offset,
+            payload => panic!("expected send_ok, got {:?}", payload),
+        }
+    }
+
+    async fn poll(node: &mut Kafka, offsets: &[(&str, i64)]) -> HashMap<String, Vec<Vec<i64>>> {
+        let offsets = offsets
+            .iter()
+            .map(|(key, offset)| (key.to_string(), *offset))
+            .collect();
+        let id = node
+            .send("c1", WithKV::Workload(KafkaPayload::Poll { offsets }))
+            .await;
+        match node.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(KafkaPayload::PollOk { msgs }) => msgs,
+            payload => panic!("expected poll_ok, got {:?}", payload),
+        }
+    }
+
+    async fn commit(node: &mut Kafka, offsets: &[(&str, i64)]) -> KafkaPayload {
+        let offsets = offsets
+            .iter()
+            .map(|(key, offset)| (key.to_string(), *offset))
+            .collect();
+        let id = node
+            .send(
+                "c1",
+                WithKV::Workload(KafkaPayload::CommitOffsets { offsets }),
+            )
+            .await;
+        match node.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(payload) => payload,
+            payload => panic!("expected a commit_offsets reply, got {:?}", payload),
+        }
+    }
+
+    async fn list(node: &mut Kafka, keys: &[&str]) -> HashMap<String, i64> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        let id = node
+            .send(
+                "c1",
+                WithKV::Workload(KafkaPayload::ListCommittedOffsets { keys }),
+            )
+            .await;
+        match node.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(KafkaPayload::ListCommittedOffsetsOk { offsets }) => offsets,
+            payload => panic!("expected list_committed_offsets_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn committed_offsets_are_stored_and_never_move_back() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in 0..6 {
+            send(&mut node, "k1", msg).await;
+        }
+        send(&mut node, "k2", 20).await;
+
+        // The first commit of a key creates it.
+        assert!(matches!(
+            commit(&mut node, &[("k1", 3)]).await,
+            KafkaPayload::CommitOffsetsOk
+        ));
+        assert_eq!(seq.get("committed:k1"), Some(3.into()));
+        let created = seq.log().into_iter().any(|call| {
+            matches!(
+                call.request,
+                gossip_glomers::kv_service::Payload::Cas {
+                    create_if_not_exists: true,
+                    ..
+                }
+            )
+        });
+        assert!(created, "no cas created committed:k1");
+
+        commit(&mut node, &[("k1", 5)]).await;
+        assert_eq!(seq.get("committed:k1"), Some(5.into()));
+        // A commit that arrives late is acknowledged but leaves the offset alone.
+        let cas = seq.count_key(KvOp::Cas, "committed:k1");
+        assert!(matches!(
+            commit(&mut node, &[("k1", 2)]).await,
+            KafkaPayload::CommitOffsetsOk
+        ));
+        assert_eq!(seq.get("committed:k1"), Some(5.into()));
+        assert_eq!(seq.count_key(KvOp::Cas, "committed:k1"), cas);
+
+        // Keys never committed are left out, whether they have messages or not.
+        assert_eq!(
+            list(&mut node, &["k1", "k2", "k3"]).await,
+            HashMap::from([("k1".to_string(), 5)])
+        );
+        assert_eq!(seq.get("committed:k2"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_commits_keep_the_highest_offset() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in 0..6 {
+            send(&mut node, "k1", msg).await;
+            send(&mut node, "k2", msg).await;
+        }
+        seq.set_latency(KvOp::Read, Duration::from_millis(5));
+        seq.set_latency(KvOp::Cas, Duration::from_millis(5));
+        // Both commits of a key read it before either stores its offset, in either
+        // order.
+        let mut ids = Vec::new();
+        for (key, offset) in [("k1", 5), ("k1", 3), ("k2", 3), ("k2", 5)] {
+            let offsets = HashMap::from([(key.to_string(), offset)]);
+            let payload = KafkaPayload::CommitOffsets { offsets };
+            ids.push(node.send("c1", WithKV::Workload(payload)).await);
+        }
+        for id in ids {
+            match node.expect_reply_to(id).await.body.payload {
+                WithKV::Workload(KafkaPayload::CommitOffsetsOk) => {}
+                payload => panic!("expected commit_offsets_ok, got {:?}", payload),
+            }
+        }
+        assert_eq!(seq.get("committed:k1"), Some(5.into()));
+        assert_eq!(seq.get("committed:k2"), Some(5.into()));
+
+        // Committing the same offset again is acknowledged without a cas.
+        let cas = seq.count(KvOp::Cas);
+        assert!(matches!(
+            commit(&mut node, &[("k1", 5)]).await,
+            KafkaPayload::CommitOffsetsOk
+        ));
+        assert_eq!(seq.count(KvOp::Cas), cas);
+        assert_eq!(
+            list(&mut node, &["k1", "k2"]).await,
+            HashMap::from([("k1".to_string(), 5), ("k2".to_string(), 5)])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commits_past_the_end_of_the_log_are_clamped_or_rejected() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in 0..3 {
+            send(&mut node, "k1", msg).await;
+        }
+        assert!(matches!(
+            commit(&mut node, &[("k1", 10), ("k2", 4)]).await,
+            KafkaPayload::CommitOffsetsOk
+        ));
+        assert_eq!(seq.get("committed:k1"), Some(2.into()));
+        // A key without messages has nothing to commit.
+        assert_eq!(seq.get("committed:k2"), None);
+        drop(node);
+
+        let mut node = kafka(&lin, &seq, &[("KAFKA_STRICT_COMMITS", Some("true"))]).await;
+        let id = node
+            .send(
+                "c1",
+                WithKV::Workload(KafkaPayload::CommitOffsets {
+                    offsets: HashMap::from([("k1".to_string(), 10)]),
+                }),
+            )
+            .await;
+        let reply = node.expect_reply_to(id).await;
+        assert_eq!(error_code(reply), ErrorCode::PreconditionFailed);
+        assert_eq!(seq.get("committed:k1"), Some(2.into()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_get_dense_offsets_that_polls_return() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        assert_eq!(send(&mut node, "k1", 10).await, 0);
+        assert_eq!(send(&mut node, "k1", 11).await, 1);
+        assert_eq!(send(&mut node, "k2", 20).await, 0);
+        assert_eq!(send(&mut node, "k1", 12).await, 2);
+
+        let msgs = poll(&mut node, &[("k1", 1), ("k2", 0), ("k3", 0)]).await;
+        assert_eq!(msgs["k1"], vec![vec![1, 11], vec![2, 12]]);
+        assert_eq!(msgs["k2"], vec![vec![0, 20]]);
+        assert_eq!(msgs["k3"], Vec::<Vec<i64>>::new());
+        assert_eq!(lin.get("k1:2"), Some(12.into()));
+        assert_eq!(lin.count_key(KvOp::Write, "k1:0"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_keys_poll_empty_and_list_nothing() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        send(&mut node, "k1", 10).await;
+        send(&mut node, "k2", 20).await;
+        commit(&mut node, &[("k1", 0)]).await;
+        drop(node);
+
+        // A restarted node has to ask storage about every key, which does not know
+        // k3.
+        let mut node = kafka(&lin, &seq, &[]).await;
+        let msgs = poll(&mut node, &[("k1", 0), ("k3", 0)]).await;
+        assert_eq!(msgs["k1"], vec![vec![0, 10]]);
+        assert_eq!(msgs["k3"], Vec::<Vec<i64>>::new());
+        assert_eq!(
+            list(&mut node, &["k1", "k2", "k3"]).await,
+            HashMap::from([("k1".to_string(), 0)])
+        );
+        assert!(lin.count_key(KvOp::Read, "k3:0") >= 1);
+        assert!(seq.count_key(KvOp::Read, "committed:k3") >= 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_of_cached_messages_skip_storage() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in 0..5 {
+            send(&mut node, "k1", msg).await;
+        }
+        let reads = lin.count(KvOp::Read);
+        let msgs = poll(&mut node, &[("k1", 0)]).await;
+        assert_eq!(msgs["k1"].len(), 5);
+        assert_eq!(lin.count(KvOp::Read), reads);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn storage_round_trips_are_counted_per_operation() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in 0..20 {
+            send(&mut node, "k1", msg).await;
+        }
+        poll(&mut node, &[("k1", 0)]).await;
+        let stats = &node.node().storage_stats;
+        let (sends, round_trips) = stats.counts(StorageOp::Send);
+        assert_eq!(sends, 20);
+        // The first send also indexes the key and looks for its tail, the others
+        // only write their message.
+        assert!(round_trips <= 2 * sends, "{} round-trips", round_trips);
+        assert_eq!(stats.counts(StorageOp::Poll), (1, 0));
+        // The hints written in the background are not put down to the sends.
+        tokio::time::sleep(HINT_FLUSH_PERIOD * 2).await;
+        let stats = &node.node().storage_stats;
+        assert_eq!(stats.counts(StorageOp::Send).1, round_trips);
+        assert!(stats.counts(StorageOp::Background).1 >= 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_least_recently_used_log_is_evicted_from_the_cache() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[("KAFKA_CACHE_ENTRIES", Some("4"))]).await;
+        for (key, msg) in [("k1", 10), ("k1", 11), ("k2", 20), ("k2", 21)] {
+            send(&mut node, key, msg).await;
+        }
+        // Polling k1 makes k2 the least recently used log, which the next append
+        // pushes out.
+        poll(&mut node, &[("k1", 0)]).await;
+        send(&mut node, "k3", 30).await;
+
+        let reads = lin.count(KvOp::Read);
+        assert_eq!(
+            poll(&mut node, &[("k1", 0)]).await["k1"],
+            vec![vec![0, 10], vec![1, 11]]
+        );
+        assert_eq!(lin.count(KvOp::Read), reads);
+        let reads = lin.count_key(KvOp::Read, "k2:0");
+        assert_eq!(
+            poll(&mut node, &[("k2", 0)]).await["k2"],
+            vec![vec![0, 20], vec![1, 21]]
+        );
+        assert_eq!(lin.count_key(KvOp::Read, "k2:0"), reads + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_of_a_long_log_return_one_window_at_a_time() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in 0..1000 {
+            send(&mut node, "k1", msg).await;
+        }
+        let window = |from: i64| {
+            (from..from + DEFAULT_POLL_KEY_LIMIT)
+                .map(|offset| vec![offset, offset])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(poll(&mut node, &[("k1", 0)]).await["k1"], window(0));
+        let next = DEFAULT_POLL_KEY_LIMIT;
+        assert_eq!(poll(&mut node, &[("k1", next)]).await["k1"], window(next));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_poll_budget_is_shared_fairly_between_keys() {
+        let (lin, seq) = storage();
+        let vars = [
+            ("KAFKA_POLL_KEY_LIMIT", Some("10")),
+            ("KAFKA_POLL_TOTAL_LIMIT", Some("25")),
+        ];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        for key in ["k1", "k2", "k3"] {
+            for msg in 0..30 {
+                send(&mut node, key, msg).await;
+            }
+        }
+        // A single key is held to the per-key limit.
+        assert_eq!(poll(&mut node, &[("k1", 0)]).await["k1"].len(), 10);
+
+        // Three keys split the total, the remainder going to the first ones.
+        let msgs = poll(&mut node, &[("k1", 0), ("k2", 0), ("k3", 0)]).await;
+        let lens: Vec<usize> = ["k1", "k2", "k3"]
+            .iter()
+            .map(|key| msgs[*key].len())
+            .collect();
+        assert_eq!(lens, vec![9, 8, 8]);
+        assert_eq!(msgs["k3"].last(), Some(&vec![7, 7]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_poll_after_a_send_sees_the_message_despite_slow_storage() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[("KAFKA_CACHE_ENTRIES", Some("1"))]).await;
+        let offset = send(&mut node, "k1", 10).await;
+        // k1 drops out of the cache, so the poll has to go to storage, which takes
+        // longer than a poll waits for it.
+        send(&mut node, "k2", 20).await;
+        let latency = POLL_KEY_TIMEOUT + Duration::from_millis(100);
+        lin.set_latency(KvOp::Read, latency);
+        let start = Instant::now();
+        let msgs = poll(&mut node, &[("k1", offset)]).await;
+        assert_eq!(msgs["k1"], vec![vec![offset, 10]]);
+        assert!(start.elapsed() >= latency);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_restarted_node_serves_the_log_from_storage() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        for msg in [10, 11, 12] {
+            send(&mut node, "k1", msg).await;
+        }
+        drop(node);
+
+        let mut node = kafka(&lin, &seq, &[]).await;
+        let msgs = poll(&mut node, &[("k1", 0)]).await;
+        assert_eq!(msgs["k1"], vec![vec![0, 10], vec![1, 11], vec![2, 12]]);
+        assert!(lin.count_key(KvOp::Read, "k1:0") >= 1);
+        assert_eq!(send(&mut node, "k1", 13).await, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_node_starting_on_existing_storage_warms_its_caches() {
+        let (lin, seq) = storage();
+        lin.put("keys", serde_json::json!(["k1", "k2"]));
+        // The hint lags behind the log, as it does after a crash.
+        lin.put("latest:k1", 2);
+        for offset in 0..5 {
+            lin.put(format!("k1:{}", offset), 10 + offset);
+        }
+        seq.put("committed:k1", 3);
+
+        let mut node = kafka(&lin, &seq, &[]).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (reads, offset_reads) = (lin.count(KvOp::Read), seq.count(KvOp::Read));
+        assert_eq!(send(&mut node, "k1", 15).await, 5);
+        assert_eq!(send(&mut node, "k2", 20).await, 0);
+        assert_eq!(
+            list(&mut node, &["k1"]).await,
+            HashMap::from([("k1".to_string(), 3)])
+        );
+        assert_eq!(lin.count(KvOp::Read), reads);
+        assert_eq!(seq.count(KvOp::Read), offset_reads);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compaction_keeps_a_margin_below_the_committed_offset() {
+        let (lin, seq) = storage();
+        let vars = [
+            ("KAFKA_COMPACTION", Some("true")),
+            ("KAFKA_COMPACTION_MARGIN", Some("5")),
+        ];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        for msg in 0..20 {
+            send(&mut node, "k1", msg).await;
+        }
+        commit(&mut node, &[("k1", 15)]).await;
+        tokio::time::sleep(COMPACTION_PERIOD * 2).await;
+
+        for offset in 0..10 {
+            assert_eq!(lin.get(format!("k1:{}", offset)), Some(Value::Null));
+        }
+        for offset in 10..20 {
+            assert_eq!(lin.get(format!("k1:{}", offset)), Some(offset.into()));
+        }
+        assert_eq!(lin.get("start:k1"), Some(10.into()));
+        // Polls of compacted offsets start at the earliest one kept.
+        let msgs = poll(&mut node, &[("k1", 3)]).await;
+        assert_eq!(msgs["k1"], (10..20).map(|i| vec![i, i]).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_during_compaction_see_no_gaps() {
+        let (lin, seq) = storage();
+        let vars = [
+            ("KAFKA_COMPACTION", Some("true")),
+            ("KAFKA_COMPACTION_MARGIN", Some("0")),
+            ("KAFKA_CACHE_ENTRIES", Some("1")),
+        ];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        for msg in 0..30 {
+            send(&mut node, "k1", msg).await;
+        }
+        commit(&mut node, &[("k1", 20)]).await;
+        // Tombstones go down one at a time while the polls come in.
+        lin.set_latency(KvOp::Write, Duration::from_millis(50));
+        let mut seen = HashSet::new();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let msgs = poll(&mut node, &[("k1", 0)]).await.remove("k1").unwrap();
+            let first = msgs[0][0];
+            assert_eq!(msgs, (first..30).map(|i| vec![i, i]).collect::<Vec<_>>());
+            seen.insert(first);
+        }
+        assert!(
+            seen.contains(&0) && seen.contains(&20),
+            "polls started at {:?}",
+            seen
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compaction_bounds_the_live_entries_of_a_long_run() {
+        let (lin, seq) = storage();
+        let margin = 10;
+        let vars = [
+            ("KAFKA_COMPACTION", Some("true")),
+            ("KAFKA_COMPACTION_MARGIN", Some("10")),
+        ];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        let mut live_max = 0;
+        for round in 0..10 {
+            for msg in 0..50 {
+                send(&mut node, "k1", round * 50 + msg).await;
+            }
+            commit(&mut node, &[("k1", round * 50 + 49)]).await;
+            tokio::time::sleep(COMPACTION_PERIOD * 2).await;
+            let live = (0..500)
+                .filter(|offset| {
+                    lin.get(format!("k1:{}", offset))
+                        .is_some_and(|v| !v.is_null())
+                })
+                .count();
+            live_max = cmp::max(live_max, live);
+        }
+        assert_eq!(live_max, margin + 1);
+        assert_eq!(send(&mut node, "k1", 500).await, 500);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_fetch_their_keys_concurrently() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+        for (msg, key) in keys.iter().enumerate() {
+            send(&mut node, key, msg as i64).await;
+        }
+        drop(node);
+
+        let mut node = kafka(&lin, &seq, &[]).await;
+        let latency = Duration::from_millis(50);
+        lin.set_latency(KvOp::Read, latency);
+        let offsets: Vec<(&str, i64)> = keys.iter().map(|key| (key.as_str(), 0)).collect();
+        let start = Instant::now();
+        let msgs = poll(&mut node, &offsets).await;
+        assert!(keys.iter().all(|key| msgs[key].len() == 1));
+        // Each key reads its message and the missing offset after it, one after the
+        // other, while the keys themselves are read side by side.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= latency && elapsed < latency * 3,
+            "poll took {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn vec_layout_appends_with_cas_and_retries_conflicts() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[("KAFKA_LOG_LAYOUT", Some("vec"))]).await;
+        lin.fail_next(KvOp::Cas, 1);
+        assert_eq!(send(&mut node, "k1", 10).await, 0);
+        assert_eq!(send(&mut node, "k1", 11).await, 1);
+
+        assert_eq!(lin.get("log:k1:0"), Some(serde_json::json!([10, 11])));
+        assert_eq!(lin.count_key(KvOp::Write, "k1:0"), 0);
+        // The index and both appends, plus the one that was failed.
+        assert_eq!(lin.count(KvOp::Cas), 4);
+        let msgs = poll(&mut node, &[("k1", 0)]).await;
+        assert_eq!(msgs["k1"], vec![vec![0, 10], vec![1, 11]]);
+    }
+
+    /// Polls `key` at a node from `offset` on until a poll comes back empty, checking
+    /// that every reply goes on where the previous one ended. Returns the length of
+    /// each reply.
+    async fn poll_through(node: &mut Kafka, key: &str, mut offset: i64) -> Vec<usize> {
+        let mut lens = Vec::new();
+        loop {
+            let msgs = poll(node, &[(key, offset)]).await.remove(key).unwrap();
+            if msgs.is_empty() {
+                return lens;
+            }
+            assert_eq!(
+                msgs,
+                (offset..offset + msgs.len() as i64)
+                    .map(|i| vec![i, i])
+                    .collect::<Vec<_>>()
+            );
+            offset += msgs.len() as i64;
+            lens.push(msgs.len());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn vec_layout_polls_stop_at_segment_boundaries() {
+        let (lin, seq) = storage();
+        let vars = [
+            ("KAFKA_LOG_LAYOUT", Some("vec")),
+            ("KAFKA_SEGMENT_SIZE", Some("4")),
+        ];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        for msg in 0..10 {
+            send(&mut node, "k1", msg).await;
+        }
+        assert_eq!(lin.get("log:k1:1"), Some(serde_json::json!([4, 5, 6, 7])));
+        assert_eq!(lin.get("log:k1:2"), Some(serde_json::json!([8, 9])));
+        drop(node);
+
+        // A restarted node reads one segment per poll, whatever the offset within it.
+        for (from, lens) in [
+            (0, vec![4, 4, 2]),
+            (3, vec![1, 4, 2]),
+            (4, vec![4, 2]),
+            (7, vec![1, 2]),
+            (9, vec![1]),
+            (10, vec![]),
+        ] {
+            let mut node = kafka(&lin, &seq, &vars).await;
+            assert_eq!(
+                poll_through(&mut node, "k1", from).await,
+                lens,
+                "from {}",
+                from
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn vec_layout_with_segments_of_one_message() {
+        let (lin, seq) = storage();
+        let vars = [
+            ("KAFKA_LOG_LAYOUT", Some("vec")),
+            ("KAFKA_SEGMENT_SIZE", Some("1")),
+        ];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        for msg in 0..5 {
+            assert_eq!(send(&mut node, "k1", msg).await, msg);
+        }
+        for segment in 0..5 {
+            assert_eq!(
+                lin.get(format!("log:k1:{}", segment)),
+                Some(serde_json::json!([segment]))
+            );
+        }
+        assert_eq!(
+            poll_through(&mut node, "k1", 0).await.iter().sum::<usize>(),
+            5
+        );
+        drop(node);
+
+        let mut node = kafka(&lin, &seq, &vars).await;
+        assert_eq!(poll_through(&mut node, "k1", 2).await, vec![1, 1, 1]);
+        assert_eq!(send(&mut node, "k1", 5).await, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn offsets_are_handed_out_from_reserved_blocks() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[("KAFKA_OFFSET_BLOCK", Some("5"))]).await;
+        for msg in 0..7 {
+            assert_eq!(send(&mut node, "k1", 10 + msg).await, msg);
+        }
+        // One CAS per block of five, the offsets within a block are handed out locally.
+        assert_eq!(lin.count_key(KvOp::Cas, "reserved:k1"), 2);
+        assert_eq!(lin.get("reserved:k1"), Some(9.into()));
+        let msgs = poll(&mut node, &[("k1", 0)]).await;
+        assert_eq!(
+            msgs["k1"],
+            (0..7).map(|i| vec![i, 10 + i]).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_restarted_node_fills_the_unused_rest_of_its_block() {
+        let (lin, seq) = storage();
+        let vars = [("KAFKA_OFFSET_BLOCK", Some("5"))];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        assert_eq!(send(&mut node, "k1", 10).await, 0);
+        assert_eq!(send(&mut node, "k1", 11).await, 1);
+        // Stops without a chance to release offsets 2 to 4.
+        drop(node);
+
+        let mut node = kafka(&lin, &seq, &vars).await;
+        assert_eq!(send(&mut node, "k1", 12).await, 5);
+        for offset in 2..5 {
+            assert_eq!(lin.get(format!("k1:{}", offset)), Some(Value::Null));
+        }
+        let msgs = poll(&mut node, &[("k1", 0)]).await;
+        assert_eq!(msgs["k1"], vec![vec![0, 10], vec![1, 11], vec![5, 12]]);
+        let msgs = poll(&mut node, &[("k1", 2)]).await;
+        assert_eq!(msgs["k1"], vec![vec![5, 12]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_sends_to_one_node_take_turns_per_key() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        lin.set_latency(KvOp::Read, Duration::from_millis(5));
+        lin.set_latency(KvOp::Write, Duration::from_millis(5));
+        let keys = ["k1", "k2", "k3"];
+        let mut ids = Vec::new();
+        for msg in 0..100 {
+            let key = keys[msg % keys.len()];
+            let payload = KafkaPayload::Send {
+                key: key.to_string(),
+                msg: msg as i64,
+            };
+            ids.push((key, node.send("c1", WithKV::Workload(payload)).await));
+        }
+        let mut offsets: HashMap<&str, Vec<i64>> = HashMap::new();
+        for (key, id) in ids {
+            let offset = send_ok(node.expect_reply_to(id).await);
+            offsets.entry(key).or_default().push(offset);
+        }
+
+        // Each key hands out its offsets in the order the sends arrived.
+        for (key, offsets) in offsets {
+            let n = offsets.len() as i64;
+            assert_eq!(offsets, (0..n).collect::<Vec<_>>(), "offsets of {}", key);
+            for offset in 0..n {
+                assert_eq!(lin.count_key(KvOp::Write, format!("{}:{}", key, offset)), 1);
+            }
+        }
+        // Only the key index is ever compared-and-set, the messages never race.
+        let cas: Vec<Value> = lin
+            .log()
+            .into_iter()
+            .filter(|call| call.op == KvOp::Cas)
+            .map(|call| call.key)
+            .collect();
+        assert!(cas.iter().all(|key| key == "keys"), "cas on {:?}", cas);
+    }
+
+    /// Sends message 10 to k1 as `client` with `msg_id`, returning its offset.
+    async fn send_with_id(node: &mut Kafka, client: &str, msg_id: usize) -> i64 {
+        let body = serde_json::json!({"type": "send", "key": "k1", "msg": 10, "msg_id": msg_id});
+        let id = node.send_json(client, body).await;
+        send_ok(node.expect_reply_to(id).await)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retried_sends_get_the_offset_of_the_first_attempt() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        assert_eq!(send_with_id(&mut node, "c1", 7).await, 0);
+        assert_eq!(send_with_id(&mut node, "c1", 7).await, 0);
+        assert_eq!(lin.count_key(KvOp::Write, "k1:0"), 1);
+        assert_eq!(lin.get("k1:1"), None);
+
+        // The same message under another msg_id, or from another client, is a new
+        // message.
+        assert_eq!(send_with_id(&mut node, "c1", 8).await, 1);
+        assert_eq!(send_with_id(&mut node, "c2", 7).await, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_storage_only_delays_sends() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        lin.set_latency(KvOp::Write, Duration::from_millis(300));
+        let start = tokio::time::Instant::now();
+        assert_eq!(send(&mut node, "k1", 10).await, 0);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(poll(&mut node, &[("k1", 0)]).await["k1"], vec![vec![0, 10]]);
+    }
+
+    fn error_code(reply: Message<Payload>) -> ErrorCode {
+        match reply.body.payload {
+            WithKV::Workload(KafkaPayload::Error { code, .. }) => ErrorCode::from_code(code),
+            payload => panic!("expected an error, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn storage_requests_in_flight_stay_within_the_limit() {
+        let (lin, seq) = storage();
+        let keys: Vec<String> = (0..40).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            lin.put(format!("{}:0", key), 1);
+        }
+        let vars = [("KAFKA_STORAGE_CONCURRENCY", Some("4"))];
+        let mut node = kafka(&lin, &seq, &vars).await;
+        let latency = Duration::from_millis(10);
+        lin.set_latency(KvOp::Read, latency);
+        let offsets: Vec<(&str, i64)> = keys.iter().map(|key| (key.as_str(), 0)).collect();
+        let msgs = poll(&mut node, &offsets).await;
+        assert!(keys.iter().all(|key| msgs[key] == vec![vec![0, 1]]));
+
+        // Every read is answered `latency` after it arrives, so the reads in flight
+        // when one arrives are those that arrived less than `latency` before.
+        let arrivals: Vec<Instant> = lin.log().iter().map(|call| call.at).collect();
+        let in_flight = arrivals
+            .iter()
+            .map(|&at| {
+                arrivals
+                    .iter()
+                    .filter(|&&other| other <= at && at < other + latency)
+                    .count()
+            })
+            .max();
+        assert_eq!(in_flight, Some(4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn storage_errors_keep_their_maelstrom_codes() {
+        let (lin, seq) = storage();
+        let node = kafka(&lin, &seq, &[]).await;
+        let kafka = node.node();
+
+        let missing: anyhow::Result<i64> = kafka.read("lin-kv", "k1:0".to_string()).await;
+        let err = missing.expect_err("read of a missing key succeeded");
+        assert_eq!(ErrorCode::of(&err), Some(ErrorCode::KeyDoesNotExist));
+        let missing: Option<i64> = kafka.read_opt("lin-kv", "k1:0".to_string()).await.unwrap();
+        assert_eq!(missing, None);
+
+        lin.put("k1:0", 10);
+        lin.fail_next(KvOp::Cas, 1);
+        let err = kafka
+            .cas("lin-kv", "k1:0".to_string(), 10, 11, false)
+            .await
+            .expect_err("failed cas succeeded");
+        assert_eq!(ErrorCode::of(&err), Some(ErrorCode::PreconditionFailed));
+
+        lin.drop_next(KvOp::Read, 1);
+        let lost: anyhow::Result<Option<i64>> = kafka.read_opt("lin-kv", "k1:0".to_string()).await;
+        let err = lost.expect_err("unanswered read succeeded");
+        assert_eq!(ErrorCode::of(&err), Some(ErrorCode::Timeout));
+        assert_eq!(
+            kafka.read_opt("lin-kv", "k1:0".to_string()).await.unwrap(),
+            Some(10)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn odd_messages_leave_the_node_serving() {
+        let (lin, seq) = storage();
+        let mut node = kafka(&lin, &seq, &[]).await;
+        let odd = [
+            // Replies to requests the node never made.
+            r#"{"src":"lin-kv","dest":"n0","body":{"type":"read_ok","value":3,"in_reply_to":999}}"#,
+            r#"{"src":"lin-kv","dest":"n0","body":{"type":"error","code":20,"text":"gone","in_reply_to":998}}"#,
+            r#"{"src":"lin-kv","dest":"n0","body":{"type":"write_ok","msg_id":5}}"#,
+            r#"{"src":"n1","dest":"n0","body":{"type":"send_ok","offset":4,"in_reply_to":997}}"#,
+            r#"{"src":"c1","dest":"n0","body":{"type":"poll_ok","msgs":{}}}"#,
+            // Requests without a msg_id, which cannot be answered.
+            r#"{"src":"c1","dest":"n0","body":{"type":"send","key":"k1","msg":10}}"#,
+            r#"{"src":"c1","dest":"n0","body":{"type":"poll","offsets":{"k1":0}}}"#,
+            r#"{"src":"c1","dest":"n0","body":{"type":"commit_offsets","offsets":{"k1":0}}}"#,
+            r#"{"src":"n1","dest":"n0","body":{"type":"gossip_committed","offsets":{}}}"#,
+        ];
+        for line in odd {
+            node.send_line(line).await;
+        }
+        node.drain().await;
+
+        assert_eq!(send(&mut node, "k1", 11).await, 1);
+        let msgs = poll(&mut node, &[("k1", 0)]).await;
+        assert_eq!(msgs["k1"], vec![vec![0, 10], vec![1, 11]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unreachable_storage_fails_requests() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1", "n2"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n0", cluster.node_ids());
+        // Let the nodes warm their caches before cutting them off.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        cluster.partition(&["n0"], &["lin-kv", "seq-kv"]);
+
+        let list =
+            |keys: Vec<String>| Payload::Workload(KafkaPayload::ListCommittedOffsets { keys });
+        let id = cluster.send("c1", "n0", list(vec![key.clone()]));
+        let reply = cluster.expect_reply_to(id).await;
+        assert_eq!(error_code(reply), ErrorCode::TemporarilyUnavailable);
+        let id = cluster.send("c1", "n1", list(vec![key.clone()]));
+        let reply: Message<Payload> = cluster.expect_reply_to(id).await;
+        assert!(matches!(
+            reply.body.payload,
+            WithKV::Workload(KafkaPayload::ListCommittedOffsetsOk { .. })
+        ));
+
+        // The outcome of a write is unknown once storage stops answering.
+        let send = |msg| {
+            Payload::Workload(KafkaPayload::Send {
+                key: key.clone(),
+                msg,
+            })
+        };
+        let id = cluster.send("c1", "n0", send(1));
+        assert_eq!(
+            error_code(cluster.expect_reply_to(id).await),
+            ErrorCode::Timeout
+        );
+
+        cluster.heal();
+        let id = cluster.send("c1", "n0", send(2));
+        let reply: Message<Payload> = cluster.expect_reply_to(id).await;
+        assert!(matches!(
+            reply.body.payload,
+            WithKV::Workload(KafkaPayload::SendOk { .. })
+        ));
+        let dropped = cluster
+            .trace()
+            .iter()
+            .filter(|delivery| delivery.fate == Fate::Dropped)
+            .count();
+        assert!(dropped >= 2, "only {} messages dropped", dropped);
+    }
+
+    /// A key of the form `k{i}` that `node` owns among `nodes`.
+    fn key_owned_by(node: &str, nodes: &[String]) -> String {
+        (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| owner(key, nodes) == node)
+            .expect("a key owned by the node")
+    }
+
+    fn cluster_send(key: &str, msg: i64) -> Payload {
+        Payload::Workload(KafkaPayload::Send {
+            key: key.to_string(),
+            msg,
+        })
+    }
+
+    fn send_ok(reply: Message<Payload>) -> i64 {
+        match reply.body.payload {
+            WithKV::Workload(KafkaPayload::SendOk { offset }) => offset,
+            payload => panic!("expected send_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_to_other_nodes_are_forwarded_to_the_owner() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n1", cluster.node_ids());
+
+        let id = cluster.send("c1", "n0", cluster_send(&key, 10));
+        assert_eq!(send_ok(cluster.expect_reply_to(id).await), 0);
+        let id = cluster.send("c1", "n1", cluster_send(&key, 11));
+        assert_eq!(send_ok(cluster.expect_reply_to(id).await), 1);
+
+        let forwarded = cluster
+            .trace()
+            .iter()
+            .filter(|delivery| delivery.from == "n0" && delivery.to == "n1")
+            .filter(|delivery| delivery.line.contains(r#""type":"send""#))
+            .count();
+        assert_eq!(forwarded, 1);
+        // Only the owner writes the log, so it never needs a CAS to append.
+        let writers: HashSet<String> = lin
+            .log()
+            .into_iter()
+            .filter(|call| call.op == KvOp::Write)
+            .filter(|call| call.key.as_str().is_some_and(|k| k.starts_with(&key)))
+            .map(|call| call.client)
+            .collect();
+        assert_eq!(writers, HashSet::from(["n1".to_string()]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_forwarded_send_the_owner_never_answers_has_an_unknown_outcome() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n1", cluster.node_ids());
+        cluster.partition(&["n0"], &["n1"]);
+
+        // The owner may have appended the message, so the client must not be told
+        // that it definitely did not happen.
+        let id = cluster.send("c1", "n0", cluster_send(&key, 10));
+        assert_eq!(
+            error_code(cluster.expect_reply_to(id).await),
+            ErrorCode::Timeout
+        );
+
+        cluster.heal();
+        let id = cluster.send("c1", "n0", cluster_send(&key, 11));
+        assert_eq!(send_ok(cluster.expect_reply_to(id).await), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_sends_at_different_nodes_get_unique_offsets() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n0", cluster.node_ids());
+        let mut ids = Vec::new();
+        for msg in 0..10 {
+            ids.push(cluster.send("c1", "n0", cluster_send(&key, msg)));
+            ids.push(cluster.send("c2", "n1", cluster_send(&key, 100 + msg)));
+        }
+        let mut offsets = Vec::new();
+        for id in ids {
+            offsets.push(send_ok(cluster.expect_reply_to(id).await));
+        }
+        offsets.sort_unstable();
+        assert_eq!(offsets, (0..20).collect::<Vec<i64>>());
+    }
+
+    /// The number of reads of `key` that `client` made.
+    fn reads_by(kv: &MockKvService, client: &str, key: &str) -> usize {
+        kv.log()
+            .iter()
+            .filter(|call| call.op == KvOp::Read && call.client == client)
+            .filter(|call| call.key.as_str() == Some(key))
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_cached_log_is_refreshed_from_where_it_ends() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n0", cluster.node_ids());
+        for msg in 0..3 {
+            let id = cluster.send("c1", "n0", cluster_send(&key, msg));
+            send_ok(cluster.expect_reply_to(id).await);
+        }
+        assert_eq!(poll_all(&mut cluster, "n1", &key).await.len(), 3);
+        assert_eq!(reads_by(&lin, "n1", &format!("{}:0", key)), 1);
+
+        // The owner appends behind n1's back.
+        for msg in 3..5 {
+            let id = cluster.send("c1", "n0", cluster_send(&key, msg));
+            send_ok(cluster.expect_reply_to(id).await);
+        }
+        let log = poll_all(&mut cluster, "n1", &key).await;
+        assert_eq!(log, (0..5).map(|i| vec![i, i]).collect::<Vec<_>>());
+        // Only the messages past the cached ones were read.
+        for offset in 0..3 {
+            assert_eq!(reads_by(&lin, "n1", &format!("{}:{}", key, offset)), 1);
+        }
+        assert!(reads_by(&lin, "n1", &format!("{}:4", key)) >= 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commits_reach_other_nodes_by_gossip() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n0", cluster.node_ids());
+        for msg in 0..3 {
+            let id = cluster.send("c1", "n0", cluster_send(&key, msg));
+            send_ok(cluster.expect_reply_to(id).await);
+        }
+        let offsets = HashMap::from([(key.clone(), 2)]);
+        let id = cluster.send(
+            "c1",
+            "n0",
+            Payload::Workload(KafkaPayload::CommitOffsets { offsets }),
+        );
+        cluster.expect_reply_to::<Payload>(id).await;
+
+        tokio::time::sleep(COMMITTED_GOSSIP_PERIOD * 2).await;
+        let keys = vec![key.clone()];
+        let id = cluster.send(
+            "c2",
+            "n1",
+            Payload::Workload(KafkaPayload::ListCommittedOffsets { keys }),
+        );
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            WithKV::Workload(KafkaPayload::ListCommittedOffsetsOk { offsets }) => {
+                assert_eq!(offsets, HashMap::from([(key.clone(), 2)]));
+            }
+            payload => panic!("expected list_committed_offsets_ok, got {:?}", payload),
+        }
+        // n1 answered from what n0 told it, without asking storage.
+        assert_eq!(reads_by(&seq, "n1", &format!("committed:{}", key)), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_nodes_learn_of_new_messages_from_gossiped_tails() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .start()
+            .await;
+        let key = key_owned_by("n0", cluster.node_ids());
+        for msg in 0..3 {
+            let id = cluster.send("c1", "n0", cluster_send(&key, msg));
+            send_ok(cluster.expect_reply_to(id).await);
+        }
+        assert_eq!(poll_all(&mut cluster, "n1", &key).await.len(), 3);
+
+        // With storage too slow to wait for, n1 serves what it has cached for as
+        // long as it has not heard of the new messages.
+        cluster.partition(&["n0"], &["n1"]);
+        lin.set_latency(KvOp::Read, POLL_KEY_TIMEOUT + Duration::from_millis(100));
+        for msg in 3..5 {
+            let id = cluster.send("c1", "n0", cluster_send(&key, msg));
+            send_ok(cluster.expect_reply_to(id).await);
+        }
+        let log = poll_all(&mut cluster, "n1", &key).await;
+        assert_eq!(log, (0..3).map(|i| vec![i, i]).collect::<Vec<_>>());
+
+        // Once gossip tells n1 where the log ends, it waits for storage instead.
+        cluster.heal();
+        tokio::time::sleep(COMMITTED_GOSSIP_PERIOD * 2).await;
+        let log = poll_all(&mut cluster, "n1", &key).await;
+        assert_eq!(log, (0..5).map(|i| vec![i, i]).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interleaved_sends_at_two_nodes_write_every_offset_once() {
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .seed(7)
+            .start()
+            .await;
+        cluster.set_jitter(Duration::from_millis(5));
+        let key = key_owned_by("n0", cluster.node_ids());
+        let mut ids = Vec::new();
+        for msg in 0..10 {
+            ids.push(cluster.send("c1", "n0", cluster_send(&key, msg)));
+            ids.push(cluster.send("c2", "n1", cluster_send(&key, 100 + msg)));
+            tokio::time::sleep(Duration::from_millis(3)).await;
+        }
+        let mut offsets = Vec::new();
+        for id in ids {
+            offsets.push(send_ok(cluster.expect_reply_to(id).await));
+        }
+        offsets.sort_unstable();
+        assert_eq!(offsets, (0..20).collect::<Vec<i64>>());
+        // Let the latest offset hints be flushed.
+        tokio::time::sleep(HINT_FLUSH_PERIOD * 3).await;
+
+        for offset in offsets {
+            assert_eq!(
+                lin.count_key(KvOp::Write, format!("{}:{}", key, offset)),
+                1,
+                "offset {} written more than once",
+                offset
+            );
+        }
+        // The hint is only ever moved forward, and never raced for with a CAS.
+        let latest = format!("latest:{}", key);
+        let hints: Vec<i64> = lin
+            .log()
+            .into_iter()
+            .filter(|call| call.key.as_str() == Some(latest.as_str()))
+            .filter_map(|call| match call.request {
+                gossip_glomers::kv_service::Payload::Read { .. } => None,
+                gossip_glomers::kv_service::Payload::Write { value, .. } => value.as_i64(),
+                request => panic!("{:?} of the latest offset hint", request),
+            })
+            .collect();
+        assert!(!hints.is_empty(), "the hint was never written");
+        assert!(
+            hints.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            hints
+        );
+        assert_eq!(hints.last(), Some(&19));
+    }
+
+    const KEYS: [&str; 3] = ["k0", "k1", "k2"];
+
+    /// The seeds the invariant tests run, or just the one in KAFKA_TEST_SEED to replay
+    /// a failure.
+    fn seeds() -> Vec<u64> {
+        match std::env::var("KAFKA_TEST_SEED") {
+            Ok(seed) => vec![seed.parse().expect("KAFKA_TEST_SEED is a number")],
+            Err(_) => (0..10).collect(),
+        }
+    }
+
+    fn workload(payload: &Value) -> KafkaPayload {
+        match serde_json::from_value::<Payload>(payload.clone()).expect("parse client message") {
+            WithKV::Workload(payload) => payload,
+            payload => panic!("unexpected client message {:?}", payload),
+        }
+    }
+
+    /// Every message of `key` from `offset` on, as `node` serves it.
+    async fn poll_all(cluster: &mut Cluster, node: &str, key: &str) -> Vec<Vec<i64>> {
+        let mut log: Vec<Vec<i64>> = Vec::new();
+        loop {
+            let offset = log.last().map_or(0, |msg| msg[0] + 1);
+            let offsets = HashMap::from([(key.to_string(), offset)]);
+            let id = cluster.send(
+                "c9",
+                node,
+                Payload::Workload(KafkaPayload::Poll { offsets }),
+            );
+            let reply: Message<Payload> = cluster.expect_reply_to(id).await;
+            match reply.body.payload {
+                WithKV::Workload(KafkaPayload::PollOk { mut msgs }) => {
+                    match msgs.remove(key).unwrap_or_default() {
+                        msgs if msgs.is_empty() => return log,
+                        msgs => log.extend(msgs),
+                    }
+                }
+                payload => panic!("expected poll_ok, got {:?}", payload),
+            }
+        }
+    }
+
+    /// Per key, the log and committed offset every node ends up with.
+    type Finals = HashMap<String, Vec<(Vec<Vec<i64>>, Option<i64>)>>;
+
+    /// Has clients send unique messages, poll, commit and list committed offsets at
+    /// random nodes of a two node cluster, then reads every log and committed offset
+    /// back at both nodes. Returns the history of the clients and what the nodes hold.
+    async fn random_run(seed: u64) -> (Vec<Operation<Value, Value>>, Finals) {
+        let mut rng = Rng::new(seed);
+        let (lin, seq) = storage();
+        let mut cluster = Cluster::builder()
+            .nodes::<KafkaNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .service(&lin)
+            .service(&seq)
+            .seed(seed)
+            .start()
+            .await;
+        let mut polled: HashMap<&str, i64> = HashMap::new();
+        for msg in 0..40 {
+            // Every client sticks to one node, whose view of the committed offsets
+            // it then sees.
+            let client = rng.below(4);
+            let node = ["n0", "n1"][client as usize % 2];
+            let client = format!("c{}", client);
+            let key = KEYS[rng.below(KEYS.len() as u64) as usize];
+            cluster.set_latency(&client, node, Duration::from_millis(rng.below(20)));
+            let request = match rng.below(4) {
+                0 | 1 => KafkaPayload::Send {
+                    key: key.to_string(),
+                    msg,
+                },
+                2 => KafkaPayload::Poll {
+                    offsets: HashMap::from([(key.to_string(), rng.below(3) as i64)]),
+                },
+                _ => match polled.get(key) {
+                    Some(&offset) if rng.chance(0.5) => KafkaPayload::CommitOffsets {
+                        offsets: HashMap::from([(
+                            key.to_string(),
+                            rng.below(offset as u64 + 1) as i64,
+                        )]),
+                    },
+                    _ => KafkaPayload::ListCommittedOffsets {
+                        keys: KEYS.iter().map(|key| key.to_string()).collect(),
+                    },
+                },
+            };
+            cluster.send(&client, node, Payload::Workload(request));
+            tokio::time::sleep(Duration::from_millis(rng.below(10))).await;
+            for reply in cluster.drain::<Payload>().await {
+                if let WithKV::Workload(KafkaPayload::PollOk { msgs }) = reply.body.payload {
+                    for (key, msgs) in msgs {
+                        if let (Some(key), Some(msg)) =
+                            (KEYS.iter().find(|k| **k == key), msgs.last())
+                        {
+                            let offset = polled.entry(key).or_default();
+                            *offset = (*offset).max(msg[0]);
+                        }
+                    }
+                }
+            }
+        }
+        // Long enough for every request to be answered and the nodes to gossip.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let mut finals = HashMap::new();
+        for node in ["n0", "n1"] {
+            for key in KEYS {
+                let log = poll_all(&mut cluster, node, key).await;
+                let keys = vec![key.to_string()];
+                let id = cluster.send(
+                    "c9",
+                    node,
+                    Payload::Workload(KafkaPayload::ListCommittedOffsets { keys }),
+                );
+                let committed = match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+                    WithKV::Workload(KafkaPayload::ListCommittedOffsetsOk { offsets }) => {
+                        offsets.get(key).copied()
+                    }
+                    payload => panic!("expected list_committed_offsets_ok, got {:?}", payload),
+                };
+                finals
+                    .entry(key.to_string())
+                    .or_insert_with(Vec::new)
+                    .push((log, committed));
+            }
+        }
+        (client_operations(&cluster.trace()), finals)
+    }
+
+    /// Checks what the Maelstrom kafka checker does: every acknowledged send is in the
+    /// log at exactly the offset it was given, logs are dense and agree between
+    /// nodes, no poll sees a message at another offset than the log has it, and
+    /// committed offsets only move forward.
+    fn check_invariants(
+        history: &[Operation<Value, Value>],
+        finals: &Finals,
+    ) -> Result<(), String> {
+        let mut logs = HashMap::new();
+        for (key, views) in finals {
+            let (log, _) = &views[0];
+            if views.iter().any(|(other, _)| other != log) {
+                return Err(format!(
+                    "the nodes disagree on the log of {}: {:?}",
+                    key, views
+                ));
+            }
+            for (i, msg) in log.iter().enumerate() {
+                if msg[0] != i as i64 {
+                    return Err(format!(
+                        "{} has a gap before offset {}: {:?}",
+                        key, msg[0], log
+                    ));
+                }
+            }
+            let mut msgs: Vec<i64> = log.iter().map(|msg| msg[1]).collect();
+            msgs.sort_unstable();
+            msgs.dedup();
+            if msgs.len() != log.len() {
+                return Err(format!("{} has a message at two offsets: {:?}", key, log));
+            }
+            logs.insert(key.clone(), log);
+        }
+        let logged = |key: &str, offset: i64| {
+            logs.get(key)
+                .and_then(|log| log.get(usize::try_from(offset).ok()?))
+                .map(|msg| msg[1])
+        };
+
+        let mut sends = 0;
+        let mut commits: HashMap<String, i64> = HashMap::new();
+        let mut listed: HashMap<(String, String), (Instant, i64)> = HashMap::new();
+        let mut operations: Vec<_> = history.iter().collect();
+        operations.sort_by_key(|operation| operation.returned);
+        for operation in operations {
+            let Some(output) = &operation.output else {
+                return Err(format!("{:?} was never answered", operation.input));
+            };
+            match (workload(&operation.input), workload(output)) {
+                (KafkaPayload::Send { key, msg }, KafkaPayload::SendOk { offset }) => {
+                    sends += 1;
+                    if logged(&key, offset) != Some(msg) {
+                        return Err(format!(
+                            "{} was acknowledged at offset {} of {}, which holds {:?}",
+                            msg,
+                            offset,
+                            key,
+                            logged(&key, offset)
+                        ));
+                    }
+                }
+                (KafkaPayload::Poll { offsets }, KafkaPayload::PollOk { msgs }) => {
+                    for (key, msgs) in msgs {
+                        let from = offsets.get(&key).copied().unwrap_or_default();
+                        for msg in msgs {
+                            if msg[0] < from || logged(&key, msg[0]) != Some(msg[1]) {
+                                return Err(format!(
+                                    "a poll of {} from {} saw {} at offset {}, the log holds {:?}",
+                                    key,
+                                    from,
+                                    msg[1],
+                                    msg[0],
+                                    logged(&key, msg[0])
+                                ));
+                            }
+                        }
+                    }
+                }
+                (KafkaPayload::CommitOffsets { offsets }, KafkaPayload::CommitOffsetsOk) => {
+                    for (key, offset) in offsets {
+                        let committed = commits.entry(key).or_insert(offset);
+                        *committed = (*committed).max(offset);
+                    }
+                }
+                (
+                    KafkaPayload::ListCommittedOffsets { .. },
+                    KafkaPayload::ListCommittedOffsetsOk { offsets },
+                ) => {
+                    let client = operation.client.clone();
+                    for (key, offset) in offsets {
+                        let returned = operation.returned.expect("answered");
+                        if let Some((at, before)) = listed.get(&(client.clone(), key.clone())) {
+                            if *before > offset && *at < operation.invoked {
+                                return Err(format!(
+                                    "{} saw the committed offset of {} go back from {} to {}",
+                                    client, key, before, offset
+                                ));
+                            }
+                        }
+                        listed.insert((client.clone(), key), (returned, offset));
+                    }
+                }
+                (input, output) => {
+                    return Err(format!("{:?} was answered with {:?}", input, output));
+                }
+            }
+        }
+        let logged: usize = logs.values().map(|log| log.len()).sum();
+        if logged != sends {
+            return Err(format!(
+                "{} sends were acknowledged but {} logged",
+                sends, logged
+            ));
+        }
+        for (key, views) in finals {
+            let committed = commits.get(key).copied();
+            if views.iter().any(|(_, offset)| *offset != committed) {
+                return Err(format!(
+                    "{} was committed up to {:?} but the nodes list {:?}",
+                    key, committed, views
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_runs_keep_the_log_invariants() {
+        for seed in seeds() {
+            let (history, finals) = random_run(seed).await;
+            if let Err(err) = check_invariants(&history, &finals) {
+                panic!(
+                    "seed {} (replay with KAFKA_TEST_SEED={}): {}",
+                    seed, seed, err
+                );
+            }
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let offsets = || prop::collection::hash_map(any::<String>(), any::<i64>(), 0..4);
+        wire::with_kv(prop_oneof![
+            (any::<String>(), any::<i64>()).prop_map(|(key, msg)| KafkaPayload::Send { key, msg }),
+            any::<i64>().prop_map(|offset| KafkaPayload::SendOk { offset }),
+            offsets().prop_map(|offsets| KafkaPayload::Poll { offsets }),
+            prop::collection::hash_map(
+                any::<String>(),
+                prop::collection::vec(prop::collection::vec(any::<i64>(), 2), 0..4),
+                0..4
+            )
+            .prop_map(|msgs| KafkaPayload::PollOk { msgs }),
+            offsets().prop_map(|offsets| KafkaPayload::CommitOffsets { offsets }),
+            Just(KafkaPayload::CommitOffsetsOk),
+            prop::collection::vec(any::<String>(), 0..4)
+                .prop_map(|keys| KafkaPayload::ListCommittedOffsets { keys }),
+            offsets().prop_map(|offsets| KafkaPayload::ListCommittedOffsetsOk { offsets }),
+            (offsets(), offsets(), offsets()).prop_map(|(offsets, tails, starts)| {
+                KafkaPayload::GossipCommitted {
+                    offsets,
+                    tails,
+                    starts,
+                }
+            }),
+            wire::error().prop_map(|(code, text)| KafkaPayload::Error { code, text }),
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("kafka");
+        wire::assert_fixture_parses::<Payload>("kv_service");
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop, kv_service::Payload, rpc::Rpc, Body, ErrorCode, Event, Init, Message, Node, Output,
+};
+use tokio::sync::Mutex;
+
+const STORAGE: &str = "lin-kv";
+
+/// Default time a forwarded request waits for lin-kv.
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// Default number of times a read is forwarded before its timeout is passed on.
+const DEFAULT_READ_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone)]
+struct KVProxyConfig {
+    timeout: Duration,
+    read_attempts: u32,
+}
+
+impl KVProxyConfig {
+    /// Reads the configuration from the environment:
+    /// - `KV_PROXY_TIMEOUT_MS`: time a forwarded request waits for lin-kv in
+    ///   milliseconds (default 1000)
+    /// - `KV_PROXY_READ_ATTEMPTS`: times a read is forwarded before the client gets a
+    ///   timeout, writes and cas are only ever forwarded once (default 3)
+    fn from_env() -> anyhow::Result<Self> {
+        let timeout_ms = gossip_glomers::env_or("KV_PROXY_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)?;
+        if timeout_ms == 0 {
+            anyhow::bail!("KV_PROXY_TIMEOUT_MS must be greater than 0");
+        }
+        let read_attempts =
+            gossip_glomers::env_or("KV_PROXY_READ_ATTEMPTS", DEFAULT_READ_ATTEMPTS)?;
+        if read_attempts == 0 {
+            anyhow::bail!("KV_PROXY_READ_ATTEMPTS must be greater than 0");
+        }
+        Ok(Self {
+            timeout: Duration::from_millis(timeout_ms),
+            read_attempts,
+        })
+    }
+}
+
+/// Forwards every read, write and cas to lin-kv and passes its answer back as is,
+/// errors included. Only a request that gets no answer at all is answered by the proxy
+/// itself: with a timeout, which leaves open whether a write or cas took effect.
+struct KVProxyNode {
+    id: AtomicUsize,
+    config: KVProxyConfig,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+impl KVProxyNode {
+    /// Forwards `request` until lin-kv answers it or the attempts run out. Reads are
+    /// safe to send again, writes and cas could take effect twice.
+    async fn forward(&self, request: Message<Payload>) -> Message<Payload> {
+        let attempts = match request.body.payload {
+            Payload::Read { .. } => self.config.read_attempts,
+            _ => 1,
+        };
+        let mut last_err = None;
+        for _ in 0..attempts {
+            let id = self.id.fetch_add(1, Ordering::Relaxed);
+            match self
+                .rpc
+                .forward(
+                    request.clone(),
+                    STORAGE,
+                    id,
+                    self.config.timeout,
+                    &self.stdout,
+                )
+                .await
+            {
+                std::result::Result::Ok(reply) => return reply,
+                Err(err) => {
+                    eprintln!("forward to {}: {:#}", STORAGE, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        let err = last_err.expect("at least one attempt");
+        let code = match ErrorCode::of(&err) {
+            Some(ErrorCode::Timeout) => ErrorCode::Timeout,
+            _ => ErrorCode::Crash,
+        };
+        Message {
+            src: request.dest,
+            dest: request.src,
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                payload: Payload::Error {
+                    code: code.code(),
+                    text: format!("{:#}", err),
+                },
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload> for KVProxyNode {
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = KVProxyConfig::from_env()?;
+        eprintln!("kv_proxy config: {:?}", config);
+        Ok(Self {
+            id: 1.into(),
+            config,
+            rpc: Rpc::new(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        // Answers of lin-kv go to the forward waiting for them.
+        let Some(message) = self.rpc.resolve(message).await else {
+            return Ok(());
+        };
+        if message.body.payload.is_reply() {
+            return Ok(());
+        }
+        let mut reply = self.forward(message).await;
+        reply.body.id = Some(self.id.fetch_add(1, Ordering::Relaxed));
+        reply.send(&self.stdout).await.context("send reply")?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<KVProxyNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Harness, KvOp, MockKvService};
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    async fn proxy(kv: &MockKvService) -> Harness<KVProxyNode, Payload> {
+        Harness::builder("n0", &["n0"])
+            .env(&[("KV_PROXY_TIMEOUT_MS", Some("100"))])
+            .service(kv)
+            .start()
+            .await
+    }
+
+    async fn request(node: &mut Harness<KVProxyNode, Payload>, body: Value) -> Message<Payload> {
+        let id = node.send_json("c1", body).await;
+        let reply = node.expect_reply_to(id).await;
+        assert_eq!((reply.src.as_str(), reply.dest.as_str()), ("n0", "c1"));
+        reply
+    }
+
+    fn error_code(reply: &Message<Payload>) -> Option<usize> {
+        match reply.body.payload {
+            Payload::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_answered_by_lin_kv() {
+        let kv = MockKvService::lin(STORAGE);
+        let mut node = proxy(&kv).await;
+        let read = json!({"type": "read", "key": "x"});
+        let reply = request(&mut node, read.clone()).await;
+        assert_eq!(error_code(&reply), Some(ErrorCode::KeyDoesNotExist.code()));
+
+        request(&mut node, json!({"type": "write", "key": "x", "value": 1})).await;
+        assert_eq!(kv.get("x"), Some(json!(1)));
+        let reply = request(
+            &mut node,
+            json!({"type": "cas", "key": "x", "from": 2, "to": 3}),
+        )
+        .await;
+        assert_eq!(
+            error_code(&reply),
+            Some(ErrorCode::PreconditionFailed.code())
+        );
+        let reply = request(
+            &mut node,
+            json!({"type": "cas", "key": "x", "from": 1, "to": 3}),
+        )
+        .await;
+        assert!(matches!(reply.body.payload, Payload::CasOk));
+        let reply = request(&mut node, read).await;
+        assert!(matches!(reply.body.payload, Payload::ReadOk { value } if value == json!(3)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_reads_are_forwarded_again() {
+        let kv = MockKvService::lin(STORAGE);
+        kv.put("x", 1);
+        let mut node = proxy(&kv).await;
+        let read = json!({"type": "read", "key": "x"});
+        kv.drop_next(KvOp::Read, 2);
+        let reply = request(&mut node, read.clone()).await;
+        assert!(matches!(reply.body.payload, Payload::ReadOk { .. }));
+        assert_eq!(kv.count(KvOp::Read), 3);
+
+        kv.drop_next(KvOp::Read, 3);
+        let reply = request(&mut node, read).await;
+        assert_eq!(error_code(&reply), Some(ErrorCode::Timeout.code()));
+
+        kv.drop_next(KvOp::Write, 1);
+        let reply = request(&mut node, json!({"type": "write", "key": "x", "value": 2})).await;
+        assert_eq!(error_code(&reply), Some(ErrorCode::Timeout.code()));
+        assert_eq!(kv.count(KvOp::Write), 1);
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(wire::service_payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("lin_kv");
+    }
+}
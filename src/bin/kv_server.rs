@@ -0,0 +1,139 @@
+use std::sync::atomic::AtomicUsize;
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop,
+    kv_service::{self, Payload, Store},
+    Event, Init, Node, Output,
+};
+use tokio::sync::Mutex;
+
+/// A linearizable key/value store on a single node, standing in for Maelstrom's
+/// lin-kv service. Every request takes the lock on the whole store, so each one takes
+/// effect at once, between receiving it and replying.
+struct KVServerNode {
+    id: AtomicUsize,
+    store: Mutex<Store>,
+    stdout: Mutex<Output>,
+}
+
+#[async_trait]
+impl Node<Payload> for KVServerNode {
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            id: 1.into(),
+            store: Mutex::default(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        if message.body.payload.is_reply() {
+            return Ok(());
+        }
+        let mut reply = message.into_reply(Some(&self.id));
+        let request = reply.body.payload;
+        reply.body.payload = kv_service::apply(&mut *self.store.lock().await, request);
+        reply.send(&self.stdout).await.context("send reply")?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<KVServerNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use gossip_glomers::testkit::{check_kv, client_operations, wire, Cluster, Operation, Rng};
+    use serde_json::json;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const CLIENTS: [&str; 4] = ["c1", "c2", "c3", "c4"];
+
+    fn random_request(rng: &mut Rng) -> Payload {
+        let key = json!(rng.below(2));
+        match rng.below(3) {
+            0 => Payload::Read { key },
+            1 => Payload::Write {
+                key,
+                value: json!(rng.below(4)),
+            },
+            _ => Payload::Cas {
+                key,
+                from: json!(rng.below(4)),
+                to: json!(rng.below(4)),
+                create_if_not_exists: rng.chance(0.2),
+            },
+        }
+    }
+
+    /// Has CLIENTS send random requests on two keys, each taking a random time to
+    /// arrive, and returns the history of them.
+    async fn random_history(seed: u64) -> Vec<Operation<Payload, Payload>> {
+        let mut rng = Rng::new(seed);
+        let mut cluster = Cluster::builder()
+            .nodes::<KVServerNode, Payload, ()>(&["n0"])
+            .start()
+            .await;
+        for _ in 0..30 {
+            let client = CLIENTS[rng.below(CLIENTS.len() as u64) as usize];
+            cluster.set_latency(client, "n0", Duration::from_millis(rng.below(20)));
+            cluster.send(client, "n0", random_request(&mut rng));
+            tokio::time::sleep(Duration::from_millis(rng.below(5))).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client_operations(&cluster.trace())
+            .into_iter()
+            .map(|operation| Operation {
+                client: operation.client,
+                input: serde_json::from_value(operation.input).expect("parse request"),
+                output: operation
+                    .output
+                    .map(|output| serde_json::from_value(output).expect("parse reply")),
+                invoked: operation.invoked,
+                returned: operation.returned,
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_histories_are_linearizable() {
+        for seed in 0..20 {
+            let history = random_history(seed).await;
+            assert!(history.iter().all(|operation| operation.output.is_some()));
+            if let Err(err) = check_kv(&history) {
+                panic!("seed {}: {}", seed, err);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(wire::service_payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("lin_kv");
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    clock::TokioClock,
+    crdt::{LwwMap, Stamp},
+    event_loop, Body, ErrorCode, Event, HybridClock, Init, KVPayload, Message, Node, Output,
+    WithKV,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum LwwPayload {
+    /// The whole store of the sender.
+    Sync {
+        entries: LwwMap<String, Value>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// Client requests and replies are the KVPayload of the lin-kv service.
+type Payload = WithKV<LwwPayload>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InjectedPayload {
+    Sync,
+}
+
+/// Default interval between two rounds of Sync messages.
+const DEFAULT_SYNC_MS: u64 = 100;
+
+/// Default distance a peer's clock may run ahead before observing it warns.
+const DEFAULT_MAX_DRIFT_MS: u64 = 1000;
+
+#[derive(Debug, Clone)]
+struct LwwKvConfig {
+    sync_period: Duration,
+    /// Number of peers every Sync round goes to, all of them if 0.
+    fanout: usize,
+    max_drift: Duration,
+}
+
+impl LwwKvConfig {
+    /// Reads the configuration from the environment:
+    /// - `LWW_KV_SYNC_MS`: interval between Sync rounds in milliseconds (default 100)
+    /// - `LWW_KV_FANOUT`: peers every Sync round goes to, taken in turns, or 0 for all
+    ///   of them (default 0)
+    /// - `LWW_KV_MAX_DRIFT_MS`: how far ahead of the local wall clock a write from a
+    ///   peer may be stamped before it is warned about, in milliseconds (default 1000)
+    fn from_env() -> anyhow::Result<Self> {
+        let sync_ms = gossip_glomers::env_or("LWW_KV_SYNC_MS", DEFAULT_SYNC_MS)?;
+        if sync_ms == 0 {
+            anyhow::bail!("LWW_KV_SYNC_MS must be greater than 0");
+        }
+        Ok(Self {
+            sync_period: Duration::from_millis(sync_ms),
+            fanout: gossip_glomers::env_or("LWW_KV_FANOUT", 0)?,
+            max_drift: Duration::from_millis(gossip_glomers::env_or(
+                "LWW_KV_MAX_DRIFT_MS",
+                DEFAULT_MAX_DRIFT_MS,
+            )?),
+        })
+    }
+}
+
+/// A key/value store every node accepts writes to, where the write with the highest
+/// stamp wins once the nodes have synced. Reads only see the local view, so a read
+/// may miss a write acknowledged by another node, and cas compares against the local
+/// view only: two nodes can both succeed a cas from the same value, and the later
+/// stamp silently wins. None of it is linearizable, it only converges.
+struct LwwKvNode {
+    id: AtomicUsize,
+    node: String,
+    /// The other nodes of the cluster.
+    peers: Vec<String>,
+    store: Mutex<LwwMap<String, Value>>,
+    /// Stamps writes close to wall time, so the write that wins is usually the one
+    /// made last, and a stamp tells when its write was made.
+    clock: HybridClock<TokioClock>,
+    config: LwwKvConfig,
+    /// Number of Sync rounds so far, which picks the peers of the next one.
+    round: AtomicUsize,
+    stdout: Mutex<Output>,
+}
+
+impl LwwKvNode {
+    fn stamp(&self) -> Stamp {
+        Stamp {
+            time: self.clock.now(),
+            node: self.node.clone(),
+        }
+    }
+
+    /// Applies a client request to the local view and returns the reply.
+    async fn apply(&self, request: KVPayload<Value>) -> Payload {
+        let mut store = self.store.lock().await;
+        match request {
+            KVPayload::Read { key } => match store.get(&key) {
+                Some(value) => WithKV::KV(KVPayload::ReadOk {
+                    value: value.clone(),
+                }),
+                None => key_does_not_exist(&key),
+            },
+            KVPayload::Write { key, value } => {
+                store.insert(key, value, self.stamp());
+                WithKV::KV(KVPayload::WriteOk {})
+            }
+            KVPayload::Cas { key, from, to, put } => match store.get(&key) {
+                Some(current) if *current == from => {
+                    store.insert(key, to, self.stamp());
+                    WithKV::KV(KVPayload::CasOk {})
+                }
+                Some(current) => WithKV::Workload(LwwPayload::Error {
+                    code: ErrorCode::PreconditionFailed.code(),
+                    text: format!("expected {}, but had {}", from, current),
+                }),
+                None if put => {
+                    store.insert(key, to, self.stamp());
+                    WithKV::KV(KVPayload::CasOk {})
+                }
+                None => key_does_not_exist(&key),
+            },
+            request => WithKV::Workload(LwwPayload::Error {
+                code: ErrorCode::NotSupported.code(),
+                text: format!("unsupported request {:?}", request),
+            }),
+        }
+    }
+
+    /// Keeps the entries of a peer that are newer than the local ones.
+    async fn merge(&self, entries: LwwMap<String, Value>) {
+        // Later local writes must win over everything seen so far.
+        self.clock.observe(entries.max_time());
+        self.store.lock().await.merge(entries);
+    }
+
+    /// Sends the whole store to the peers of this round, so lost or partitioned syncs
+    /// are made up for by the next ones.
+    async fn sync(&self) -> anyhow::Result<()> {
+        if self.peers.is_empty() {
+            return Ok(());
+        }
+        let entries = self.store.lock().await.clone();
+        let fanout = match self.config.fanout {
+            0 => self.peers.len(),
+            fanout => fanout.min(self.peers.len()),
+        };
+        let first = self.round.fetch_add(1, Ordering::Relaxed) * fanout;
+        for i in first..first + fanout {
+            let sync_msg: Message<Payload> = Message {
+                src: self.node.clone(),
+                dest: self.peers[i % self.peers.len()].clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: WithKV::Workload(LwwPayload::Sync {
+                        entries: entries.clone(),
+                    }),
+                },
+            };
+            sync_msg
+                .send(&self.stdout)
+                .await
+                .context("send sync message")?;
+        }
+        Ok(())
+    }
+}
+
+fn key_does_not_exist(key: &str) -> Payload {
+    WithKV::Workload(LwwPayload::Error {
+        code: ErrorCode::KeyDoesNotExist.code(),
+        text: format!("key {:?} does not exist", key),
+    })
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for LwwKvNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = LwwKvConfig::from_env()?;
+        eprintln!("lww_kv config: {:?}", config);
+        gossip_glomers::spawn_timer(tx, config.sync_period, InjectedPayload::Sync);
+
+        Ok(Self {
+            id: 1.into(),
+            peers: init
+                .node_ids
+                .into_iter()
+                .filter(|node| *node != init.node_id)
+                .collect(),
+            node: init.node_id,
+            store: Mutex::default(),
+            clock: HybridClock::with_clock(config.max_drift, TokioClock::new()),
+            config,
+            round: 0.into(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            // The node keeps no stats to report.
+            Event::EOF => {}
+            Event::Injected(InjectedPayload::Sync) => self.sync().await?,
+            Event::Message(message) => {
+                let mut reply = message.into_reply(Some(&self.id));
+                match reply.body.payload {
+                    WithKV::Workload(LwwPayload::Sync { entries }) => self.merge(entries).await,
+                    WithKV::Workload(LwwPayload::Error { .. }) => {}
+                    WithKV::KV(
+                        KVPayload::ReadOk { .. } | KVPayload::WriteOk {} | KVPayload::CasOk {},
+                    ) => {}
+                    WithKV::KV(request) => {
+                        reply.body.payload = self.apply(request).await;
+                        reply.send(&self.stdout).await.context("send reply")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<LwwKvNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{assert_golden, wire, Cluster, Harness};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    async fn write(cluster: &mut Cluster, node: &str, key: &str, value: Value) {
+        let request = KVPayload::Write {
+            key: key.to_string(),
+            value,
+        };
+        let id = cluster.send("c1", node, Payload::KV(request));
+        let reply = cluster.expect_reply_to::<Payload>(id).await;
+        assert!(matches!(
+            reply.body.payload,
+            WithKV::KV(KVPayload::WriteOk {})
+        ));
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str, key: &str) -> Option<Value> {
+        let request = KVPayload::Read {
+            key: key.to_string(),
+        };
+        let id = cluster.send("c1", node, Payload::KV(request));
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            WithKV::KV(KVPayload::ReadOk { value }) => Some(value),
+            WithKV::Workload(LwwPayload::Error { code, .. })
+                if code == ErrorCode::KeyDoesNotExist.code() =>
+            {
+                None
+            }
+            payload => panic!("expected read_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_on_both_sides_of_a_partition_converge_to_the_last() {
+        let mut cluster = Cluster::builder()
+            .nodes::<LwwKvNode, Payload, InjectedPayload>(&NODES)
+            .start()
+            .await;
+        cluster.partition(&["n0"], &["n1", "n2"]);
+        write(&mut cluster, "n1", "x", json!(1)).await;
+        write(&mut cluster, "n0", "y", json!("only n0")).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        write(&mut cluster, "n0", "x", json!(2)).await;
+        tokio::time::sleep(Duration::from_millis(DEFAULT_SYNC_MS * 3)).await;
+        assert_eq!(read(&mut cluster, "n2", "x").await, Some(json!(1)));
+        assert_eq!(read(&mut cluster, "n2", "y").await, None);
+
+        cluster.heal();
+        tokio::time::sleep(Duration::from_millis(DEFAULT_SYNC_MS * 3)).await;
+        for node in NODES {
+            assert_eq!(read(&mut cluster, node, "x").await, Some(json!(2)));
+            assert_eq!(read(&mut cluster, node, "y").await, Some(json!("only n0")));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kv_replies_match_their_golden_file() {
+        let mut node = Harness::<LwwKvNode, Payload, InjectedPayload>::new("n0", &["n0"]).await;
+        for body in [
+            json!({"type": "read", "key": "x"}),
+            json!({"type": "write", "key": "x", "value": 1}),
+            json!({"type": "read", "key": "x"}),
+            json!({"type": "cas", "key": "x", "from": 1, "to": 2}),
+            json!({"type": "cas", "key": "x", "from": 1, "to": 3}),
+            json!({"type": "cas", "key": "y", "from": 1, "to": 2}),
+            json!({"type": "cas", "key": "y", "from": 1, "to": 2, "create_if_not_exists": true}),
+            json!({"type": "read", "key": "y"}),
+        ] {
+            let id = node.send_json("c1", body).await;
+            node.expect_reply_to(id).await;
+        }
+        assert_golden("lww_kv", node.transcript());
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        wire::with_kv(prop_oneof![
+            wire::lww_map().prop_map(|entries| LwwPayload::Sync { entries }),
+            wire::error().prop_map(|(code, text)| LwwPayload::Error { code, text }),
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    /// The node serves what nodes send to lin-kv, in place of it.
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("kv_service");
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{event_loop, Body, Event, Init, Message, Node, Output};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    /// Adds `delta` to the counter, which may be negative.
+    Add {
+        delta: i64,
+    },
+    AddOk,
+    Read,
+    ReadOk {
+        value: i64,
+    },
+    Sync {
+        counter: PNCounter,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InjectedPayload {
+    Sync,
+}
+
+/// Default interval between two rounds of Sync messages.
+const DEFAULT_SYNC_MS: u64 = 500;
+
+/// A counter that can go both ways, made of two grow-only counters per node: one for
+/// what it added and one for what it subtracted. Every entry is only written by its
+/// node and only grows, so merging takes the maximum of each and any two replicas that
+/// have seen the same updates agree, whatever the order they got them in.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct PNCounter {
+    increments: HashMap<String, u64>,
+    decrements: HashMap<String, u64>,
+}
+
+impl PNCounter {
+    /// Adds `delta` to the share of `node`.
+    fn add(&mut self, node: &str, delta: i64) {
+        let entries = if delta >= 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        *entries.entry(node.to_string()).or_default() += delta.unsigned_abs();
+    }
+
+    fn value(&self) -> i64 {
+        let increments: u64 = self.increments.values().sum();
+        let decrements: u64 = self.decrements.values().sum();
+        increments as i64 - decrements as i64
+    }
+
+    fn merge(&mut self, other: &PNCounter) {
+        for (mine, theirs) in [
+            (&mut self.increments, &other.increments),
+            (&mut self.decrements, &other.decrements),
+        ] {
+            for (node, value) in theirs {
+                let current = mine.entry(node.clone()).or_default();
+                *current = cmp::max(*current, *value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PNCounterConfig {
+    sync_period: Duration,
+    /// Number of peers every Sync round goes to, all of them if 0.
+    fanout: usize,
+}
+
+impl PNCounterConfig {
+    /// Reads the configuration from the environment:
+    /// - `PN_COUNTER_SYNC_MS`: interval between Sync rounds in milliseconds (default 500)
+    /// - `PN_COUNTER_FANOUT`: peers every Sync round goes to, taken in turns so each
+    ///   one is reached within a few rounds, or 0 for all of them (default 0)
+    fn from_env() -> anyhow::Result<Self> {
+        let sync_ms = gossip_glomers::env_or("PN_COUNTER_SYNC_MS", DEFAULT_SYNC_MS)?;
+        if sync_ms == 0 {
+            anyhow::bail!("PN_COUNTER_SYNC_MS must be greater than 0");
+        }
+        Ok(Self {
+            sync_period: Duration::from_millis(sync_ms),
+            fanout: gossip_glomers::env_or("PN_COUNTER_FANOUT", 0)?,
+        })
+    }
+}
+
+struct PNCounterNode {
+    id: AtomicUsize,
+    node: String,
+    /// The other nodes of the cluster.
+    peers: Vec<String>,
+    counter: Mutex<PNCounter>,
+    config: PNCounterConfig,
+    /// Number of Sync rounds so far, which picks the peers of the next one.
+    round: AtomicUsize,
+    stdout: Mutex<Output>,
+}
+
+impl PNCounterNode {
+    /// Sends the whole counter to the peers of this round. Syncs carry the full state,
+    /// so a lost one is made up for by the next and partitioned nodes catch up as soon
+    /// as they can talk again.
+    async fn sync(&self) -> anyhow::Result<()> {
+        if self.peers.is_empty() {
+            return Ok(());
+        }
+        let counter = self.counter.lock().await.clone();
+        let fanout = match self.config.fanout {
+            0 => self.peers.len(),
+            fanout => fanout.min(self.peers.len()),
+        };
+        let first = self.round.fetch_add(1, Ordering::Relaxed) * fanout;
+        for i in first..first + fanout {
+            let sync_msg = Message {
+                src: self.node.clone(),
+                dest: self.peers[i % self.peers.len()].clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Sync {
+                        counter: counter.clone(),
+                    },
+                },
+            };
+            sync_msg
+                .send(&self.stdout)
+                .await
+                .context("send sync message")?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for PNCounterNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = PNCounterConfig::from_env()?;
+        eprintln!("pn_counter config: {:?}", config);
+        gossip_glomers::spawn_timer(tx, config.sync_period, InjectedPayload::Sync);
+
+        Ok(Self {
+            id: 1.into(),
+            peers: init
+                .node_ids
+                .into_iter()
+                .filter(|node| *node != init.node_id)
+                .collect(),
+            node: init.node_id,
+            counter: Mutex::default(),
+            config,
+            round: 0.into(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            // The node keeps no stats to report.
+            Event::EOF => {}
+            Event::Injected(InjectedPayload::Sync) => self.sync().await?,
+            Event::Message(message) => {
+                let mut reply = message.into_reply(Some(&self.id));
+                match reply.body.payload {
+                    Payload::Add { delta } => {
+                        self.counter.lock().await.add(&self.node, delta);
+                        reply.body.payload = Payload::AddOk;
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send add response")?;
+                    }
+                    Payload::Read => {
+                        let value = self.counter.lock().await.value();
+                        reply.body.payload = Payload::ReadOk { value };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send read response")?;
+                    }
+                    Payload::Sync { counter } => self.counter.lock().await.merge(&counter),
+                    Payload::AddOk | Payload::ReadOk { .. } => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<PNCounterNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use gossip_glomers::testkit::{assert_golden, wire, Cluster, Harness};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    async fn cluster(vars: &[(&str, Option<&str>)]) -> Cluster {
+        Cluster::builder()
+            .nodes::<PNCounterNode, Payload, InjectedPayload>(&NODES)
+            .env(vars)
+            .start()
+            .await
+    }
+
+    async fn add(cluster: &mut Cluster, node: &str, delta: i64) {
+        let id = cluster.send("c1", node, Payload::Add { delta });
+        let reply = cluster.expect_reply_to::<Payload>(id).await;
+        assert!(matches!(reply.body.payload, Payload::AddOk));
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str) -> i64 {
+        let id = cluster.send("c1", node, Payload::Read);
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            Payload::ReadOk { value } => value,
+            payload => panic!("expected read_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counters_converge_after_a_partition_heals() {
+        let mut cluster = cluster(&[]).await;
+        cluster.partition(&["n0"], &["n1", "n2"]);
+        for (node, delta) in [("n0", 5), ("n1", -3), ("n2", 10), ("n0", -7), ("n1", 2)] {
+            add(&mut cluster, node, delta).await;
+        }
+        tokio::time::sleep(Duration::from_millis(DEFAULT_SYNC_MS * 2)).await;
+        assert_eq!(read(&mut cluster, "n0").await, -2);
+        assert_eq!(read(&mut cluster, "n1").await, 9);
+
+        cluster.heal();
+        tokio::time::sleep(Duration::from_millis(DEFAULT_SYNC_MS * 2)).await;
+        for node in NODES {
+            assert_eq!(read(&mut cluster, node).await, 7, "at {}", node);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counters_converge_with_a_fanout_of_one() {
+        let mut cluster = cluster(&[
+            ("PN_COUNTER_SYNC_MS", Some("100")),
+            ("PN_COUNTER_FANOUT", Some("1")),
+        ])
+        .await;
+        for (node, delta) in [("n0", -4), ("n1", 6), ("n2", -1)] {
+            add(&mut cluster, node, delta).await;
+        }
+        // Every peer is reached within as many rounds as there are peers.
+        tokio::time::sleep(Duration::from_millis(100 * NODES.len() as u64)).await;
+        for node in NODES {
+            assert_eq!(read(&mut cluster, node).await, 1, "at {}", node);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pn_counter_messages_match_their_golden_file() {
+        let mut node =
+            Harness::<PNCounterNode, Payload, InjectedPayload>::new("n0", &["n0", "n1"]).await;
+        for delta in [5, -3] {
+            let id = node
+                .send_json("c1", serde_json::json!({"type": "add", "delta": delta}))
+                .await;
+            node.expect_reply_to(id).await;
+        }
+        let id = node
+            .send_json("c1", serde_json::json!({"type": "read"}))
+            .await;
+        node.expect_reply_to(id).await;
+        node.advance(Duration::from_millis(DEFAULT_SYNC_MS)).await;
+        assert_golden("pn_counter", node.transcript());
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let counts = || prop::collection::hash_map(wire::node_id(), any::<u64>(), 0..4);
+        prop_oneof![
+            any::<i64>().prop_map(|delta| Payload::Add { delta }),
+            Just(Payload::AddOk),
+            Just(Payload::Read),
+            any::<i64>().prop_map(|value| Payload::ReadOk { value }),
+            (counts(), counts()).prop_map(|(increments, decrements)| Payload::Sync {
+                counter: PNCounter {
+                    increments,
+                    decrements,
+                },
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("pn_counter");
+        wire::assert_fixture_parses::<Payload>("g_counter");
+    }
+}
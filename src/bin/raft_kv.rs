@@ -0,0 +1,586 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop,
+    raft::{Outgoing, PersistentState, Raft, RaftConfig, RaftPayload},
+    Body, ErrorCode, Event, Init, Message, Node, Output,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+/// The lin-kv requests and their replies, with JSON values as keys like kv_server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum KvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// Either a client request or reply, or a message between the Raft nodes, read from
+/// the same stdin stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Payload {
+    Kv(KvPayload),
+    Raft(RaftPayload<Command>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InjectedPayload {
+    Tick,
+}
+
+/// Interval between two ticks of the Raft timers.
+const TICK_PERIOD: Duration = Duration::from_millis(10);
+
+/// A client request in the Raft log. Clients number their requests with increasing
+/// message ids, which make up a session: a request already applied is answered from
+/// the session rather than applied twice.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Command {
+    client: String,
+    serial: usize,
+    request: KvPayload,
+}
+
+/// The last request applied for a client and its reply.
+struct Session {
+    serial: usize,
+    reply: KvPayload,
+}
+
+/// A request this node proposed and has not answered yet.
+struct Pending {
+    command: Command,
+    reply: Message<Payload>,
+}
+
+/// Everything Raft and the requests it commits touch, behind a single lock so the log
+/// and the store move together.
+struct State {
+    raft: Raft<Command>,
+    /// Values by the JSON encoding of their key, which tells `1` and `"1"` apart.
+    store: HashMap<String, Value>,
+    sessions: HashMap<String, Session>,
+    /// Requests by the index they were proposed at.
+    pending: HashMap<u64, Pending>,
+}
+
+impl State {
+    /// Applies the newly committed requests and returns the replies to the ones this
+    /// node proposed.
+    fn apply_committed(&mut self) -> Vec<Message<Payload>> {
+        let mut replies = Vec::new();
+        for (index, command) in self.raft.committed() {
+            let reply = self.apply(&command);
+            if let Some(pending) = self.pending.remove(&index) {
+                let same = (&pending.command.client, pending.command.serial)
+                    == (&command.client, command.serial);
+                replies.push(if same {
+                    with_payload(pending.reply, reply)
+                } else {
+                    displaced(pending.reply)
+                });
+            }
+        }
+        // Whatever is left below the commit index lost its place to an entry of another
+        // leader, possibly one without a command.
+        let commit_index = self.raft.commit_index();
+        let displaced_indexes: Vec<_> = self
+            .pending
+            .keys()
+            .filter(|index| **index <= commit_index)
+            .copied()
+            .collect();
+        for index in displaced_indexes {
+            let pending = self.pending.remove(&index).expect("pending index");
+            replies.push(displaced(pending.reply));
+        }
+        replies
+    }
+
+    /// Applies `command` to the store once per session and returns its reply.
+    fn apply(&mut self, command: &Command) -> KvPayload {
+        if let Some(session) = self.sessions.get(&command.client) {
+            if session.serial == command.serial {
+                return session.reply.clone();
+            }
+            if session.serial > command.serial {
+                return KvPayload::Error {
+                    code: ErrorCode::Abort.code(),
+                    text: "request superseded by a later one of the same client".to_string(),
+                };
+            }
+        }
+        let reply = self.execute(command.request.clone());
+        self.sessions.insert(
+            command.client.clone(),
+            Session {
+                serial: command.serial,
+                reply: reply.clone(),
+            },
+        );
+        reply
+    }
+
+    fn execute(&mut self, request: KvPayload) -> KvPayload {
+        match request {
+            KvPayload::Read { key } => match self.store.get(&key.to_string()) {
+                Some(value) => KvPayload::ReadOk {
+                    value: value.clone(),
+                },
+                None => key_does_not_exist(&key),
+            },
+            KvPayload::Write { key, value } => {
+                self.store.insert(key.to_string(), value);
+                KvPayload::WriteOk
+            }
+            KvPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.store.get_mut(&key.to_string()) {
+                Some(current) if *current == from => {
+                    *current = to;
+                    KvPayload::CasOk
+                }
+                Some(current) => KvPayload::Error {
+                    code: ErrorCode::PreconditionFailed.code(),
+                    text: format!("expected {}, but had {}", from, current),
+                },
+                None if create_if_not_exists => {
+                    self.store.insert(key.to_string(), to);
+                    KvPayload::CasOk
+                }
+                None => key_does_not_exist(&key),
+            },
+            request => KvPayload::Error {
+                code: ErrorCode::NotSupported.code(),
+                text: format!("unsupported request {:?}", request),
+            },
+        }
+    }
+}
+
+fn key_does_not_exist(key: &Value) -> KvPayload {
+    KvPayload::Error {
+        code: ErrorCode::KeyDoesNotExist.code(),
+        text: format!("key {} does not exist", key),
+    }
+}
+
+fn with_payload(mut reply: Message<Payload>, payload: KvPayload) -> Message<Payload> {
+    reply.body.payload = Payload::Kv(payload);
+    reply
+}
+
+/// Answers a request whose log entry was replaced, so it was definitely not applied.
+fn displaced(reply: Message<Payload>) -> Message<Payload> {
+    with_payload(
+        reply,
+        KvPayload::Error {
+            code: ErrorCode::TemporarilyUnavailable.code(),
+            text: "leadership changed before the request committed".to_string(),
+        },
+    )
+}
+
+struct RaftKvNode {
+    id: AtomicUsize,
+    node: String,
+    state: Mutex<State>,
+    stdout: Mutex<Output>,
+}
+
+impl RaftKvNode {
+    /// Proposes the request in `message` to the log, or answers it right away if this
+    /// node does not lead or the request was already applied.
+    async fn propose(&self, message: Message<KvPayload>) -> anyhow::Result<()> {
+        let serial = message.body.id.unwrap_or_default();
+        let reply = message.into_reply(Some(&self.id));
+        let command = Command {
+            client: reply.dest.clone(),
+            serial,
+            request: reply.body.payload.clone(),
+        };
+        let reply = reply.map_payload(Payload::Kv);
+        let mut state = self.state.lock().await;
+        if let Some(session) = state.sessions.get(&command.client) {
+            if session.serial == serial {
+                let reply = with_payload(reply, session.reply.clone());
+                drop(state);
+                return reply.send(&self.stdout).await.context("send cached reply");
+            }
+        }
+        let Some((index, outgoing)) = state.raft.propose(command.clone()) else {
+            let text = match state.raft.leader() {
+                Some(leader) => format!("not the leader, try {}", leader),
+                None => "no leader is known".to_string(),
+            };
+            drop(state);
+            return with_payload(
+                reply,
+                KvPayload::Error {
+                    code: ErrorCode::TemporarilyUnavailable.code(),
+                    text,
+                },
+            )
+            .send(&self.stdout)
+            .await
+            .context("send redirect");
+        };
+        state.pending.insert(index, Pending { command, reply });
+        // A single node commits at once.
+        let replies = state.apply_committed();
+        drop(state);
+        self.send(outgoing, replies).await
+    }
+
+    async fn send(
+        &self,
+        outgoing: Vec<Outgoing<Command>>,
+        replies: Vec<Message<Payload>>,
+    ) -> anyhow::Result<()> {
+        for message in outgoing {
+            Message {
+                src: self.node.clone(),
+                dest: message.dest,
+                body: Body {
+                    id: Some(self.id.fetch_add(1, Ordering::Relaxed)),
+                    in_reply_to: None,
+                    payload: Payload::Raft(message.payload),
+                },
+            }
+            .send(&self.stdout)
+            .await
+            .context("send raft message")?;
+        }
+        for reply in replies {
+            reply.send(&self.stdout).await.context("send reply")?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for RaftKvNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        // `RAFT_SEED` seeds the election timeouts (default 0), so a test can vary them.
+        let config = RaftConfig {
+            seed: gossip_glomers::env_or("RAFT_SEED", 0)?,
+            ..RaftConfig::default()
+        };
+        gossip_glomers::spawn_timer(tx, TICK_PERIOD, InjectedPayload::Tick);
+        let raft = Raft::new(
+            init.node_id.clone(),
+            &init.node_ids,
+            config,
+            PersistentState::default(),
+            gossip_glomers::now(),
+        );
+        Ok(Self {
+            id: 1.into(),
+            node: init.node_id,
+            state: Mutex::new(State {
+                raft,
+                store: HashMap::new(),
+                sessions: HashMap::new(),
+                pending: HashMap::new(),
+            }),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            // The node keeps no stats to report.
+            Event::EOF => {}
+            Event::Injected(InjectedPayload::Tick) => {
+                let mut state = self.state.lock().await;
+                let outgoing = state.raft.tick(gossip_glomers::now());
+                let replies = state.apply_committed();
+                drop(state);
+                self.send(outgoing, replies).await?;
+            }
+            Event::Message(message) => match message.body.payload {
+                Payload::Raft(payload) => {
+                    let mut state = self.state.lock().await;
+                    let outgoing = state
+                        .raft
+                        .receive(gossip_glomers::now(), &message.src, payload);
+                    let replies = state.apply_committed();
+                    drop(state);
+                    self.send(outgoing, replies).await?;
+                }
+                Payload::Kv(
+                    KvPayload::ReadOk { .. }
+                    | KvPayload::WriteOk
+                    | KvPayload::CasOk
+                    | KvPayload::Error { .. },
+                ) => {}
+                Payload::Kv(request) => {
+                    let message = Message {
+                        src: message.src,
+                        dest: message.dest,
+                        body: Body {
+                            id: message.body.id,
+                            in_reply_to: message.body.in_reply_to,
+                            payload: request,
+                        },
+                    };
+                    self.propose(message).await?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<RaftKvNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Cluster};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    /// Long enough for any election to end, several election timeouts.
+    const SETTLE: Duration = Duration::from_secs(3);
+
+    async fn cluster() -> Cluster {
+        let mut cluster = Cluster::builder()
+            .nodes::<RaftKvNode, Payload, InjectedPayload>(&NODES)
+            .start()
+            .await;
+        cluster.advance::<Payload>(SETTLE).await;
+        cluster
+    }
+
+    async fn request(cluster: &mut Cluster, node: &str, request: KvPayload) -> KvPayload {
+        let id = cluster.send("c1", node, Payload::Kv(request));
+        reply(cluster, id).await
+    }
+
+    async fn reply(cluster: &mut Cluster, id: usize) -> KvPayload {
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            Payload::Kv(reply) => reply,
+            payload => panic!("expected a kv reply, got {:?}", payload),
+        }
+    }
+
+    fn read() -> KvPayload {
+        KvPayload::Read { key: json!("x") }
+    }
+
+    fn write(value: i64) -> KvPayload {
+        KvPayload::Write {
+            key: json!("x"),
+            value: json!(value),
+        }
+    }
+
+    fn cas(from: i64, to: i64) -> KvPayload {
+        KvPayload::Cas {
+            key: json!("x"),
+            from: json!(from),
+            to: json!(to),
+            create_if_not_exists: false,
+        }
+    }
+
+    fn code(reply: &KvPayload) -> Option<usize> {
+        match reply {
+            KvPayload::Error { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns the node of `nodes` that leads, after checking that the others send
+    /// clients to it.
+    async fn leader(cluster: &mut Cluster, nodes: &[&str]) -> String {
+        let mut leaders = Vec::new();
+        let mut redirects = Vec::new();
+        for node in nodes {
+            match request(cluster, node, read()).await {
+                KvPayload::Error { code, text }
+                    if code == ErrorCode::TemporarilyUnavailable.code() =>
+                {
+                    redirects.push(text)
+                }
+                _ => leaders.push(node.to_string()),
+            }
+        }
+        assert_eq!(leaders.len(), 1, "leaders among {:?}: {:?}", nodes, leaders);
+        let leader = leaders.remove(0);
+        for text in redirects {
+            assert_eq!(text, format!("not the leader, try {}", leader));
+        }
+        leader
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn followers_send_clients_to_the_leader() {
+        let mut cluster = cluster().await;
+        let leader = leader(&mut cluster, &NODES).await;
+        assert!(matches!(
+            request(&mut cluster, &leader, write(1)).await,
+            KvPayload::WriteOk
+        ));
+        for follower in NODES.iter().filter(|node| **node != leader) {
+            let reply = request(&mut cluster, follower, write(2)).await;
+            assert_eq!(code(&reply), Some(ErrorCode::TemporarilyUnavailable.code()));
+        }
+        assert!(matches!(
+            request(&mut cluster, &leader, read()).await,
+            KvPayload::ReadOk { value } if value == json!(1)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retried_requests_apply_once() {
+        let mut cluster = cluster().await;
+        let leader = leader(&mut cluster, &NODES).await;
+        assert!(matches!(
+            request(&mut cluster, &leader, write(0)).await,
+            KvPayload::WriteOk
+        ));
+        let retried = json!({"type": "cas", "key": "x", "from": 0, "to": 1, "msg_id": 100});
+
+        // A retry that comes in before the first try committed, and one after.
+        let id = cluster.send_json("c2", &leader, retried.clone());
+        cluster.send_json("c2", &leader, retried.clone());
+        for _ in 0..2 {
+            assert!(matches!(reply(&mut cluster, id).await, KvPayload::CasOk));
+        }
+        cluster.send_json("c2", &leader, retried);
+        assert!(matches!(reply(&mut cluster, id).await, KvPayload::CasOk));
+
+        // Applying it again would have failed, as a new request shows.
+        let reply = request(&mut cluster, &leader, cas(0, 1)).await;
+        assert_eq!(code(&reply), Some(ErrorCode::PreconditionFailed.code()));
+        assert!(matches!(
+            request(&mut cluster, &leader, read()).await,
+            KvPayload::ReadOk { value } if value == json!(1)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_cut_off_leaders_entries_give_way_to_the_new_leaders() {
+        let mut cluster = cluster().await;
+        let old = leader(&mut cluster, &NODES).await;
+        let others: Vec<&str> = NODES.iter().copied().filter(|node| *node != old).collect();
+        cluster.partition(&[&old], &others);
+
+        // The old leader appends the write but cannot commit it.
+        let lost = cluster.send("c1", &old, Payload::Kv(write(1)));
+        cluster.advance::<Payload>(SETTLE).await;
+        let new = leader(&mut cluster, &others).await;
+        assert!(matches!(
+            request(&mut cluster, &new, write(2)).await,
+            KvPayload::WriteOk
+        ));
+
+        // Once it hears of the new term, the old leader's entry is replaced by the new
+        // leader's and its client told the write did not happen.
+        cluster.heal();
+        let reply = reply(&mut cluster, lost).await;
+        assert_eq!(code(&reply), Some(ErrorCode::TemporarilyUnavailable.code()));
+        cluster.advance::<Payload>(SETTLE).await;
+        assert_eq!(leader(&mut cluster, &NODES).await, new);
+        assert!(matches!(
+            request(&mut cluster, &new, read()).await,
+            KvPayload::ReadOk { value } if value == json!(2)
+        ));
+    }
+
+    fn kv_payload() -> impl Strategy<Value = KvPayload> {
+        prop_oneof![
+            wire::json().prop_map(|key| KvPayload::Read { key }),
+            wire::json().prop_map(|value| KvPayload::ReadOk { value }),
+            (wire::json(), wire::json()).prop_map(|(key, value)| KvPayload::Write { key, value }),
+            Just(KvPayload::WriteOk),
+            (wire::json(), wire::json(), wire::json(), any::<bool>()).prop_map(
+                |(key, from, to, create_if_not_exists)| KvPayload::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                }
+            ),
+            Just(KvPayload::CasOk),
+            wire::error().prop_map(|(code, text)| KvPayload::Error { code, text }),
+        ]
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let command = (wire::node_id(), any::<usize>(), kv_payload()).prop_map(
+            |(client, serial, request)| Command {
+                client,
+                serial,
+                request,
+            },
+        );
+        prop_oneof![
+            kv_payload().prop_map(Payload::Kv),
+            wire::raft_payload(command).prop_map(Payload::Raft),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("lin_kv");
+    }
+}
@@ -0,0 +1,662 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use gossip_glomers::Message;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    process::Command,
+    sync::watch,
+};
+
+/// Nodes `--node` takes, which are the binaries built next to this one.
+const NODES: &[&str] = &[
+    "broadcast",
+    "broadcast_efficient",
+    "chain_kv",
+    "counter",
+    "datomic",
+    "dynamo_kv",
+    "echo",
+    "g_counter_kv",
+    "kafka",
+    "kv_proxy",
+    "kv_server",
+    "lww_kv",
+    "pn_counter",
+    "raft_kv",
+    "seq_kv_server",
+    "total_order",
+    "tpc_txn",
+    "txn",
+    "unique_ids",
+];
+
+/// Default time the node gets to send what follows from the last input.
+const DEFAULT_SETTLE_MS: u64 = 1000;
+
+/// Longest a reply in the recording waits for the node to send the request it answers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most cells of the table the diff of one destination fills in. Beyond it, the
+/// messages that differ are reported as all removed and all added.
+const MAX_DIFF_CELLS: usize = 1 << 24;
+
+const USAGE: &str =
+    "usage: replay --node <node> [--timing] [--settle-ms <ms>] [--out <path>] <recording>";
+
+#[derive(Debug, Clone)]
+struct ReplayConfig {
+    node: String,
+    recording: PathBuf,
+    out: PathBuf,
+    timing: bool,
+    settle: Duration,
+}
+
+impl ReplayConfig {
+    /// Reads the configuration from the arguments:
+    /// - `--node <node>`: the binary to run, one of NODES
+    /// - `--timing`: waits between inputs as long as the recording did, if its lines
+    ///   carry times, rather than feeding them as fast as the node takes them
+    /// - `--settle-ms <ms>`: time the node gets after the last input before its input
+    ///   is closed, in milliseconds (default 1000)
+    /// - `--out <path>`: where the replayed output goes (default the recording with
+    ///   `.replay` appended)
+    /// - `<recording>`: the log to replay
+    fn from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut node = None;
+        let mut recording = None;
+        let mut out = None;
+        let mut timing = false;
+        let mut settle = Duration::from_millis(DEFAULT_SETTLE_MS);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{} takes a value", arg))
+            };
+            match arg.as_str() {
+                "--node" => node = Some(value()?),
+                "--out" => out = Some(PathBuf::from(value()?)),
+                "--timing" => timing = true,
+                "--settle-ms" => {
+                    let raw = value()?;
+                    let ms = raw
+                        .parse()
+                        .with_context(|| format!("invalid value {:?} for --settle-ms", raw))?;
+                    settle = Duration::from_millis(ms);
+                }
+                flag if flag.starts_with("--") => anyhow::bail!("unknown flag {}\n{}", flag, USAGE),
+                _ if recording.is_none() => recording = Some(PathBuf::from(arg)),
+                _ => anyhow::bail!("more than one recording given\n{}", USAGE),
+            }
+        }
+        let node = node.with_context(|| format!("--node is missing\n{}", USAGE))?;
+        if !NODES.contains(&node.as_str()) {
+            anyhow::bail!(
+                "unknown node {}, expected one of {}",
+                node,
+                NODES.join(", ")
+            );
+        }
+        let recording: PathBuf =
+            recording.with_context(|| format!("no recording given\n{}", USAGE))?;
+        let out = out.unwrap_or_else(|| {
+            let mut out = recording.clone().into_os_string();
+            out.push(".replay");
+            out.into()
+        });
+        Ok(Self {
+            node,
+            recording,
+            out,
+            timing,
+            settle,
+        })
+    }
+}
+
+/// A message of the recording, with the time it was logged at if its line had one.
+#[derive(Debug, Clone)]
+struct Recorded {
+    at: Option<Duration>,
+    message: Message<Value>,
+}
+
+/// What one node received and sent in a recorded run.
+#[derive(Debug, Default)]
+struct Recording {
+    /// The id of the node, from its init message.
+    node: String,
+    /// Messages to the node, init first.
+    inputs: Vec<Recorded>,
+    /// Messages from the node, empty if only its input was recorded.
+    outputs: Vec<Message<Value>>,
+}
+
+impl Recording {
+    /// Reads a recording with one message per line. A message may follow a prefix, as
+    /// in the logs of Maelstrom's own nodes; if the prefix is a number, it is the time
+    /// the message was logged at in seconds. Lines without a message are skipped, and
+    /// so is everything before the init message. Messages to the node are its input
+    /// and those from it its output, so a log of its input alone replays too.
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut recording = Self::default();
+        for line in text.lines() {
+            let Some(recorded) = parse_line(line) else {
+                continue;
+            };
+            let message = &recorded.message;
+            if recording.node.is_empty() {
+                if message.body.payload["type"] == "init" {
+                    recording.node = message.body.payload["node_id"]
+                        .as_str()
+                        .context("init message without a node_id")?
+                        .to_string();
+                    recording.inputs.push(recorded);
+                }
+            } else if message.dest == recording.node {
+                recording.inputs.push(recorded);
+            } else if message.src == recording.node {
+                recording.outputs.push(recorded.message);
+            }
+        }
+        anyhow::ensure!(!recording.node.is_empty(), "recording has no init message");
+        Ok(recording)
+    }
+
+    /// Returns, for every destination, the position of each msg_id among those the
+    /// node sent there.
+    fn request_positions(&self) -> HashMap<String, HashMap<usize, usize>> {
+        let mut positions: HashMap<String, HashMap<usize, usize>> = HashMap::new();
+        for message in &self.outputs {
+            if let Some(id) = message.body.id {
+                let sent = positions.entry(message.dest.clone()).or_default();
+                let position = sent.len();
+                sent.entry(id).or_insert(position);
+            }
+        }
+        positions
+    }
+}
+
+/// Returns the message in `line`, if it holds one, with the time before it.
+fn parse_line(line: &str) -> Option<Recorded> {
+    let start = line.find('{')?;
+    let message = serde_json::from_str(&line[start..]).ok()?;
+    let at = line[..start]
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64);
+    Some(Recorded { at, message })
+}
+
+/// What the node sent so far in the replay.
+#[derive(Debug, Default)]
+struct Replayed {
+    lines: Vec<String>,
+    /// The msg_ids the node sent to each destination, in order.
+    ids: HashMap<String, Vec<usize>>,
+}
+
+/// Feeds the input of `recording` to a node through `input` and returns every line it
+/// sends on `output`. Replies to the node's requests are rewritten to answer the
+/// msg_ids the node uses now: the reply to the n-th request the node sent somewhere in
+/// the recording answers the n-th request it sends there in the replay.
+async fn replay(
+    recording: &Recording,
+    config: &ReplayConfig,
+    mut input: impl AsyncWrite + Unpin,
+    output: impl AsyncBufRead + Unpin + Send + 'static,
+) -> anyhow::Result<Vec<String>> {
+    let replayed = Arc::new(Mutex::new(Replayed::default()));
+    let (sent, mut changed) = watch::channel(());
+    let reader = tokio::spawn(read_output(output, replayed.clone(), sent));
+
+    let positions = recording.request_positions();
+    let started = tokio::time::Instant::now();
+    let first_at = recording.inputs.iter().find_map(|recorded| recorded.at);
+    for recorded in &recording.inputs {
+        if let (true, Some(at), Some(first_at)) = (config.timing, recorded.at, first_at) {
+            tokio::time::sleep_until(started + at.saturating_sub(first_at)).await;
+        }
+        let mut message = recorded.message.clone();
+        let request = message.body.in_reply_to.and_then(|id| {
+            let position = positions.get(&message.src)?.get(&id)?;
+            Some((id, *position))
+        });
+        if let Some((id, position)) = request {
+            let deadline = tokio::time::Instant::now() + REQUEST_TIMEOUT;
+            loop {
+                let sent = lock(&replayed)
+                    .ids
+                    .get(&message.src)
+                    .and_then(|ids| ids.get(position).copied());
+                if let Some(sent) = sent {
+                    message.body.in_reply_to = Some(sent);
+                    break;
+                }
+                if tokio::time::timeout_at(deadline, changed.changed())
+                    .await
+                    .map_or(true, |changed| changed.is_err())
+                {
+                    eprintln!(
+                        "node never sent request {} to {}, replying to msg_id {} as recorded",
+                        position + 1,
+                        message.src,
+                        id
+                    );
+                    break;
+                }
+            }
+        }
+        let line = serde_json::to_string(&message).context("serialize input")?;
+        input
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .context("write input to node")?;
+        input.flush().await.context("flush input to node")?;
+    }
+
+    tokio::time::sleep(config.settle).await;
+    drop(input);
+    // A node that keeps running after its input closed is cut off after another
+    // settle period.
+    if tokio::time::timeout(config.settle, reader).await.is_err() {
+        eprintln!(
+            "node still running {:?} after its input closed",
+            config.settle
+        );
+    }
+    let lines = std::mem::take(&mut lock(&replayed).lines);
+    Ok(lines)
+}
+
+/// Collects the lines the node sends on `output` into `replayed` until it closes,
+/// signalling `sent` after each.
+async fn read_output(
+    output: impl AsyncBufRead + Unpin,
+    replayed: Arc<Mutex<Replayed>>,
+    sent: watch::Sender<()>,
+) {
+    let mut lines = output.lines();
+    loop {
+        let line = match lines.next_line().await {
+            std::result::Result::Ok(Some(line)) => line,
+            std::result::Result::Ok(None) => return,
+            Err(err) => {
+                eprintln!("failed to read output of node: {}", err);
+                return;
+            }
+        };
+        let mut replayed = lock(&replayed);
+        if let std::result::Result::Ok(message) = serde_json::from_str::<Message<Value>>(&line) {
+            if let Some(id) = message.body.id {
+                replayed.ids.entry(message.dest).or_default().push(id);
+            }
+        }
+        replayed.lines.push(line);
+        drop(replayed);
+        sent.send_replace(());
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Returns the diff of what the node sent in the recording and in the replay, one
+/// destination after another. Messages are compared without their msg_id, which
+/// differs between runs, and in the order they went to their destination.
+fn diff(recorded: &[Message<Value>], replayed: &[String]) -> Vec<String> {
+    let mut by_dest: BTreeMap<&str, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    for message in recorded {
+        by_dest
+            .entry(&message.dest)
+            .or_default()
+            .0
+            .push(comparable(message.clone()));
+    }
+    let replayed: Vec<Message<Value>> = replayed
+        .iter()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    for message in &replayed {
+        by_dest
+            .entry(&message.dest)
+            .or_default()
+            .1
+            .push(comparable(message.clone()));
+    }
+    by_dest
+        .into_iter()
+        .flat_map(|(dest, (recorded, replayed))| {
+            diff_lines(&recorded, &replayed)
+                .into_iter()
+                .map(move |line| format!("{}: {}", dest, line))
+        })
+        .collect()
+}
+
+/// Returns `message` as a line without its msg_id.
+fn comparable(message: Message<Value>) -> String {
+    let mut message = serde_json::to_value(message).expect("a message serializes");
+    if let Some(body) = message["body"].as_object_mut() {
+        body.remove("msg_id");
+    }
+    message.to_string()
+}
+
+/// Returns the lines only `old` has, prefixed with `-`, and those only `new` has,
+/// prefixed with `+`, in order, from a longest common subsequence of the two.
+fn diff_lines(old: &[String], new: &[String]) -> Vec<String> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let (old, new) = (&old[prefix..], &new[prefix..]);
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old, new) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+    let removed = |line: &String| format!("- {}", line);
+    let added = |line: &String| format!("+ {}", line);
+    if (old.len() + 1) * (new.len() + 1) > MAX_DIFF_CELLS {
+        return old
+            .iter()
+            .map(removed)
+            .chain(new.iter().map(added))
+            .collect();
+    }
+
+    // common[i][j] is the length of a longest common subsequence of old[i..] and
+    // new[j..].
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = if old[i] == new[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len()
+            || (i < old.len() && common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            lines.push(removed(&old[i]));
+            i += 1;
+        } else {
+            lines.push(added(&new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Replays a recorded run of a node, to reproduce a failed Maelstrom run offline. It
+/// runs the node binary named by `--node`, which reads its configuration from the
+/// environment as under Maelstrom, writes what it sends to `--out` and prints how that
+/// differs from what it sent in the recording.
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = ReplayConfig::from_args(std::env::args().skip(1))?;
+    let text = std::fs::read_to_string(&config.recording)
+        .with_context(|| format!("read recording {}", config.recording.display()))?;
+    let recording = Recording::parse(&text)?;
+
+    let binary = std::env::current_exe()
+        .context("find replay binary")?
+        .with_file_name(&config.node);
+    let mut node = Command::new(&binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("run {}, is it built?", binary.display()))?;
+    let input = node.stdin.take().context("node stdin")?;
+    let output = BufReader::new(node.stdout.take().context("node stdout")?);
+    let replayed = replay(&recording, &config, input, output).await?;
+
+    let mut out = replayed.join("\n");
+    out.push('\n');
+    std::fs::write(&config.out, out)
+        .with_context(|| format!("write replayed output to {}", config.out.display()))?;
+    if recording.outputs.is_empty() {
+        println!(
+            "recording holds no output of {}, wrote the replayed output to {}",
+            recording.node,
+            config.out.display()
+        );
+        return Ok(());
+    }
+    let diff = diff(&recording.outputs, &replayed);
+    for line in &diff {
+        println!("{}", line);
+    }
+    anyhow::ensure!(
+        diff.is_empty(),
+        "replayed output, in {}, differs from the recording in {} messages",
+        config.out.display(),
+        diff.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use gossip_glomers::{Body, Event, Init, Node, Output};
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::Mutex as AsyncMutex;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Payload {
+        Ask,
+        AskOk { value: u64 },
+        Lookup,
+        LookupOk { value: u64 },
+    }
+
+    /// Answers each ask with the value of a lookup at `svc`, numbering its messages
+    /// from 100 so they never match the recording's.
+    struct LookupNode {
+        node: String,
+        id: AtomicUsize,
+        /// The asks waiting for a lookup, by the msg_id of the lookup.
+        asks: AsyncMutex<HashMap<usize, Message<Payload>>>,
+        stdout: AsyncMutex<Output>,
+    }
+
+    #[async_trait]
+    impl Node<Payload> for LookupNode {
+        fn from_init(
+            init: Init,
+            _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+            stdout: AsyncMutex<Output>,
+        ) -> anyhow::Result<Self> {
+            Ok(Self {
+                node: init.node_id,
+                id: 100.into(),
+                asks: AsyncMutex::default(),
+                stdout,
+            })
+        }
+
+        async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+            let Event::Message(message) = event else {
+                return Ok(());
+            };
+            match message.body.payload {
+                Payload::Ask => {
+                    let id = self.id.fetch_add(1, Ordering::Relaxed);
+                    self.asks.lock().await.insert(id, message);
+                    let lookup = Message {
+                        src: self.node.clone(),
+                        dest: "svc".to_string(),
+                        body: Body {
+                            id: Some(id),
+                            in_reply_to: None,
+                            payload: Payload::Lookup,
+                        },
+                    };
+                    lookup.send(&self.stdout).await
+                }
+                Payload::LookupOk { value } => {
+                    let Some(id) = message.body.in_reply_to else {
+                        return Ok(());
+                    };
+                    let Some(ask) = self.asks.lock().await.remove(&id) else {
+                        return Ok(());
+                    };
+                    let mut reply = ask.into_reply(Some(&self.id));
+                    reply.body.payload = Payload::AskOk { value };
+                    reply.send(&self.stdout).await
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn config() -> ReplayConfig {
+        ReplayConfig {
+            node: "echo".to_string(),
+            recording: PathBuf::from("n0.log"),
+            out: PathBuf::from("n0.log.replay"),
+            timing: false,
+            settle: Duration::from_millis(50),
+        }
+    }
+
+    async fn replay_lookup_node(recording: &Recording) -> Vec<String> {
+        let (input, node_input) = tokio::io::duplex(1 << 16);
+        let (node_output, output) = tokio::io::duplex(1 << 16);
+        tokio::spawn(gossip_glomers::run::<LookupNode, _, ()>(
+            node_input,
+            node_output,
+        ));
+        replay(recording, &config(), input, BufReader::new(output))
+            .await
+            .expect("replay")
+    }
+
+    const RECORDING: &str = r#"
+Received {"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}
+Sent {"src":"n0","dest":"c0","body":{"msg_id":0,"in_reply_to":1,"type":"init_ok"}}
+0.10 {"src":"c1","dest":"n0","body":{"type":"ask","msg_id":1}}
+{"src":"n0","dest":"svc","body":{"msg_id":1,"type":"lookup"}}
+0.15 {"src":"c1","dest":"n0","body":{"type":"ask","msg_id":2}}
+{"src":"n0","dest":"svc","body":{"msg_id":2,"type":"lookup"}}
+0.20 {"src":"svc","dest":"n0","body":{"type":"lookup_ok","in_reply_to":2,"value":20}}
+{"src":"n0","dest":"c1","body":{"msg_id":3,"in_reply_to":2,"type":"ask_ok","value":20}}
+0.25 {"src":"svc","dest":"n0","body":{"type":"lookup_ok","in_reply_to":1,"value":10}}
+{"src":"n0","dest":"c1","body":{"msg_id":4,"in_reply_to":1,"type":"ask_ok","value":10}}
+"#;
+
+    #[test]
+    fn parses_inputs_and_outputs_of_the_node() {
+        let recording = Recording::parse(RECORDING).expect("parse");
+        assert_eq!(recording.node, "n0");
+        assert_eq!(recording.inputs.len(), 5);
+        assert_eq!(recording.outputs.len(), 5);
+        assert_eq!(recording.inputs[0].at, None);
+        assert_eq!(recording.inputs[1].at, Some(Duration::from_millis(100)));
+        assert_eq!(
+            recording.request_positions()["svc"],
+            HashMap::from([(1, 0), (2, 1)])
+        );
+        assert!(Recording::parse("not a message\n").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_answer_the_requests_the_node_sends_now() {
+        let recording = Recording::parse(RECORDING).expect("parse");
+        let replayed = replay_lookup_node(&recording).await;
+        assert_eq!(replayed.len(), 5, "{:?}", replayed);
+        assert_eq!(diff(&recording.outputs, &replayed), Vec::<String>::new());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_what_the_node_sends_differently() {
+        let recording = Recording::parse(
+            &RECORDING.replace("\"ask_ok\",\"value\":10", "\"ask_ok\",\"value\":11"),
+        )
+        .expect("parse");
+        let replayed = replay_lookup_node(&recording).await;
+        let diff = diff(&recording.outputs, &replayed);
+        assert_eq!(diff.len(), 2, "{:?}", diff);
+        assert!(diff[0].starts_with("c1: - ") && diff[0].contains("\"value\":11"));
+        assert!(diff[1].starts_with("c1: + ") && diff[1].contains("\"value\":10"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timing_waits_as_long_as_the_recording() {
+        let recording = Recording::parse(RECORDING).expect("parse");
+        let (input, node_input) = tokio::io::duplex(1 << 16);
+        let (node_output, output) = tokio::io::duplex(1 << 16);
+        tokio::spawn(gossip_glomers::run::<LookupNode, _, ()>(
+            node_input,
+            node_output,
+        ));
+        let config = ReplayConfig {
+            timing: true,
+            ..config()
+        };
+        let started = tokio::time::Instant::now();
+        replay(&recording, &config, input, BufReader::new(output))
+            .await
+            .expect("replay");
+        // From the first timed input to the last, then the settle period.
+        assert!(started.elapsed() >= Duration::from_millis(150) + config.settle);
+    }
+
+    #[test]
+    fn diff_lines_keeps_the_common_lines_out() {
+        let lines = |lines: &[&str]| {
+            lines
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            diff_lines(&lines(&["a", "b", "c", "d"]), &lines(&["a", "c", "e", "d"])),
+            lines(&["- b", "+ e"])
+        );
+        assert!(diff_lines(&lines(&["a"]), &lines(&["a"])).is_empty());
+    }
+
+    #[test]
+    fn takes_its_flags() {
+        let args = |args: &[&str]| ReplayConfig::from_args(args.iter().map(|arg| arg.to_string()));
+        let config = args(&["--node", "echo", "--timing", "n0.log"]).expect("config");
+        assert_eq!(config.node, "echo");
+        assert!(config.timing);
+        assert_eq!(config.out, PathBuf::from("n0.log.replay"));
+        assert!(args(&["--node", "nope", "n0.log"]).is_err());
+        assert!(args(&["--node", "echo"]).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{sync::atomic::AtomicUsize, time::Duration};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop,
+    kv_service::{History, Payload},
+    Event, Init, Node, Output,
+};
+use tokio::{sync::Mutex, time::Instant};
+
+/// Default age of the writes a read may miss.
+const DEFAULT_STALENESS_MS: u64 = 500;
+
+#[derive(Debug, Clone)]
+struct SeqKVConfig {
+    staleness: Duration,
+}
+
+impl SeqKVConfig {
+    /// Reads the configuration from the environment:
+    /// - `SEQ_KV_STALENESS_MS`: how old a write can be and still be missed by a read,
+    ///   in milliseconds, or 0 to always read the latest values (default 500)
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            staleness: Duration::from_millis(gossip_glomers::env_or(
+                "SEQ_KV_STALENESS_MS",
+                DEFAULT_STALENESS_MS,
+            )?),
+        })
+    }
+}
+
+/// A sequentially consistent key/value store on a single node, standing in for
+/// Maelstrom's seq-kv service. Writes and cas apply to the latest values, but reads
+/// may be served from a version up to the staleness window old, so a read can miss
+/// writes other clients already saw acknowledged. Each client still sees its own
+/// writes and never goes back in time, and writing a fresh value before reading is
+/// enough to see every write acknowledged before it. Every version is kept, which is
+/// fine for the length of a test.
+struct SeqKVServerNode {
+    id: AtomicUsize,
+    history: Mutex<History>,
+    config: SeqKVConfig,
+    stdout: Mutex<Output>,
+}
+
+#[async_trait]
+impl Node<Payload> for SeqKVServerNode {
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = SeqKVConfig::from_env()?;
+        eprintln!("seq_kv_server config: {:?}", config);
+        Ok(Self {
+            id: 1.into(),
+            history: Mutex::default(),
+            config,
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        if message.body.payload.is_reply() {
+            return Ok(());
+        }
+        let mut reply = message.into_reply(Some(&self.id));
+        let mut history = self.history.lock().await;
+        let now = Instant::now();
+        reply.body.payload = match reply.body.payload {
+            Payload::Read { key } => history.read(&reply.dest, &key, now, self.config.staleness),
+            request => history.update(&reply.dest, request, now),
+        };
+        drop(history);
+        reply.send(&self.stdout).await.context("send reply")?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<SeqKVServerNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Harness};
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    async fn request(
+        node: &mut Harness<SeqKVServerNode, Payload>,
+        client: &str,
+        body: Value,
+    ) -> Payload {
+        let id = node.send_json(client, body).await;
+        node.expect_reply_to(id).await.body.payload
+    }
+
+    async fn read(node: &mut Harness<SeqKVServerNode, Payload>, client: &str) -> Value {
+        match request(node, client, json!({"type": "read", "key": "counter"})).await {
+            Payload::ReadOk { value } => value,
+            payload => panic!("expected read_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_reads_are_served_until_the_client_writes() {
+        let mut node = Harness::<SeqKVServerNode, Payload>::new("seq-kv", &["seq-kv"]).await;
+        let write = |value| json!({"type": "write", "key": "counter", "value": value});
+        request(&mut node, "n1", write(1)).await;
+        node.advance(Duration::from_millis(DEFAULT_STALENESS_MS * 2))
+            .await;
+        request(&mut node, "n1", write(5)).await;
+
+        // n2 has not seen anything yet, so it may be served the old value, while n1
+        // sees its own write.
+        assert_eq!(read(&mut node, "n2").await, json!(1));
+        assert_eq!(read(&mut node, "n1").await, json!(5));
+
+        // Writing a fresh value moves n2 to the latest version, as the g-counter does.
+        let nonce = json!({"type": "write", "key": "nonce-n2", "value": 1});
+        request(&mut node, "n2", nonce).await;
+        assert_eq!(read(&mut node, "n2").await, json!(5));
+
+        // Once the window has passed, everyone sees the write.
+        node.advance(Duration::from_millis(DEFAULT_STALENESS_MS * 2))
+            .await;
+        assert_eq!(read(&mut node, "n3").await, json!(5));
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(wire::service_payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("lin_kv");
+    }
+}
@@ -0,0 +1,602 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::AtomicUsize,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{event_loop, Body, Event, Init, Message, Node, Output};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Broadcast {
+        #[serde(rename = "message")]
+        msg: usize,
+    },
+    BroadcastOk,
+    Read,
+    /// The delivered messages, in the order every node delivers them.
+    ReadOk {
+        #[serde(rename = "messages")]
+        msgs: Vec<usize>,
+    },
+    Topology {
+        #[serde(rename = "topology")]
+        topo: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+    /// Asks the sequencer of `epoch` to give `entry` a sequence number.
+    Forward {
+        epoch: usize,
+        entry: Entry,
+    },
+    /// The entries at sequence numbers `start` and up, either freshly sequenced or
+    /// sent again to fill a gap.
+    Entries {
+        epoch: usize,
+        start: usize,
+        entries: Vec<Entry>,
+    },
+    /// Sent by the sequencer of `epoch` to show it is alive and that it sequenced
+    /// everything below `next`.
+    Heartbeat {
+        epoch: usize,
+        next: usize,
+    },
+    /// Asks for the entries at sequence numbers `from` and up.
+    Resend {
+        epoch: usize,
+        from: usize,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InjectedPayload {
+    Tick,
+}
+
+/// Default interval between two heartbeats of the sequencer.
+const DEFAULT_HEARTBEAT_MS: u64 = 100;
+
+/// Default time without a heartbeat after which the next node takes over sequencing.
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// A broadcast message, named by the node it was sent to and the order it arrived in
+/// there, so that the sequencer assigns it a single sequence number however many times
+/// it is forwarded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    origin: String,
+    serial: usize,
+    msg: usize,
+}
+
+#[derive(Debug, Clone)]
+struct TotalOrderConfig {
+    heartbeat: Duration,
+    timeout: Duration,
+}
+
+impl TotalOrderConfig {
+    /// Reads the configuration from the environment:
+    /// - `TOTAL_ORDER_HEARTBEAT_MS`: interval between heartbeats of the sequencer, and
+    ///   between retries of forwards and gap requests, in milliseconds (default 100)
+    /// - `TOTAL_ORDER_TIMEOUT_MS`: time without a heartbeat after which the next node
+    ///   takes over sequencing, in milliseconds (default 1000)
+    fn from_env() -> anyhow::Result<Self> {
+        let heartbeat_ms =
+            gossip_glomers::env_or("TOTAL_ORDER_HEARTBEAT_MS", DEFAULT_HEARTBEAT_MS)?;
+        let timeout_ms = gossip_glomers::env_or("TOTAL_ORDER_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)?;
+        if heartbeat_ms == 0 {
+            anyhow::bail!("TOTAL_ORDER_HEARTBEAT_MS must be greater than 0");
+        }
+        if timeout_ms <= heartbeat_ms {
+            anyhow::bail!(
+                "TOTAL_ORDER_TIMEOUT_MS ({}) must be greater than TOTAL_ORDER_HEARTBEAT_MS ({})",
+                timeout_ms,
+                heartbeat_ms
+            );
+        }
+        Ok(Self {
+            heartbeat: Duration::from_millis(heartbeat_ms),
+            timeout: Duration::from_millis(timeout_ms),
+        })
+    }
+}
+
+struct State {
+    /// Sequencing moves through the sorted nodes one epoch at a time, starting with
+    /// the lowest id.
+    epoch: usize,
+    /// The delivered entries, by sequence number.
+    log: Vec<Entry>,
+    /// Entries received ahead of a gap, by sequence number.
+    buffer: BTreeMap<usize, Entry>,
+    /// The (origin, serial) of every delivered entry.
+    delivered: HashSet<(String, usize)>,
+    /// The broadcasts received by this node and not delivered yet, with the reply
+    /// sent once they are.
+    pending: BTreeMap<usize, (Entry, Message<Payload>)>,
+    next_serial: usize,
+    /// When the sequencer of the current epoch was last heard from.
+    last_heard: Instant,
+    /// Until when a new sequencer collects the entries of its predecessor before
+    /// sequencing anything itself.
+    takeover_until: Option<Instant>,
+}
+
+/// Delivers broadcast messages in the same order on every node. The sequencer, the
+/// lowest node id at first, numbers every message and sends it to all nodes, which
+/// deliver messages strictly by sequence number and ask for the missing ones when they
+/// see a gap. Nodes that have not heard from the sequencer for a while move on to the
+/// next node, which first collects the entries the others have and it lacks.
+///
+/// Failover assumes the sequencer crashed: one that is only cut off keeps sequencing
+/// for whoever still reaches it, and the two sides can then disagree on the order,
+/// which only consensus (see `raft_kv`) would prevent.
+struct TotalOrderNode {
+    id: AtomicUsize,
+    node: String,
+    /// All nodes of the cluster, sorted.
+    nodes: Vec<String>,
+    config: TotalOrderConfig,
+    state: Mutex<State>,
+    stdout: Mutex<Output>,
+}
+
+impl TotalOrderNode {
+    fn sequencer(&self, epoch: usize) -> &str {
+        &self.nodes[epoch % self.nodes.len()]
+    }
+
+    fn message(&self, dest: &str, payload: Payload) -> Message<Payload> {
+        Message {
+            src: self.node.clone(),
+            dest: dest.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
+    /// Sends `payload` to every other node.
+    fn to_all(&self, payload: Payload, out: &mut Vec<Message<Payload>>) {
+        for node in self.nodes.iter().filter(|node| **node != self.node) {
+            out.push(self.message(node, payload.clone()));
+        }
+    }
+
+    /// Moves to `epoch` if it is newer than the current one, so that the nodes agree
+    /// on the sequencer as soon as one of them moved on.
+    fn adopt(&self, state: &mut State, epoch: usize, now: Instant) {
+        if epoch <= state.epoch {
+            return;
+        }
+        state.epoch = epoch;
+        state.last_heard = now;
+        state.takeover_until = if self.sequencer(epoch) == self.node {
+            Some(now + self.config.timeout)
+        } else {
+            None
+        };
+    }
+
+    /// Gives `entry` the next sequence number and sends it to all nodes, unless it
+    /// already has one.
+    fn sequence(&self, state: &mut State, entry: Entry, out: &mut Vec<Message<Payload>>) {
+        if state
+            .delivered
+            .contains(&(entry.origin.clone(), entry.serial))
+        {
+            return;
+        }
+        let start = state.log.len();
+        self.to_all(
+            Payload::Entries {
+                epoch: state.epoch,
+                start,
+                entries: vec![entry.clone()],
+            },
+            out,
+        );
+        self.insert(state, start, entry, out);
+    }
+
+    /// Stores the entry at sequence number `seq` and delivers everything that follows
+    /// the log without a gap.
+    fn insert(&self, state: &mut State, seq: usize, entry: Entry, out: &mut Vec<Message<Payload>>) {
+        if seq < state.log.len() {
+            return;
+        }
+        state.buffer.entry(seq).or_insert(entry);
+        while let Some(entry) = state.buffer.remove(&state.log.len()) {
+            if entry.origin == self.node {
+                if let Some((_, reply)) = state.pending.remove(&entry.serial) {
+                    out.push(reply);
+                }
+            }
+            state.delivered.insert((entry.origin.clone(), entry.serial));
+            state.log.push(entry);
+        }
+    }
+
+    /// Forwards the pending broadcasts to the sequencer, or sequences them if this
+    /// node is the sequencer and done taking over.
+    fn flush_pending(&self, state: &mut State, out: &mut Vec<Message<Payload>>) {
+        let entries: Vec<Entry> = state
+            .pending
+            .values()
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        let sequencer = self.sequencer(state.epoch);
+        for entry in entries {
+            if sequencer != self.node {
+                out.push(self.message(
+                    sequencer,
+                    Payload::Forward {
+                        epoch: state.epoch,
+                        entry,
+                    },
+                ));
+            } else if state.takeover_until.is_none() {
+                self.sequence(state, entry, out);
+            }
+        }
+    }
+
+    fn tick(&self, state: &mut State, now: Instant, out: &mut Vec<Message<Payload>>) {
+        if self.sequencer(state.epoch) == self.node {
+            if state.takeover_until.is_some_and(|until| now >= until) {
+                state.takeover_until = None;
+                // Nobody answered for the gaps, so whatever follows them is given up
+                // rather than numbered twice.
+                state.buffer.clear();
+            }
+            // Nodes ahead of a new sequencer answer the heartbeat with what it lacks.
+            self.to_all(
+                Payload::Heartbeat {
+                    epoch: state.epoch,
+                    next: state.log.len(),
+                },
+                out,
+            );
+        } else if now.duration_since(state.last_heard) > self.config.timeout {
+            let epoch = state.epoch + 1;
+            eprintln!(
+                "no heartbeat from {}, moving to {}",
+                self.sequencer(state.epoch),
+                self.sequencer(epoch)
+            );
+            self.adopt(state, epoch, now);
+        } else if !state.buffer.is_empty() {
+            out.push(self.message(
+                self.sequencer(state.epoch),
+                Payload::Resend {
+                    epoch: state.epoch,
+                    from: state.log.len(),
+                },
+            ));
+        }
+        self.flush_pending(state, out);
+    }
+
+    fn receive(
+        &self,
+        state: &mut State,
+        now: Instant,
+        message: Message<Payload>,
+        out: &mut Vec<Message<Payload>>,
+    ) {
+        let src = message.src.clone();
+        let mut reply = message.into_reply(Some(&self.id));
+        match reply.body.payload {
+            Payload::Broadcast { msg } => {
+                let entry = Entry {
+                    origin: self.node.clone(),
+                    serial: state.next_serial,
+                    msg,
+                };
+                state.next_serial += 1;
+                reply.body.payload = Payload::BroadcastOk;
+                state.pending.insert(entry.serial, (entry.clone(), reply));
+                let sequencer = self.sequencer(state.epoch);
+                if sequencer != self.node {
+                    out.push(self.message(
+                        sequencer,
+                        Payload::Forward {
+                            epoch: state.epoch,
+                            entry,
+                        },
+                    ));
+                } else if state.takeover_until.is_none() {
+                    self.sequence(state, entry, out);
+                }
+            }
+            Payload::Read => {
+                reply.body.payload = Payload::ReadOk {
+                    msgs: state.log.iter().map(|entry| entry.msg).collect(),
+                };
+                out.push(reply);
+            }
+            // Every node sends to every other node directly.
+            Payload::Topology { .. } => {
+                reply.body.payload = Payload::TopologyOk;
+                out.push(reply);
+            }
+            Payload::Forward { epoch, entry } => {
+                self.adopt(state, epoch, now);
+                // Forwards that are too early or for another sequencer come back with
+                // the next retry.
+                if self.sequencer(state.epoch) == self.node && state.takeover_until.is_none() {
+                    self.sequence(state, entry, out);
+                }
+            }
+            Payload::Entries {
+                epoch,
+                start,
+                entries,
+            } => {
+                // Entries of an earlier sequencer may conflict with the current one.
+                if epoch < state.epoch {
+                    return;
+                }
+                self.adopt(state, epoch, now);
+                if src == self.sequencer(epoch) {
+                    state.last_heard = now;
+                }
+                for (offset, entry) in entries.into_iter().enumerate() {
+                    self.insert(state, start + offset, entry, out);
+                }
+            }
+            Payload::Heartbeat { epoch, next } => {
+                if epoch < state.epoch {
+                    return;
+                }
+                self.adopt(state, epoch, now);
+                state.last_heard = now;
+                if next > state.log.len() {
+                    out.push(self.message(
+                        &src,
+                        Payload::Resend {
+                            epoch,
+                            from: state.log.len(),
+                        },
+                    ));
+                } else if next < state.log.len() {
+                    out.push(self.message(
+                        &src,
+                        Payload::Entries {
+                            epoch,
+                            start: next,
+                            entries: state.log[next..].to_vec(),
+                        },
+                    ));
+                }
+            }
+            Payload::Resend { epoch, from } => {
+                self.adopt(state, epoch, now);
+                if from < state.log.len() {
+                    out.push(self.message(
+                        &src,
+                        Payload::Entries {
+                            epoch: state.epoch,
+                            start: from,
+                            entries: state.log[from..].to_vec(),
+                        },
+                    ));
+                }
+            }
+            Payload::BroadcastOk | Payload::ReadOk { .. } | Payload::TopologyOk => {}
+        }
+    }
+
+    async fn send(&self, out: Vec<Message<Payload>>) -> anyhow::Result<()> {
+        for message in out {
+            message.send(&self.stdout).await.context("send message")?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for TotalOrderNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = TotalOrderConfig::from_env()?;
+        eprintln!("total_order config: {:?}", config);
+        gossip_glomers::spawn_timer(tx, config.heartbeat, InjectedPayload::Tick);
+
+        let mut nodes = init.node_ids;
+        nodes.sort_unstable();
+        nodes.dedup();
+        Ok(Self {
+            id: 1.into(),
+            node: init.node_id,
+            nodes,
+            config,
+            state: Mutex::new(State {
+                epoch: 0,
+                log: Vec::new(),
+                buffer: BTreeMap::new(),
+                delivered: HashSet::new(),
+                pending: BTreeMap::new(),
+                next_serial: 0,
+                last_heard: gossip_glomers::now(),
+                takeover_until: None,
+            }),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        let mut out = Vec::new();
+        let mut state = self.state.lock().await;
+        match event {
+            // The node keeps no stats to report.
+            Event::EOF => {}
+            Event::Injected(InjectedPayload::Tick) => {
+                self.tick(&mut state, gossip_glomers::now(), &mut out)
+            }
+            Event::Message(message) => {
+                self.receive(&mut state, gossip_glomers::now(), message, &mut out)
+            }
+        }
+        drop(state);
+        self.send(out).await
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<TotalOrderNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Cluster};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const NODES: [&str; 5] = ["n0", "n1", "n2", "n3", "n4"];
+
+    async fn cluster(seed: u64) -> Cluster {
+        Cluster::builder()
+            .nodes::<TotalOrderNode, Payload, InjectedPayload>(&NODES)
+            .seed(seed)
+            .start()
+            .await
+    }
+
+    /// Broadcasts `msgs` at the nodes in turn, all at once, and waits until every one
+    /// of them is delivered where it was sent.
+    async fn broadcast_all(cluster: &mut Cluster, nodes: &[&str], msgs: std::ops::Range<usize>) {
+        let ids: Vec<_> = msgs
+            .map(|msg| cluster.send("c1", nodes[msg % nodes.len()], Payload::Broadcast { msg }))
+            .collect();
+        for id in ids {
+            let reply = cluster.expect_reply_to::<Payload>(id).await;
+            assert!(matches!(reply.body.payload, Payload::BroadcastOk));
+        }
+    }
+
+    async fn read(cluster: &mut Cluster, node: &str) -> Vec<usize> {
+        let id = cluster.send("c1", node, Payload::Read);
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            Payload::ReadOk { msgs } => msgs,
+            payload => panic!("expected read_ok, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nodes_deliver_the_same_order_despite_reordering_and_loss() {
+        for seed in 0..5 {
+            let mut cluster = cluster(seed).await;
+            cluster.set_drop_rate(0.2);
+            cluster.set_jitter(Duration::from_millis(50));
+            broadcast_all(&mut cluster, &NODES, 0..20).await;
+            // Reads along the way see prefixes of the same order.
+            let mut reads = Vec::new();
+            for node in NODES {
+                reads.push(read(&mut cluster, node).await);
+            }
+            cluster.set_drop_rate(0.0);
+            tokio::time::sleep(Duration::from_millis(DEFAULT_HEARTBEAT_MS * 5)).await;
+            let order = read(&mut cluster, "n0").await;
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..20).collect::<Vec<_>>(), "seed {}", seed);
+            for node in NODES {
+                assert_eq!(read(&mut cluster, node).await, order, "seed {}", seed);
+            }
+            for prefix in reads {
+                assert_eq!(prefix, order[..prefix.len()], "seed {}", seed);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_next_node_takes_over_from_a_crashed_sequencer() {
+        let mut cluster = cluster(0).await;
+        broadcast_all(&mut cluster, &NODES, 0..5).await;
+        tokio::time::sleep(Duration::from_millis(DEFAULT_HEARTBEAT_MS * 2)).await;
+        cluster.partition(&["n0"], &["n1", "n2", "n3", "n4"]);
+
+        broadcast_all(&mut cluster, &NODES[1..], 5..10).await;
+        tokio::time::sleep(Duration::from_millis(DEFAULT_HEARTBEAT_MS * 5)).await;
+        let order = read(&mut cluster, "n1").await;
+        assert_eq!(order[..5], [0, 1, 2, 3, 4]);
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+        for node in &NODES[2..] {
+            assert_eq!(read(&mut cluster, node).await, order);
+        }
+    }
+
+    fn entry() -> impl Strategy<Value = Entry> {
+        (wire::node_id(), any::<usize>(), any::<usize>()).prop_map(|(origin, serial, msg)| Entry {
+            origin,
+            serial,
+            msg,
+        })
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        prop_oneof![
+            any::<usize>().prop_map(|msg| Payload::Broadcast { msg }),
+            Just(Payload::BroadcastOk),
+            Just(Payload::Read),
+            prop::collection::vec(any::<usize>(), 0..8).prop_map(|msgs| Payload::ReadOk { msgs }),
+            prop::collection::hash_map(
+                wire::node_id(),
+                prop::collection::vec(wire::node_id(), 0..4),
+                0..4
+            )
+            .prop_map(|topo| Payload::Topology { topo }),
+            Just(Payload::TopologyOk),
+            (any::<usize>(), entry()).prop_map(|(epoch, entry)| Payload::Forward { epoch, entry }),
+            (
+                any::<usize>(),
+                any::<usize>(),
+                prop::collection::vec(entry(), 0..4)
+            )
+                .prop_map(|(epoch, start, entries)| Payload::Entries {
+                    epoch,
+                    start,
+                    entries
+                }),
+            (any::<usize>(), any::<usize>())
+                .prop_map(|(epoch, next)| Payload::Heartbeat { epoch, next }),
+            (any::<usize>(), any::<usize>())
+                .prop_map(|(epoch, from)| Payload::Resend { epoch, from }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("broadcast");
+    }
+}
@@ -0,0 +1,880 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop, rpc::Rpc, sharding, Body, ErrorCode, Event, Init, MaelstromError, Message, Node,
+    Output,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::{Mutex, Notify};
+
+/// A single micro-operation of a txn-rw-register txn. On the wire it is an array:
+/// `["r", key, value]` with the value read, null in requests and for missing keys, or
+/// `["w", key, value]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op {
+    Read { key: u64, value: Option<i64> },
+    Write { key: u64, value: i64 },
+}
+
+impl Op {
+    fn key(&self) -> u64 {
+        match self {
+            Op::Read { key, .. } | Op::Write { key, .. } => *key,
+        }
+    }
+}
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Read { key, value } => ("r", key, value).serialize(serializer),
+            Self::Write { key, value } => ("w", key, value).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (op, key, value) = <(String, u64, serde_json::Value)>::deserialize(deserializer)?;
+        match op.as_str() {
+            "r" => serde_json::from_value(value)
+                .map(|value| Self::Read { key, value })
+                .map_err(de::Error::custom),
+            "w" => serde_json::from_value(value)
+                .map(|value| Self::Write { key, value })
+                .map_err(de::Error::custom),
+            op => Err(de::Error::unknown_variant(op, &["r", "w"])),
+        }
+    }
+}
+
+/// Identifies a txn: the node coordinating it and a number unique on that node.
+type TxnId = (String, u64);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+    /// Runs the whole txn on the one shard it touches, answered with ExecuteOk.
+    Execute {
+        txn_id: TxnId,
+        txn: Vec<Op>,
+    },
+    ExecuteOk {
+        txn: Vec<Op>,
+    },
+    /// Asks a shard to lock the keys of its part of a txn and vote. A yes vote is a
+    /// PrepareOk with what the part read, a no vote an error.
+    Prepare {
+        txn_id: TxnId,
+        txn: Vec<Op>,
+    },
+    PrepareOk {
+        txn: Vec<Op>,
+    },
+    /// The decision on a prepared txn, sent until the shard acknowledges it.
+    Commit {
+        txn_id: TxnId,
+    },
+    Abort {
+        txn_id: TxnId,
+    },
+    DecisionOk,
+}
+
+/// Default time a shard waits for the locks of a txn before it votes no.
+const DEFAULT_LOCK_TIMEOUT_MS: u64 = 200;
+
+/// Default time a coordinator waits for a vote before it aborts the txn.
+const DEFAULT_PREPARE_TIMEOUT_MS: u64 = 1000;
+
+/// Default time a coordinator waits for a shard to acknowledge a decision before it
+/// sends it again.
+const DEFAULT_DECISION_RETRY_MS: u64 = 200;
+
+#[derive(Debug, Clone)]
+struct TpcTxnConfig {
+    lock_timeout: Duration,
+    prepare_timeout: Duration,
+    decision_retry: Duration,
+}
+
+impl TpcTxnConfig {
+    /// Reads the configuration from the environment:
+    /// - `TPC_TXN_LOCK_TIMEOUT_MS`: time a shard waits for the locks of a txn before it
+    ///   votes to abort, which also breaks deadlocks, in milliseconds (default 200)
+    /// - `TPC_TXN_PREPARE_TIMEOUT_MS`: time the coordinator waits for a vote, or for
+    ///   the answer to a single-shard txn, in milliseconds (default 1000)
+    /// - `TPC_TXN_DECISION_RETRY_MS`: time the coordinator waits for a shard to
+    ///   acknowledge a commit or abort before sending it again in milliseconds
+    ///   (default 200)
+    fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            lock_timeout: Duration::from_millis(gossip_glomers::env_or(
+                "TPC_TXN_LOCK_TIMEOUT_MS",
+                DEFAULT_LOCK_TIMEOUT_MS,
+            )?),
+            prepare_timeout: Duration::from_millis(gossip_glomers::env_or(
+                "TPC_TXN_PREPARE_TIMEOUT_MS",
+                DEFAULT_PREPARE_TIMEOUT_MS,
+            )?),
+            decision_retry: Duration::from_millis(gossip_glomers::env_or(
+                "TPC_TXN_DECISION_RETRY_MS",
+                DEFAULT_DECISION_RETRY_MS,
+            )?),
+        };
+        if config.prepare_timeout.is_zero() || config.decision_retry.is_zero() {
+            anyhow::bail!(
+                "TPC_TXN_PREPARE_TIMEOUT_MS and TPC_TXN_DECISION_RETRY_MS must be greater than 0"
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// Where a txn stands on a shard.
+enum Phase {
+    /// Voted yes: the keys are locked and the writes wait for the decision.
+    Prepared {
+        keys: Vec<u64>,
+        writes: Vec<(u64, i64)>,
+        reads: Vec<Op>,
+    },
+    Committed {
+        reads: Vec<Op>,
+    },
+    /// Voted no, or told to abort. Kept so a prepare that arrives late votes no.
+    Aborted,
+}
+
+/// The keys this node owns, with the locks and phases of the txns touching them. Every
+/// txn id is kept for the length of a test, so a message sent again finds the same
+/// answer as the first time.
+#[derive(Default)]
+struct Shard {
+    store: HashMap<u64, i64>,
+    /// The txn holding every locked key.
+    locks: HashMap<u64, TxnId>,
+    txns: HashMap<TxnId, Phase>,
+}
+
+impl Shard {
+    /// Locks all of `keys` for `txn_id` if none is locked by another txn, else none.
+    fn try_lock(&mut self, txn_id: &TxnId, keys: &[u64]) -> bool {
+        if keys
+            .iter()
+            .any(|key| self.locks.get(key).is_some_and(|holder| holder != txn_id))
+        {
+            return false;
+        }
+        for key in keys {
+            self.locks.insert(*key, txn_id.clone());
+        }
+        true
+    }
+
+    fn unlock(&mut self, txn_id: &TxnId, keys: &[u64]) {
+        for key in keys {
+            if self.locks.get(key) == Some(txn_id) {
+                self.locks.remove(key);
+            }
+        }
+    }
+
+    /// Runs `txn` against the store without changing it. Returns the ops with the
+    /// values read, which see the earlier writes of the txn, and the writes to apply.
+    fn execute(&self, txn: &[Op]) -> (Vec<Op>, Vec<(u64, i64)>) {
+        let mut written: BTreeMap<u64, i64> = BTreeMap::new();
+        let mut done = Vec::with_capacity(txn.len());
+        for op in txn {
+            match op {
+                Op::Read { key, .. } => done.push(Op::Read {
+                    key: *key,
+                    value: written.get(key).or_else(|| self.store.get(key)).copied(),
+                }),
+                Op::Write { key, value } => {
+                    written.insert(*key, *value);
+                    done.push(op.clone());
+                }
+            }
+        }
+        (done, written.into_iter().collect())
+    }
+}
+
+/// A txn-rw-register store whose keys are sharded over the nodes by rendezvous
+/// hashing. The node a txn arrives at coordinates it. A txn that only touches keys of
+/// one shard runs there in one step, under the locks of its keys. Any other txn runs
+/// two-phase commit: every shard it touches locks the keys, runs its part and votes,
+/// and the coordinator commits only if all voted yes in time, otherwise it aborts and
+/// the client gets a txn-conflict error.
+///
+/// Shards hold locks from prepare until the decision, so txns are serializable.
+/// Waiting for a lock is bounded by a timeout, after which the shard votes no, which
+/// also breaks deadlocks. Decisions are sent until every shard acknowledges them, and
+/// every message is answered the same way when it comes again. A coordinator that
+/// crashes between prepare and decision leaves its locks held, as shards never ask
+/// what became of a txn.
+struct TpcTxnNode {
+    id: AtomicUsize,
+    node: String,
+    node_ids: Vec<String>,
+    config: TpcTxnConfig,
+    shard: Mutex<Shard>,
+    /// Woken whenever locks are released.
+    released: Notify,
+    next_txn: AtomicU64,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+impl TpcTxnNode {
+    /// Sends `payload` to `to` and returns the answer, or the error it carried.
+    async fn rpc(&self, to: &str, payload: Payload, timeout: Duration) -> anyhow::Result<Payload> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::Relaxed)),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        match self
+            .rpc
+            .call(msg, timeout, &self.stdout)
+            .await?
+            .body
+            .payload
+        {
+            Payload::Error { code, text } => Err(MaelstromError::from_code(code, text).into()),
+            payload => Ok(payload),
+        }
+    }
+
+    /// Waits until `keys` are all locked for `txn_id`. Returns false if that took longer
+    /// than the lock timeout.
+    async fn lock(&self, txn_id: &TxnId, keys: &[u64]) -> bool {
+        let deadline = tokio::time::Instant::now() + self.config.lock_timeout;
+        loop {
+            // Registered before the locks are checked, so a release right after the
+            // check still wakes this waiter.
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if self.shard.lock().await.try_lock(txn_id, keys) {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Runs a single-shard txn under the locks of its keys and applies it. Returns None
+    /// if the locks did not come in time.
+    async fn execute(&self, txn_id: &TxnId, txn: &[Op]) -> Option<Vec<Op>> {
+        let keys = keys(txn);
+        if !self.lock(txn_id, &keys).await {
+            return None;
+        }
+        let mut shard = self.shard.lock().await;
+        let (done, writes) = shard.execute(txn);
+        shard.store.extend(writes);
+        shard.unlock(txn_id, &keys);
+        drop(shard);
+        self.released.notify_waiters();
+        Some(done)
+    }
+
+    /// Locks the keys of this shard's part of a txn and runs it. Returns the vote:
+    /// what the part read if yes, None if no.
+    async fn prepare(&self, txn_id: &TxnId, txn: &[Op]) -> Option<Vec<Op>> {
+        if let Some(vote) = self.vote(txn_id).await {
+            return vote;
+        }
+        let keys = keys(txn);
+        if !self.lock(txn_id, &keys).await {
+            self.shard
+                .lock()
+                .await
+                .txns
+                .entry(txn_id.clone())
+                .or_insert(Phase::Aborted);
+            return self.vote(txn_id).await.flatten();
+        }
+        let mut shard = self.shard.lock().await;
+        // An abort or another copy of this prepare may have come in while it waited. A
+        // txn holds its own locks, so this copy got them even if the other one did: they
+        // are only its to keep if the txn is still prepared.
+        match shard.txns.get(txn_id) {
+            Some(Phase::Prepared { reads, .. }) => return Some(reads.clone()),
+            Some(phase) => {
+                let vote = match phase {
+                    Phase::Committed { reads } => Some(reads.clone()),
+                    _ => None,
+                };
+                shard.unlock(txn_id, &keys);
+                drop(shard);
+                self.released.notify_waiters();
+                return vote;
+            }
+            None => {}
+        }
+        let (reads, writes) = shard.execute(txn);
+        shard.txns.insert(
+            txn_id.clone(),
+            Phase::Prepared {
+                keys,
+                writes,
+                reads: reads.clone(),
+            },
+        );
+        Some(reads)
+    }
+
+    /// Returns the vote already cast on `txn_id`, or None if there is none yet.
+    async fn vote(&self, txn_id: &TxnId) -> Option<Option<Vec<Op>>> {
+        match self.shard.lock().await.txns.get(txn_id)? {
+            Phase::Prepared { reads, .. } | Phase::Committed { reads } => Some(Some(reads.clone())),
+            Phase::Aborted => Some(None),
+        }
+    }
+
+    /// Applies the decision on `txn_id` to this shard. Deciding a txn again changes
+    /// nothing.
+    async fn decide(&self, txn_id: &TxnId, commit: bool) {
+        let mut shard = self.shard.lock().await;
+        let phase = shard.txns.remove(txn_id);
+        let phase = match (phase, commit) {
+            (
+                Some(Phase::Prepared {
+                    keys,
+                    writes,
+                    reads,
+                }),
+                true,
+            ) => {
+                shard.store.extend(writes);
+                shard.unlock(txn_id, &keys);
+                Phase::Committed { reads }
+            }
+            (Some(Phase::Prepared { keys, .. }), false) => {
+                shard.unlock(txn_id, &keys);
+                Phase::Aborted
+            }
+            (Some(phase @ Phase::Committed { .. }), _) | (Some(phase @ Phase::Aborted), _) => {
+                if matches!(phase, Phase::Committed { .. }) != commit {
+                    eprintln!("txn {:?} decided both ways", txn_id);
+                }
+                phase
+            }
+            // Aborted before it was prepared here: the prepare may still come, and
+            // must vote no. A local prepare the coordinator gave up on may have taken
+            // locks without recording the txn.
+            (None, _) => {
+                if commit {
+                    eprintln!("txn {:?} committed without being prepared", txn_id);
+                }
+                shard.locks.retain(|_, holder| holder != txn_id);
+                Phase::Aborted
+            }
+        };
+        shard.txns.insert(txn_id.clone(), phase);
+        drop(shard);
+        self.released.notify_waiters();
+    }
+
+    /// Prepares the part of a txn on `shard`, here or on another node. A vote that does
+    /// not come in time counts as no.
+    async fn prepare_on(&self, shard: &str, txn_id: &TxnId, txn: Vec<Op>) -> Option<Vec<Op>> {
+        if shard == self.node {
+            return self.prepare(txn_id, &txn).await;
+        }
+        let payload = Payload::Prepare {
+            txn_id: txn_id.clone(),
+            txn,
+        };
+        match self.rpc(shard, payload, self.config.prepare_timeout).await {
+            std::result::Result::Ok(Payload::PrepareOk { txn }) => Some(txn),
+            std::result::Result::Ok(payload) => {
+                eprintln!("unexpected answer to prepare: {:?}", payload);
+                None
+            }
+            Err(err) => {
+                eprintln!("prepare {:?} on {}: {:#}", txn_id, shard, err);
+                None
+            }
+        }
+    }
+
+    /// Tells `shard` the decision on a txn, until it acknowledges it.
+    async fn decide_on(&self, shard: &str, txn_id: &TxnId, commit: bool) {
+        if shard == self.node {
+            return self.decide(txn_id, commit).await;
+        }
+        loop {
+            let payload = match commit {
+                true => Payload::Commit {
+                    txn_id: txn_id.clone(),
+                },
+                false => Payload::Abort {
+                    txn_id: txn_id.clone(),
+                },
+            };
+            match self.rpc(shard, payload, self.config.decision_retry).await {
+                std::result::Result::Ok(_) => return,
+                Err(err) => eprintln!("decide {:?} on {}: {:#}", txn_id, shard, err),
+            }
+        }
+    }
+
+    /// Coordinates a client txn and returns its ops with the values read.
+    async fn transact(&self, txn: Vec<Op>) -> Result<Vec<Op>, MaelstromError> {
+        let txn_id = (
+            self.node.clone(),
+            self.next_txn.fetch_add(1, Ordering::Relaxed),
+        );
+        // The ops of every shard in txn order, with their index in the txn.
+        let mut parts: BTreeMap<&str, Vec<(usize, Op)>> = BTreeMap::new();
+        for (index, op) in txn.iter().enumerate() {
+            let shard = sharding::owner(&op.key().to_string(), &self.node_ids);
+            parts.entry(shard).or_default().push((index, op.clone()));
+        }
+        let conflict = || {
+            MaelstromError::new(
+                ErrorCode::TxnConflict,
+                format!("txn {:?} aborted: keys locked by other txns", txn_id),
+            )
+        };
+
+        if parts.len() <= 1 {
+            let Some(shard) = parts.keys().next() else {
+                return std::result::Result::Ok(txn);
+            };
+            if *shard == self.node {
+                return self.execute(&txn_id, &txn).await.ok_or_else(conflict);
+            }
+            let payload = Payload::Execute {
+                txn_id: txn_id.clone(),
+                txn,
+            };
+            return match self.rpc(shard, payload, self.config.prepare_timeout).await {
+                std::result::Result::Ok(Payload::ExecuteOk { txn }) => std::result::Result::Ok(txn),
+                std::result::Result::Ok(payload) => Err(MaelstromError::new(
+                    ErrorCode::Crash,
+                    format!("unexpected answer to execute: {:?}", payload),
+                )),
+                Err(err) => Err(err.downcast::<MaelstromError>().unwrap_or_else(|err| {
+                    // No answer: the txn may or may not have run.
+                    MaelstromError::new(ErrorCode::Crash, format!("{:#}", err))
+                })),
+            };
+        }
+
+        // The first no vote decides, so the other shards release their locks without
+        // waiting for the rest of the votes.
+        let votes = futures::future::try_join_all(parts.iter().map(|(shard, ops)| {
+            let ops = ops.iter().map(|(_, op)| op.clone()).collect();
+            let vote = self.prepare_on(shard, &txn_id, ops);
+            async move { vote.await.ok_or(()) }
+        }))
+        .await;
+        let commit = votes.is_ok();
+        futures::future::join_all(
+            parts
+                .keys()
+                .map(|shard| self.decide_on(shard, &txn_id, commit)),
+        )
+        .await;
+        if !commit {
+            return Err(conflict());
+        }
+        let mut done = txn;
+        for ((_, ops), reads) in parts.iter().zip(votes.expect("all voted yes")) {
+            for ((index, _), op) in ops.iter().zip(reads) {
+                done[*index] = op;
+            }
+        }
+        std::result::Result::Ok(done)
+    }
+}
+
+/// Returns the keys `txn` touches, each once.
+fn keys(txn: &[Op]) -> Vec<u64> {
+    let mut keys: Vec<u64> = txn.iter().map(Op::key).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys
+}
+
+#[async_trait]
+impl Node<Payload> for TpcTxnNode {
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = TpcTxnConfig::from_env()?;
+        eprintln!("tpc_txn config: {:?}", config);
+        Ok(Self {
+            id: 1.into(),
+            node: init.node_id,
+            node_ids: init.node_ids,
+            config,
+            shard: Mutex::default(),
+            released: Notify::new(),
+            next_txn: 0.into(),
+            rpc: Rpc::new(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        // Votes and acknowledgements go to the coordinator waiting for them.
+        let Some(message) = self.rpc.resolve(message).await else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(&self.id));
+        reply.body.payload = match reply.body.payload {
+            Payload::Txn { txn } => match self.transact(txn).await {
+                std::result::Result::Ok(txn) => Payload::TxnOk { txn },
+                Err(err) => Payload::Error {
+                    code: err.code.code(),
+                    text: err.text,
+                },
+            },
+            Payload::Execute { txn_id, txn } => match self.execute(&txn_id, &txn).await {
+                Some(txn) => Payload::ExecuteOk { txn },
+                None => Payload::Error {
+                    code: ErrorCode::TxnConflict.code(),
+                    text: format!("txn {:?}: keys locked by other txns", txn_id),
+                },
+            },
+            Payload::Prepare { txn_id, txn } => match self.prepare(&txn_id, &txn).await {
+                Some(txn) => Payload::PrepareOk { txn },
+                None => Payload::Error {
+                    code: ErrorCode::TxnConflict.code(),
+                    text: format!("txn {:?} votes no", txn_id),
+                },
+            },
+            Payload::Commit { txn_id } => {
+                self.decide(&txn_id, true).await;
+                Payload::DecisionOk
+            }
+            Payload::Abort { txn_id } => {
+                self.decide(&txn_id, false).await;
+                Payload::DecisionOk
+            }
+            Payload::TxnOk { .. }
+            | Payload::ExecuteOk { .. }
+            | Payload::PrepareOk { .. }
+            | Payload::DecisionOk
+            | Payload::Error { .. } => return Ok(()),
+        };
+        reply.send(&self.stdout).await.context("send reply")?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<TpcTxnNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{wire, Cluster};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    async fn cluster() -> Cluster {
+        Cluster::builder()
+            .nodes::<TpcTxnNode, Payload, ()>(&NODES)
+            .start()
+            .await
+    }
+
+    /// The first key owned by each node, in the order of NODES.
+    fn keys_by_owner() -> [u64; 3] {
+        let node_ids: Vec<String> = NODES.iter().map(|node| node.to_string()).collect();
+        NODES.map(|node| {
+            (0..)
+                .find(|key: &u64| sharding::owner(&key.to_string(), &node_ids) == node)
+                .expect("every node owns a key")
+        })
+    }
+
+    async fn request(cluster: &mut Cluster, node: &str, payload: Payload) -> Payload {
+        let id = cluster.send("c1", node, payload);
+        cluster.expect_reply_to::<Payload>(id).await.body.payload
+    }
+
+    async fn transact(cluster: &mut Cluster, node: &str, txn: Vec<Op>) -> Result<Vec<Op>, usize> {
+        match request(cluster, node, Payload::Txn { txn }).await {
+            Payload::TxnOk { txn } => std::result::Result::Ok(txn),
+            Payload::Error { code, .. } => Err(code),
+            payload => panic!("expected txn_ok or an error, got {:?}", payload),
+        }
+    }
+
+    fn read(key: u64) -> Op {
+        Op::Read { key, value: None }
+    }
+
+    fn write(key: u64, value: i64) -> Op {
+        Op::Write { key, value }
+    }
+
+    /// How many messages of type `kind` went between nodes.
+    fn sent(cluster: &Cluster, kind: &str) -> usize {
+        let kind = format!(r#""type":"{}""#, kind);
+        cluster
+            .trace()
+            .iter()
+            .filter(|delivery| delivery.line.contains(&kind))
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn txns_across_shards_commit_whole() {
+        let mut cluster = cluster().await;
+        let [a, b, c] = keys_by_owner();
+        let txn = vec![write(b, 1), write(c, 2), read(b)];
+        assert_eq!(
+            transact(&mut cluster, "n0", txn).await,
+            std::result::Result::Ok(vec![
+                write(b, 1),
+                write(c, 2),
+                Op::Read {
+                    key: b,
+                    value: Some(1)
+                }
+            ])
+        );
+        assert_eq!(sent(&cluster, "prepare"), 2);
+        assert_eq!(sent(&cluster, "commit"), 2);
+        let reads = transact(&mut cluster, "n1", vec![read(a), read(b), read(c)]).await;
+        assert_eq!(
+            reads,
+            std::result::Result::Ok(vec![
+                Op::Read {
+                    key: a,
+                    value: None
+                },
+                Op::Read {
+                    key: b,
+                    value: Some(1)
+                },
+                Op::Read {
+                    key: c,
+                    value: Some(2)
+                },
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn single_shard_txns_take_one_step() {
+        let mut cluster = cluster().await;
+        let [_, b, _] = keys_by_owner();
+        assert!(transact(&mut cluster, "n0", vec![write(b, 1), read(b)])
+            .await
+            .is_ok());
+        assert_eq!(sent(&cluster, "execute"), 1);
+        assert_eq!(sent(&cluster, "prepare"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_shard_that_cannot_lock_in_time_votes_no() {
+        let mut cluster = cluster().await;
+        let [_, b, c] = keys_by_owner();
+        // A txn of a coordinator that went quiet holds the lock of b.
+        let stuck = ("n9".to_string(), 0);
+        let prepare = Payload::Prepare {
+            txn_id: stuck.clone(),
+            txn: vec![write(b, 9)],
+        };
+        assert!(matches!(
+            request(&mut cluster, "n1", prepare).await,
+            Payload::PrepareOk { .. }
+        ));
+
+        let txn = vec![write(b, 1), write(c, 2)];
+        assert_eq!(
+            transact(&mut cluster, "n0", txn.clone()).await,
+            Err(ErrorCode::TxnConflict.code())
+        );
+        // c was prepared but aborted, so its lock is free and nothing was written.
+        assert_eq!(
+            transact(&mut cluster, "n2", vec![read(c)]).await,
+            std::result::Result::Ok(vec![Op::Read {
+                key: c,
+                value: None
+            }])
+        );
+
+        let abort = Payload::Abort { txn_id: stuck };
+        assert!(matches!(
+            request(&mut cluster, "n1", abort).await,
+            Payload::DecisionOk
+        ));
+        assert!(transact(&mut cluster, "n0", txn).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_coordinator_aborts_when_a_vote_does_not_come() {
+        let mut cluster = cluster().await;
+        let [a, b, _] = keys_by_owner();
+        cluster.partition(&["n0"], &["n1"]);
+        let id = cluster.send(
+            "c1",
+            "n0",
+            Payload::Txn {
+                txn: vec![write(a, 1), write(b, 2)],
+            },
+        );
+        // The abort is sent until n1 acknowledges it, so the answer waits for the heal.
+        tokio::time::sleep(Duration::from_millis(DEFAULT_PREPARE_TIMEOUT_MS * 2)).await;
+        cluster.heal();
+        let reply = cluster.expect_reply_to::<Payload>(id).await;
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Error { code, .. } if code == ErrorCode::TxnConflict.code()
+        ));
+        assert_eq!(
+            transact(&mut cluster, "n2", vec![read(a), read(b)]).await,
+            std::result::Result::Ok(vec![
+                Op::Read {
+                    key: a,
+                    value: None
+                },
+                Op::Read {
+                    key: b,
+                    value: None
+                },
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn redelivered_prepares_and_decisions_get_the_same_answer() {
+        let mut cluster = cluster().await;
+        let [_, b, _] = keys_by_owner();
+        let txn_id = ("n9".to_string(), 0);
+        let prepare = Payload::Prepare {
+            txn_id: txn_id.clone(),
+            txn: vec![read(b), write(b, 1)],
+        };
+        for _ in 0..2 {
+            let vote = request(&mut cluster, "n1", prepare.clone()).await;
+            let Payload::PrepareOk { txn } = vote else {
+                panic!("expected a yes vote, got {:?}", vote);
+            };
+            assert_eq!(
+                txn,
+                [
+                    Op::Read {
+                        key: b,
+                        value: None
+                    },
+                    write(b, 1)
+                ]
+            );
+        }
+        for _ in 0..2 {
+            let commit = Payload::Commit {
+                txn_id: txn_id.clone(),
+            };
+            assert!(matches!(
+                request(&mut cluster, "n1", commit).await,
+                Payload::DecisionOk
+            ));
+        }
+        // A prepare of a txn that was aborted before it arrived votes no.
+        let late = ("n9".to_string(), 1);
+        let abort = Payload::Abort {
+            txn_id: late.clone(),
+        };
+        assert!(matches!(
+            request(&mut cluster, "n1", abort).await,
+            Payload::DecisionOk
+        ));
+        let prepare = Payload::Prepare {
+            txn_id: late,
+            txn: vec![write(b, 2)],
+        };
+        assert!(matches!(
+            request(&mut cluster, "n1", prepare).await,
+            Payload::Error { .. }
+        ));
+        assert_eq!(
+            transact(&mut cluster, "n0", vec![read(b)]).await,
+            std::result::Result::Ok(vec![Op::Read {
+                key: b,
+                value: Some(1)
+            }])
+        );
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (any::<u64>(), any::<Option<i64>>()).prop_map(|(key, value)| Op::Read { key, value }),
+            (any::<u64>(), any::<i64>()).prop_map(|(key, value)| Op::Write { key, value }),
+        ]
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let txn = || prop::collection::vec(op(), 0..4);
+        let txn_id = || (wire::node_id(), any::<u64>());
+        prop_oneof![
+            txn().prop_map(|txn| Payload::Txn { txn }),
+            txn().prop_map(|txn| Payload::TxnOk { txn }),
+            wire::error().prop_map(|(code, text)| Payload::Error { code, text }),
+            (txn_id(), txn()).prop_map(|(txn_id, txn)| Payload::Execute { txn_id, txn }),
+            txn().prop_map(|txn| Payload::ExecuteOk { txn }),
+            (txn_id(), txn()).prop_map(|(txn_id, txn)| Payload::Prepare { txn_id, txn }),
+            txn().prop_map(|txn| Payload::PrepareOk { txn }),
+            txn_id().prop_map(|txn_id| Payload::Commit { txn_id }),
+            txn_id().prop_map(|txn_id| Payload::Abort { txn_id }),
+            Just(Payload::DecisionOk),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("txn_rw_register");
+    }
+}
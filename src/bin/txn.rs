@@ -1,86 +1,3893 @@
-use std::{collections::HashMap, sync::atomic::AtomicUsize};
+use std::{
+    collections::{hash_map, BTreeMap, HashMap, HashSet, VecDeque},
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use gossip_glomers::{
+    event_loop, rpc::Rpc, Body, ErrorCode, Event, Init, KVPayload, LamportClock, MaelstromError,
+    Message, Node, Output, VectorClock, WithKV, KV,
+};
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::{Mutex, RwLock, RwLockWriteGuard};
 
-type Operation = (String, u32, Option<u32>);
+/// A single micro-operation of a txn-rw-register or txn-list-append txn. On the wire
+/// it is an array: `["r", key, value]` with the value read, null in requests and for
+/// missing keys, `["w", key, value]`, or `["append", key, element]`. A write without a
+/// value is accepted here, and its txn is answered with a malformed-request error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op {
+    Read { key: u64, value: Option<Value> },
+    Write { key: u64, value: Option<i64> },
+    Append { key: u64, element: i64 },
+}
+
+impl Op {
+    fn key(&self) -> u64 {
+        match self {
+            Self::Read { key, .. } | Self::Write { key, .. } | Self::Append { key, .. } => *key,
+        }
+    }
+}
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Read { key, value } => ("r", key, value).serialize(serializer),
+            Self::Write { key, value } => ("w", key, value).serialize(serializer),
+            Self::Append { key, element } => ("append", key, element).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (op, key, value) = <(String, u64, serde_json::Value)>::deserialize(deserializer)?;
+        match op.as_str() {
+            "r" => serde_json::from_value(value)
+                .map(|value| Self::Read { key, value })
+                .map_err(de::Error::custom),
+            "w" => serde_json::from_value(value)
+                .map(|value| Self::Write { key, value })
+                .map_err(de::Error::custom),
+            "append" => serde_json::from_value(value)
+                .map(|element| Self::Append { key, element })
+                .map_err(de::Error::custom),
+            op => Err(de::Error::unknown_variant(op, &["r", "w", "append"])),
+        }
+    }
+}
+
+/// What a read returns, depending on the workload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum Value {
+    Register(i64),
+    List(Vec<i64>),
+}
+
+/// The Maelstrom workload served, which decides what a key holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Workload {
+    /// Keys hold the value of the write with the highest stamp. Writes replace it, so
+    /// replicas converge no matter in which order they receive the writes.
+    RwRegister,
+    /// Keys hold lists, which appends only ever extend at the end, in the order they
+    /// are applied. Elements already read therefore keep their place.
+    ListAppend,
+}
+
+impl Workload {
+    /// Returns what a read of a key holding `elements` sees.
+    fn value(self, elements: Option<Vec<i64>>) -> Option<Value> {
+        match self {
+            Self::RwRegister => elements
+                .and_then(|elements| elements.last().copied())
+                .map(Value::Register),
+            Self::ListAppend => elements.map(Value::List),
+        }
+    }
+}
+
+impl FromStr for Workload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rw-register" => std::result::Result::Ok(Self::RwRegister),
+            "list-append" => std::result::Result::Ok(Self::ListAppend),
+            _ => anyhow::bail!(
+                "unknown workload {:?}, expected rw-register or list-append",
+                s
+            ),
+        }
+    }
+}
+
+/// Orders txns across nodes: a Lamport clock, ties broken by node id. Every txn gets
+/// its own stamp, which also identifies it.
+type Stamp = (u64, String);
+
+/// Summarizes the storage: the number of elements and the newest stamp of every
+/// shard, in shard order.
+type Digest = Vec<(usize, Option<Stamp>)>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
-    Txn { txn: Vec<Operation> },
-    TxnOk { txn: Vec<Operation> },
+enum TxnPayload {
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+    /// All appends of a txn applied on another node, or its writes for
+    /// txn-rw-register, sent to every other node until they acknowledge it. The txns
+    /// of a node are numbered by `seq` in the order they are sent, which is the order
+    /// other nodes apply them in.
+    Replicate {
+        appends: Vec<(u64, i64)>,
+        stamp: Stamp,
+        seq: u64,
+        /// The txns the origin had committed or applied by then, if TXN_VECTOR_CLOCKS.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<VectorClock>,
+    },
+    ReplicateOk {
+        stamp: Stamp,
+    },
+    /// The txns of a node due for a peer, collected for up to TXN_BATCH_MS.
+    ReplicateBatch {
+        txns: Vec<ReplicatedTxn>,
+    },
+    /// Acknowledges the txns of a batch once they are applied. Held txns are
+    /// acknowledged along with later batches.
+    ReplicateBatchOk {
+        stamps: Vec<Stamp>,
+    },
+    /// Appends that no longer fit a node's outbox, by key and txn, sent periodically
+    /// until acknowledged.
+    Sync {
+        appends: Vec<(u64, Stamp, Vec<i64>)>,
+    },
+    /// The appends of a Sync that were applied, by key and txn.
+    SyncOk {
+        appends: Vec<(u64, Stamp)>,
+    },
+    /// Asks a peer for the appends the sender is missing, and for its own digest in
+    /// return so the sender can push the appends the peer is missing with a Sync.
+    AntiEntropy {
+        digest: Digest,
+    },
+    AntiEntropyOk {
+        digest: Digest,
+        appends: Vec<(u64, Stamp, Vec<i64>)>,
+    },
 }
 
+/// Messages of the txn node: the txn workload, and lin-kv replies for checkpoints.
+type Payload = WithKV<TxnPayload>;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum InjectedPayload {}
+enum InjectedPayload {
+    Flush,
+    Sync,
+    AntiEntropy,
+    Restore,
+    Checkpoint,
+    Reorder,
+}
+
+/// A txn in a ReplicateBatch, see TxnPayload::Replicate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ReplicatedTxn {
+    appends: Vec<(u64, i64)>,
+    stamp: Stamp,
+    seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock: Option<VectorClock>,
+}
+
+/// A copy of the storage kept in lin-kv under `txn:{node}:snapshot`, so a restarted
+/// node picks up where it left off rather than starting empty.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Checkpoint {
+    /// One more than that of the checkpoint it replaced. Checkpoints are only
+    /// written by CAS from the one the writer last saw, so a process that missed a
+    /// newer checkpoint cannot overwrite it.
+    version: u64,
+    appends: Vec<(u64, Stamp, Vec<i64>)>,
+}
 
 struct TxnNode {
     id: AtomicUsize,
-    stdout: Mutex<tokio::io::Stdout>,
-    storage: Mutex<HashMap<u32, u32>>,
+    node: String,
+    node_ids: Vec<String>,
+    stdout: Mutex<Output>,
+    storage: Storage,
+    clock: LamportClock,
+    rpc: Rpc<Payload>,
+    config: TxnConfig,
+    outboxes: Mutex<HashMap<String, Outbox>>,
+    /// Anti-entropy rounds started, which picks the peer of the next one.
+    anti_entropy_rounds: AtomicUsize,
+    stats: TxnStats,
+    timings: Option<TxnTimings>,
+    /// The checkpoint last written or restored, which the next one replaces.
+    checkpoint: Mutex<Option<Checkpoint>>,
+    /// Local txns applied but not in the outbox of every peer yet, which keeps the
+    /// keys they wrote from being evicted.
+    queuing: Mutex<HashSet<Stamp>>,
+    /// Replicated txns recently applied, by origin.
+    applied: Mutex<HashMap<String, Applied>>,
+    /// Sequence number of the next local txn sent to peers.
+    next_seq: AtomicU64,
+    /// Replicated txns waiting for earlier ones of their origin, by origin.
+    inbound: Mutex<HashMap<String, Inbound>>,
+    /// Index of the node in the cluster, its entry in vector clocks.
+    index: usize,
+    /// The local txns and the replicated ones received so far, if TXN_VECTOR_CLOCKS.
+    causality: Option<Mutex<VectorClock>>,
 }
 
-#[async_trait]
-impl Node<Payload, InjectedPayload> for TxnNode {
-    fn from_init(
-        _init: Init,
-        _tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
-    ) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
+/// Counters describing how txns fared and how much replication traffic they caused.
+#[derive(Default)]
+struct TxnStats {
+    committed: AtomicU64,
+    /// Txns aborted because their locks were not free in time.
+    aborted_lock_timeout: AtomicU64,
+    /// Strict txns refused because no majority was reachable.
+    refused_unavailable: AtomicU64,
+    /// Strict txns applied locally but not acknowledged by a majority in time.
+    unconfirmed: AtomicU64,
+    /// Txns sent to a peer, once per peer and attempt.
+    replicate_sent: AtomicU64,
+    batches_sent: AtomicU64,
+    replicate_received: AtomicU64,
+    replicate_duplicate: AtomicU64,
+    /// Replicated txns held back until earlier ones of their origin arrived.
+    replicate_reordered: AtomicU64,
+    /// Replicated txns concurrent with the local clock on arrival, if TXN_VECTOR_CLOCKS.
+    replicate_concurrent: AtomicU64,
+    /// Gaps in the txns of an origin given up on after REORDER_TIMEOUT.
+    reorder_gaps_skipped: AtomicU64,
+    /// Most unacknowledged txns an outbox held at once.
+    outbox_high_water: AtomicU64,
+    /// Txns aborted because they would have created keys beyond TXN_MAX_KEYS.
+    rejected_key_limit: AtomicU64,
+    evicted_keys: AtomicU64,
+}
+
+impl TxnStats {
+    fn report(&self, anti_entropy_rounds: usize, keys: usize) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        eprintln!(
+            "txn stats: committed={} aborted_lock_timeout={} refused_unavailable={} unconfirmed={} replicate_sent={} batches_sent={} replicate_received={} replicate_duplicate={} replicate_reordered={} replicate_concurrent={} reorder_gaps_skipped={} outbox_high_water={} anti_entropy_rounds={} keys={} rejected_key_limit={} evicted_keys={}",
+            load(&self.committed),
+            load(&self.aborted_lock_timeout),
+            load(&self.refused_unavailable),
+            load(&self.unconfirmed),
+            load(&self.replicate_sent),
+            load(&self.batches_sent),
+            load(&self.replicate_received),
+            load(&self.replicate_duplicate),
+            load(&self.replicate_reordered),
+            load(&self.replicate_concurrent),
+            load(&self.reorder_gaps_skipped),
+            load(&self.outbox_high_water),
+            anti_entropy_rounds,
+            keys,
+            load(&self.rejected_key_limit),
+            load(&self.evicted_keys),
+        );
+    }
+}
+
+/// Number of buckets of a Histogram. The last one takes everything from 2^22us, about
+/// 4s, up.
+const HISTOGRAM_BUCKETS: usize = 24;
+
+/// Durations counted in buckets by their power of two of microseconds.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Lists the non-empty buckets by their upper bound, as in `<64us=3`.
+    fn summary(&self) -> String {
+        let mut count = 0;
+        let mut buckets = Vec::new();
+        for (bucket, counter) in self.buckets.iter().enumerate() {
+            let n = counter.load(Ordering::Relaxed);
+            if n == 0 {
+                continue;
+            }
+            count += n;
+            if bucket == HISTOGRAM_BUCKETS - 1 {
+                buckets.push(format!(">={}us={}", 1u64 << (bucket - 1), n));
+            } else {
+                buckets.push(format!("<{}us={}", 1u64 << bucket, n));
+            }
+        }
+        format!("count={} {}", count, buckets.join(" "))
+    }
+}
+
+/// Where the time of txns went, collected if TXN_TIMINGS is set.
+#[derive(Default)]
+struct TxnTimings {
+    /// Waiting for the locks of txns that append.
+    lock_wait: Histogram,
+    /// Running txns that append under their locks, and read-only txns altogether.
+    apply: Histogram,
+    /// Catching up with a majority before a strict txn.
+    read_repair: Histogram,
+    /// Waiting for a majority to acknowledge the appends of a strict txn.
+    quorum_wait: Histogram,
+}
+
+impl TxnTimings {
+    fn report(&self) {
+        for (name, histogram) in [
+            ("lock_wait", &self.lock_wait),
+            ("apply", &self.apply),
+            ("read_repair", &self.read_repair),
+            ("quorum_wait", &self.quorum_wait),
+        ] {
+            eprintln!("txn timings: {} {}", name, histogram.summary());
+        }
+    }
+}
+
+/// Default time in milliseconds a txn waits for its locks before it is aborted.
+const DEFAULT_LOCK_TIMEOUT_MS: u64 = 1000;
+
+/// Default time in milliseconds a strict txn waits for a majority of nodes.
+const DEFAULT_QUORUM_TIMEOUT_MS: u64 = 500;
+
+/// Default time in milliseconds txns are collected before they are sent to peers.
+const DEFAULT_BATCH_MS: u64 = 25;
+
+/// Default number of txns for a peer that are sent before DEFAULT_BATCH_MS is up.
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// What happens once the storage holds `TXN_MAX_KEYS` keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyLimit {
+    /// Txns appending to new keys are aborted with an `abort` error (code 14).
+    /// Replicated appends are always applied, as their txns committed elsewhere, so
+    /// the limit may still be exceeded. So may it by concurrent txns on different
+    /// shards.
+    Reject,
+    /// The least recently written keys are dropped, unless a txn that wrote them has
+    /// not been acknowledged by every peer yet. Reads of an evicted key only return
+    /// what was appended after the eviction, and older appends to it that arrive
+    /// later are dropped.
+    Evict,
+}
+
+impl FromStr for KeyLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => std::result::Result::Ok(Self::Reject),
+            "evict" => std::result::Result::Ok(Self::Evict),
+            _ => anyhow::bail!("unknown key limit {:?}, expected reject or evict", s),
+        }
+    }
+}
+
+/// Default interval between two checkpoints in milliseconds.
+const DEFAULT_CHECKPOINT_MS: u64 = 5000;
+
+/// Service the checkpoints are kept in.
+const CHECKPOINT_STORAGE: &str = "lin-kv";
+
+/// How long to wait for storage before giving up on a request.
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+struct TxnConfig {
+    workload: Workload,
+    /// How long a txn waits for its locks before it is aborted.
+    lock_timeout: Duration,
+    strict: bool,
+    quorum_timeout: Duration,
+    /// Interval between checkpoints, if the storage is checkpointed at all.
+    checkpoint_period: Option<Duration>,
+    /// Most keys the storage should hold, if there is a limit at all.
+    max_keys: Option<usize>,
+    key_limit: KeyLimit,
+    /// How often txns are sent to peers, and overdue ones sent again.
+    batch_interval: Duration,
+    /// Number of new txns for a peer that are sent without waiting for the interval.
+    batch_size: usize,
+    timings: bool,
+    vector_clocks: bool,
+}
+
+impl TxnConfig {
+    /// Reads the configuration from the environment:
+    /// - `TXN_WORKLOAD`: `rw-register` or `list-append`, see Workload (default
+    ///   rw-register)
+    /// - `TXN_LOCK_TIMEOUT_MS`: how long a txn waits for its locks (default 1000)
+    /// - `TXN_STRICT`: only acknowledge txns a majority of nodes has seen, see
+    ///   TxnNode::serve_strict (default false)
+    /// - `TXN_QUORUM_TIMEOUT_MS`: how long a strict txn waits for a majority (default 500)
+    /// - `TXN_CHECKPOINT`: checkpoint the storage to lin-kv and restore it on start
+    ///   (default false)
+    /// - `TXN_CHECKPOINT_MS`: interval between checkpoints (default 5000)
+    /// - `TXN_MAX_KEYS`: most keys to hold, 0 for no limit (default 0)
+    /// - `TXN_KEY_LIMIT`: `reject` or `evict`, see KeyLimit (default reject)
+    /// - `TXN_BATCH_MS`: how long txns are collected before they are replicated, except
+    ///   in strict mode (default 25)
+    /// - `TXN_BATCH_SIZE`: number of txns replicated without waiting (default 64)
+    /// - `TXN_TIMINGS`: time the parts of every txn, see TxnTimings, and print them as
+    ///   histograms on EOF (default false)
+    /// - `TXN_VECTOR_CLOCKS`: send a vector clock with every replicated txn and count
+    ///   the ones concurrent with what the receiver has seen (default false)
+    fn from_env() -> anyhow::Result<Self> {
+        let lock_timeout_ms =
+            gossip_glomers::env_or("TXN_LOCK_TIMEOUT_MS", DEFAULT_LOCK_TIMEOUT_MS)?;
+        let quorum_timeout_ms =
+            gossip_glomers::env_or("TXN_QUORUM_TIMEOUT_MS", DEFAULT_QUORUM_TIMEOUT_MS)?;
+        let max_keys = gossip_glomers::env_or("TXN_MAX_KEYS", 0)?;
+        let checkpoint = gossip_glomers::env_or("TXN_CHECKPOINT", false)?;
+        let checkpoint_ms = gossip_glomers::env_or("TXN_CHECKPOINT_MS", DEFAULT_CHECKPOINT_MS)?;
+        if checkpoint && checkpoint_ms == 0 {
+            anyhow::bail!("TXN_CHECKPOINT_MS must be greater than 0");
+        }
+        let batch_ms = gossip_glomers::env_or("TXN_BATCH_MS", DEFAULT_BATCH_MS)?;
+        if batch_ms == 0 {
+            anyhow::bail!("TXN_BATCH_MS must be greater than 0");
+        }
+        let batch_size = gossip_glomers::env_or("TXN_BATCH_SIZE", DEFAULT_BATCH_SIZE)?;
+        if batch_size == 0 {
+            anyhow::bail!("TXN_BATCH_SIZE must be greater than 0");
+        }
         Ok(Self {
-            id: 1.into(),
-            stdout,
-            storage: Mutex::new(HashMap::new()),
+            workload: gossip_glomers::env_or("TXN_WORKLOAD", Workload::RwRegister)?,
+            lock_timeout: Duration::from_millis(lock_timeout_ms),
+            strict: gossip_glomers::env_or("TXN_STRICT", false)?,
+            quorum_timeout: Duration::from_millis(quorum_timeout_ms),
+            checkpoint_period: checkpoint.then(|| Duration::from_millis(checkpoint_ms)),
+            max_keys: Some(max_keys).filter(|max| *max > 0),
+            key_limit: gossip_glomers::env_or("TXN_KEY_LIMIT", KeyLimit::Reject)?,
+            batch_interval: Duration::from_millis(batch_ms),
+            batch_size,
+            timings: gossip_glomers::env_or("TXN_TIMINGS", false)?,
+            vector_clocks: gossip_glomers::env_or("TXN_VECTOR_CLOCKS", false)?,
         })
     }
+}
 
-    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
-        match event {
-            Event::EOF => {}
-            Event::Message(payload) => {
-                let mut reply = payload.into_reply(Some(&self.id));
-                match reply.body.payload {
-                    Payload::Txn { txn } => {
-                        let mut storage = self.storage.lock().await;
-                        let mut txn_ok = vec![];
-                        for op in txn {
-                            match op.0.as_str() {
-                                "r" => {
-                                    let val = storage.get(&op.1);
-                                    txn_ok.push((op.0, op.1, val.cloned()));
-                                }
-                                "w" => {
-                                    if let Some(val) = op.2 {
-                                        storage.insert(op.1, val);
-                                    }
-                                    txn_ok.push(op.clone());
-                                }
-                                _ => {}
-                            }
-                        }
-                        reply.body.payload = Payload::TxnOk { txn: txn_ok };
-                        reply.send(&self.stdout).await.context("send reply")?;
+/// Wait before the first retransmission of a txn, doubled after every attempt.
+const RETRANSMIT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Longest wait between two retransmissions of a txn.
+const MAX_RETRANSMIT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How often appends that overflowed an outbox are synced.
+const SYNC_PERIOD: Duration = Duration::from_secs(1);
+
+/// Number of unacknowledged txns kept per peer.
+const OUTBOX_CAPACITY: usize = 1024;
+
+/// How often a node compares its storage with one of its peers.
+const ANTI_ENTROPY_PERIOD: Duration = Duration::from_secs(2);
+
+/// Most appends of distinct txns and keys sent in one anti-entropy message.
+const MAX_ANTI_ENTROPY_APPENDS: usize = 1000;
+
+/// Number of replicated txns remembered per origin to skip redeliveries of.
+const DEDUP_WINDOW: usize = 4096;
+
+/// How long replicated txns wait for an earlier txn of their origin before they are
+/// applied without it. Txns folded out of an outbox are never replicated, so their
+/// successors always wait this long.
+const REORDER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often txns waiting for earlier ones are checked for REORDER_TIMEOUT.
+const REORDER_PERIOD: Duration = Duration::from_millis(100);
+
+/// Most replicated txns held back per origin. Once that many wait, further ones are
+/// applied right away.
+const REORDER_CAPACITY: usize = 1024;
+
+/// Number of independently locked parts of the storage.
+const STORAGE_SHARDS: u64 = 16;
+
+#[derive(Default)]
+struct Shard {
+    /// Every element along with the stamp of the txn appending it, in the order they
+    /// were applied. Registers hold a single element, the value of the newest write.
+    lists: HashMap<u64, Vec<(Stamp, i64)>>,
+    /// Newest stamp of every evicted key. Appends up to it are dropped, so replication
+    /// and anti-entropy do not bring evicted lists back.
+    evicted: HashMap<u64, Stamp>,
+}
+
+/// List storage split into shards by key, so txns on disjoint keys do not wait for
+/// each other.
+struct Storage {
+    workload: Workload,
+    shards: Vec<RwLock<Shard>>,
+    /// Number of keys in all shards.
+    keys: AtomicUsize,
+}
+
+impl Storage {
+    fn new(workload: Workload) -> Self {
+        Self {
+            workload,
+            shards: (0..STORAGE_SHARDS).map(|_| RwLock::default()).collect(),
+            keys: AtomicUsize::new(0),
+        }
+    }
+
+    fn key_count(&self) -> usize {
+        self.keys.load(Ordering::Relaxed)
+    }
+
+    /// Evicts up to `count` keys, least recently written first. Keys holding appends
+    /// of a txn in `pinned` or `queuing` are left alone; `queuing` is checked under the
+    /// lock of each shard, so txns committing meanwhile are seen. Returns how many keys
+    /// were evicted.
+    async fn evict(
+        &self,
+        count: usize,
+        pinned: &HashSet<&Stamp>,
+        queuing: &Mutex<HashSet<Stamp>>,
+    ) -> usize {
+        let mut by_age = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().await;
+            by_age.extend(
+                shard
+                    .lists
+                    .iter()
+                    .filter_map(|(key, list)| list.last().map(|(stamp, _)| (stamp.clone(), *key))),
+            );
+        }
+        by_age.sort_unstable();
+        let mut evicted = 0;
+        for (_, key) in by_age {
+            if evicted == count {
+                break;
+            }
+            let mut shard = self.shards[Self::shard_of(key)].write().await;
+            let queuing = queuing.lock().await;
+            let Some(list) = shard.lists.get(&key) else {
+                continue;
+            };
+            if list
+                .iter()
+                .any(|(stamp, _)| pinned.contains(stamp) || queuing.contains(stamp))
+            {
+                continue;
+            }
+            let Some(newest) = list.iter().map(|(stamp, _)| stamp).max().cloned() else {
+                continue;
+            };
+            shard.lists.remove(&key);
+            shard.evicted.insert(key, newest);
+            self.keys.fetch_sub(1, Ordering::Relaxed);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn shard_of(key: u64) -> usize {
+        (key % STORAGE_SHARDS) as usize
+    }
+
+    /// Returns the shards holding `keys` in ascending order, the order they are
+    /// always locked in, so txns sharing shards cannot deadlock.
+    fn shards_of(keys: impl IntoIterator<Item = u64>) -> Vec<usize> {
+        let mut shards: Vec<usize> = keys.into_iter().map(Self::shard_of).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+    }
+
+    /// Locks the shards holding `keys` for writing.
+    async fn lock(&self, keys: impl IntoIterator<Item = u64>) -> LockedShards<'_> {
+        let shards = Self::shards_of(keys);
+        let mut guards = HashMap::with_capacity(shards.len());
+        for shard in shards {
+            guards.insert(shard, self.shards[shard].write().await);
+        }
+        LockedShards {
+            workload: self.workload,
+            guards,
+            keys: &self.keys,
+        }
+    }
+
+    /// Runs `txn`, which only reads. The shards it reads are shared with other
+    /// readers, and only held while the lists are copied. They are all held at once,
+    /// so the txn sees either all or none of the appends of any other txn.
+    async fn read(&self, txn: Vec<Op>) -> Vec<Op> {
+        let mut guards = HashMap::new();
+        for shard in Self::shards_of(txn.iter().map(Op::key)) {
+            guards.insert(shard, self.shards[shard].read().await);
+        }
+        txn.into_iter()
+            .map(|op| match op {
+                Op::Read { key, .. } => Op::Read {
+                    key,
+                    value: self.workload.value(
+                        guards[&Self::shard_of(key)]
+                            .lists
+                            .get(&key)
+                            .map(|list| list.iter().map(|(_, element)| *element).collect()),
+                    ),
+                },
+                op => op,
+            })
+            .collect()
+    }
+
+    async fn digest(&self) -> Digest {
+        let mut digest = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            digest.push(Self::shard_digest(&*shard.read().await));
+        }
+        digest
+    }
+
+    fn shard_digest(shard: &Shard) -> (usize, Option<Stamp>) {
+        let elements = shard.lists.values().map(Vec::len).sum();
+        let newest = shard
+            .lists
+            .values()
+            .flat_map(|list| list.iter().map(|(stamp, _)| stamp))
+            .max()
+            .cloned();
+        (elements, newest)
+    }
+
+    /// Returns the appends, by key and txn, that the node with `digest` is missing as
+    /// far as it can be told: those newer than its newest stamp of their shard, or all
+    /// of a shard whose newest stamp is as new as theirs but whose number of elements
+    /// differs. At most MAX_ANTI_ENTROPY_APPENDS are returned, starting at a position
+    /// that moves with `round` so that large differences are covered over several
+    /// rounds.
+    async fn missing(&self, digest: &Digest, round: usize) -> Vec<(u64, Stamp, Vec<i64>)> {
+        let mut missing = Vec::new();
+        for (shard, theirs) in self.shards.iter().zip(digest) {
+            let shard = shard.read().await;
+            let ours = Self::shard_digest(&shard);
+            if ours == *theirs {
+                continue;
+            }
+            let groups = Self::appends_of(&shard);
+            let newer: Vec<_> = groups
+                .iter()
+                .filter(|(_, stamp, _)| theirs.1.as_ref().is_none_or(|newest| stamp > newest))
+                .cloned()
+                .collect();
+            missing.extend(if newer.is_empty() { groups } else { newer });
+        }
+        missing.sort_by(|(_, a, _), (_, b, _)| a.cmp(b));
+        if missing.len() > MAX_ANTI_ENTROPY_APPENDS {
+            let start = round * MAX_ANTI_ENTROPY_APPENDS % missing.len();
+            missing.rotate_left(start);
+            missing.truncate(MAX_ANTI_ENTROPY_APPENDS);
+        }
+        missing
+    }
+
+    /// Returns the appends of every key and txn, as of now, ordered by key and then in
+    /// list order, so applying them in order rebuilds the lists as they are.
+    async fn snapshot(&self) -> Vec<(u64, Stamp, Vec<i64>)> {
+        let mut appends = Vec::new();
+        for shard in &self.shards {
+            appends.extend(Self::appends_of(&*shard.read().await));
+        }
+        appends.sort_by_key(|(key, ..)| *key);
+        appends
+    }
+
+    /// Returns the appends in `shard`, by key and txn, each key's in list order.
+    fn appends_of(shard: &Shard) -> Vec<(u64, Stamp, Vec<i64>)> {
+        let mut appends = Vec::new();
+        for (key, list) in &shard.lists {
+            for txn in list.chunk_by(|(a, _), (b, _)| a == b) {
+                let elements = txn.iter().map(|(_, element)| *element).collect();
+                appends.push((*key, txn[0].0.clone(), elements));
+            }
+        }
+        appends
+    }
+
+    /// Applies the appends of a txn from another node. Returns false if they all had
+    /// been applied before.
+    async fn apply_replicated(&self, appends: &[(u64, i64)], stamp: &Stamp) -> bool {
+        self.lock(appends.iter().map(|(key, _)| *key))
+            .await
+            .apply(appends, stamp)
+    }
+}
+
+/// The shards a txn works on, locked for as long as this lives.
+struct LockedShards<'a> {
+    workload: Workload,
+    guards: HashMap<usize, RwLockWriteGuard<'a, Shard>>,
+    keys: &'a AtomicUsize,
+}
+
+impl LockedShards<'_> {
+    /// Returns the list of `key`, whose shard has to be locked.
+    fn get(&self, key: u64) -> Option<Vec<i64>> {
+        self.guards[&Storage::shard_of(key)]
+            .lists
+            .get(&key)
+            .map(|list| list.iter().map(|(_, element)| *element).collect())
+    }
+
+    /// Returns whether `key`, whose shard has to be locked, holds a list.
+    fn contains(&self, key: u64) -> bool {
+        self.guards[&Storage::shard_of(key)]
+            .lists
+            .contains_key(&key)
+    }
+
+    /// Applies all `appends` of the txn `stamp`. The shards of every append have to be
+    /// locked, and stay locked throughout, so nobody sees some of the appends of a txn
+    /// but not the others. Returns false if the appends of no key were new.
+    fn apply(&mut self, appends: &[(u64, i64)], stamp: &Stamp) -> bool {
+        let mut by_key: Vec<(u64, Vec<i64>)> = Vec::new();
+        for (key, element) in appends {
+            match by_key.iter_mut().find(|(k, _)| k == key) {
+                Some((_, elements)) => elements.push(*element),
+                None => by_key.push((*key, vec![*element])),
+            }
+        }
+        let mut applied = false;
+        for (key, elements) in by_key {
+            applied |= self.apply_key(key, elements, stamp);
+        }
+        applied
+    }
+
+    /// Applies the `elements` the txn `stamp` appended to or wrote to `key`. Appends go
+    /// at the end of the list, unless it already holds elements of that txn, which
+    /// makes applying redelivered appends a no-op. A write replaces the register if
+    /// its stamp is newer. Returns whether the list changed.
+    fn apply_key(&mut self, key: u64, elements: Vec<i64>, stamp: &Stamp) -> bool {
+        let Some(shard) = self.guards.get_mut(&Storage::shard_of(key)) else {
+            return false;
+        };
+        if elements.is_empty()
+            || shard
+                .evicted
+                .get(&key)
+                .is_some_and(|newest| stamp <= newest)
+        {
+            return false;
+        }
+        let list = match shard.lists.entry(key) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                self.keys.fetch_add(1, Ordering::Relaxed);
+                entry.insert(Vec::new())
+            }
+        };
+        match self.workload {
+            Workload::RwRegister => {
+                if list.last().is_some_and(|(current, _)| current >= stamp) {
+                    return false;
+                }
+                let value = elements[elements.len() - 1];
+                *list = vec![(stamp.clone(), value)];
+            }
+            Workload::ListAppend => {
+                if list.iter().any(|(current, _)| current == stamp) {
+                    return false;
+                }
+                list.extend(elements.into_iter().map(|element| (stamp.clone(), element)));
+            }
+        }
+        true
+    }
+}
+
+/// The appends of a txn sent to a peer and not acknowledged yet.
+struct Unacked {
+    appends: Vec<(u64, i64)>,
+    seq: u64,
+    clock: Option<VectorClock>,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// What still has to reach one peer.
+#[derive(Default)]
+struct Outbox {
+    unacked: BTreeMap<Stamp, Unacked>,
+    /// Appends of the oldest txns, dropped from `unacked` once it held
+    /// OUTBOX_CAPACITY of them, by key and txn. They are synced per key, so a peer may
+    /// see some of the appends of such a txn before the others.
+    folded: HashMap<u64, BTreeMap<Stamp, Vec<i64>>>,
+    /// Number of txns pushed since the last flush that were not sent yet.
+    unsent: usize,
+}
+
+impl Outbox {
+    /// Adds a txn, which is sent with the next batch unless it was `sent` already.
+    fn push(
+        &mut self,
+        stamp: Stamp,
+        seq: u64,
+        clock: Option<VectorClock>,
+        appends: Vec<(u64, i64)>,
+        sent: bool,
+    ) {
+        let now = gossip_glomers::now();
+        self.unacked.insert(
+            stamp,
+            Unacked {
+                appends,
+                seq,
+                clock,
+                attempts: 0,
+                next_attempt: if sent { now + RETRANSMIT_BACKOFF } else { now },
+            },
+        );
+        if !sent {
+            self.unsent += 1;
+        }
+        while self.unacked.len() > OUTBOX_CAPACITY {
+            let Some((stamp, oldest)) = self.unacked.pop_first() else {
+                break;
+            };
+            for (key, element) in oldest.appends {
+                self.folded
+                    .entry(key)
+                    .or_default()
+                    .entry(stamp.clone())
+                    .or_default()
+                    .push(element);
+            }
+        }
+    }
+
+    /// Returns the txns not sent yet or due for another attempt in sequence order, and
+    /// schedules the next attempt.
+    fn due(&mut self, now: Instant) -> Vec<ReplicatedTxn> {
+        self.unsent = 0;
+        let mut due = Vec::new();
+        for (stamp, unacked) in &mut self.unacked {
+            if unacked.next_attempt > now {
+                continue;
+            }
+            unacked.attempts += 1;
+            let backoff = RETRANSMIT_BACKOFF.saturating_mul(1 << unacked.attempts.min(16));
+            unacked.next_attempt = now + backoff.min(MAX_RETRANSMIT_BACKOFF);
+            due.push(ReplicatedTxn {
+                appends: unacked.appends.clone(),
+                stamp: stamp.clone(),
+                seq: unacked.seq,
+                clock: unacked.clock.clone(),
+            });
+        }
+        due.sort_unstable_by_key(|txn| txn.seq);
+        due
+    }
+}
+
+/// The last DEDUP_WINDOW txns replicated from one origin, by the lamport time in their
+/// stamp, which the origin never hands out twice. Redeliveries of older txns are
+/// still skipped, just not before locking: every list keeps the stamps of its
+/// appends.
+#[derive(Default)]
+struct Applied {
+    times: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl Applied {
+    fn contains(&self, time: u64) -> bool {
+        self.times.contains(&time)
+    }
+
+    fn insert(&mut self, time: u64) {
+        if !self.times.insert(time) {
+            return;
+        }
+        self.order.push_back(time);
+        if self.order.len() > DEDUP_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.times.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A replicated txn waiting for earlier txns of its origin.
+struct Held {
+    appends: Vec<(u64, i64)>,
+    stamp: Stamp,
+    /// Acknowledgements of every delivery, sent once the txn is applied.
+    acks: Vec<Message<TxnPayload>>,
+    since: Instant,
+}
+
+/// The replicated txns of one origin.
+#[derive(Default)]
+struct Inbound {
+    /// Sequence number of the next txn to apply.
+    next: u64,
+    held: BTreeMap<u64, Held>,
+}
+
+impl Inbound {
+    /// Takes the held txns that are next in line.
+    fn ready(&mut self) -> Vec<Held> {
+        let mut ready = Vec::new();
+        while let Some(held) = self.held.remove(&self.next) {
+            ready.push(held);
+            self.next += 1;
+        }
+        ready
+    }
+
+    /// Gives up on the gap before the first held txn if it waited REORDER_TIMEOUT, and
+    /// takes the txns that are next in line then. Returns whether there was a gap.
+    fn skip_gap(&mut self, now: Instant) -> Option<Vec<Held>> {
+        let (seq, held) = self.held.first_key_value()?;
+        if now.duration_since(held.since) < REORDER_TIMEOUT {
+            return None;
+        }
+        self.next = *seq;
+        Some(self.ready())
+    }
+}
+
+impl TxnNode {
+    /// Runs `txn` against the local storage and returns the completed ops along with
+    /// the appends applied. An aborted txn leaves the storage untouched.
+    ///
+    /// The shards of every key the txn touches stay locked until it is done, and
+    /// replicated appends take the same locks, so the txn reads from a snapshot: two
+    /// reads of a key return the same list unless the txn appended to it in between.
+    async fn run(
+        &self,
+        txn: Vec<Op>,
+        stamp: &Stamp,
+    ) -> Result<(Vec<Op>, Vec<(u64, i64)>), MaelstromError> {
+        // Read-only txns neither wait for nor hold up writers of other shards.
+        if txn.iter().all(|op| matches!(op, Op::Read { .. })) {
+            let reading = self.start_timing();
+            let txn_ok = self.storage.read(txn).await;
+            self.record_timing(reading, |timings| &timings.apply);
+            self.stats.committed.fetch_add(1, Ordering::Relaxed);
+            return std::result::Result::Ok((txn_ok, vec![]));
+        }
+        let keys = txn.iter().map(Op::key);
+        let lock_timeout = self.config.lock_timeout;
+        let waiting = self.start_timing();
+        let mut storage = tokio::time::timeout(lock_timeout, self.storage.lock(keys))
+            .await
+            .map_err(|_| {
+                self.stats
+                    .aborted_lock_timeout
+                    .fetch_add(1, Ordering::Relaxed);
+                MaelstromError::new(
+                    ErrorCode::TxnConflict,
+                    format!("timed out after {:?} waiting for locks", lock_timeout),
+                )
+            })?;
+        self.record_timing(waiting, |timings| &timings.lock_wait);
+        let applying = self.start_timing();
+        let mut txn_ok = vec![];
+        // Appends and writes are staged and only applied once the whole txn ran; reads
+        // see the txn's own earlier appends at the end of the list, and its own last
+        // write.
+        let workload = self.config.workload;
+        let mut staged: HashMap<u64, Vec<i64>> = HashMap::new();
+        let mut appends = vec![];
+        for op in txn {
+            match (op, workload) {
+                (Op::Read { key, .. }, _) => {
+                    let mut elements = storage.get(key);
+                    if let Some(staged) = staged.get(&key) {
+                        elements.get_or_insert_with(Vec::new).extend(staged);
                     }
-                    Payload::TxnOk { .. } => {}
+                    let value = workload.value(elements);
+                    txn_ok.push(Op::Read { key, value });
+                }
+                (Op::Write { key, value: None }, Workload::RwRegister) => {
+                    return Err(MaelstromError::new(
+                        ErrorCode::MalformedRequest,
+                        format!("write to {} without a value", key),
+                    ));
+                }
+                (
+                    op @ Op::Write {
+                        key,
+                        value: Some(element),
+                    },
+                    Workload::RwRegister,
+                )
+                | (op @ Op::Append { key, element }, Workload::ListAppend) => {
+                    staged.entry(key).or_default().push(element);
+                    appends.push((key, element));
+                    txn_ok.push(op);
                 }
+                (op, workload) => {
+                    return Err(MaelstromError::new(
+                        ErrorCode::NotSupported,
+                        format!(
+                            "{} is not part of the {:?} workload",
+                            serde_json::json!(op),
+                            workload
+                        ),
+                    ));
+                }
+            }
+        }
+        if let (Some(max), KeyLimit::Reject) = (self.config.max_keys, self.config.key_limit) {
+            let new_keys = staged.keys().filter(|key| !storage.contains(**key)).count();
+            if new_keys > 0 && self.storage.key_count() + new_keys > max {
+                self.stats
+                    .rejected_key_limit
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(MaelstromError::new(
+                    ErrorCode::Abort,
+                    format!(
+                        "txn would create {} keys beyond the limit of {}",
+                        new_keys, max
+                    ),
+                ));
+            }
+        }
+        if !appends.is_empty() {
+            self.queuing.lock().await.insert(stamp.clone());
+        }
+        // The stamp is newer than any the node has seen, so writes replace their
+        // registers.
+        storage.apply(&appends, stamp);
+        self.record_timing(applying, |timings| &timings.apply);
+        self.stats.committed.fetch_add(1, Ordering::Relaxed);
+        std::result::Result::Ok((txn_ok, appends))
+    }
+
+    /// Runs `txn` in strict mode, which gives up availability for consistency. First
+    /// the node catches up with a majority of nodes, so reads see every append a
+    /// majority has acknowledged. Without a majority the txn is refused with
+    /// temporarily_unavailable, before anything was applied. Then the txn runs locally,
+    /// and is only acknowledged once a majority has its appends as well. If that times
+    /// out the txn may or may not take effect, as the appends stay queued for the
+    /// missing nodes, so the reply is an indefinite timeout.
+    async fn serve_strict(&self, txn: Vec<Op>) -> anyhow::Result<Result<Vec<Op>, MaelstromError>> {
+        let needed = self.node_ids.len() / 2;
+        let repairing = self.start_timing();
+        let repaired = self.read_repair(needed).await.context("read repair")?;
+        self.record_timing(repairing, |timings| &timings.read_repair);
+        if repaired < needed {
+            self.stats
+                .refused_unavailable
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(Err(MaelstromError::new(
+                ErrorCode::TemporarilyUnavailable,
+                format!(
+                    "only {}/{} peers reachable for read repair",
+                    repaired, needed
+                ),
+            )));
+        }
+        let stamp = (self.clock.tick(), self.node.clone());
+        let (txn_ok, appends) = match self.run(txn, &stamp).await {
+            std::result::Result::Ok(ran) => ran,
+            Err(err) => return Ok(Err(err)),
+        };
+        if appends.is_empty() {
+            return Ok(std::result::Result::Ok(txn_ok));
+        }
+        let waiting = self.start_timing();
+        let acked = self
+            .replicate_to_quorum(appends, stamp, needed)
+            .await
+            .context("replicate appends to quorum")?;
+        self.record_timing(waiting, |timings| &timings.quorum_wait);
+        if acked < needed {
+            self.stats.unconfirmed.fetch_add(1, Ordering::Relaxed);
+            return Ok(Err(MaelstromError::new(
+                ErrorCode::Timeout,
+                format!("only {}/{} peers acknowledged the appends", acked, needed),
+            )));
+        }
+        Ok(std::result::Result::Ok(txn_ok))
+    }
+
+    /// Pulls the appends this node is missing from all peers and applies the ones
+    /// of the first `needed` to answer. Returns how many answered.
+    async fn read_repair(&self, needed: usize) -> anyhow::Result<usize> {
+        let digest = self.storage.digest().await;
+        let requests = self
+            .peers()
+            .map(|node| {
+                self.request(
+                    node,
+                    TxnPayload::AntiEntropy {
+                        digest: digest.clone(),
+                    },
+                )
+            })
+            .collect();
+        let replies = self
+            .rpc
+            .quorum(requests, needed, self.config.quorum_timeout, &self.stdout)
+            .await
+            .context("anti-entropy quorum")?;
+        let mut answered = 0;
+        for reply in replies {
+            if let WithKV::Workload(TxnPayload::AntiEntropyOk { appends, .. }) = reply.body.payload
+            {
+                self.apply_synced(appends).await;
+                answered += 1;
+            }
+        }
+        Ok(answered)
+    }
+
+    /// Like `replicate`, but waits until `needed` peers acknowledged the appends.
+    /// Returns how many did.
+    async fn replicate_to_quorum(
+        &self,
+        appends: Vec<(u64, i64)>,
+        stamp: Stamp,
+        needed: usize,
+    ) -> anyhow::Result<usize> {
+        // Sent right away rather than batched, the client waits for the quorum.
+        let (seq, clock) = self.enqueue(&stamp, &appends, true).await;
+        let mut requests = Vec::new();
+        for node in self.peers() {
+            self.stats.replicate_sent.fetch_add(1, Ordering::Relaxed);
+            requests.push(self.request(
+                node,
+                TxnPayload::Replicate {
+                    appends: appends.clone(),
+                    stamp: stamp.clone(),
+                    seq,
+                    clock: clock.clone(),
+                },
+            ));
+        }
+        let replies = self
+            .rpc
+            .quorum(requests, needed, self.config.quorum_timeout, &self.stdout)
+            .await
+            .context("replicate quorum")?;
+        let mut acked = 0;
+        let mut outboxes = self.outboxes.lock().await;
+        for reply in &replies {
+            match &reply.body.payload {
+                WithKV::Workload(TxnPayload::ReplicateOk { stamp: acked }) if *acked == stamp => {}
+                _ => continue,
             }
-            Event::Injected(..) => {}
+            if let Some(outbox) = outboxes.get_mut(&reply.src) {
+                outbox.unacked.remove(&stamp);
+            }
+            acked += 1;
+        }
+        Ok(acked)
+    }
+
+    /// Applies the appends of the txn `stamp` of a peer, unless they were applied
+    /// already. Returns whether they were.
+    async fn apply_replicated(&self, appends: &[(u64, i64)], stamp: &Stamp) -> bool {
+        let (time, origin) = stamp;
+        let seen = self
+            .applied
+            .lock()
+            .await
+            .get(origin)
+            .is_some_and(|applied| applied.contains(*time));
+        if seen {
+            return false;
+        }
+        // Concurrent redeliveries may both get here, the stamps in the lists keep the
+        // second from appending twice.
+        let fresh = self.storage.apply_replicated(appends, stamp).await;
+        self.applied
+            .lock()
+            .await
+            .entry(origin.clone())
+            .or_default()
+            .insert(*time);
+        fresh
+    }
+
+    /// Returns when a part of a txn started, if txns are timed at all.
+    fn start_timing(&self) -> Option<Instant> {
+        self.timings.as_ref().map(|_| gossip_glomers::now())
+    }
+
+    /// Records the time since `started` in the histogram `part` picks.
+    fn record_timing(
+        &self,
+        started: Option<Instant>,
+        part: impl FnOnce(&TxnTimings) -> &Histogram,
+    ) {
+        if let (Some(timings), Some(started)) = (&self.timings, started) {
+            part(timings).record(gossip_glomers::now() - started);
+        }
+    }
+
+    fn peers(&self) -> impl Iterator<Item = &String> {
+        self.node_ids.iter().filter(|node| **node != self.node)
+    }
+
+    /// Builds a message to `to` carrying a msg_id, which its reply is matched on.
+    fn request(&self, to: &str, payload: TxnPayload) -> Message<Payload> {
+        Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                in_reply_to: None,
+                payload: WithKV::Workload(payload),
+            },
+        }
+    }
+
+    /// Queues `appends` for every other node, and keeps them in its outbox until it
+    /// acknowledges them. They are sent with the next batch, right away once a batch
+    /// is full. Nobody waits for that, so the txn is acknowledged even while other
+    /// nodes are unreachable.
+    async fn replicate(&self, appends: Vec<(u64, i64)>, stamp: Stamp) -> anyhow::Result<()> {
+        self.enqueue(&stamp, &appends, false).await;
+        let full = self
+            .outboxes
+            .lock()
+            .await
+            .values()
+            .any(|outbox| outbox.unsent >= self.config.batch_size);
+        if full {
+            self.flush().await.context("flush full batch")?;
         }
         Ok(())
     }
-}
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    event_loop::<TxnNode, _, _>().await
+    /// Keeps the appends of the local txn `stamp` in the outbox of every peer until it
+    /// acknowledges them. Returns the sequence number of the txn and its vector clock.
+    async fn enqueue(
+        &self,
+        stamp: &Stamp,
+        appends: &[(u64, i64)],
+        sent: bool,
+    ) -> (u64, Option<VectorClock>) {
+        let mut outboxes = self.outboxes.lock().await;
+        // Numbered under the lock, so txns are in the outboxes in sequence.
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let clock = match &self.causality {
+            Some(causality) => {
+                let mut causality = causality.lock().await;
+                causality.increment(self.index);
+                Some(causality.clone())
+            }
+            None => None,
+        };
+        for node in self.peers() {
+            let outbox = outboxes.entry(node.clone()).or_default();
+            outbox.push(stamp.clone(), seq, clock.clone(), appends.to_vec(), sent);
+            self.stats
+                .outbox_high_water
+                .fetch_max(outbox.unacked.len() as u64, Ordering::Relaxed);
+        }
+        self.queuing.lock().await.remove(stamp);
+        (seq, clock)
+    }
+
+    /// Takes in the vector clock of a replicated txn, counting it if the txn is
+    /// concurrent with what this node has seen: neither saw the other's latest txn.
+    async fn observe_causality(&self, clock: Option<&VectorClock>) {
+        let (Some(causality), Some(clock)) = (&self.causality, clock) else {
+            return;
+        };
+        let mut causality = causality.lock().await;
+        if clock.concurrent(&causality) {
+            self.stats
+                .replicate_concurrent
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        causality.merge(clock);
+    }
+
+    /// Applies the replicated txn `seq` of `stamp`'s origin once the txns sent before
+    /// it are applied, and returns the acknowledgements of the txns applied meanwhile,
+    /// `ack` among them once this one is. Txns redelivered after they were applied are
+    /// acknowledged right away.
+    async fn receive(
+        &self,
+        appends: Vec<(u64, i64)>,
+        stamp: Stamp,
+        seq: u64,
+        ack: Message<TxnPayload>,
+    ) -> Vec<Message<TxnPayload>> {
+        // Held while applying, so the txns of an origin are applied one at a time.
+        let mut inbound = self.inbound.lock().await;
+        let origin = inbound.entry(stamp.1.clone()).or_default();
+        if seq > origin.next {
+            if let Some(held) = origin.held.get_mut(&seq) {
+                held.acks.push(ack);
+                return vec![];
+            }
+            if origin.held.len() < REORDER_CAPACITY {
+                self.stats
+                    .replicate_reordered
+                    .fetch_add(1, Ordering::Relaxed);
+                origin.held.insert(
+                    seq,
+                    Held {
+                        appends,
+                        stamp,
+                        acks: vec![ack],
+                        since: gossip_glomers::now(),
+                    },
+                );
+                return vec![];
+            }
+        }
+        let mut ready = vec![Held {
+            appends,
+            stamp,
+            acks: vec![ack],
+            since: gossip_glomers::now(),
+        }];
+        if seq == origin.next {
+            origin.next += 1;
+            ready.extend(origin.ready());
+        }
+        self.apply_held(ready).await
+    }
+
+    /// Applies the txns that waited REORDER_TIMEOUT for an earlier one, which is
+    /// assumed lost, and returns their acknowledgements. The appends of the lost txn
+    /// still end up in the right place once anti-entropy brings them, as lists are
+    /// ordered by stamp.
+    async fn skip_gaps(&self) -> Vec<Message<TxnPayload>> {
+        let now = gossip_glomers::now();
+        let mut inbound = self.inbound.lock().await;
+        let mut ready = Vec::new();
+        for origin in inbound.values_mut() {
+            while let Some(held) = origin.skip_gap(now) {
+                self.stats
+                    .reorder_gaps_skipped
+                    .fetch_add(1, Ordering::Relaxed);
+                ready.extend(held);
+            }
+        }
+        self.apply_held(ready).await
+    }
+
+    /// Sends the acknowledgements of applied txns. Those not answering a request are
+    /// combined into one ReplicateBatchOk per origin.
+    async fn acknowledge(&self, acks: Vec<Message<TxnPayload>>) -> anyhow::Result<()> {
+        let mut batched: HashMap<String, Vec<Stamp>> = HashMap::new();
+        for ack in acks {
+            match ack.body.payload {
+                TxnPayload::ReplicateOk { stamp } if ack.body.in_reply_to.is_none() => {
+                    batched.entry(ack.dest).or_default().push(stamp);
+                }
+                _ => ack.send(&self.stdout).await.context("send replicate ok")?,
+            }
+        }
+        for (origin, stamps) in batched {
+            self.send(&origin, TxnPayload::ReplicateBatchOk { stamps })
+                .await
+                .context("send replicate batch ok")?;
+        }
+        Ok(())
+    }
+
+    async fn apply_held(&self, held: Vec<Held>) -> Vec<Message<TxnPayload>> {
+        let mut acks = Vec::new();
+        for held in held {
+            if !self.apply_replicated(&held.appends, &held.stamp).await {
+                self.stats
+                    .replicate_duplicate
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            acks.extend(held.acks);
+        }
+        acks
+    }
+
+    /// Evicts keys beyond TXN_MAX_KEYS, if keys are to be evicted at all.
+    async fn enforce_key_limit(&self) {
+        let (Some(max), KeyLimit::Evict) = (self.config.max_keys, self.config.key_limit) else {
+            return;
+        };
+        let excess = self.storage.key_count().saturating_sub(max);
+        if excess == 0 {
+            return;
+        }
+        // Txns only leave `queuing` once they are in every outbox, so holding the
+        // outboxes meanwhile keeps each unacknowledged txn in one of them.
+        let outboxes = self.outboxes.lock().await;
+        let mut pinned = HashSet::new();
+        for outbox in outboxes.values() {
+            pinned.extend(outbox.unacked.keys());
+            pinned.extend(outbox.folded.values().flat_map(BTreeMap::keys));
+        }
+        let evicted = self.storage.evict(excess, &pinned, &self.queuing).await;
+        self.stats
+            .evicted_keys
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    /// Sends every peer the txns it has not been sent yet and those whose
+    /// acknowledgement is overdue, in batches of at most TXN_BATCH_SIZE.
+    async fn flush(&self) -> anyhow::Result<()> {
+        let now = gossip_glomers::now();
+        let due: Vec<_> = self
+            .outboxes
+            .lock()
+            .await
+            .iter_mut()
+            .map(|(node, outbox)| (node.clone(), outbox.due(now)))
+            .collect();
+        for (node, txns) in due {
+            for batch in txns.chunks(self.config.batch_size) {
+                self.stats
+                    .replicate_sent
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                self.stats.batches_sent.fetch_add(1, Ordering::Relaxed);
+                let txns = batch.to_vec();
+                self.send(&node, TxnPayload::ReplicateBatch { txns })
+                    .await
+                    .context("send replicate batch")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends every peer the appends folded out of its outbox.
+    async fn sync(&self) -> anyhow::Result<()> {
+        let folded: Vec<_> = self
+            .outboxes
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, outbox)| !outbox.folded.is_empty())
+            .map(|(node, outbox)| {
+                let appends = outbox
+                    .folded
+                    .iter()
+                    .flat_map(|(key, txns)| {
+                        txns.iter()
+                            .map(|(stamp, elements)| (*key, stamp.clone(), elements.clone()))
+                    })
+                    .collect();
+                (node.clone(), appends)
+            })
+            .collect();
+        for (node, appends) in folded {
+            self.send(&node, TxnPayload::Sync { appends })
+                .await
+                .context("send sync")?;
+        }
+        Ok(())
+    }
+
+    /// Starts an anti-entropy round with the next peer.
+    async fn anti_entropy(&self) -> anyhow::Result<()> {
+        let peers: Vec<_> = self
+            .node_ids
+            .iter()
+            .filter(|node| **node != self.node)
+            .collect();
+        if peers.is_empty() {
+            return Ok(());
+        }
+        let round = self.anti_entropy_rounds.fetch_add(1, Ordering::SeqCst);
+        let digest = self.storage.digest().await;
+        self.send(
+            peers[round % peers.len()],
+            TxnPayload::AntiEntropy { digest },
+        )
+        .await
+        .context("send anti-entropy digest")
+    }
+
+    /// Writes a checkpoint of the storage, replacing the one this node last saw. If
+    /// another process of this node replaced that one meanwhile, its appends are
+    /// merged in instead and the next checkpoint replaces its checkpoint.
+    async fn checkpoint(&self) -> anyhow::Result<()> {
+        let mut last = self.checkpoint.lock().await;
+        // Taking the snapshot only locks one shard at a time, txns on the others
+        // carry on.
+        let appends = self.storage.snapshot().await;
+        if last
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.appends == appends)
+        {
+            return Ok(());
+        }
+        let next = Checkpoint {
+            version: last.as_ref().map_or(0, |checkpoint| checkpoint.version) + 1,
+            appends,
+        };
+        match self
+            .cas(
+                CHECKPOINT_STORAGE,
+                self.checkpoint_key(),
+                last.clone(),
+                Some(next.clone()),
+                true,
+            )
+            .await
+        {
+            std::result::Result::Ok(()) => *last = Some(next),
+            Err(err) if ErrorCode::of(&err) == Some(ErrorCode::PreconditionFailed) => {
+                let current = self
+                    .read_checkpoint()
+                    .await
+                    .context("read newer checkpoint")?;
+                eprintln!(
+                    "checkpoint {} lost to checkpoint {:?}, merging it",
+                    next.version,
+                    current.as_ref().map(|checkpoint| checkpoint.version)
+                );
+                if let Some(current) = &current {
+                    self.apply_synced(current.appends.clone()).await;
+                }
+                *last = current;
+            }
+            Err(err) => return Err(err).context("write checkpoint"),
+        }
+        Ok(())
+    }
+
+    /// Loads the last checkpoint of this node, then asks a peer for what happened
+    /// since.
+    async fn restore(&self) -> anyhow::Result<()> {
+        let mut last = self.checkpoint.lock().await;
+        let checkpoint = self.read_checkpoint().await.context("read checkpoint")?;
+        if let Some(checkpoint) = &checkpoint {
+            eprintln!(
+                "restoring checkpoint {} with {} appends",
+                checkpoint.version,
+                checkpoint.appends.len()
+            );
+            self.apply_synced(checkpoint.appends.clone()).await;
+        }
+        *last = checkpoint;
+        drop(last);
+        self.anti_entropy().await
+    }
+
+    async fn read_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        match self.read(CHECKPOINT_STORAGE, self.checkpoint_key()).await {
+            std::result::Result::Ok(checkpoint) => Ok(checkpoint),
+            Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn checkpoint_key(&self) -> String {
+        format!("txn:{}:snapshot", self.node)
+    }
+
+    async fn rpc(
+        &self,
+        to: &str,
+        payload: KVPayload<serde_json::Value>,
+    ) -> anyhow::Result<Message<Payload>> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                in_reply_to: None,
+                payload: WithKV::KV(payload),
+            },
+        };
+        self.rpc.call(msg, RPC_TIMEOUT, &self.stdout).await
+    }
+
+    /// Applies appends of other nodes by key and txn, and returns which were applied.
+    async fn apply_synced(&self, appends: Vec<(u64, Stamp, Vec<i64>)>) -> Vec<(u64, Stamp)> {
+        let mut storage = self
+            .storage
+            .lock(appends.iter().map(|(key, ..)| *key))
+            .await;
+        let mut applied = Vec::with_capacity(appends.len());
+        for (key, stamp, elements) in appends {
+            self.clock.observe(stamp.0);
+            storage.apply_key(key, elements, &stamp);
+            applied.push((key, stamp));
+        }
+        applied
+    }
+
+    async fn send(&self, to: &str, payload: TxnPayload) -> anyhow::Result<()> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload,
+            },
+        };
+        msg.send(&self.stdout).await
+    }
+}
+
+#[async_trait]
+impl<T> KV<T> for TxnNode
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T> {
+        let payload = KVPayload::Read { key };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("read from storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::ReadOk { value }) => {
+                serde_json::from_value(value).context("deserialize stored value")
+            }
+            WithKV::Workload(TxnPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write(&self, storage: &str, key: String, value: T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value).context("serialize value")?;
+        let payload = KVPayload::Write { key, value };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("write to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::WriteOk {}) => Ok(()),
+            WithKV::Workload(TxnPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        let from = serde_json::to_value(from).context("serialize from value")?;
+        let to = serde_json::to_value(to).context("serialize to value")?;
+        let payload = KVPayload::Cas { key, from, to, put };
+        let result = self.rpc(storage, payload).await.context("cas to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::CasOk {}) => Ok(()),
+            WithKV::Workload(TxnPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for TxnNode {
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = TxnConfig::from_env()?;
+        let timings = config.timings.then(TxnTimings::default);
+        let index = init.node_index()?;
+        let vector_clocks = config.vector_clocks;
+        eprintln!("txn config: {:?}", config);
+        gossip_glomers::spawn_timer(tx.clone(), config.batch_interval, InjectedPayload::Flush);
+        gossip_glomers::spawn_timer(tx.clone(), SYNC_PERIOD, InjectedPayload::Sync);
+        gossip_glomers::spawn_timer(tx.clone(), REORDER_PERIOD, InjectedPayload::Reorder);
+        gossip_glomers::spawn_timer(
+            tx.clone(),
+            ANTI_ENTROPY_PERIOD,
+            InjectedPayload::AntiEntropy,
+        );
+        if let Some(period) = config.checkpoint_period {
+            let restore = tx.clone();
+            tokio::spawn(async move {
+                let _ = restore
+                    .send(Event::Injected(InjectedPayload::Restore))
+                    .await;
+            });
+            gossip_glomers::spawn_timer(tx, period, InjectedPayload::Checkpoint);
+        }
+        Ok(Self {
+            id: 1.into(),
+            node: init.node_id,
+            node_ids: init.node_ids,
+            stdout,
+            storage: Storage::new(config.workload),
+            clock: LamportClock::new(),
+            rpc: Rpc::new(),
+            config,
+            outboxes: Mutex::new(HashMap::new()),
+            anti_entropy_rounds: AtomicUsize::new(0),
+            stats: TxnStats::default(),
+            timings,
+            checkpoint: Mutex::new(None),
+            queuing: Mutex::new(HashSet::new()),
+            applied: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0),
+            inbound: Mutex::new(HashMap::new()),
+            index,
+            causality: vector_clocks.then(|| Mutex::new(VectorClock::new())),
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::EOF => {
+                self.stats.report(
+                    self.anti_entropy_rounds.load(Ordering::Relaxed),
+                    self.storage.key_count(),
+                );
+                if let Some(timings) = &self.timings {
+                    timings.report();
+                }
+            }
+            Event::Message(message) => {
+                // Storage replies go to the RPC waiting for them, and so do replies
+                // to strict mode quorums.
+                let Some(message) = self.rpc.resolve(message).await else {
+                    return Ok(());
+                };
+                let Message { src, dest, body } = message;
+                let payload = match body.payload {
+                    WithKV::Workload(payload) => payload,
+                    WithKV::KV(payload) => {
+                        eprintln!("unexpected storage message from {}: {:?}", src, payload);
+                        return Ok(());
+                    }
+                };
+                let message = Message {
+                    src,
+                    dest,
+                    body: Body {
+                        id: body.id,
+                        in_reply_to: body.in_reply_to,
+                        payload,
+                    },
+                };
+                let mut reply = message.into_reply(Some(&self.id));
+                match reply.body.payload {
+                    TxnPayload::Txn { txn } if self.config.strict => {
+                        reply.body.payload = match self.serve_strict(txn).await? {
+                            std::result::Result::Ok(txn_ok) => TxnPayload::TxnOk { txn: txn_ok },
+                            Err(err) => TxnPayload::Error {
+                                code: err.code.code(),
+                                text: err.text,
+                            },
+                        };
+                        reply.send(&self.stdout).await.context("send reply")?;
+                    }
+                    TxnPayload::Txn { txn } => {
+                        let stamp = (self.clock.tick(), self.node.clone());
+                        match self.run(txn, &stamp).await {
+                            std::result::Result::Ok((txn_ok, appends)) => {
+                                reply.body.payload = TxnPayload::TxnOk { txn: txn_ok };
+                                reply.send(&self.stdout).await.context("send reply")?;
+                                if !appends.is_empty() {
+                                    self.replicate(appends, stamp)
+                                        .await
+                                        .context("replicate appends")?;
+                                }
+                            }
+                            // Nothing was applied, so there is nothing to replicate.
+                            Err(err) => {
+                                reply.body.payload = TxnPayload::Error {
+                                    code: err.code.code(),
+                                    text: err.text,
+                                };
+                                reply.send(&self.stdout).await.context("send abort")?;
+                            }
+                        }
+                    }
+                    TxnPayload::Replicate {
+                        appends,
+                        stamp,
+                        seq,
+                        clock,
+                    } => {
+                        self.clock.observe(stamp.0);
+                        self.observe_causality(clock.as_ref()).await;
+                        self.stats
+                            .replicate_received
+                            .fetch_add(1, Ordering::Relaxed);
+                        // Txns are only acknowledged once applied, so held ones are
+                        // retransmitted meanwhile. Redelivered txns are acknowledged
+                        // again, the first acknowledgement may have been lost.
+                        reply.body.payload = TxnPayload::ReplicateOk {
+                            stamp: stamp.clone(),
+                        };
+                        let acks = self.receive(appends, stamp, seq, reply).await;
+                        self.acknowledge(acks).await?;
+                    }
+                    TxnPayload::ReplicateBatch { txns } => {
+                        let mut acks = Vec::new();
+                        for ReplicatedTxn {
+                            appends,
+                            stamp,
+                            seq,
+                            clock,
+                        } in txns
+                        {
+                            self.clock.observe(stamp.0);
+                            self.observe_causality(clock.as_ref()).await;
+                            self.stats
+                                .replicate_received
+                                .fetch_add(1, Ordering::Relaxed);
+                            let ack = Message {
+                                src: self.node.clone(),
+                                dest: reply.dest.clone(),
+                                body: Body {
+                                    id: None,
+                                    in_reply_to: None,
+                                    payload: TxnPayload::ReplicateOk {
+                                        stamp: stamp.clone(),
+                                    },
+                                },
+                            };
+                            acks.extend(self.receive(appends, stamp, seq, ack).await);
+                        }
+                        self.acknowledge(acks).await?;
+                    }
+                    TxnPayload::ReplicateBatchOk { stamps } => {
+                        if let Some(outbox) = self.outboxes.lock().await.get_mut(&reply.dest) {
+                            for stamp in &stamps {
+                                outbox.unacked.remove(stamp);
+                            }
+                        }
+                    }
+                    TxnPayload::ReplicateOk { stamp } => {
+                        if let Some(outbox) = self.outboxes.lock().await.get_mut(&reply.dest) {
+                            outbox.unacked.remove(&stamp);
+                        }
+                    }
+                    TxnPayload::Sync { appends } => {
+                        let applied = self.apply_synced(appends).await;
+                        reply.body.payload = TxnPayload::SyncOk { appends: applied };
+                        reply.send(&self.stdout).await.context("send sync ok")?;
+                    }
+                    TxnPayload::SyncOk { appends } => {
+                        if let Some(outbox) = self.outboxes.lock().await.get_mut(&reply.dest) {
+                            for (key, stamp) in appends {
+                                if let Some(txns) = outbox.folded.get_mut(&key) {
+                                    txns.remove(&stamp);
+                                    if txns.is_empty() {
+                                        outbox.folded.remove(&key);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    TxnPayload::AntiEntropy { digest } => {
+                        let round = self.anti_entropy_rounds.load(Ordering::SeqCst);
+                        let appends = self.storage.missing(&digest, round).await;
+                        reply.body.payload = TxnPayload::AntiEntropyOk {
+                            digest: self.storage.digest().await,
+                            appends,
+                        };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send anti-entropy reply")?;
+                    }
+                    TxnPayload::AntiEntropyOk { digest, appends } => {
+                        self.apply_synced(appends).await;
+                        let round = self.anti_entropy_rounds.load(Ordering::SeqCst);
+                        let appends = self.storage.missing(&digest, round).await;
+                        if !appends.is_empty() {
+                            self.send(&reply.dest, TxnPayload::Sync { appends })
+                                .await
+                                .context("push anti-entropy appends")?;
+                        }
+                    }
+                    TxnPayload::Error { code, text } => {
+                        eprintln!("Error {}: {}", code, text);
+                    }
+                    TxnPayload::TxnOk { .. } => {}
+                }
+                // Writes add keys and acknowledgements unpin them.
+                self.enforce_key_limit().await;
+            }
+            Event::Injected(InjectedPayload::Reorder) => {
+                let acks = self.skip_gaps().await;
+                self.acknowledge(acks).await?;
+            }
+            Event::Injected(InjectedPayload::Flush) => {
+                self.flush().await.context("flush txns")?;
+            }
+            Event::Injected(InjectedPayload::Sync) => {
+                self.sync().await.context("sync folded appends")?;
+            }
+            Event::Injected(InjectedPayload::Restore) => {
+                self.restore().await.context("restore checkpoint")?;
+                self.enforce_key_limit().await;
+            }
+            Event::Injected(InjectedPayload::Checkpoint) => {
+                self.checkpoint().await.context("checkpoint storage")?;
+            }
+            Event::Injected(InjectedPayload::AntiEntropy) => {
+                self.anti_entropy()
+                    .await
+                    .context("start anti-entropy round")?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn malformed(
+        &self,
+        message: Message<serde_json::Value>,
+        err: serde_json::Error,
+    ) -> anyhow::Result<()> {
+        // Only txn requests are answered, like those with an unknown op. Anything else
+        // has nobody waiting for an answer.
+        let payload = &message.body.payload;
+        if payload.get("type").and_then(|t| t.as_str()) != Some("txn") || message.body.id.is_none()
+        {
+            eprintln!(
+                "dropping message that could not be deserialized ({}): {:?}",
+                err, message
+            );
+            return Ok(());
+        }
+        // The untagged WithKV hides why the payload did not parse, the txn payload on
+        // its own tells.
+        let reason = serde_json::from_value::<TxnPayload>(payload.clone())
+            .err()
+            .map_or_else(|| err.to_string(), |err| err.to_string());
+        message
+            .into_reply(Some(&self.id))
+            .map_payload(|_| -> Payload {
+                WithKV::Workload(TxnPayload::Error {
+                    code: ErrorCode::MalformedRequest.code(),
+                    text: format!("malformed txn: {}", reason),
+                })
+            })
+            .send(&self.stdout)
+            .await
+            .context("send error message")
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<TxnNode, _, _>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use futures::FutureExt;
+    use gossip_glomers::testkit::{
+        client_operations, wire, Cluster, Fate, Harness, KvOp, MockKvService, Rng,
+    };
+    use proptest::prelude::*;
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    fn stamp(time: u64, node: &str) -> Stamp {
+        (time, node.to_string())
+    }
+
+    async fn list(storage: &Storage, key: u64) -> Option<Value> {
+        match storage
+            .read(vec![Op::Read { key, value: None }])
+            .await
+            .pop()
+        {
+            Some(Op::Read { value, .. }) => value,
+            op => panic!("expected a read, got {:?}", op),
+        }
+    }
+
+    #[tokio::test]
+    async fn older_appends_go_at_the_end() {
+        let storage = Storage::new(Workload::ListAppend);
+        assert!(storage.apply_replicated(&[(1, 10)], &stamp(5, "n0")).await);
+        assert!(
+            storage
+                .apply_replicated(&[(1, 20), (1, 21)], &stamp(2, "n1"))
+                .await
+        );
+        assert_eq!(list(&storage, 1).await, Some(Value::List(vec![10, 20, 21])));
+    }
+
+    #[tokio::test]
+    async fn redelivered_appends_are_skipped() {
+        let storage = Storage::new(Workload::ListAppend);
+        assert!(storage.apply_replicated(&[(1, 10)], &stamp(1, "n1")).await);
+        assert!(storage.apply_replicated(&[(1, 20)], &stamp(2, "n0")).await);
+        assert!(!storage.apply_replicated(&[(1, 10)], &stamp(1, "n1")).await);
+        assert_eq!(list(&storage, 1).await, Some(Value::List(vec![10, 20])));
+    }
+
+    #[tokio::test]
+    async fn register_keeps_the_newest_write() {
+        let storage = Storage::new(Workload::RwRegister);
+        assert!(storage.apply_replicated(&[(1, 10)], &stamp(5, "n0")).await);
+        assert!(!storage.apply_replicated(&[(1, 20)], &stamp(2, "n1")).await);
+        assert!(
+            storage
+                .apply_replicated(&[(1, 30), (1, 31)], &stamp(6, "n1"))
+                .await
+        );
+        assert_eq!(list(&storage, 1).await, Some(Value::Register(31)));
+        assert_eq!(list(&storage, 2).await, None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_keeps_list_order() {
+        let storage = Storage::new(Workload::ListAppend);
+        storage.apply_replicated(&[(1, 10)], &stamp(5, "n0")).await;
+        storage.apply_replicated(&[(1, 20)], &stamp(2, "n1")).await;
+        let restored = Storage::new(Workload::ListAppend);
+        for (key, stamp, elements) in storage.snapshot().await {
+            restored.lock([key]).await.apply_key(key, elements, &stamp);
+        }
+        assert_eq!(list(&restored, 1).await, Some(Value::List(vec![10, 20])));
+    }
+
+    /// Read-only txns only wait for writers of the shards they read: a txn holding the
+    /// lock of one shard holds up reads of it, but not of any other.
+    #[tokio::test(start_paused = true)]
+    async fn reads_do_not_wait_for_writers_of_other_shards() {
+        let storage = Storage::new(Workload::ListAppend);
+        storage
+            .apply_replicated(&[(1, 10), (2, 20)], &stamp(1, "n0"))
+            .await;
+        assert_ne!(Storage::shard_of(1), Storage::shard_of(2));
+        let read = |key| vec![Op::Read { key, value: None }];
+
+        let mut writer = storage.lock([1]).await;
+        let others = storage.read(read(2)).now_or_never();
+        assert_eq!(
+            others,
+            Some(vec![Op::Read {
+                key: 2,
+                value: Some(Value::List(vec![20]))
+            }])
+        );
+        let blocked = tokio::time::timeout(Duration::from_secs(1), storage.read(read(1))).await;
+        assert!(blocked.is_err(), "read of a shard locked for writing");
+
+        // Once the writer is done, reads see all of its appends.
+        writer.apply(&[(1, 11)], &stamp(2, "n0"));
+        drop(writer);
+        assert_eq!(
+            storage.read(read(1)).await,
+            vec![Op::Read {
+                key: 1,
+                value: Some(Value::List(vec![10, 11]))
+            }]
+        );
+    }
+
+    /// Asserts that `json` deserializes to `txn` and serializes back to itself.
+    fn round_trip(json: &str, txn: Vec<Op>) {
+        let parsed: Vec<Op> = serde_json::from_str(json).expect("deserialize txn");
+        assert_eq!(parsed, txn);
+        let expected: serde_json::Value = serde_json::from_str(json).expect("parse txn");
+        assert_eq!(
+            serde_json::to_value(&parsed).expect("serialize txn"),
+            expected
+        );
+    }
+
+    #[test]
+    fn rw_register_ops_round_trip() {
+        round_trip(
+            r#"[["r",9,null],["w",9,3],["r",1,4],["w",2,null]]"#,
+            vec![
+                Op::Read {
+                    key: 9,
+                    value: None,
+                },
+                Op::Write {
+                    key: 9,
+                    value: Some(3),
+                },
+                Op::Read {
+                    key: 1,
+                    value: Some(Value::Register(4)),
+                },
+                Op::Write {
+                    key: 2,
+                    value: None,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn list_append_ops_round_trip() {
+        round_trip(
+            r#"[["append",9,3],["r",9,[1,2,3]],["r",1,[]],["r",2,null]]"#,
+            vec![
+                Op::Append { key: 9, element: 3 },
+                Op::Read {
+                    key: 9,
+                    value: Some(Value::List(vec![1, 2, 3])),
+                },
+                Op::Read {
+                    key: 1,
+                    value: Some(Value::List(vec![])),
+                },
+                Op::Read {
+                    key: 2,
+                    value: None,
+                },
+            ],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_txn_is_answered_with_its_reads() {
+        let mut node = Harness::<TxnNode, Payload, InjectedPayload>::new("n0", &["n0"]).await;
+        node.send_line(
+            r#"{"src":"c4","dest":"n0","body":{"type":"txn","msg_id":3,"txn":[["r",1,null],["w",1,6],["r",1,null]]}}"#,
+        )
+        .await;
+        let reply: serde_json::Value =
+            serde_json::from_str(&node.recv_line().await).expect("parse reply");
+        assert_eq!(
+            reply,
+            serde_json::json!({
+                "src": "n0",
+                "dest": "c4",
+                "body": {
+                    "type": "txn_ok",
+                    "msg_id": 1,
+                    "in_reply_to": 3,
+                    "txn": [["r", 1, null], ["w", 1, 6], ["r", 1, 6]],
+                },
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_txns_are_answered_with_code_12() {
+        let mut node = Harness::<TxnNode, Payload, InjectedPayload>::new("n0", &["n0"]).await;
+        let id = node
+            .send_json(
+                "c1",
+                serde_json::json!({"type": "txn", "txn": [["cas", 1, 2]]}),
+            )
+            .await;
+        let reply = node.expect_reply_to(id).await;
+        let WithKV::Workload(TxnPayload::Error { code, text }) = reply.body.payload else {
+            panic!("expected an error, got {:?}", reply.body.payload);
+        };
+        assert_eq!(code, 12);
+        assert!(text.contains("unknown variant `cas`"), "{}", text);
+
+        // Nobody waits for the answer to a message without a msg_id.
+        node.send_line(r#"{"src":"c1","dest":"n0","body":{"type":"txn","txn":[["cas",1,2]]}}"#)
+            .await;
+        assert!(node.drain().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_without_a_value_are_malformed() {
+        let mut node = Harness::<TxnNode, Payload, InjectedPayload>::new("n0", &["n0"]).await;
+        let id = node
+            .send_json(
+                "c1",
+                serde_json::json!({"type": "txn", "txn": [["w", 1, 2], ["w", 2, null]]}),
+            )
+            .await;
+        let reply = node.expect_reply_to(id).await;
+        let WithKV::Workload(TxnPayload::Error { code, text }) = reply.body.payload else {
+            panic!("expected an error, got {:?}", reply.body.payload);
+        };
+        assert_eq!(ErrorCode::from_code(code), ErrorCode::MalformedRequest);
+        assert!(text.contains("without a value"), "{}", text);
+
+        // Neither write took effect.
+        let id = node
+            .send_json(
+                "c1",
+                serde_json::json!({"type": "txn", "txn": [["r", 1, null]]}),
+            )
+            .await;
+        let reply = node.expect_reply_to(id).await;
+        let WithKV::Workload(TxnPayload::TxnOk { txn }) = reply.body.payload else {
+            panic!("expected txn_ok, got {:?}", reply.body.payload);
+        };
+        assert_eq!(
+            txn,
+            [Op::Read {
+                key: 1,
+                value: None
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_or_mistyped_ops_are_rejected() {
+        for json in [
+            r#"["cas",1,2]"#,
+            r#"["w",1,[2]]"#,
+            r#"["append",1,null]"#,
+            r#"["r",-1,null]"#,
+            r#"["r",1]"#,
+        ] {
+            assert!(
+                serde_json::from_str::<Op>(json).is_err(),
+                "accepted {}",
+                json
+            );
+        }
+    }
+
+    /// Two txn nodes with the variables `vars` set.
+    async fn cluster(vars: &[(&str, Option<&str>)]) -> Cluster {
+        Cluster::builder()
+            .nodes::<TxnNode, Payload, InjectedPayload>(&["n0", "n1"])
+            .env(vars)
+            .start()
+            .await
+    }
+
+    /// Runs `txn` at `node`, returning its reads or the error code it failed with.
+    async fn run(cluster: &mut Cluster, node: &str, txn: Vec<Op>) -> Result<Vec<Op>, usize> {
+        let id = cluster.send("c1", node, Payload::Workload(TxnPayload::Txn { txn }));
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            WithKV::Workload(TxnPayload::TxnOk { txn }) => std::result::Result::Ok(txn),
+            WithKV::Workload(TxnPayload::Error { code, .. }) => Err(code),
+            payload => panic!("expected txn_ok or an error, got {:?}", payload),
+        }
+    }
+
+    /// The values of `keys` as one txn at `node` reads them.
+    async fn read_keys(cluster: &mut Cluster, node: &str, keys: &[u64]) -> Vec<Option<Value>> {
+        let txn = keys
+            .iter()
+            .map(|&key| Op::Read { key, value: None })
+            .collect();
+        run(cluster, node, txn)
+            .await
+            .expect("a read-only txn commits")
+            .into_iter()
+            .map(|op| match op {
+                Op::Read { value, .. } => value,
+                op => panic!("expected a read, got {:?}", op),
+            })
+            .collect()
+    }
+
+    fn elements(elements: &[i64]) -> Option<Value> {
+        Some(Value::List(elements.to_vec()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_txn_is_never_partly_visible_at_other_nodes() {
+        let mut cluster = cluster(&[("TXN_WORKLOAD", Some("list-append"))]).await;
+        cluster.set_hold_partitioned(true);
+        cluster.partition(&["n0"], &["n1"]);
+        let appends = vec![
+            Op::Append {
+                key: 1,
+                element: 10,
+            },
+            Op::Append {
+                key: 2,
+                element: 20,
+            },
+        ];
+        run(&mut cluster, "n0", appends).await.expect("txn commits");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(read_keys(&mut cluster, "n1", &[1, 2]).await, [None, None]);
+
+        // Replication resumes while n1 keeps reading.
+        cluster.heal();
+        cluster.set_latency("n0", "n1", Duration::from_millis(50));
+        let mut seen = Vec::new();
+        for _ in 0..20 {
+            let read = read_keys(&mut cluster, "n1", &[1, 2]).await;
+            assert!(
+                read == [None, None] || read == [elements(&[10]), elements(&[20])],
+                "n1 read {:?}",
+                read
+            );
+            seen.push(read);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(seen.last(), Some(&vec![elements(&[10]), elements(&[20])]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aborted_txns_leave_no_trace() {
+        let mut cluster = cluster(&[("TXN_MAX_KEYS", Some("1"))]).await;
+        let write = |key, value| Op::Write { key, value };
+        run(&mut cluster, "n0", vec![write(1, Some(1))])
+            .await
+            .expect("txn commits");
+        // One write without a value, the other a key too many.
+        let aborted = run(&mut cluster, "n0", vec![write(1, Some(7)), write(1, None)]).await;
+        assert_eq!(aborted, Err(ErrorCode::MalformedRequest.code()));
+        let aborted = run(
+            &mut cluster,
+            "n0",
+            vec![write(1, Some(2)), write(2, Some(3))],
+        )
+        .await;
+        assert_eq!(aborted, Err(ErrorCode::Abort.code()));
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        for node in ["n0", "n1"] {
+            assert_eq!(
+                read_keys(&mut cluster, node, &[1, 2]).await,
+                [Some(Value::Register(1)), None],
+                "at {}",
+                node
+            );
+        }
+        for delivery in cluster.trace() {
+            if delivery.from.starts_with('n') && delivery.to.starts_with('n') {
+                for leaked in ["[1,7]", "[1,2]", "[2,3]"] {
+                    assert!(!delivery.line.contains(leaked), "{}", delivery.line);
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_at_other_nodes_never_go_back_in_origin_order() {
+        let mut cluster = cluster(&[("TXN_WORKLOAD", Some("list-append"))]).await;
+        // The first txn's batch is slow, so the second overtakes it.
+        cluster.set_latency("n0", "n1", Duration::from_millis(150));
+        let append = |element| vec![Op::Append { key: 1, element }];
+        run(&mut cluster, "n0", append(10))
+            .await
+            .expect("txn commits");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cluster.set_latency("n0", "n1", Duration::ZERO);
+        run(&mut cluster, "n0", append(11))
+            .await
+            .expect("txn commits");
+
+        let mut seen = Vec::new();
+        for _ in 0..30 {
+            let read = read_keys(&mut cluster, "n1", &[1]).await.remove(0);
+            assert!(
+                [None, elements(&[10]), elements(&[10, 11])].contains(&read),
+                "n1 read {:?} after {:?}",
+                read,
+                seen
+            );
+            seen.push(read);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(seen.last(), Some(&elements(&[10, 11])));
+    }
+
+    /// Runs the txn `first` at `node` and then `second`, interleaved: while the test
+    /// holds the shard of key 2, `first` takes the shard of key 1 and waits, then
+    /// `second` is started and gets to wait behind it. Returns the ops `first`
+    /// completed with and what `second` returned.
+    async fn interleave<T>(
+        node: &TxnNode,
+        first: Vec<Op>,
+        second: impl std::future::Future<Output = T>,
+    ) -> (Vec<Op>, T) {
+        let shard = |key| &node.storage.shards[Storage::shard_of(key)];
+        let (held, shard_held) = oneshot::channel();
+        let (started, second_started) = oneshot::channel();
+        let hold = async {
+            let guard = shard(2).write().await;
+            held.send(()).expect("first waits");
+            second_started.await.expect("second runs");
+            // Virtual time only passes once second waits as well.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        };
+        let first = async {
+            shard_held.await.expect("shard held");
+            node.run(first, &stamp(1, "n0")).await
+        };
+        let second = async {
+            while shard(1).try_write().is_ok() {
+                tokio::task::yield_now().await;
+            }
+            started.send(()).expect("shard held");
+            second.await
+        };
+        let ((), first, second) = tokio::join!(hold, first, second);
+        (first.expect("txn commits").0, second)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_concurrent_read_sees_all_or_none_of_a_txn() {
+        let harness = Harness::<TxnNode, Payload, InjectedPayload>::with_env(
+            "n0",
+            &["n0"],
+            &[("TXN_WORKLOAD", Some("list-append"))],
+        )
+        .await;
+        let node = harness.node();
+        let reads = || vec![read(1, None), read(2, None)];
+        let run = |txn, time| async move {
+            node.run(txn, &stamp(time, "n0"))
+                .await
+                .expect("txn commits")
+                .0
+        };
+
+        // The read waits behind a txn that has locked key 1 but not key 2 yet.
+        let appends = vec![append(1, 10), append(2, 20)];
+        let (_, seen) = interleave(node, appends, run(reads(), 2)).await;
+        assert_eq!(seen, [read(1, elements(&[10])), read(2, elements(&[20]))]);
+
+        // The txn waits behind a read that has locked key 1 but not key 2 yet.
+        let appends = vec![append(1, 11), append(2, 21)];
+        let (seen, _) = interleave(node, reads(), run(appends, 3)).await;
+        assert_eq!(seen, [read(1, elements(&[10])), read(2, elements(&[20]))]);
+        assert_eq!(
+            run(reads(), 4).await,
+            [read(1, elements(&[10, 11])), read(2, elements(&[20, 21]))]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_replicate_during_a_txn_is_not_seen_by_its_reads() {
+        let mut harness =
+            Harness::<TxnNode, Payload, InjectedPayload>::new("n0", &["n0", "n1"]).await;
+        let node = harness.node();
+        node.run(vec![write(1, 1)], &stamp(1, "n0"))
+            .await
+            .expect("txn commits");
+        let replicate = Message {
+            src: "n1".to_string(),
+            dest: "n0".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                payload: Payload::Workload(TxnPayload::Replicate {
+                    appends: vec![(1, 99)],
+                    stamp: stamp(5, "n1"),
+                    seq: 0,
+                    clock: None,
+                }),
+            },
+        };
+
+        // The replicated write arrives after the txn locked key 1, between its reads.
+        let txn = vec![read(1, None), write(2, 5), read(1, None)];
+        let (seen, handled) = interleave(node, txn, node.handle(Event::Message(replicate))).await;
+        handled.expect("replicate is applied");
+        let register = |value| Some(Value::Register(value));
+        assert_eq!(
+            seen,
+            [read(1, register(1)), write(2, 5), read(1, register(1))]
+        );
+        let (seen, _) = node
+            .run(vec![read(1, None)], &stamp(6, "n0"))
+            .await
+            .expect("txn commits");
+        assert_eq!(seen, [read(1, register(99))]);
+        let ack = harness.recv().await;
+        assert!(
+            matches!(
+                ack.body.payload,
+                WithKV::Workload(TxnPayload::ReplicateOk { .. })
+            ),
+            "{:?}",
+            ack
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_become_readable_at_other_nodes() {
+        let mut cluster = cluster(&[]).await;
+        // The txn is acknowledged without waiting for n1, which cannot hear from n0.
+        cluster.set_hold_partitioned(true);
+        cluster.partition(&["n0"], &["n1"]);
+        run(&mut cluster, "n0", vec![write(1, 5)])
+            .await
+            .expect("txn commits");
+        assert_eq!(read_keys(&mut cluster, "n1", &[1]).await, [None]);
+
+        cluster.heal();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            read_keys(&mut cluster, "n1", &[1]).await,
+            [Some(Value::Register(5))]
+        );
+        let replicated = cluster
+            .trace()
+            .into_iter()
+            .any(|delivery| delivery.to == "n1" && delivery.line.contains("replicate_batch"));
+        assert!(replicated, "n0 never replicated to n1");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_read_during_a_replicate_sees_all_or_none_of_it() {
+        let harness = Harness::<TxnNode, Payload, InjectedPayload>::with_env(
+            "n0",
+            &["n0", "n1"],
+            &[("TXN_WORKLOAD", Some("list-append"))],
+        )
+        .await;
+        let node = harness.node();
+        let shard = |key| &node.storage.shards[Storage::shard_of(key)];
+        let reads = || vec![read(1, None), read(2, None)];
+        let replicate = Message {
+            src: "n1".to_string(),
+            dest: "n0".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                payload: Payload::Workload(TxnPayload::Replicate {
+                    appends: vec![(1, 10), (2, 20)],
+                    stamp: stamp(1, "n1"),
+                    seq: 0,
+                    clock: None,
+                }),
+            },
+        };
+
+        // The replicated appends have been received and key 1 is locked for them,
+        // but key 2 is not, when the read comes in.
+        let (held, shard_held) = oneshot::channel();
+        let hold = async {
+            let guard = shard(2).write().await;
+            held.send(()).expect("replicate waits");
+            while shard(1).try_write().is_ok() {
+                tokio::task::yield_now().await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        };
+        let replicated = async {
+            shard_held.await.expect("shard held");
+            node.handle(Event::Message(replicate)).await
+        };
+        let reading = async {
+            while shard(1).try_write().is_ok() {
+                tokio::task::yield_now().await;
+            }
+            node.run(reads(), &stamp(2, "n0")).await
+        };
+        let ((), replicated, seen) = tokio::join!(hold, replicated, reading);
+        replicated.expect("replicate is applied");
+        let (seen, _) = seen.expect("txn commits");
+        assert_eq!(seen, [read(1, elements(&[10])), read(2, elements(&[20]))]);
+    }
+
+    #[test]
+    fn keys_and_values_beyond_u32_round_trip() {
+        let big = u32::MAX as i64 + 1;
+        round_trip(
+            &format!(
+                r#"[["w",{},-7],["w",1,{}],["r",{},{}],["append",{},{}]]"#,
+                u64::MAX,
+                big,
+                u32::MAX,
+                i64::MIN,
+                u64::MAX,
+                i64::MAX
+            ),
+            vec![
+                write(u64::MAX, -7),
+                write(1, big),
+                read(u32::MAX as u64, Some(Value::Register(i64::MIN))),
+                append(u64::MAX, i64::MAX),
+            ],
+        );
+        // What fit before still reads the same.
+        round_trip(
+            r#"[["w",4294967295,4294967295],["r",0,0]]"#,
+            vec![
+                write(u32::MAX as u64, u32::MAX as i64),
+                read(0, Some(Value::Register(0))),
+            ],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn large_and_negative_values_are_replicated() {
+        let mut cluster = cluster(&[("TXN_WORKLOAD", Some("list-append"))]).await;
+        let key = u64::MAX;
+        let appended = [-1, u32::MAX as i64 + 1, i64::MIN];
+        for element in appended {
+            run(&mut cluster, "n0", vec![append(key, element)])
+                .await
+                .expect("txn commits");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            read_keys(&mut cluster, "n1", &[key]).await,
+            [elements(&appended)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn txns_on_other_shards_do_not_wait() {
+        let harness = Harness::<TxnNode, Payload, InjectedPayload>::new("n0", &["n0"]).await;
+        let node = harness.node();
+        let guard = node.storage.shards[Storage::shard_of(1)].write().await;
+        let wait = Duration::from_millis(100);
+        // Key 2 is on another shard, key 17 on that of key 1.
+        let (first, second) = (stamp(1, "n0"), stamp(2, "n0"));
+        let other = node.run(vec![write(2, 20)], &first);
+        assert!(
+            tokio::time::timeout(wait, other).await.is_ok(),
+            "txn on another shard waited"
+        );
+        let same = node.run(vec![write(17, 170)], &second);
+        assert!(
+            tokio::time::timeout(wait, same).await.is_err(),
+            "txn on a locked shard went ahead"
+        );
+        drop(guard);
+        let (seen, _) = node
+            .run(vec![read(2, None), read(17, None)], &stamp(3, "n0"))
+            .await
+            .expect("txn commits");
+        assert_eq!(seen, [read(2, Some(Value::Register(20))), read(17, None)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_txns_on_shared_keys_apply_whole() {
+        let harness = Harness::<TxnNode, Payload, InjectedPayload>::with_env(
+            "n0",
+            &["n0"],
+            &[("TXN_WORKLOAD", Some("list-append"))],
+        )
+        .await;
+        let node = harness.node();
+        // Keys 1 and 2 are on different shards, 1 and 17 on the same one.
+        let txns = (0..50).map(|i| {
+            let txn = vec![append(1, i), append(2, i), append(17, i)];
+            async move { node.run(txn, &stamp(i as u64 + 1, "n0")).await }
+        });
+        for ran in futures::future::join_all(txns).await {
+            ran.expect("txn commits");
+        }
+        let (seen, _) = node
+            .run(
+                vec![read(1, None), read(2, None), read(17, None)],
+                &stamp(100, "n0"),
+            )
+            .await
+            .expect("txn commits");
+        let lists: Vec<&[i64]> = seen
+            .iter()
+            .map(|op| match op {
+                Op::Read {
+                    value: Some(value), ..
+                } => values(value),
+                op => panic!("expected a list, got {:?}", op),
+            })
+            .collect();
+        assert_eq!(lists[0].len(), 50);
+        assert!(lists.iter().all(|list| *list == lists[0]), "{:?}", lists);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn txns_lost_in_a_partition_are_retried_until_applied_once() {
+        let mut cluster = cluster(&[("TXN_WORKLOAD", Some("list-append"))]).await;
+        cluster.partition(&["n0"], &["n1"]);
+        for element in 0..10 {
+            run(&mut cluster, "n0", vec![append(1, element)])
+                .await
+                .expect("txn commits");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        cluster.heal();
+        tokio::time::sleep(MAX_RETRANSMIT_BACKOFF * 2).await;
+
+        // Retransmits can outlast the reorder buffer, so only the multiset
+        // of elements is certain: each append arrives, and arrives once.
+        let [Some(list)] = &read_keys(&mut cluster, "n1", &[1]).await[..] else {
+            panic!("n1 has no list for key 1");
+        };
+        let mut seen = values(list).to_vec();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..10).collect::<Vec<_>>());
+        let batches = |fate: Fate| {
+            cluster
+                .trace()
+                .into_iter()
+                .filter(|delivery| delivery.to == "n1" && delivery.fate == fate)
+                .filter(|delivery| delivery.line.contains("replicate_batch"))
+                .count()
+        };
+        assert!(batches(Fate::Dropped) > 0);
+        assert!(batches(Fate::Delivered) > 0);
+    }
+
+    /// A register write of a key and value, with the stamp its node gave it.
+    type StampedWrite = (u64, i64, Stamp);
+
+    /// Register writes by `NODES.len()` nodes, along with the order every node
+    /// receives all of them in.
+    fn concurrent_writes() -> impl Strategy<Value = (Vec<StampedWrite>, Vec<Vec<usize>>)> {
+        let write = (0..NODES.len(), 0..4u64, any::<i64>(), 0..3u64);
+        prop::collection::vec(write, 1..30).prop_flat_map(|writes| {
+            let mut clocks = [0; NODES.len()];
+            let writes: Vec<_> = writes
+                .into_iter()
+                .map(|(node, key, value, skew)| {
+                    clocks[node] += 1 + skew;
+                    (key, value, stamp(clocks[node], NODES[node]))
+                })
+                .collect();
+            let order = Just((0..writes.len()).collect::<Vec<_>>()).prop_shuffle();
+            (Just(writes), prop::collection::vec(order, NODES.len()))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_register_writes_converge((writes, orders) in concurrent_writes()) {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .expect("build runtime");
+            let snapshots: Vec<_> = runtime.block_on(async {
+                let mut snapshots = Vec::new();
+                for order in &orders {
+                    let storage = Storage::new(Workload::RwRegister);
+                    for &i in order {
+                        let (key, value, stamp) = &writes[i];
+                        storage.apply_replicated(&[(*key, *value)], stamp).await;
+                    }
+                    snapshots.push(storage.snapshot().await);
+                }
+                snapshots
+            });
+            for snapshot in &snapshots[1..] {
+                prop_assert_eq!(snapshot, &snapshots[0]);
+            }
+            for (key, _, _) in &writes {
+                let newest = writes.iter().filter(|(k, ..)| k == key).max_by_key(|(.., s)| s);
+                let (_, value, stamp) = newest.expect("a write of the key");
+                prop_assert!(snapshots[0].contains(&(*key, stamp.clone(), vec![*value])));
+            }
+        }
+    }
+
+    type TxnHarness = Harness<TxnNode, Payload, InjectedPayload>;
+
+    /// Passes the anti-entropy and sync messages `from` sent on to `to`, dropping
+    /// everything else. Returns whether there were any.
+    async fn pass_anti_entropy(from: &mut TxnHarness, to: &mut TxnHarness) -> bool {
+        let mut passed = false;
+        for message in from.drain().await {
+            if let Payload::Workload(
+                TxnPayload::AntiEntropy { .. }
+                | TxnPayload::AntiEntropyOk { .. }
+                | TxnPayload::Sync { .. }
+                | TxnPayload::SyncOk { .. },
+            ) = message.body.payload
+            {
+                to.send_line(&serde_json::to_string(&message).expect("serialize message"))
+                    .await;
+                passed = true;
+            }
+        }
+        passed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn anti_entropy_catches_up_a_wiped_node() {
+        let env = [("TXN_WORKLOAD", Some("list-append"))];
+        let mut h0 = TxnHarness::with_env("n0", &NODES[..2], &env).await;
+        for time in 1..=20 {
+            let txn = vec![
+                append(time % 5, time as i64),
+                append(time + 20, time as i64),
+            ];
+            h0.node()
+                .run(txn, &stamp(time, "n0"))
+                .await
+                .expect("txn commits");
+        }
+        // n1 lost everything but a txn of its own that n0 never heard of.
+        let mut h1 = TxnHarness::with_env("n1", &NODES[..2], &env).await;
+        h1.node()
+            .run(vec![append(50, 99)], &stamp(30, "n1"))
+            .await
+            .expect("txn commits");
+
+        for round in 0..3 {
+            if round % 2 == 0 {
+                h1.inject(InjectedPayload::AntiEntropy).await;
+            } else {
+                h0.inject(InjectedPayload::AntiEntropy).await;
+            }
+            while pass_anti_entropy(&mut h0, &mut h1).await
+                | pass_anti_entropy(&mut h1, &mut h0).await
+            {}
+        }
+
+        let snapshot = h0.node().storage.snapshot().await;
+        assert_eq!(snapshot.len(), 41);
+        assert_eq!(h1.node().storage.snapshot().await, snapshot);
+    }
+
+    async fn strict_cluster() -> Cluster {
+        Cluster::builder()
+            .nodes::<TxnNode, Payload, InjectedPayload>(&NODES)
+            .env(&[("TXN_STRICT", Some("true"))])
+            .start()
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_writes_are_read_at_once_everywhere() {
+        let mut cluster = strict_cluster().await;
+        run(&mut cluster, "n0", vec![write(1, 10), write(2, 20)])
+            .await
+            .expect("txn commits");
+        for node in ["n1", "n2"] {
+            let register = |value| Some(Value::Register(value));
+            assert_eq!(
+                read_keys(&mut cluster, node, &[1, 2]).await,
+                [register(10), register(20)]
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_txns_cut_off_from_a_majority_are_refused() {
+        let mut cluster = strict_cluster().await;
+        cluster.partition(&["n0"], &["n1", "n2"]);
+        assert_eq!(
+            run(&mut cluster, "n0", vec![write(1, 10)]).await,
+            Err(ErrorCode::TemporarilyUnavailable.code())
+        );
+        cluster.heal();
+        assert_eq!(read_keys(&mut cluster, "n1", &[1]).await, [None]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_txns_without_a_quorum_of_acks_time_out() {
+        let mut cluster = strict_cluster().await;
+        // The read repair goes through, but the peers' replies arrive after they are
+        // cut off, so the appends never reach them.
+        let latency = Duration::from_millis(10);
+        cluster.set_latency("n1", "n0", latency);
+        cluster.set_latency("n2", "n0", latency);
+        let id = cluster.send(
+            "c1",
+            "n0",
+            Payload::Workload(TxnPayload::Txn {
+                txn: vec![write(1, 10)],
+            }),
+        );
+        tokio::time::sleep(latency / 2).await;
+        cluster.partition(&["n0"], &["n1", "n2"]);
+        match cluster.expect_reply_to::<Payload>(id).await.body.payload {
+            WithKV::Workload(TxnPayload::Error { code, .. }) => {
+                assert_eq!(code, ErrorCode::Timeout.code())
+            }
+            payload => panic!("expected an error, got {:?}", payload),
+        }
+    }
+
+    /// The lines exchanged with n0 while it runs a few txns and replicates them.
+    async fn transcript(vars: &[(&str, Option<&str>)]) -> Vec<String> {
+        let mut harness = TxnHarness::with_env("n0", &NODES[..2], vars).await;
+        for (key, value) in [(1, 10), (2, 20), (1, 11)] {
+            let txn = vec![read(key, None), write(key, value)];
+            let id = harness
+                .send("c1", Payload::Workload(TxnPayload::Txn { txn }))
+                .await;
+            harness.expect_reply_to(id).await;
+        }
+        harness.advance(Duration::from_secs(1)).await;
+        harness.transcript().to_vec()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_mode_off_is_the_default() {
+        let default = transcript(&[("TXN_STRICT", None)]).await;
+        assert_eq!(transcript(&[("TXN_STRICT", Some("false"))]).await, default);
+        assert_ne!(transcript(&[("TXN_STRICT", Some("true"))]).await, default);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stats_count_a_scripted_run_exactly() {
+        let mut harness = TxnHarness::new("n0", &NODES).await;
+        let txns = [
+            vec![write(1, 10)],
+            vec![write(2, 20)],
+            vec![read(1, None), write(1, 11)],
+            vec![read(2, None)],
+        ];
+        for txn in txns {
+            let id = harness
+                .send("c1", Payload::Workload(TxnPayload::Txn { txn }))
+                .await;
+            harness.expect_reply_to(id).await;
+        }
+        // The three writing txns go to both peers in one batch each, which they
+        // acknowledge.
+        for message in harness
+            .advance(Duration::from_millis(DEFAULT_BATCH_MS))
+            .await
+        {
+            let Payload::Workload(TxnPayload::ReplicateBatch { txns }) = message.body.payload
+            else {
+                panic!("expected a replicate batch, got {:?}", message.body.payload);
+            };
+            let stamps = txns.into_iter().map(|txn| txn.stamp).collect();
+            harness
+                .send(
+                    &message.dest,
+                    Payload::Workload(TxnPayload::ReplicateBatchOk { stamps }),
+                )
+                .await;
+        }
+        // n1 sends its first txn twice, then its third, whose second never comes.
+        for (seq, value) in [(0, 30), (0, 30), (2, 32)] {
+            let appends = vec![(3, value)];
+            let stamp = stamp(100 + seq, "n1");
+            let clock = None;
+            let replicate = TxnPayload::Replicate {
+                appends,
+                stamp,
+                seq,
+                clock,
+            };
+            harness.send("n1", Payload::Workload(replicate)).await;
+        }
+        harness.advance(REORDER_TIMEOUT + REORDER_PERIOD).await;
+        harness.inject(InjectedPayload::AntiEntropy).await;
+        harness.drain().await;
+
+        let node = harness.node();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let stats = &node.stats;
+        assert_eq!(
+            [
+                load(&stats.committed),
+                load(&stats.aborted_lock_timeout),
+                load(&stats.refused_unavailable),
+                load(&stats.unconfirmed),
+                load(&stats.replicate_sent),
+                load(&stats.batches_sent),
+                load(&stats.replicate_received),
+                load(&stats.replicate_duplicate),
+                load(&stats.replicate_reordered),
+                load(&stats.replicate_concurrent),
+                load(&stats.reorder_gaps_skipped),
+                load(&stats.outbox_high_water),
+                load(&stats.rejected_key_limit),
+                load(&stats.evicted_keys),
+            ],
+            [4, 0, 0, 0, 6, 2, 3, 1, 1, 0, 1, 3, 0, 0]
+        );
+        assert_eq!(node.anti_entropy_rounds.load(Ordering::Relaxed), 1);
+    }
+
+    /// Starts n0 with checkpoints to `kv`, only taken when the test asks for one, and
+    /// lets it restore the last one.
+    async fn checkpointing(kv: &MockKvService) -> TxnHarness {
+        let mut harness = TxnHarness::builder("n0", &NODES[..2])
+            .env(&[
+                ("TXN_WORKLOAD", Some("list-append")),
+                ("TXN_CHECKPOINT", Some("true")),
+                ("TXN_CHECKPOINT_MS", Some("3600000")),
+            ])
+            .service(kv)
+            .start()
+            .await;
+        harness.drain().await;
+        harness
+    }
+
+    async fn commit(harness: &mut TxnHarness, txn: Vec<Op>) -> Vec<Op> {
+        let id = harness
+            .send("c1", Payload::Workload(TxnPayload::Txn { txn }))
+            .await;
+        match harness.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(TxnPayload::TxnOk { txn }) => txn,
+            payload => panic!("expected txn_ok, got {:?}", payload),
+        }
+    }
+
+    fn checkpoint_version(kv: &MockKvService) -> Option<u64> {
+        kv.get("txn:n0:snapshot")
+            .map(|checkpoint| checkpoint["version"].as_u64().expect("a version"))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_restarted_node_restores_its_checkpoint() {
+        let kv = MockKvService::lin(CHECKPOINT_STORAGE);
+        let mut before = checkpointing(&kv).await;
+        commit(&mut before, vec![append(1, 10), append(2, 20)]).await;
+        commit(&mut before, vec![append(1, 11)]).await;
+        before.node().checkpoint().await.expect("checkpoint");
+        assert_eq!(checkpoint_version(&kv), Some(1));
+        // Nothing changed, so there is nothing to write.
+        before.node().checkpoint().await.expect("checkpoint");
+        assert_eq!(kv.count_key(KvOp::Cas, "txn:n0:snapshot"), 1);
+        drop(before);
+
+        let mut after = checkpointing(&kv).await;
+        assert_eq!(
+            commit(&mut after, vec![read(1, None), read(2, None)]).await,
+            [read(1, elements(&[10, 11])), read(2, elements(&[20]))]
+        );
+        // It asks a peer for what happened since the checkpoint.
+        assert!(after
+            .transcript()
+            .iter()
+            .any(|line| { line.starts_with("< ") && line.contains(r#""type":"anti_entropy""#) }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stale_process_does_not_overwrite_a_newer_checkpoint() {
+        let kv = MockKvService::lin(CHECKPOINT_STORAGE);
+        let mut stale = checkpointing(&kv).await;
+        commit(&mut stale, vec![append(1, 10)]).await;
+        stale.node().checkpoint().await.expect("checkpoint");
+
+        // A second process of n0 starts from that checkpoint and writes a newer one.
+        let mut newer = checkpointing(&kv).await;
+        commit(&mut newer, vec![append(2, 20)]).await;
+        newer.node().checkpoint().await.expect("checkpoint");
+        assert_eq!(checkpoint_version(&kv), Some(2));
+
+        commit(&mut stale, vec![append(3, 30)]).await;
+        stale.node().checkpoint().await.expect("checkpoint");
+        assert_eq!(checkpoint_version(&kv), Some(2));
+        // It merged the newer checkpoint instead, and the next one holds both.
+        stale.node().checkpoint().await.expect("checkpoint");
+        assert_eq!(checkpoint_version(&kv), Some(3));
+        let restored = checkpointing(&kv).await;
+        let appends = restored.node().storage.snapshot().await;
+        let keys: Vec<_> = appends.iter().map(|(key, ..)| *key).collect();
+        assert_eq!(keys, [1, 2, 3]);
+    }
+
+    /// Starts n0, whose only peer n1 never answers, with `vars` set.
+    async fn limited(vars: &[(&str, Option<&str>)]) -> TxnHarness {
+        TxnHarness::with_env("n0", &NODES[..2], vars).await
+    }
+
+    async fn try_commit(harness: &mut TxnHarness, txn: Vec<Op>) -> Result<Vec<Op>, usize> {
+        let id = harness
+            .send("c1", Payload::Workload(TxnPayload::Txn { txn }))
+            .await;
+        match harness.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(TxnPayload::TxnOk { txn }) => std::result::Result::Ok(txn),
+            WithKV::Workload(TxnPayload::Error { code, .. }) => Err(code),
+            payload => panic!("expected txn_ok or an error, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn txns_creating_keys_beyond_the_limit_are_rejected() {
+        let mut harness = limited(&[("TXN_MAX_KEYS", Some("2"))]).await;
+        for key in [1, 2] {
+            assert!(try_commit(&mut harness, vec![write(key, 10)]).await.is_ok());
+        }
+        assert_eq!(
+            try_commit(&mut harness, vec![write(1, 11), write(3, 30)]).await,
+            Err(ErrorCode::Abort.code())
+        );
+        // Keys that exist can still be written.
+        assert!(try_commit(&mut harness, vec![write(2, 21)]).await.is_ok());
+        let register = |value| Some(Value::Register(value));
+        assert_eq!(
+            try_commit(
+                &mut harness,
+                vec![read(1, None), read(2, None), read(3, None)]
+            )
+            .await,
+            std::result::Result::Ok(vec![
+                read(1, register(10)),
+                read(2, register(21)),
+                read(3, None)
+            ])
+        );
+        assert_eq!(harness.node().storage.key_count(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_replicated_keys_are_evicted() {
+        let mut harness = limited(&[
+            ("TXN_MAX_KEYS", Some("2")),
+            ("TXN_KEY_LIMIT", Some("evict")),
+        ])
+        .await;
+        for key in [1, 2, 3] {
+            assert!(try_commit(&mut harness, vec![write(key, 10)]).await.is_ok());
+        }
+        // n1 has not acknowledged any of them, however long it takes.
+        let batches = harness.advance(Duration::from_secs(1)).await;
+        assert_eq!(harness.node().storage.key_count(), 3);
+        assert_eq!(harness.node().stats.evicted_keys.load(Ordering::Relaxed), 0);
+
+        let stamps = batches
+            .into_iter()
+            .flat_map(|message| match message.body.payload {
+                Payload::Workload(TxnPayload::ReplicateBatch { txns }) => txns,
+                payload => panic!("expected a replicate batch, got {:?}", payload),
+            })
+            .map(|txn| txn.stamp)
+            .collect();
+        harness
+            .send(
+                "n1",
+                Payload::Workload(TxnPayload::ReplicateBatchOk { stamps }),
+            )
+            .await;
+        harness.drain().await;
+        // The least recently written key goes.
+        assert_eq!(harness.node().storage.key_count(), 2);
+        let register = |value| Some(Value::Register(value));
+        assert_eq!(
+            try_commit(
+                &mut harness,
+                vec![read(1, None), read(2, None), read(3, None)]
+            )
+            .await,
+            std::result::Result::Ok(vec![
+                read(1, None),
+                read(2, register(10)),
+                read(3, register(10))
+            ])
+        );
+    }
+
+    /// Delivers the txn `seq` of `origin`, appending `element` to key 1 at `time`, and
+    /// returns the acknowledgements n0 sends for it.
+    async fn replicate(
+        harness: &mut TxnHarness,
+        origin: &str,
+        seq: u64,
+        time: u64,
+        element: i64,
+    ) -> Vec<Message<Payload>> {
+        let replicate = TxnPayload::Replicate {
+            appends: vec![(1, element)],
+            stamp: stamp(time, origin),
+            seq,
+            clock: None,
+        };
+        harness.send(origin, Payload::Workload(replicate)).await;
+        harness.drain().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn redelivered_txns_are_applied_once() {
+        let env = [("TXN_WORKLOAD", Some("list-append"))];
+        let mut harness = TxnHarness::with_env("n0", &NODES, &env).await;
+        let duplicates = |harness: &TxnHarness| {
+            let stats = &harness.node().stats;
+            stats.replicate_duplicate.load(Ordering::Relaxed)
+        };
+        for _ in 0..2 {
+            let acks = replicate(&mut harness, "n1", 0, 10, 10).await;
+            assert_eq!(acks.len(), 1, "every delivery is acknowledged");
+        }
+        assert_eq!(duplicates(&harness), 1);
+
+        // Once the dedup window forgot it, the stamp in the list still catches it.
+        harness.node().applied.lock().await.clear();
+        replicate(&mut harness, "n1", 0, 10, 10).await;
+        assert_eq!(duplicates(&harness), 2);
+
+        // Duplicates of two origins, interleaved.
+        for (origin, seq, time, element) in [
+            ("n2", 0, 11, 20),
+            ("n1", 1, 12, 11),
+            ("n2", 0, 11, 20),
+            ("n1", 1, 12, 11),
+            ("n2", 1, 13, 21),
+            ("n1", 1, 12, 11),
+        ] {
+            replicate(&mut harness, origin, seq, time, element).await;
+        }
+        assert_eq!(duplicates(&harness), 5);
+        let storage = &harness.node().storage;
+        assert_eq!(list(storage, 1).await, elements(&[10, 20, 11, 21]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replicated_txns_of_an_origin_apply_in_order() {
+        let env = [("TXN_WORKLOAD", Some("list-append"))];
+        let mut harness = TxnHarness::with_env("n0", &NODES, &env).await;
+        for (seq, element) in [(2, 12), (1, 11)] {
+            let acks = replicate(&mut harness, "n1", seq, seq + 1, element).await;
+            assert!(acks.is_empty(), "held txns are acknowledged once applied");
+            assert_eq!(list(&harness.node().storage, 1).await, None);
+        }
+        let acks = replicate(&mut harness, "n1", 0, 1, 10).await;
+        assert_eq!(acks.len(), 3);
+        assert_eq!(
+            list(&harness.node().storage, 1).await,
+            elements(&[10, 11, 12])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lost_replicated_txn_holds_up_later_ones_only_briefly() {
+        let env = [("TXN_WORKLOAD", Some("list-append"))];
+        let mut harness = TxnHarness::with_env("n0", &NODES, &env).await;
+        replicate(&mut harness, "n1", 0, 1, 10).await;
+        replicate(&mut harness, "n1", 2, 3, 12).await;
+        harness.advance(REORDER_TIMEOUT - REORDER_PERIOD).await;
+        assert_eq!(list(&harness.node().storage, 1).await, elements(&[10]));
+
+        harness.advance(REORDER_PERIOD * 2).await;
+        assert_eq!(list(&harness.node().storage, 1).await, elements(&[10, 12]));
+        let stats = &harness.node().stats;
+        assert_eq!(stats.reorder_gaps_skipped.load(Ordering::Relaxed), 1);
+    }
+
+    /// The txns of the replicate batches in `messages`, one Vec per batch.
+    fn batches(messages: Vec<Message<Payload>>) -> Vec<Vec<ReplicatedTxn>> {
+        messages
+            .into_iter()
+            .filter_map(|message| match message.body.payload {
+                Payload::Workload(TxnPayload::ReplicateBatch { txns }) => Some(txns),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replication_is_batched_and_acknowledged_per_batch() {
+        let env = [("TXN_BATCH_SIZE", Some("4"))];
+        let mut harness = TxnHarness::with_env("n0", &NODES[..2], &env).await;
+        for value in 0..10 {
+            commit(&mut harness, vec![write(value as u64, value)]).await;
+        }
+        let sent = batches(
+            harness
+                .advance(Duration::from_millis(DEFAULT_BATCH_MS))
+                .await,
+        );
+        let sizes: Vec<_> = sent.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [4, 4, 2]);
+        let stamps: BTreeSet<_> = sent.iter().flatten().map(|txn| &txn.stamp).collect();
+        assert_eq!(stamps.len(), 10, "every txn is replicated once");
+
+        // Only the first batch is acknowledged, so only the others are sent again.
+        let stamps = sent[0].iter().map(|txn| txn.stamp.clone()).collect();
+        harness
+            .send(
+                "n1",
+                Payload::Workload(TxnPayload::ReplicateBatchOk { stamps }),
+            )
+            .await;
+        let resent = batches(harness.advance(RETRANSMIT_BACKOFF * 2).await);
+        let resent: BTreeSet<_> = resent.iter().flatten().map(|txn| &txn.stamp).collect();
+        let unacked: BTreeSet<_> = sent[1..].iter().flatten().map(|txn| &txn.stamp).collect();
+        assert_eq!(resent, unacked);
+
+        let stamps = sent[1..]
+            .iter()
+            .flatten()
+            .map(|txn| txn.stamp.clone())
+            .collect();
+        harness
+            .send(
+                "n1",
+                Payload::Workload(TxnPayload::ReplicateBatchOk { stamps }),
+            )
+            .await;
+        assert!(batches(harness.advance(MAX_RETRANSMIT_BACKOFF * 2).await).is_empty());
+    }
+
+    /// Answers `request`, which n0 sent to a peer, with `payload`.
+    async fn answer(harness: &mut TxnHarness, request: &Message<Payload>, payload: TxnPayload) {
+        let reply = Message {
+            src: request.dest.clone(),
+            dest: request.src.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                payload: Payload::Workload(payload),
+            },
+        };
+        harness
+            .send_line(&serde_json::to_string(&reply).expect("serialize reply"))
+            .await;
+    }
+
+    /// The bucket of the slowest part timed in `histogram`.
+    fn slowest(histogram: &Histogram) -> Option<usize> {
+        histogram
+            .buckets
+            .iter()
+            .rposition(|counter| counter.load(Ordering::Relaxed) > 0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_peers_show_up_as_quorum_wait() {
+        let env = [("TXN_STRICT", Some("true")), ("TXN_TIMINGS", Some("true"))];
+        let mut harness = TxnHarness::with_env("n0", &NODES, &env).await;
+        let id = harness
+            .send(
+                "c1",
+                Payload::Workload(TxnPayload::Txn {
+                    txn: vec![write(1, 10)],
+                }),
+            )
+            .await;
+        // The peers are quick to help with the read repair, but slow to acknowledge.
+        for _ in 1..NODES.len() {
+            let request = harness.recv().await;
+            let digest = Vec::new();
+            let appends = Vec::new();
+            answer(
+                &mut harness,
+                &request,
+                TxnPayload::AntiEntropyOk { digest, appends },
+            )
+            .await;
+        }
+        let replicates = [harness.recv().await, harness.recv().await];
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for request in &replicates {
+            let Payload::Workload(TxnPayload::Replicate { stamp, .. }) = &request.body.payload
+            else {
+                panic!("expected a replicate, got {:?}", request.body.payload);
+            };
+            let stamp = stamp.clone();
+            answer(&mut harness, request, TxnPayload::ReplicateOk { stamp }).await;
+        }
+        let reply = harness.expect_reply_to(id).await;
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Workload(TxnPayload::TxnOk { .. })
+        ));
+
+        let timings = harness.node().timings.as_ref().expect("txns are timed");
+        let quorum_wait = slowest(&timings.quorum_wait).expect("the quorum wait is timed");
+        for other in [&timings.lock_wait, &timings.apply, &timings.read_repair] {
+            assert!(slowest(other) < Some(quorum_wait));
+        }
+    }
+
+    /// How a txn of a history ended.
+    #[derive(Debug, Clone)]
+    enum Outcome {
+        /// Answered with txn_ok and these ops, reads filled in.
+        Committed(Vec<Op>),
+        /// Answered with an error Maelstrom takes as definite, so it took no effect.
+        Failed(usize),
+        /// Never answered, or with an indefinite error, so it may or may not have
+        /// taken effect.
+        Unknown,
+    }
+
+    /// A txn of a history: the ops a client requested and how it ended.
+    #[derive(Debug, Clone)]
+    struct Txn {
+        ops: Vec<Op>,
+        outcome: Outcome,
+    }
+
+    impl Txn {
+        /// The values the txn wrote or appended, by key.
+        fn writes(&self) -> impl Iterator<Item = (u64, i64)> + '_ {
+            self.ops.iter().filter_map(|op| match op {
+                Op::Write {
+                    key,
+                    value: Some(value),
+                } => Some((*key, *value)),
+                Op::Append { key, element } => Some((*key, *element)),
+                _ => None,
+            })
+        }
+
+        /// The reads of the txn if it committed, with the values they returned.
+        fn reads(&self) -> impl Iterator<Item = (u64, &Value)> + '_ {
+            let ops = match &self.outcome {
+                Outcome::Committed(ops) => ops.as_slice(),
+                _ => &[],
+            };
+            ops.iter().filter_map(|op| match op {
+                Op::Read {
+                    key,
+                    value: Some(value),
+                } => Some((*key, value)),
+                _ => None,
+            })
+        }
+    }
+
+    fn values(value: &Value) -> &[i64] {
+        match value {
+            Value::Register(value) => std::slice::from_ref(value),
+            Value::List(elements) => elements,
+        }
+    }
+
+    /// Finds the txn that wrote every value, by key. The checks rely on no two txns
+    /// writing the same value to a key, as Maelstrom's generators ensure.
+    fn writers(history: &[Txn]) -> Result<HashMap<(u64, i64), usize>, String> {
+        let mut writers = HashMap::new();
+        for (i, txn) in history.iter().enumerate() {
+            for write in txn.writes() {
+                if let Some(other) = writers.insert(write, i) {
+                    if other != i {
+                        return Err(format!(
+                            "{:?} and {:?} both wrote {} to {}",
+                            history[other], txn, write.1, write.0
+                        ));
+                    }
+                }
+            }
+        }
+        std::result::Result::Ok(writers)
+    }
+
+    /// Checks `history` for aborted reads (G1a): no committed txn may read a value
+    /// written by a txn that failed. Reads of values nobody wrote are reported too.
+    fn check_g1a(history: &[Txn]) -> Result<(), String> {
+        let writers = writers(history)?;
+        for txn in history {
+            for (key, value) in txn.reads() {
+                for &value in values(value) {
+                    let Some(&writer) = writers.get(&(key, value)) else {
+                        return Err(format!(
+                            "{:?} read {} from {}, which no txn wrote",
+                            txn, value, key
+                        ));
+                    };
+                    if let Outcome::Failed(code) = history[writer].outcome {
+                        return Err(format!(
+                            "G1a: {:?} read {} from {}, written by {:?}, which failed with code {}",
+                            txn, value, key, history[writer].ops, code
+                        ));
+                    }
+                }
+            }
+        }
+        std::result::Result::Ok(())
+    }
+
+    /// Pairs of values of every key, the first of which was installed before the
+    /// second. For lists this is the order of their elements, which every read has to
+    /// agree with. For registers it is only known where a committed txn read a value
+    /// and then overwrote it.
+    fn version_order(history: &[Txn]) -> Result<Vec<(u64, i64, i64)>, String> {
+        let mut longest: BTreeMap<u64, &[i64]> = BTreeMap::new();
+        let mut order = Vec::new();
+        for txn in history {
+            let Outcome::Committed(ops) = &txn.outcome else {
+                continue;
+            };
+            let mut read: HashMap<u64, i64> = HashMap::new();
+            let mut written: HashMap<u64, i64> = HashMap::new();
+            for op in ops {
+                match op {
+                    Op::Read {
+                        key,
+                        value: Some(Value::List(elements)),
+                    } => {
+                        let seen = longest.entry(*key).or_default();
+                        let (shorter, longer) = if seen.len() < elements.len() {
+                            (*seen, elements.as_slice())
+                        } else {
+                            (elements.as_slice(), *seen)
+                        };
+                        if !longer.starts_with(shorter) {
+                            return Err(format!(
+                                "reads of {} disagree on its order: {:?} and {:?}",
+                                key, shorter, longer
+                            ));
+                        }
+                        *seen = longer;
+                    }
+                    Op::Read {
+                        key,
+                        value: Some(Value::Register(value)),
+                    } if !written.contains_key(key) => {
+                        read.entry(*key).or_insert(*value);
+                    }
+                    Op::Write {
+                        key,
+                        value: Some(value),
+                    } => {
+                        written.insert(*key, *value);
+                    }
+                    _ => {}
+                }
+            }
+            for (key, value) in written {
+                if let Some(&before) = read.get(&key) {
+                    order.push((key, before, value));
+                }
+            }
+        }
+        for (key, elements) in longest {
+            for pair in elements.windows(2) {
+                order.push((key, pair[0], pair[1]));
+            }
+        }
+        std::result::Result::Ok(order)
+    }
+
+    /// Checks `history` for write cycles (G0): txns that each installed a version of
+    /// some key right before one of the other.
+    fn check_g0(history: &[Txn]) -> Result<(), String> {
+        let writers = writers(history)?;
+        let mut edges: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        for (key, before, after) in version_order(history)? {
+            if let (Some(&from), Some(&to)) =
+                (writers.get(&(key, before)), writers.get(&(key, after)))
+            {
+                if from != to {
+                    edges.entry(from).or_default().insert(to);
+                }
+            }
+        }
+        // A depth-first search, which meets a txn still on its path once it went round
+        // a cycle.
+        let mut done = HashSet::new();
+        for &start in edges.keys() {
+            let mut path = vec![start];
+            let mut next = vec![edges[&start].iter()];
+            while let Some(successors) = next.last_mut() {
+                let Some(&txn) = successors.next() else {
+                    done.insert(path.pop().expect("path as long as next"));
+                    next.pop();
+                    continue;
+                };
+                if let Some(i) = path.iter().position(|on_path| *on_path == txn) {
+                    let cycle: Vec<_> = path[i..].iter().map(|i| &history[*i].ops).collect();
+                    return Err(format!("G0: write cycle between {:?}", cycle));
+                }
+                if done.contains(&txn) {
+                    continue;
+                }
+                path.push(txn);
+                next.push(edges.get(&txn).map(|e| e.iter()).unwrap_or_default());
+            }
+        }
+        std::result::Result::Ok(())
+    }
+
+    fn read(key: u64, value: Option<Value>) -> Op {
+        Op::Read { key, value }
+    }
+
+    fn write(key: u64, value: i64) -> Op {
+        Op::Write {
+            key,
+            value: Some(value),
+        }
+    }
+
+    fn append(key: u64, element: i64) -> Op {
+        Op::Append { key, element }
+    }
+
+    /// A txn that committed with `ops`, requested with their reads left empty.
+    fn committed(ops: Vec<Op>) -> Txn {
+        Txn {
+            ops: ops
+                .iter()
+                .map(|op| match op {
+                    Op::Read { key, .. } => read(*key, None),
+                    op => op.clone(),
+                })
+                .collect(),
+            outcome: Outcome::Committed(ops),
+        }
+    }
+
+    fn ended(ops: Vec<Op>, outcome: Outcome) -> Txn {
+        Txn { ops, outcome }
+    }
+
+    #[test]
+    fn aborted_reads_are_g1a() {
+        let register = |value| Some(Value::Register(value));
+        let valid = [
+            committed(vec![write(1, 1)]),
+            ended(vec![write(1, 2)], Outcome::Failed(30)),
+            // A txn that timed out may still have taken effect.
+            ended(vec![write(1, 3)], Outcome::Unknown),
+            committed(vec![read(1, register(1)), read(2, None)]),
+            committed(vec![read(1, register(3))]),
+        ];
+        assert_eq!(check_g1a(&valid), std::result::Result::Ok(()));
+
+        let violating = [
+            committed(vec![append(1, 1)]),
+            ended(vec![append(1, 2), append(2, 1)], Outcome::Failed(14)),
+            committed(vec![read(1, elements(&[1])), read(2, elements(&[1]))]),
+        ];
+        let err = check_g1a(&violating).expect_err("read of an aborted append");
+        assert!(err.starts_with("G1a:"), "{}", err);
+
+        let garbage = [committed(vec![read(1, register(9))])];
+        assert!(check_g1a(&garbage).is_err());
+    }
+
+    #[test]
+    fn write_cycles_are_g0() {
+        let valid = [
+            committed(vec![append(1, 1), append(2, 1)]),
+            committed(vec![append(1, 2), append(2, 2)]),
+            committed(vec![read(1, elements(&[1, 2])), read(2, elements(&[1]))]),
+            committed(vec![read(2, elements(&[1, 2]))]),
+        ];
+        assert_eq!(check_g0(&valid), std::result::Result::Ok(()));
+
+        // Each txn overwrote the other's version of one of the keys.
+        let violating = [
+            committed(vec![append(1, 1), append(2, 1)]),
+            committed(vec![append(1, 2), append(2, 2)]),
+            committed(vec![read(1, elements(&[1, 2])), read(2, elements(&[2, 1]))]),
+        ];
+        let err = check_g0(&violating).expect_err("write cycle");
+        assert!(err.starts_with("G0:"), "{}", err);
+
+        let register = |value| Some(Value::Register(value));
+        let valid = [
+            committed(vec![write(1, 1), write(2, 1)]),
+            committed(vec![read(1, register(1)), write(1, 2), write(2, 2)]),
+            committed(vec![read(2, register(2)), write(2, 3)]),
+        ];
+        assert_eq!(check_g0(&valid), std::result::Result::Ok(()));
+
+        let violating = [
+            committed(vec![read(2, register(2)), write(1, 1), write(2, 1)]),
+            committed(vec![read(1, register(1)), write(1, 2), write(2, 2)]),
+        ];
+        let err = check_g0(&violating).expect_err("write cycle");
+        assert!(err.starts_with("G0:"), "{}", err);
+    }
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    const KEYS: [u64; 4] = [0, 1, 2, 3];
+
+    /// A txn of one to three random ops, whose writes never repeat a value. Register
+    /// writes often read the key first, which tells the checks what they overwrote,
+    /// and now and then have no value, which fails the txn.
+    fn random_txn(rng: &mut Rng, workload: Workload, next_value: &mut i64) -> Vec<Op> {
+        let mut txn = Vec::new();
+        for _ in 0..=rng.below(3) {
+            let key = KEYS[rng.below(KEYS.len() as u64) as usize];
+            if rng.chance(0.4) {
+                txn.push(read(key, None));
+                continue;
+            }
+            *next_value += 1;
+            match workload {
+                Workload::RwRegister if rng.chance(0.05) => {
+                    txn.push(Op::Write { key, value: None })
+                }
+                Workload::RwRegister => {
+                    if rng.chance(0.5) {
+                        txn.push(read(key, None));
+                    }
+                    txn.push(write(key, *next_value));
+                }
+                Workload::ListAppend => txn.push(append(key, *next_value)),
+            }
+        }
+        txn
+    }
+
+    /// Has clients send random txns to the nodes of a cluster while it is partitioned
+    /// every so often, and returns the history of them along with final reads of every
+    /// key at every node. Only three keys fit a node, so txns creating a fourth fail.
+    async fn random_history(seed: u64, workload: Workload) -> Vec<Txn> {
+        let mut rng = Rng::new(seed);
+        let name = match workload {
+            Workload::RwRegister => "rw-register",
+            Workload::ListAppend => "list-append",
+        };
+        let mut cluster = Cluster::builder()
+            .nodes::<TxnNode, Payload, InjectedPayload>(&NODES)
+            .env(&[("TXN_WORKLOAD", Some(name)), ("TXN_MAX_KEYS", Some("3"))])
+            .seed(seed)
+            .start()
+            .await;
+        cluster.set_jitter(Duration::from_millis(20));
+        let mut next_value = 0;
+        for i in 0..40 {
+            if i % 10 == 0 {
+                let node = NODES[rng.below(NODES.len() as u64) as usize];
+                let others: Vec<_> = NODES.into_iter().filter(|n| *n != node).collect();
+                match rng.below(3) {
+                    0 => cluster.heal(),
+                    _ => cluster.partition(&[node], &others),
+                }
+            }
+            let client = format!("c{}", rng.below(4));
+            let node = NODES[rng.below(NODES.len() as u64) as usize];
+            let txn = random_txn(&mut rng, workload, &mut next_value);
+            cluster.send(&client, node, Payload::Workload(TxnPayload::Txn { txn }));
+            tokio::time::sleep(Duration::from_millis(rng.below(10))).await;
+        }
+        cluster.heal();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        cluster.drain::<Payload>().await;
+        for node in NODES {
+            read_keys(&mut cluster, node, &KEYS).await;
+        }
+        client_operations(&cluster.trace())
+            .into_iter()
+            .map(|operation| {
+                let txn = match serde_json::from_value(operation.input).expect("parse txn") {
+                    TxnPayload::Txn { txn } => txn,
+                    payload => panic!("expected a txn, got {:?}", payload),
+                };
+                let outcome = match operation.output.map(serde_json::from_value) {
+                    Some(std::result::Result::Ok(TxnPayload::TxnOk { txn })) => {
+                        Outcome::Committed(txn)
+                    }
+                    Some(std::result::Result::Ok(TxnPayload::Error { code, .. }))
+                        if ![ErrorCode::Timeout, ErrorCode::Crash]
+                            .contains(&ErrorCode::from_code(code)) =>
+                    {
+                        Outcome::Failed(code)
+                    }
+                    _ => Outcome::Unknown,
+                };
+                ended(txn, outcome)
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_histories_have_no_g0_or_g1a() {
+        let mut failed = 0;
+        for seed in 0..8 {
+            for workload in [Workload::RwRegister, Workload::ListAppend] {
+                let history = random_history(seed, workload).await;
+                failed += history
+                    .iter()
+                    .filter(|txn| matches!(txn.outcome, Outcome::Failed(_)))
+                    .count();
+                if let Err(err) = check_g1a(&history) {
+                    panic!("seed {} {:?}: {}", seed, workload, err);
+                }
+                // Nodes append concurrent txns to a list in the order they arrive, so
+                // lists only agree on the order of appends from a single node. Registers
+                // keep the write with the highest stamp everywhere.
+                if workload == Workload::RwRegister {
+                    if let Err(err) = check_g0(&history) {
+                        panic!("seed {}: {}", seed, err);
+                    }
+                }
+            }
+        }
+        assert!(failed > 0, "no txn failed, so G1a went unchecked");
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let value = prop_oneof![
+            any::<i64>().prop_map(Value::Register),
+            prop::collection::vec(any::<i64>(), 0..4).prop_map(Value::List),
+        ];
+        prop_oneof![
+            (any::<u64>(), proptest::option::of(value))
+                .prop_map(|(key, value)| Op::Read { key, value }),
+            (any::<u64>(), any::<Option<i64>>()).prop_map(|(key, value)| Op::Write { key, value }),
+            (any::<u64>(), any::<i64>()).prop_map(|(key, element)| Op::Append { key, element }),
+        ]
+    }
+
+    /// Nodes that number themselves by their place among the node ids refuse to start
+    /// without one.
+    #[tokio::test(start_paused = true)]
+    #[should_panic(expected = "node n3 is not among the node ids")]
+    async fn a_node_missing_from_node_ids_refuses_to_start() {
+        TxnHarness::new("n3", &["n0", "n1", "n2"]).await;
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let txn = || prop::collection::vec(op(), 0..4);
+        let stamp = || (any::<u64>(), wire::node_id());
+        let appends = || prop::collection::vec((any::<u64>(), any::<i64>()), 0..4);
+        let synced = || {
+            prop::collection::vec(
+                (
+                    any::<u64>(),
+                    stamp(),
+                    prop::collection::vec(any::<i64>(), 0..4),
+                ),
+                0..4,
+            )
+        };
+        let digest =
+            || prop::collection::vec((any::<usize>(), proptest::option::of(stamp())), 0..4);
+        let replicated = || {
+            (
+                appends(),
+                stamp(),
+                any::<u64>(),
+                proptest::option::of(wire::vector_clock()),
+            )
+                .prop_map(|(appends, stamp, seq, clock)| ReplicatedTxn {
+                    appends,
+                    stamp,
+                    seq,
+                    clock,
+                })
+        };
+        wire::with_kv(prop_oneof![
+            txn().prop_map(|txn| TxnPayload::Txn { txn }),
+            txn().prop_map(|txn| TxnPayload::TxnOk { txn }),
+            wire::error().prop_map(|(code, text)| TxnPayload::Error { code, text }),
+            replicated().prop_map(|txn| TxnPayload::Replicate {
+                appends: txn.appends,
+                stamp: txn.stamp,
+                seq: txn.seq,
+                clock: txn.clock,
+            }),
+            stamp().prop_map(|stamp| TxnPayload::ReplicateOk { stamp }),
+            prop::collection::vec(replicated(), 0..4)
+                .prop_map(|txns| TxnPayload::ReplicateBatch { txns }),
+            prop::collection::vec(stamp(), 0..4)
+                .prop_map(|stamps| TxnPayload::ReplicateBatchOk { stamps }),
+            synced().prop_map(|appends| TxnPayload::Sync { appends }),
+            prop::collection::vec((any::<u64>(), stamp()), 0..4)
+                .prop_map(|appends| TxnPayload::SyncOk { appends }),
+            digest().prop_map(|digest| TxnPayload::AntiEntropy { digest }),
+            (digest(), synced())
+                .prop_map(|(digest, appends)| TxnPayload::AntiEntropyOk { digest, appends }),
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("txn_rw_register");
+        wire::assert_fixture_parses::<Payload>("txn_list_append");
+        wire::assert_fixture_parses::<Payload>("kv_service");
+    }
 }
@@ -1,60 +1,431 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    sync::Mutex as SyncMutex,
+    time::Duration,
+};
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
-use serde::{Deserialize, Serialize};
+use gossip_glomers::{
+    clock::TokioClock,
+    event_loop,
+    ids::{self, UuidV7, UUID_COUNTER_BITS, UUID_NODE_BITS},
+    rpc::Rpc,
+    Body, ErrorCode, Event, Init, KVPayload, MaelstromError, Message, Node, Output, WithKV, KV,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::sync::Mutex;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
-    Generate,
+enum UniqueIdsPayload {
+    /// Asks for `count` ids, one if absent.
+    Generate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<u64>,
+    },
     GenerateOk {
+        #[serde(flatten)]
+        guids: Guids,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Guids {
+    /// The reply to a request for a single id, the shape the Maelstrom workload expects.
+    One {
         #[serde(rename = "id")]
         guid: String,
     },
+    Many {
+        #[serde(rename = "ids")]
+        guids: Vec<String>,
+    },
+}
+
+type Payload = WithKV<UniqueIdsPayload>;
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    /// Reserves the first block of ids, so the first request does not wait for it.
+    Reserve,
+}
+
+/// Storage holding the number of the next block of ids of every node.
+const BLOCK_STORAGE: &str = "seq-kv";
+
+/// Default number of ids in a block.
+const DEFAULT_BLOCK_SIZE: u64 = 1_000_000;
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most ids handed out for one generate request.
+const MAX_GENERATE_COUNT: u64 = 10_000;
+
+/// The shape of generated ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdMode {
+    /// `{index}-{n}`, counting up from 0 on every node, see Init::node_index.
+    NodeSeq,
+    /// RFC 9562 version 7 UUIDs: the unix time in milliseconds, never going backwards
+    /// on a node, followed by 12 random bits, the node's index and its counter
+    /// scrambled by a random mask.
+    UuidV7,
+}
+
+impl FromStr for IdMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "node-seq" => Ok(Self::NodeSeq),
+            "uuidv7" => Ok(Self::UuidV7),
+            "snowflake" => anyhow::bail!("snowflake ids are not supported, use node-seq or uuidv7"),
+            _ => anyhow::bail!("unknown id mode {:?}, expected node-seq or uuidv7", s),
+        }
+    }
+}
+
+/// Settings read from the environment at startup, each falling back to a default.
+#[derive(Debug, Clone)]
+struct UniqueIdsConfig {
+    mode: IdMode,
+    /// Number of ids in a block reserved in seq-kv, if ids are persisted at all.
+    block_size: Option<u64>,
+}
+
+impl UniqueIdsConfig {
+    /// Reads the configuration from the environment:
+    /// - `UNIQUE_ID_MODE`: `node-seq` or `uuidv7`, see IdMode (default node-seq)
+    /// - `UNIQUE_IDS_PERSIST`: only hand out ids from blocks reserved in seq-kv, which
+    ///   keeps them unique across restarts (default false)
+    /// - `UNIQUE_IDS_BLOCK_SIZE`: number of ids in a block, only with
+    ///   `UNIQUE_IDS_PERSIST` (default 1000000)
+    fn from_env() -> anyhow::Result<Self> {
+        let mode = gossip_glomers::env_or("UNIQUE_ID_MODE", IdMode::NodeSeq)?;
+        let persist = gossip_glomers::env_or("UNIQUE_IDS_PERSIST", false)?;
+        let block_size = gossip_glomers::env_or("UNIQUE_IDS_BLOCK_SIZE", DEFAULT_BLOCK_SIZE)?;
+        if !persist && std::env::var_os("UNIQUE_IDS_BLOCK_SIZE").is_some() {
+            anyhow::bail!("UNIQUE_IDS_BLOCK_SIZE has no effect without UNIQUE_IDS_PERSIST");
+        }
+        if block_size == 0 {
+            anyhow::bail!("UNIQUE_IDS_BLOCK_SIZE must be greater than 0");
+        }
+        if mode == IdMode::UuidV7 && block_size > 1 << UUID_COUNTER_BITS {
+            anyhow::bail!(
+                "UNIQUE_IDS_BLOCK_SIZE must be at most 2^{} for uuidv7 ids",
+                UUID_COUNTER_BITS
+            );
+        }
+        Ok(Self {
+            mode,
+            block_size: persist.then_some(block_size),
+        })
+    }
+
+    /// Checks the configuration against the cluster the node is part of.
+    fn validate(&self, init: &Init) -> anyhow::Result<()> {
+        if self.mode == IdMode::UuidV7 && init.node_ids.len() > 1 << UUID_NODE_BITS {
+            anyhow::bail!(
+                "uuidv7 ids tell at most 2^{} nodes apart, got {}",
+                UUID_NODE_BITS,
+                init.node_ids.len()
+            );
+        }
+        Ok(())
+    }
 }
 
 struct UniqueIdsNode {
     node: String,
+    /// Index of the node in the cluster, which ids carry rather than the node id.
+    index: usize,
     id: AtomicUsize,
-    stdout: Mutex<tokio::io::Stdout>,
+    /// Counter the generated ids are unique by within this node.
+    next_guid: AtomicU64,
+    /// Set if ids are UUIDv7s rather than `{index}-{n}`.
+    uuid: Option<UuidV7<TokioClock>>,
+    /// Set if ids are only taken from blocks reserved in seq-kv. Generating only
+    /// holds this lock for as long as it takes to count, refilling holds `refilling`.
+    block: Option<SyncMutex<Block>>,
+    refilling: Mutex<()>,
+    block_size: u64,
+    rpc: Rpc<Payload>,
+    stdout: Mutex<Output>,
+}
+
+/// The counter values a node reserved and has not handed out yet.
+#[derive(Default)]
+struct Block {
+    next: u64,
+    end: u64,
+}
+
+impl UniqueIdsNode {
+    /// Hands out `count` ids. They are taken from the counter at once, or from the
+    /// reserved blocks while holding them.
+    async fn generate(&self, count: u64) -> UniqueIdsPayload {
+        if count == 0 || count > MAX_GENERATE_COUNT {
+            return UniqueIdsPayload::Error {
+                code: ErrorCode::MalformedRequest.code(),
+                text: format!(
+                    "count must be between 1 and {}, got {}",
+                    MAX_GENERATE_COUNT, count
+                ),
+            };
+        }
+        let numbers = match &self.block {
+            None => {
+                let first = self.next_guid.fetch_add(count, Ordering::Relaxed);
+                (first..first + count).collect()
+            }
+            Some(block) => match self.take(block, count).await {
+                std::result::Result::Ok(numbers) => numbers,
+                // No id was handed out, so the request can safely be retried.
+                Err(err) => {
+                    return UniqueIdsPayload::Error {
+                        code: ErrorCode::TemporarilyUnavailable.code(),
+                        text: format!("cannot reserve a block of ids: {:#}", err),
+                    }
+                }
+            },
+        };
+        let guid = |n| match &self.uuid {
+            Some(uuid) => uuid.format(n),
+            None => ids::node_seq(self.index, n),
+        };
+        let guids = if count == 1 {
+            Guids::One {
+                guid: guid(numbers[0]),
+            }
+        } else {
+            Guids::Many {
+                guids: numbers.into_iter().map(guid).collect(),
+            }
+        };
+        UniqueIdsPayload::GenerateOk { guids }
+    }
+
+    /// Takes `count` counter values from `block`, reserving new blocks as it runs out.
+    async fn take(&self, block: &SyncMutex<Block>, count: u64) -> anyhow::Result<Vec<u64>> {
+        let mut numbers = Vec::with_capacity(count as usize);
+        loop {
+            {
+                let mut block = block.lock().unwrap();
+                let end = block.end.min(block.next + count - numbers.len() as u64);
+                numbers.extend(block.next..end);
+                block.next = end;
+            }
+            if numbers.len() as u64 == count {
+                return Ok(numbers);
+            }
+            self.refill(block).await?;
+        }
+    }
+
+    /// Reserves a new block for `block` unless it has ids left, which happens when
+    /// another request refilled it first.
+    async fn refill(&self, block: &SyncMutex<Block>) -> anyhow::Result<()> {
+        let _refilling = self.refilling.lock().await;
+        {
+            let block = block.lock().unwrap();
+            if block.next < block.end {
+                return Ok(());
+            }
+        }
+        let next = self.reserve().await?;
+        *block.lock().unwrap() = Block {
+            next,
+            end: next + self.block_size,
+        };
+        Ok(())
+    }
+
+    /// Reserves the next block of this node in seq-kv and returns its first counter
+    /// value. Blocks are never handed out twice, so a restarted node does not reissue
+    /// the ids of its former self, but the rest of the previous block is lost.
+    async fn reserve(&self) -> anyhow::Result<u64> {
+        let key = format!("unique_ids:{}:block", self.node);
+        loop {
+            let reserved = match self.read(BLOCK_STORAGE, key.clone()).await {
+                std::result::Result::Ok(reserved) => reserved,
+                Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => 0,
+                Err(err) => return Err(err).context("read reserved blocks"),
+            };
+            // A stale read makes the CAS fail, after which the read is up to date.
+            match self
+                .cas(BLOCK_STORAGE, key.clone(), reserved, reserved + 1, true)
+                .await
+            {
+                std::result::Result::Ok(()) => return Ok(reserved * self.block_size),
+                Err(err) if ErrorCode::of(&err) == Some(ErrorCode::PreconditionFailed) => {}
+                Err(err) => return Err(err).context("reserve block"),
+            }
+        }
+    }
+
+    async fn rpc(
+        &self,
+        to: &str,
+        payload: KVPayload<serde_json::Value>,
+    ) -> anyhow::Result<Message<Payload>> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(self.id.fetch_add(1, Ordering::Relaxed)),
+                in_reply_to: None,
+                payload: WithKV::KV(payload),
+            },
+        };
+        self.rpc.call(msg, RPC_TIMEOUT, &self.stdout).await
+    }
 }
 
 #[async_trait]
-impl Node<Payload> for UniqueIdsNode {
+impl<T> KV<T> for UniqueIdsNode
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T> {
+        let payload = KVPayload::Read { key };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("read from storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::ReadOk { value }) => {
+                serde_json::from_value(value).context("deserialize stored value")
+            }
+            WithKV::Workload(UniqueIdsPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write(&self, storage: &str, key: String, value: T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value).context("serialize value")?;
+        let payload = KVPayload::Write { key, value };
+        let result = self
+            .rpc(storage, payload)
+            .await
+            .context("write to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::WriteOk {}) => Ok(()),
+            WithKV::Workload(UniqueIdsPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        let from = serde_json::to_value(from).context("serialize from value")?;
+        let to = serde_json::to_value(to).context("serialize to value")?;
+        let payload = KVPayload::Cas { key, from, to, put };
+        let result = self.rpc(storage, payload).await.context("cas to storage")?;
+        match result.body.payload {
+            WithKV::KV(KVPayload::CasOk {}) => Ok(()),
+            WithKV::Workload(UniqueIdsPayload::Error { code, text }) => {
+                Err(MaelstromError::from_code(code, text).into())
+            }
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for UniqueIdsNode {
     fn from_init(
         init: Init,
-        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Mutex<Output>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
+        let config = UniqueIdsConfig::from_env()?;
+        config.validate(&init)?;
+        eprintln!("unique_ids config: {:?}", config);
+        let index = init.node_index()?;
+        if config.block_size.is_some() {
+            tokio::spawn(async move {
+                let _ = tx.send(Event::Injected(InjectedPayload::Reserve)).await;
+            });
+        }
         Ok(Self {
             node: init.node_id,
+            index,
             id: 1.into(),
+            next_guid: 0.into(),
+            uuid: (config.mode == IdMode::UuidV7)
+                .then(|| UuidV7::with_clock(index as u64, TokioClock::new())),
+            block: config.block_size.map(|_| SyncMutex::default()),
+            refilling: Mutex::default(),
+            block_size: config.block_size.unwrap_or(DEFAULT_BLOCK_SIZE),
+            rpc: Rpc::new(),
             stdout,
         })
     }
 
-    async fn handle(&self, event: gossip_glomers::Event<Payload>) -> anyhow::Result<()> {
-        let gossip_glomers::Event::Message(message) = event else {
-            panic!("unexpected event: {:?}", event);
-        };
-        let mut reply = message.into_reply(Some(&self.id));
-        match reply.body.payload {
-            Payload::Generate => {
-                let guid = format!("{}-{}", self.node, self.id.load(Ordering::SeqCst));
-                reply.body.payload = Payload::GenerateOk { guid };
-                reply
-                    .send(&self.stdout)
-                    .await
-                    .context("send response message")?;
-            }
-            Payload::GenerateOk { .. } => {}
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            // The node keeps no state to report.
+            Event::EOF => {}
+            Event::Injected(InjectedPayload::Reserve) => {
+                if let Some(block) = &self.block {
+                    self.refill(block).await.context("reserve first block")?;
+                }
+            }
+            Event::Message(message) => {
+                // Storage replies go to the RPC waiting for them.
+                let Some(message) = self.rpc.resolve(message).await else {
+                    return Ok(());
+                };
+                let Message { src, dest, body } = message;
+                let payload = match body.payload {
+                    WithKV::Workload(payload) => payload,
+                    WithKV::KV(payload) => {
+                        eprintln!("unexpected storage message from {}: {:?}", src, payload);
+                        return Ok(());
+                    }
+                };
+                let message = Message {
+                    src,
+                    dest,
+                    body: Body {
+                        id: body.id,
+                        in_reply_to: body.in_reply_to,
+                        payload,
+                    },
+                };
+                let mut reply = message.into_reply(Some(&self.id));
+                match reply.body.payload {
+                    UniqueIdsPayload::Generate { count } => {
+                        reply.body.payload = self.generate(count.unwrap_or(1)).await;
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send response message")?;
+                    }
+                    UniqueIdsPayload::GenerateOk { .. } | UniqueIdsPayload::Error { .. } => {}
+                }
+            }
         }
         Ok(())
     }
@@ -64,3 +435,335 @@ impl Node<Payload> for UniqueIdsNode {
 async fn main() -> anyhow::Result<()> {
     event_loop::<UniqueIdsNode, _, _>().await
 }
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::testkit::{assert_golden, wire, with_env, Harness, KvOp, MockKvService};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// A node handing out `{index}-{n}` ids from its counter, as without any config.
+    fn node() -> UniqueIdsNode {
+        UniqueIdsNode {
+            node: "n1".to_string(),
+            index: 1,
+            id: 1.into(),
+            next_guid: 0.into(),
+            uuid: None,
+            block: None,
+            refilling: Mutex::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            rpc: Rpc::new(),
+            stdout: Mutex::new(Box::new(tokio::io::sink())),
+        }
+    }
+
+    fn to_json(payload: &UniqueIdsPayload) -> serde_json::Value {
+        serde_json::to_value(payload).expect("serialize payload")
+    }
+
+    #[test]
+    fn generate_ok_shapes_round_trip() {
+        for golden in [
+            serde_json::json!({"type": "generate_ok", "id": "1-7"}),
+            serde_json::json!({"type": "generate_ok", "ids": ["1-7", "1-8"]}),
+        ] {
+            let payload: UniqueIdsPayload =
+                serde_json::from_value(golden.clone()).expect("deserialize payload");
+            assert_eq!(to_json(&payload), golden);
+        }
+        let one: UniqueIdsPayload =
+            serde_json::from_value(serde_json::json!({"type": "generate_ok", "id": "1-7"}))
+                .unwrap();
+        assert!(matches!(
+            one,
+            UniqueIdsPayload::GenerateOk {
+                guids: Guids::One { .. }
+            }
+        ));
+    }
+
+    #[test]
+    fn generate_count_is_optional() {
+        let plain: UniqueIdsPayload =
+            serde_json::from_value(serde_json::json!({"type": "generate"})).unwrap();
+        assert!(matches!(plain, UniqueIdsPayload::Generate { count: None }));
+        assert_eq!(to_json(&plain), serde_json::json!({"type": "generate"}));
+        let batch: UniqueIdsPayload =
+            serde_json::from_value(serde_json::json!({"type": "generate", "count": 3})).unwrap();
+        assert!(matches!(
+            batch,
+            UniqueIdsPayload::Generate { count: Some(3) }
+        ));
+        assert_eq!(
+            to_json(&batch),
+            serde_json::json!({"type": "generate", "count": 3})
+        );
+    }
+
+    #[tokio::test]
+    async fn one_id_is_sent_as_id_and_more_as_ids() {
+        let node = node();
+        assert_eq!(
+            to_json(&node.generate(1).await),
+            serde_json::json!({"type": "generate_ok", "id": "1-0"})
+        );
+        assert_eq!(
+            to_json(&node.generate(3).await),
+            serde_json::json!({"type": "generate_ok", "ids": ["1-1", "1-2", "1-3"]})
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_are_bounded() {
+        let node = node();
+        match node.generate(MAX_GENERATE_COUNT).await {
+            UniqueIdsPayload::GenerateOk {
+                guids: Guids::Many { guids },
+            } => assert_eq!(guids.len() as u64, MAX_GENERATE_COUNT),
+            other => panic!("expected {} ids, got {:?}", MAX_GENERATE_COUNT, other),
+        }
+        for count in [0, MAX_GENERATE_COUNT + 1] {
+            match node.generate(count).await {
+                UniqueIdsPayload::Error { code, .. } => {
+                    assert_eq!(code, ErrorCode::MalformedRequest.code())
+                }
+                other => panic!("expected an error for {} ids, got {:?}", count, other),
+            }
+        }
+        // Refused batches take nothing from the counter.
+        assert_eq!(
+            to_json(&node.generate(1).await),
+            serde_json::json!({"type": "generate_ok", "id": format!("1-{}", MAX_GENERATE_COUNT)})
+        );
+    }
+
+    #[test]
+    fn modes_parse() {
+        assert_eq!("node-seq".parse::<IdMode>().unwrap(), IdMode::NodeSeq);
+        assert_eq!("uuidv7".parse::<IdMode>().unwrap(), IdMode::UuidV7);
+        assert!("snowflake".parse::<IdMode>().is_err());
+        assert!("uuid".parse::<IdMode>().is_err());
+    }
+
+    #[test]
+    fn uuidv7_is_refused_for_clusters_it_cannot_tell_apart() {
+        let config = UniqueIdsConfig {
+            mode: IdMode::UuidV7,
+            block_size: None,
+        };
+        let init = |nodes: usize| Init {
+            node_id: "n0".to_string(),
+            node_ids: (0..nodes).map(|i| format!("n{}", i)).collect(),
+        };
+        assert!(config.validate(&init(1 << UUID_NODE_BITS)).is_ok());
+        assert!(config.validate(&init((1 << UUID_NODE_BITS) + 1)).is_err());
+        let node_seq = UniqueIdsConfig {
+            mode: IdMode::NodeSeq,
+            ..config
+        };
+        assert!(node_seq.validate(&init((1 << UUID_NODE_BITS) + 1)).is_ok());
+    }
+
+    /// The only test that sets variables by hand, all inside with_env so harnesses set
+    /// up in parallel do not see them.
+    #[test]
+    fn config_from_env() {
+        // Holds off harnesses, which read the variables set here.
+        with_env(&[], || {
+            let vars = [
+                "UNIQUE_ID_MODE",
+                "UNIQUE_IDS_PERSIST",
+                "UNIQUE_IDS_BLOCK_SIZE",
+            ];
+            let clear = || vars.iter().for_each(|name| std::env::remove_var(name));
+            let mode = || UniqueIdsConfig::from_env().map(|config| config.mode);
+            clear();
+
+            let config = UniqueIdsConfig::from_env().unwrap();
+            assert_eq!(config.mode, IdMode::NodeSeq);
+            assert_eq!(config.block_size, None);
+
+            for (value, expected) in [("node-seq", IdMode::NodeSeq), ("uuidv7", IdMode::UuidV7)] {
+                std::env::set_var("UNIQUE_ID_MODE", value);
+                assert_eq!(mode().unwrap(), expected, "UNIQUE_ID_MODE={}", value);
+                clear();
+            }
+
+            std::env::set_var("UNIQUE_ID_MODE", "snowflake");
+            assert!(mode().is_err());
+            clear();
+
+            std::env::set_var("UNIQUE_IDS_PERSIST", "true");
+            std::env::set_var("UNIQUE_IDS_BLOCK_SIZE", "500");
+            assert_eq!(UniqueIdsConfig::from_env().unwrap().block_size, Some(500));
+            std::env::set_var("UNIQUE_IDS_BLOCK_SIZE", "0");
+            assert!(UniqueIdsConfig::from_env().is_err());
+            // Blocks of uuidv7 ids cannot outgrow the counter bits.
+            std::env::set_var("UNIQUE_ID_MODE", "uuidv7");
+            std::env::set_var(
+                "UNIQUE_IDS_BLOCK_SIZE",
+                ((1u64 << UUID_COUNTER_BITS) + 1).to_string(),
+            );
+            assert!(UniqueIdsConfig::from_env().is_err());
+            clear();
+
+            // A block size does nothing without persistence, which is refused.
+            std::env::set_var("UNIQUE_IDS_BLOCK_SIZE", "500");
+            assert!(UniqueIdsConfig::from_env().is_err());
+            clear();
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn generate_messages_match_their_golden_file() {
+        let mut node =
+            Harness::<UniqueIdsNode, Payload, InjectedPayload>::new("n1", &["n0", "n1"]).await;
+        for count in [None, Some(3), Some(0)] {
+            let payload = WithKV::Workload(UniqueIdsPayload::Generate { count });
+            let id = node.send("c1", payload).await;
+            node.expect_reply_to(id).await;
+        }
+        assert_golden("unique_ids", node.transcript());
+    }
+
+    type UniqueIdsHarness = Harness<UniqueIdsNode, Payload, InjectedPayload>;
+
+    const BLOCK_KEY: &str = "unique_ids:n1:block";
+
+    /// A node taking its ids from blocks of `block_size` reserved in `kv`, once it
+    /// reserved its first.
+    async fn persisted(kv: &MockKvService, block_size: &str) -> UniqueIdsHarness {
+        let mut harness = UniqueIdsHarness::builder("n1", &["n0", "n1"])
+            .env(&[
+                ("UNIQUE_IDS_PERSIST", Some("true")),
+                ("UNIQUE_IDS_BLOCK_SIZE", Some(block_size)),
+            ])
+            .service(kv)
+            .start()
+            .await;
+        harness.drain().await;
+        harness
+    }
+
+    async fn generate(harness: &mut UniqueIdsHarness, count: u64) -> UniqueIdsPayload {
+        let payload = WithKV::Workload(UniqueIdsPayload::Generate { count: Some(count) });
+        let id = harness.send("c1", payload).await;
+        match harness.expect_reply_to(id).await.body.payload {
+            WithKV::Workload(payload) => payload,
+            payload => panic!("expected a generate reply, got {:?}", payload),
+        }
+    }
+
+    async fn guids(harness: &mut UniqueIdsHarness, count: u64) -> Vec<String> {
+        match generate(harness, count).await {
+            UniqueIdsPayload::GenerateOk {
+                guids: Guids::One { guid },
+            } => vec![guid],
+            UniqueIdsPayload::GenerateOk {
+                guids: Guids::Many { guids },
+            } => guids,
+            payload => panic!("expected {} ids, got {:?}", count, payload),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ids_come_from_blocks_reserved_as_needed() {
+        let kv = MockKvService::seq(BLOCK_STORAGE, Duration::ZERO);
+        let mut harness = persisted(&kv, "3").await;
+        // The first block is reserved before any request.
+        assert_eq!(kv.get(BLOCK_KEY), Some(serde_json::json!(1)));
+
+        assert_eq!(guids(&mut harness, 2).await, ["1-0", "1-1"]);
+        assert_eq!(kv.count(KvOp::Cas), 1);
+        // A batch running past the end of the block takes the rest from the next one.
+        assert_eq!(guids(&mut harness, 3).await, ["1-2", "1-3", "1-4"]);
+        assert_eq!(kv.get(BLOCK_KEY), Some(serde_json::json!(2)));
+        assert_eq!(guids(&mut harness, 1).await, ["1-5"]);
+        assert_eq!(kv.count(KvOp::Cas), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_exhausted_block_that_cannot_be_refilled_fails_the_request() {
+        let kv = MockKvService::seq(BLOCK_STORAGE, Duration::ZERO);
+        let mut harness = persisted(&kv, "2").await;
+        assert_eq!(guids(&mut harness, 2).await, ["1-0", "1-1"]);
+
+        kv.drop_next(KvOp::Read, 1);
+        match generate(&mut harness, 1).await {
+            UniqueIdsPayload::Error { code, .. } => {
+                assert_eq!(code, ErrorCode::TemporarilyUnavailable.code())
+            }
+            payload => panic!("expected an error, got {:?}", payload),
+        }
+        // No id was handed out, so none is skipped once seq-kv answers again.
+        assert_eq!(guids(&mut harness, 1).await, ["1-2"]);
+        assert_eq!(kv.get(BLOCK_KEY), Some(serde_json::json!(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_restarted_node_does_not_reissue_ids() {
+        let kv = MockKvService::seq(BLOCK_STORAGE, Duration::ZERO);
+        let mut before = persisted(&kv, "3").await;
+        assert_eq!(guids(&mut before, 1).await, ["1-0"]);
+        drop(before);
+
+        // The rest of the former block is lost rather than handed out again.
+        let mut after = persisted(&kv, "3").await;
+        assert_eq!(guids(&mut after, 4).await, ["1-3", "1-4", "1-5", "1-6"]);
+        assert_eq!(kv.get(BLOCK_KEY), Some(serde_json::json!(3)));
+    }
+
+    /// Maelstrom clients always number their requests, but a generate without a
+    /// msg_id is still answered, and the node goes on serving.
+    #[tokio::test(start_paused = true)]
+    async fn generate_without_a_msg_id_is_answered() {
+        let mut node = UniqueIdsHarness::new("n1", &["n0", "n1"]).await;
+        node.send_line(r#"{"src":"c1","dest":"n1","body":{"type":"generate"}}"#)
+            .await;
+        let reply = node.recv().await;
+        assert_eq!(reply.body.in_reply_to, None);
+        assert!(matches!(
+            reply.body.payload,
+            WithKV::Workload(UniqueIdsPayload::GenerateOk {
+                guids: Guids::One { ref guid }
+            }) if guid == "1-0"
+        ));
+        assert_eq!(guids(&mut node, 1).await, ["1-1"]);
+    }
+
+    /// Nodes that number themselves by their place among the node ids refuse to start
+    /// without one.
+    #[tokio::test(start_paused = true)]
+    #[should_panic(expected = "node n3 is not among the node ids")]
+    async fn a_node_missing_from_node_ids_refuses_to_start() {
+        UniqueIdsHarness::new("n3", &["n0", "n1", "n2"]).await;
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        let guids = prop_oneof![
+            any::<String>().prop_map(|guid| Guids::One { guid }),
+            prop::collection::vec(any::<String>(), 0..4).prop_map(|guids| Guids::Many { guids }),
+        ];
+        wire::with_kv(prop_oneof![
+            any::<Option<u64>>().prop_map(|count| UniqueIdsPayload::Generate { count }),
+            guids.prop_map(|guids| UniqueIdsPayload::GenerateOk { guids }),
+            wire::error().prop_map(|(code, text)| UniqueIdsPayload::Error { code, text }),
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("unique_ids");
+        wire::assert_fixture_parses::<Payload>("kv_service");
+    }
+}
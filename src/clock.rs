@@ -0,0 +1,567 @@
+//! Logical clocks. A Lamport clock ticks on every local event and jumps past every
+//! time it sees from another node, so an event always gets a later time than every
+//! event it could have observed. A hybrid logical clock does the same while staying
+//! close to wall time. A vector clock also tells which events could not have observed
+//! each other.
+//!
+//! The clocks that follow wall time read it through a `Clock`. Nodes use a
+//! `TokioClock`, which moves with tokio's clock, so the virtual time of tests drives
+//! them like it drives every timer.
+
+use std::{
+    cmp::{self, Ordering as CmpOrdering},
+    collections::BTreeMap,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// A source of wall time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the time since the Unix epoch.
+    fn unix_time(&self) -> Duration;
+}
+
+/// Reads the system clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Reads the system clock once, when created, and adds the time that passed on tokio's
+/// clock since. It stands still and jumps along with timers under `tokio::time::pause`,
+/// and does not follow the system clock being set.
+#[derive(Debug, Clone, Copy)]
+pub struct TokioClock {
+    started: tokio::time::Instant,
+    unix_started: Duration,
+}
+
+impl TokioClock {
+    pub fn new() -> Self {
+        Self {
+            started: tokio::time::Instant::now(),
+            unix_started: SystemClock.unix_time(),
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn unix_time(&self) -> Duration {
+        self.unix_started + self.started.elapsed()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LamportClock {
+    time: AtomicU64,
+}
+
+impl LamportClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advances the clock for a local event and returns its time.
+    pub fn tick(&self) -> u64 {
+        self.time.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Moves the clock past `time`, seen in a message from another node.
+    pub fn observe(&self, time: u64) {
+        self.time.fetch_max(time, Ordering::SeqCst);
+    }
+}
+
+/// Bits of a hybrid time that hold the logical counter, below the milliseconds.
+const LOGICAL_BITS: u32 = 16;
+
+/// A hybrid logical clock (Kulkarni et al., 2014). Its times are a single u64 holding
+/// milliseconds since the Unix epoch in the upper 48 bits and a logical counter in the
+/// lower 16, so they compare as numbers and fit wherever a Lamport time does.
+///
+/// A time is the wall clock whenever that is ahead of every time the clock handed out
+/// or observed. Otherwise it is the latest of those plus one, which bumps the counter.
+/// A counter that runs over, after 65536 events within a millisecond, carries into the
+/// milliseconds and puts the clock a millisecond ahead of the wall clock until it
+/// catches up.
+#[derive(Debug)]
+pub struct HybridClock<C = SystemClock> {
+    /// The latest time handed out or observed.
+    last: AtomicU64,
+    /// How far ahead of the wall clock a peer may be before observing it warns.
+    max_drift: Duration,
+    wall: C,
+}
+
+impl HybridClock {
+    pub fn new(max_drift: Duration) -> Self {
+        Self::with_clock(max_drift, SystemClock)
+    }
+}
+
+impl<C: Clock> HybridClock<C> {
+    /// Returns a clock reading wall time from `wall`.
+    pub fn with_clock(max_drift: Duration, wall: C) -> Self {
+        Self {
+            last: AtomicU64::new(0),
+            max_drift,
+            wall,
+        }
+    }
+
+    /// Returns the time of a local event.
+    pub fn now(&self) -> u64 {
+        self.now_at(self.wall_millis())
+    }
+
+    /// Returns the time of a local event when the wall clock reads `millis`.
+    pub fn now_at(&self, millis: u64) -> u64 {
+        let physical = HybridClock::pack(millis, 0);
+        let next = |last: u64| cmp::max(last.saturating_add(1), physical);
+        let last = self
+            .last
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .expect("update always succeeds");
+        next(last)
+    }
+
+    /// Moves the clock past `remote`, seen in a message from another node, and returns
+    /// the time of receiving it. Warns if `remote` is further ahead of the wall clock
+    /// than the max drift, which a node with a clock set far ahead drags every node
+    /// it talks to along with.
+    pub fn observe(&self, remote: u64) -> u64 {
+        self.observe_at(remote, self.wall_millis())
+    }
+
+    /// Observes `remote` when the wall clock reads `millis`.
+    pub fn observe_at(&self, remote: u64, millis: u64) -> u64 {
+        let physical = HybridClock::pack(millis, 0);
+        let next = |last: u64| cmp::max(cmp::max(last, remote).saturating_add(1), physical);
+        let last = self
+            .last
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .expect("update always succeeds");
+        if let Some(ahead) = self.drift(remote, last, millis) {
+            eprintln!(
+                "hybrid clock: observed time {}ms ahead of the wall clock, max drift is {:?}",
+                ahead.as_millis(),
+                self.max_drift
+            );
+        }
+        next(last)
+    }
+
+    /// Returns how far `remote` is ahead of the wall clock reading `millis` if that is
+    /// more than the max drift. Only times that move the clock past `last` count, so
+    /// the same time seen again does not warn twice.
+    fn drift(&self, remote: u64, last: u64, millis: u64) -> Option<Duration> {
+        let ahead = Duration::from_millis(HybridClock::millis(remote).saturating_sub(millis));
+        (remote > last && ahead > self.max_drift).then_some(ahead)
+    }
+
+    fn wall_millis(&self) -> u64 {
+        self.wall.unix_time().as_millis() as u64
+    }
+}
+
+impl HybridClock {
+    /// Returns the time with the milliseconds and logical counter given. Milliseconds
+    /// above 48 bits are cut off.
+    pub fn pack(millis: u64, logical: u16) -> u64 {
+        millis << LOGICAL_BITS | u64::from(logical)
+    }
+
+    /// Returns the milliseconds of a time.
+    pub fn millis(time: u64) -> u64 {
+        time >> LOGICAL_BITS
+    }
+
+    /// Returns the logical counter of a time.
+    pub fn logical(time: u64) -> u16 {
+        (time & ((1 << LOGICAL_BITS) - 1)) as u16
+    }
+}
+
+/// Most nodes a vector clock takes, which bounds what a malformed one can allocate.
+const MAX_VECTOR_NODES: usize = 1 << 16;
+
+/// A counter of events per node, by the index of the node in the sorted node ids (see
+/// `Init::node_index`). One clock happened before another if it is behind or level on
+/// every node and behind on at least one. If each is ahead on some node, the two are
+/// concurrent.
+///
+/// The counters are kept in a single vector with no zeros at its end, so clocks that
+/// count the same events are equal, and comparing or merging allocates nothing.
+/// Clocks serialize as a map of the non-zero counters, as in `{"0":3,"2":1}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorClock {
+    counters: Vec<u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an event of the node at `index` and returns its new counter.
+    pub fn increment(&mut self, index: usize) -> u64 {
+        if index >= self.counters.len() {
+            self.counters.resize(index + 1, 0);
+        }
+        self.counters[index] += 1;
+        self.counters[index]
+    }
+
+    /// Returns the events of the node at `index` counted so far.
+    pub fn get(&self, index: usize) -> u64 {
+        self.counters.get(index).copied().unwrap_or_default()
+    }
+
+    /// Takes in the events counted by `other`, as when receiving a message.
+    pub fn merge(&mut self, other: &VectorClock) {
+        if other.counters.len() > self.counters.len() {
+            self.counters.resize(other.counters.len(), 0);
+        }
+        for (mine, theirs) in self.counters.iter_mut().zip(&other.counters) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    pub fn happened_before(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other) == Some(CmpOrdering::Less)
+    }
+
+    pub fn concurrent(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        let (mut behind, mut ahead) = (false, false);
+        for index in 0..self.counters.len().max(other.counters.len()) {
+            match self.get(index).cmp(&other.get(index)) {
+                CmpOrdering::Less => behind = true,
+                CmpOrdering::Greater => ahead = true,
+                CmpOrdering::Equal => {}
+            }
+        }
+        match (behind, ahead) {
+            (false, false) => Some(CmpOrdering::Equal),
+            (true, false) => Some(CmpOrdering::Less),
+            (false, true) => Some(CmpOrdering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+impl Serialize for VectorClock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let non_zero = self.counters.iter().filter(|counter| **counter > 0).count();
+        let mut map = serializer.serialize_map(Some(non_zero))?;
+        for (index, counter) in self.counters.iter().enumerate() {
+            if *counter > 0 {
+                map.serialize_entry(&index, counter)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// A node index as a map key, which JSON writes as a string. Buffered payloads, as in
+/// untagged enums, hand the key over as that string rather than a number.
+struct NodeIndex(usize);
+
+impl<'de> Deserialize<'de> for NodeIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IndexVisitor;
+
+        impl Visitor<'_> for IndexVisitor {
+            type Value = NodeIndex;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a node index")
+            }
+
+            fn visit_u64<E: de::Error>(self, index: u64) -> Result<NodeIndex, E> {
+                usize::try_from(index).map(NodeIndex).map_err(E::custom)
+            }
+
+            fn visit_str<E: de::Error>(self, index: &str) -> Result<NodeIndex, E> {
+                index.parse().map(NodeIndex).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(IndexVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for VectorClock {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ClockVisitor;
+
+        impl<'de> Visitor<'de> for ClockVisitor {
+            type Value = VectorClock;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of node indexes to counters")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<VectorClock, A::Error> {
+                let mut counters = BTreeMap::new();
+                while let Some((NodeIndex(index), counter)) = map.next_entry::<NodeIndex, u64>()? {
+                    if index >= MAX_VECTOR_NODES {
+                        return Err(de::Error::custom(format!(
+                            "node index {} is beyond {}",
+                            index, MAX_VECTOR_NODES
+                        )));
+                    }
+                    if counter > 0 {
+                        counters.insert(index, counter);
+                    }
+                }
+                let mut clock = VectorClock {
+                    counters: vec![0; counters.last_key_value().map_or(0, |(index, _)| index + 1)],
+                };
+                for (index, counter) in counters {
+                    clock.counters[index] = counter;
+                }
+                Ok(clock)
+            }
+        }
+
+        deserializer.deserialize_map(ClockVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_DRIFT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn hybrid_follows_a_wall_clock_that_is_ahead() {
+        let clock = HybridClock::new(MAX_DRIFT);
+        assert_eq!(clock.now_at(1000), HybridClock::pack(1000, 0));
+        assert_eq!(clock.now_at(1005), HybridClock::pack(1005, 0));
+        assert_eq!(
+            clock.observe_at(HybridClock::pack(1003, 7), 1010),
+            HybridClock::pack(1010, 0)
+        );
+    }
+
+    #[test]
+    fn hybrid_counts_while_the_wall_clock_is_behind() {
+        let clock = HybridClock::new(MAX_DRIFT);
+        clock.now_at(1000);
+        // The wall clock stalls, then goes back.
+        assert_eq!(clock.now_at(1000), HybridClock::pack(1000, 1));
+        assert_eq!(clock.now_at(990), HybridClock::pack(1000, 2));
+        // A peer ahead of the wall clock moves the clock past its time.
+        let remote = HybridClock::pack(1050, 4);
+        assert_eq!(clock.observe_at(remote, 1000), HybridClock::pack(1050, 5));
+        assert_eq!(clock.now_at(1001), HybridClock::pack(1050, 6));
+        // Observing an older time still moves the clock forward.
+        assert_eq!(
+            clock.observe_at(HybridClock::pack(900, 0), 1001),
+            HybridClock::pack(1050, 7)
+        );
+        assert_eq!(clock.now_at(1060), HybridClock::pack(1060, 0));
+    }
+
+    #[test]
+    fn hybrid_counter_overflow_carries_into_the_millis() {
+        let clock = HybridClock::new(MAX_DRIFT);
+        let full = HybridClock::pack(1000, u16::MAX);
+        assert_eq!(clock.observe_at(full - 1, 1000), full);
+        let carried = clock.now_at(1000);
+        assert_eq!(carried, HybridClock::pack(1001, 0));
+        assert_eq!(HybridClock::millis(carried), 1001);
+        assert_eq!(HybridClock::logical(carried), 0);
+        // Until the wall clock catches up, the clock stays ahead of it.
+        assert_eq!(clock.now_at(1000), HybridClock::pack(1001, 1));
+        assert_eq!(clock.now_at(1002), HybridClock::pack(1002, 0));
+    }
+
+    #[test]
+    fn hybrid_times_always_increase() {
+        let clock = HybridClock::new(MAX_DRIFT);
+        let mut last = 0;
+        for (step, millis) in [5, 3, 3, 8, 1, 8, 20, 2].into_iter().enumerate() {
+            let time = if step % 3 == 0 {
+                clock.observe_at(HybridClock::pack(millis + 2, 1), millis)
+            } else {
+                clock.now_at(millis)
+            };
+            assert!(time > last, "{} after {}", time, last);
+            last = time;
+        }
+    }
+
+    #[test]
+    fn hybrid_drift_warns_only_when_far_ahead_and_new() {
+        let clock = HybridClock::new(MAX_DRIFT);
+        let last = HybridClock::pack(1000, 0);
+        let near = HybridClock::pack(1100, 0);
+        let far = HybridClock::pack(1101, 0);
+        assert_eq!(clock.drift(near, last, 1000), None);
+        assert_eq!(
+            clock.drift(far, last, 1000),
+            Some(Duration::from_millis(101))
+        );
+        // A time the clock is already past, as when seen again, does not warn.
+        assert_eq!(clock.drift(far, far, 1000), None);
+        // Neither does one behind the wall clock.
+        assert_eq!(clock.drift(last, 0, 2000), None);
+        // Observing a far time moves the clock regardless.
+        assert_eq!(clock.observe_at(far, 1000), far + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hybrid_on_a_tokio_clock_follows_virtual_time() {
+        let clock = HybridClock::with_clock(MAX_DRIFT, TokioClock::new());
+        let before = clock.now();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let after = clock.now();
+        assert_eq!(
+            HybridClock::millis(after) - HybridClock::millis(before),
+            10_000
+        );
+        assert_eq!(HybridClock::logical(after), 0);
+    }
+
+    fn clock(counters: &[u64]) -> VectorClock {
+        let mut clock = VectorClock::new();
+        for (index, counter) in counters.iter().enumerate() {
+            for _ in 0..*counter {
+                clock.increment(index);
+            }
+        }
+        clock
+    }
+
+    /// Every clock of three nodes with counters up to 2.
+    fn clocks() -> Vec<VectorClock> {
+        let mut clocks = Vec::new();
+        for a in 0..3 {
+            for b in 0..3 {
+                for c in 0..3 {
+                    clocks.push(clock(&[a, b, c]));
+                }
+            }
+        }
+        clocks
+    }
+
+    #[test]
+    fn comparison_is_a_partial_order() {
+        let clocks = clocks();
+        for a in &clocks {
+            assert_eq!(a.partial_cmp(a), Some(CmpOrdering::Equal));
+            for b in &clocks {
+                assert_eq!(
+                    a.partial_cmp(b),
+                    b.partial_cmp(a).map(CmpOrdering::reverse),
+                    "{:?} vs {:?}",
+                    a,
+                    b
+                );
+                if a <= b && b <= a {
+                    assert_eq!(a, b);
+                }
+                assert_eq!(a.concurrent(b), !(a <= b || b <= a));
+                for c in &clocks {
+                    if a.happened_before(b) && b.happened_before(c) {
+                        assert!(a.happened_before(c), "{:?} < {:?} < {:?}", a, b, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn merge_is_the_least_upper_bound() {
+        let clocks = clocks();
+        for a in &clocks {
+            for b in &clocks {
+                let mut merged = a.clone();
+                merged.merge(b);
+                assert!(a <= &merged && b <= &merged);
+                let mut swapped = b.clone();
+                swapped.merge(a);
+                assert_eq!(merged, swapped);
+                for upper in clocks.iter().filter(|c| a <= *c && b <= *c) {
+                    assert!(&merged <= upper);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_zeros_do_not_matter() {
+        let short = clock(&[1]);
+        let mut long = clock(&[1]);
+        long.merge(&VectorClock::new());
+        assert_eq!(long, short);
+        assert_eq!(clock(&[1, 0, 0]), short);
+        assert_eq!(short.get(5), 0);
+        assert_eq!(
+            short.partial_cmp(&clock(&[1, 0, 1])),
+            Some(CmpOrdering::Less)
+        );
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        for clock in clocks() {
+            let json = serde_json::to_value(&clock).expect("serialize clock");
+            assert_eq!(
+                serde_json::from_value::<VectorClock>(json.clone()).expect("deserialize clock"),
+                clock
+            );
+            let text = json.to_string();
+            assert_eq!(
+                serde_json::from_str::<VectorClock>(&text).expect("deserialize clock"),
+                clock
+            );
+        }
+        assert_eq!(
+            serde_json::to_string(&clock(&[3, 0, 1])).expect("serialize clock"),
+            r#"{"0":3,"2":1}"#
+        );
+    }
+
+    #[test]
+    fn zero_counters_are_dropped_when_deserializing() {
+        let clock: VectorClock =
+            serde_json::from_str(r#"{"0":2,"4":0}"#).expect("deserialize clock");
+        assert_eq!(clock, self::clock(&[2]));
+    }
+
+    #[test]
+    fn indexes_beyond_the_limit_are_rejected() {
+        let json = format!(r#"{{"{}":1}}"#, MAX_VECTOR_NODES);
+        assert!(serde_json::from_str::<VectorClock>(&json).is_err());
+        assert!(serde_json::from_str::<VectorClock>(r#"{"x":1}"#).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Replicated data types that converge whatever order their replicas merge in.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Orders the writes of all nodes: by the time of a Lamport or hybrid clock, then by
+/// node for writes made at the same time, so every node picks the same winner.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    pub time: u64,
+    pub node: String,
+}
+
+/// The latest write to a key: its value, or None if it was a delete.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LwwEntry<V> {
+    pub stamp: Stamp,
+    pub value: Option<V>,
+}
+
+/// A map where the write with the highest stamp wins for every key. Deletes are writes
+/// of a tombstone, so a delete and a concurrent write also resolve by stamp rather than
+/// by the order they arrive in.
+///
+/// The same type is both the full state and a delta: `delta` picks the entries written
+/// after some time, and merging it has the same effect as merging the whole map.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+#[serde(bound(
+    serialize = "K: Serialize + Eq + Hash, V: Serialize",
+    deserialize = "K: Deserialize<'de> + Eq + Hash, V: Deserialize<'de>"
+))]
+pub struct LwwMap<K, V> {
+    entries: HashMap<K, LwwEntry<V>>,
+}
+
+/// Maps are equal if they hold the same writes, tombstones included.
+impl<K: Eq + Hash, V: PartialEq> PartialEq for LwwMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K: Eq + Hash, V: Eq> Eq for LwwMap<K, V> {}
+
+impl<K, V> Default for LwwMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K, V> LwwMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes `value` to `key` unless the key has a later write. Returns whether the
+    /// write took.
+    pub fn insert(&mut self, key: K, value: V, stamp: Stamp) -> bool {
+        self.apply(
+            key,
+            LwwEntry {
+                stamp,
+                value: Some(value),
+            },
+        )
+    }
+
+    /// Deletes `key` unless it has a later write. Returns whether the delete took.
+    pub fn remove(&mut self, key: K, stamp: Stamp) -> bool {
+        self.apply(key, LwwEntry { stamp, value: None })
+    }
+
+    /// Keeps `entry` for `key` unless the key has a later write, as if it was written
+    /// here. Returns whether it took.
+    pub fn apply(&mut self, key: K, entry: LwwEntry<V>) -> bool {
+        match self.entries.entry(key) {
+            Entry::Occupied(mut current) => {
+                if current.get().stamp >= entry.stamp {
+                    return false;
+                }
+                current.insert(entry);
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+            }
+        }
+        true
+    }
+
+    /// Returns the value of `key`, or None if it was never written or was deleted.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.value.as_ref()
+    }
+
+    /// Returns the latest write to `key`, deletes included.
+    pub fn entry(&self, key: &K) -> Option<&LwwEntry<V>> {
+        self.entries.get(key)
+    }
+
+    /// Returns the stamp of the latest write to `key`, deletes included.
+    pub fn stamp(&self, key: &K) -> Option<&Stamp> {
+        self.entries.get(key).map(|entry| &entry.stamp)
+    }
+
+    /// Iterates over the keys that have a value, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| Some((key, entry.value.as_ref()?)))
+    }
+
+    /// Returns the number of keys that have a value.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the highest time of any write, deletes included, or 0 if there is none.
+    /// A node that merges the map observes it on its clock, so its own writes win over
+    /// every write it has seen.
+    pub fn max_time(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|entry| entry.stamp.time)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Keeps the later write of every key. Merging is commutative, associative and
+    /// idempotent, so replicas that merged the same writes agree.
+    pub fn merge(&mut self, other: LwwMap<K, V>) {
+        for (key, entry) in other.entries {
+            self.apply(key, entry);
+        }
+    }
+
+    /// Returns the writes made after `since`, deletes included, for a peer that has
+    /// every write up to then.
+    pub fn delta(&self, since: u64) -> LwwMap<K, V> {
+        LwwMap {
+            entries: self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.stamp.time > since)
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+        }
+    }
+
+    /// Drops the tombstones of deletes made before `horizon` and returns how many were
+    /// dropped. Only safe once every replica has seen those deletes: a replica that
+    /// missed one still has the older write and brings it back on the next merge.
+    pub fn gc(&mut self, horizon: u64) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| entry.value.is_some() || entry.stamp.time >= horizon);
+        before - self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(time: u64, node: &str) -> Stamp {
+        Stamp {
+            time,
+            node: node.to_string(),
+        }
+    }
+
+    /// Replicas that saw different writes, deletes and a same-time race included.
+    fn replicas() -> Vec<LwwMap<&'static str, i64>> {
+        let mut a = LwwMap::new();
+        a.insert("x", 1, stamp(1, "n0"));
+        a.insert("y", 2, stamp(3, "n0"));
+        a.remove("z", stamp(4, "n0"));
+        let mut b = LwwMap::new();
+        b.insert("x", 10, stamp(2, "n1"));
+        b.insert("y", 20, stamp(3, "n1"));
+        let mut c = LwwMap::new();
+        c.insert("z", 30, stamp(2, "n2"));
+        c.remove("x", stamp(2, "n0"));
+        vec![a, b, c]
+    }
+
+    fn merged(
+        maps: impl IntoIterator<Item = LwwMap<&'static str, i64>>,
+    ) -> LwwMap<&'static str, i64> {
+        let mut merged = LwwMap::new();
+        for map in maps {
+            merged.merge(map);
+        }
+        merged
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        for map in replicas() {
+            let mut twice = map.clone();
+            twice.merge(map.clone());
+            assert_eq!(twice, map);
+        }
+        let all = merged(replicas());
+        let mut again = all.clone();
+        again.merge(merged(replicas()));
+        assert_eq!(again, all);
+    }
+
+    #[test]
+    fn merge_order_does_not_matter() {
+        let replicas = replicas();
+        let expected = merged(replicas.clone());
+        for order in [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ] {
+            let maps = order.map(|i| replicas[i].clone());
+            assert_eq!(merged(maps), expected, "merged in order {:?}", order);
+        }
+        // Merging a merge is the same as merging everything at once.
+        let mut nested = merged([replicas[0].clone(), replicas[1].clone()]);
+        nested.merge(replicas[2].clone());
+        assert_eq!(nested, expected);
+        assert_eq!(expected.get(&"x"), Some(&10));
+        assert_eq!(expected.get(&"y"), Some(&20));
+        assert_eq!(expected.get(&"z"), None);
+    }
+
+    #[test]
+    fn delete_and_write_at_the_same_time_resolve_by_node() {
+        let mut write_first = LwwMap::new();
+        assert!(write_first.insert("x", 1, stamp(5, "n0")));
+        assert!(write_first.remove("x", stamp(5, "n1")));
+        let mut delete_first = LwwMap::new();
+        assert!(delete_first.remove("x", stamp(5, "n1")));
+        assert!(!delete_first.insert("x", 1, stamp(5, "n0")));
+        assert_eq!(write_first, delete_first);
+        assert_eq!(write_first.get(&"x"), None);
+
+        let mut write_wins = LwwMap::new();
+        write_wins.remove("x", stamp(5, "n0"));
+        assert!(write_wins.insert("x", 1, stamp(5, "n1")));
+        assert_eq!(write_wins.get(&"x"), Some(&1));
+    }
+
+    #[test]
+    fn an_equal_stamp_does_not_replace() {
+        let mut map = LwwMap::new();
+        assert!(map.insert("x", 1, stamp(5, "n0")));
+        assert!(!map.insert("x", 2, stamp(5, "n0")));
+        assert!(!map.remove("x", stamp(5, "n0")));
+        assert_eq!(map.get(&"x"), Some(&1));
+    }
+
+    #[test]
+    fn gc_only_drops_tombstones_before_the_horizon() {
+        let mut map = LwwMap::new();
+        map.insert("kept", 1, stamp(1, "n0"));
+        map.remove("old", stamp(2, "n0"));
+        map.remove("at", stamp(5, "n0"));
+        map.remove("new", stamp(7, "n0"));
+        assert_eq!(map.gc(5), 1);
+        assert_eq!(map.entry(&"old"), None);
+        assert!(map.entry(&"at").is_some());
+        assert!(map.entry(&"new").is_some());
+        assert_eq!(map.get(&"kept"), Some(&1));
+        assert_eq!(map.gc(5), 0);
+    }
+
+    #[test]
+    fn gc_before_every_replica_saw_the_delete_resurrects() {
+        let mut stale = LwwMap::new();
+        stale.insert("x", 1, stamp(1, "n0"));
+        let mut map = stale.clone();
+        map.remove("x", stamp(2, "n1"));
+        map.gc(3);
+        map.merge(stale);
+        assert_eq!(map.get(&"x"), Some(&1));
+    }
+
+    #[test]
+    fn delta_merges_like_the_whole_map() {
+        let all = merged(replicas());
+        let mut peer = LwwMap::new();
+        for (key, entry) in &all.entries {
+            if entry.stamp.time <= 2 {
+                peer.apply(*key, entry.clone());
+            }
+        }
+        peer.merge(all.delta(2));
+        assert_eq!(peer, all);
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        let map = merged(replicas());
+        let json = serde_json::to_string(&map).expect("serialize map");
+        let back: LwwMap<String, i64> = serde_json::from_str(&json).expect("deserialize map");
+        assert_eq!(back.len(), map.len());
+        assert_eq!(
+            back.entry(&"z".to_string()),
+            map.entry(&"z").cloned().as_ref()
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! The echo node: every echo is answered with an echo_ok carrying the same value. It
+//! lives in the library rather than in its binary so the benchmarks measure the node
+//! that ships.
+
+use std::{sync::atomic::AtomicUsize, time::Duration};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{ErrorCode, Event, Init, Message, Node, Output};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    /// Any JSON value is echoed back as is. Integers keep their exact value within the
+    /// range of i64 and u64, larger ones become floats, as the message is buffered to
+    /// find its type and serde_json's arbitrary_precision does not survive that.
+    Echo {
+        echo: serde_json::Value,
+    },
+    EchoOk {
+        echo: serde_json::Value,
+        /// Filler inflating the reply, see EchoConfig.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        padding: Option<String>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// Knobs making echo stand in for a heavier node when profiling the framework. Both
+/// are off by default.
+#[derive(Debug, Clone)]
+struct EchoConfig {
+    /// How long every echo is held before it is answered.
+    delay: Duration,
+    /// Size of the padding field added to every echo_ok, none if 0.
+    padding: usize,
+}
+
+impl EchoConfig {
+    /// Reads the configuration from the environment:
+    /// - `ECHO_DELAY_MS`: artificial delay of every echo in milliseconds (default 0)
+    /// - `ECHO_PADDING_BYTES`: bytes of padding in every echo_ok (default 0)
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            delay: Duration::from_millis(crate::env_or("ECHO_DELAY_MS", 0)?),
+            padding: crate::env_or("ECHO_PADDING_BYTES", 0)?,
+        })
+    }
+}
+
+/// Answers echos, configured from the environment by EchoConfig.
+pub struct EchoNode {
+    id: AtomicUsize,
+    delay: Duration,
+    /// The same bytes every time, so traces of padded runs compress well.
+    padding: Option<String>,
+    stdout: Mutex<Output>,
+}
+
+#[async_trait]
+impl Node<Payload> for EchoNode {
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Mutex<Output>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let config = EchoConfig::from_env()?;
+        eprintln!("echo config: {:?}", config);
+        Ok(Self {
+            id: 1.into(),
+            delay: config.delay,
+            padding: (config.padding > 0).then(|| "x".repeat(config.padding)),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(&self.id));
+        match reply.body.payload {
+            Payload::Echo { echo } => {
+                if self.delay > Duration::ZERO {
+                    tokio::time::sleep(self.delay).await;
+                }
+                reply.body.payload = Payload::EchoOk {
+                    echo,
+                    padding: self.padding.clone(),
+                };
+                reply
+                    .send(&self.stdout)
+                    .await
+                    .context("send response message")?;
+            }
+            Payload::EchoOk { .. } | Payload::Error { .. } => {}
+        };
+        Ok(())
+    }
+
+    async fn malformed(
+        &self,
+        message: Message<serde_json::Value>,
+        err: serde_json::Error,
+    ) -> anyhow::Result<()> {
+        // Only an echo without its field is answered, anything else is not ours to judge.
+        let payload = &message.body.payload;
+        if payload.get("type").and_then(|t| t.as_str()) != Some("echo")
+            || payload.get("echo").is_some()
+        {
+            eprintln!(
+                "dropping message that could not be deserialized ({}): {:?}",
+                err, message
+            );
+            return Ok(());
+        }
+        message
+            .into_reply(Some(&self.id))
+            .map_payload(|_| Payload::Error {
+                code: ErrorCode::MalformedRequest.code(),
+                text: "echo request is missing its echo field".to_string(),
+            })
+            .send(&self.stdout)
+            .await
+            .context("send error message")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{assert_golden, wire, Harness};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Sends `echo` to a node and returns the echo of its reply as sent.
+    async fn echo_back(echo: &str) -> serde_json::Value {
+        let mut node = Harness::<EchoNode, Payload>::new("n1", &["n1"]).await;
+        node.send_line(&format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":1,"echo":{}}}}}"#,
+            echo
+        ))
+        .await;
+        let sent: serde_json::Value =
+            serde_json::from_str(&node.recv_line().await).expect("parse reply");
+        assert_eq!(sent["body"]["type"], "echo_ok");
+        assert_eq!(sent["body"]["in_reply_to"], 1);
+        sent["body"]["echo"].clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn any_json_is_echoed_unchanged() {
+        for echo in [
+            r#""Please echo 35""#,
+            r#""héllo wörld ✓ 🦀 日本語""#,
+            r#"{"a":{"b":[1,2,{"c":null}]},"d":true,"e":-0.5}"#,
+            r#"[1,"two",3.25,[],{}]"#,
+            "null",
+            "false",
+            "9223372036854775807",
+            "-9223372036854775808",
+            "18446744073709551615",
+        ] {
+            let expected: serde_json::Value = serde_json::from_str(echo).expect("parse echo");
+            assert_eq!(echo_back(echo).await, expected, "echo of {}", echo);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn big_integers_keep_their_digits() {
+        assert_eq!(
+            echo_back("18446744073709551615").await.to_string(),
+            "18446744073709551615"
+        );
+        assert_eq!(
+            echo_back(r#"{"n":-9223372036854775808}"#).await.to_string(),
+            r#"{"n":-9223372036854775808}"#
+        );
+        // Beyond u64 integers become floats, see Payload::Echo.
+        assert!(echo_back("18446744073709551616").await.is_f64());
+    }
+
+    #[test]
+    fn missing_echo_is_not_an_echo() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1}}"#;
+        assert!(serde_json::from_str::<Message<Payload>>(line).is_err());
+    }
+
+    async fn configured(vars: &[(&str, Option<&str>)]) -> Harness<EchoNode, Payload> {
+        Harness::<EchoNode, Payload>::builder("n1", &["n1"])
+            .env(vars)
+            .start()
+            .await
+    }
+
+    fn echo() -> Payload {
+        Payload::Echo {
+            echo: serde_json::json!("Please echo 35"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn echoes_are_held_for_the_delay() {
+        let mut node = configured(&[("ECHO_DELAY_MS", Some("100"))]).await;
+        let id = node.send("c1", echo()).await;
+        assert!(node.advance(Duration::from_millis(99)).await.is_empty());
+        let replies = node.advance(Duration::from_millis(1)).await;
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].body.in_reply_to, Some(id));
+        assert!(matches!(replies[0].body.payload, Payload::EchoOk { .. }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_carry_the_configured_padding() {
+        for (bytes, expected) in [
+            (None, None),
+            (Some("0"), None),
+            (Some("64"), Some("x".repeat(64))),
+        ] {
+            let mut node = configured(&[("ECHO_PADDING_BYTES", bytes)]).await;
+            let id = node.send("c1", echo()).await;
+            match node.expect_reply_to(id).await.body.payload {
+                Payload::EchoOk { padding, .. } => {
+                    assert_eq!(padding, expected, "ECHO_PADDING_BYTES={:?}", bytes)
+                }
+                payload => panic!("expected echo_ok, got {:?}", payload),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_init_handshake_matches_its_golden_file() {
+        let node = Harness::<EchoNode, Payload>::new("n2", &["n1", "n2", "n3"]).await;
+        assert_golden("init", node.transcript());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn echo_messages_match_their_golden_file() {
+        let mut node = Harness::<EchoNode, Payload>::new("n1", &["n1"]).await;
+        let id = node
+            .send_json(
+                "c1",
+                serde_json::json!({"type": "echo", "echo": "Please echo 35"}),
+            )
+            .await;
+        node.expect_reply_to(id).await;
+        node.send_line(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7}}"#)
+            .await;
+        node.expect_reply_to(7).await;
+        // The node keeps serving after a malformed echo.
+        let id = node
+            .send_json("c1", serde_json::json!({"type": "echo", "echo": [35]}))
+            .await;
+        node.expect_reply_to(id).await;
+        assert_golden("echo", node.transcript());
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        prop_oneof![
+            wire::json().prop_map(|echo| Payload::Echo { echo }),
+            (wire::json(), proptest::option::of(".*"))
+                .prop_map(|(echo, padding)| Payload::EchoOk { echo, padding }),
+            wire::error().prop_map(|(code, text)| Payload::Error { code, text }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(payload())) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<Payload>("echo");
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+/// Error codes defined by the Maelstrom protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    /// Any code not defined by Maelstrom itself.
+    Custom(usize),
+}
+
+impl ErrorCode {
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            0 => Self::Timeout,
+            1 => Self::NodeNotFound,
+            10 => Self::NotSupported,
+            11 => Self::TemporarilyUnavailable,
+            12 => Self::MalformedRequest,
+            13 => Self::Crash,
+            14 => Self::Abort,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            30 => Self::TxnConflict,
+            code => Self::Custom(code),
+        }
+    }
+
+    pub fn code(self) -> usize {
+        match self {
+            Self::Timeout => 0,
+            Self::NodeNotFound => 1,
+            Self::NotSupported => 10,
+            Self::TemporarilyUnavailable => 11,
+            Self::MalformedRequest => 12,
+            Self::Crash => 13,
+            Self::Abort => 14,
+            Self::KeyDoesNotExist => 20,
+            Self::KeyAlreadyExists => 21,
+            Self::PreconditionFailed => 22,
+            Self::TxnConflict => 30,
+            Self::Custom(code) => code,
+        }
+    }
+
+    /// Returns the code of the MaelstromError somewhere in `err`, if there is one.
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        err.downcast_ref::<MaelstromError>().map(|err| err.code)
+    }
+}
+
+/// An error reply as sent by Maelstrom services and nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    pub fn from_code(code: usize, text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::from_code(code), text)
+    }
+}
+
+impl fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({}): {}", self.code, self.code.code(), self.text)
+    }
+}
+
+impl std::error::Error for MaelstromError {}
@@ -0,0 +1,243 @@
+//! Building blocks for gossiping a growing set of integers to peers: a set stored as
+//! ranges, which stays small while the integers come mostly in order, and an outbox
+//! per peer that batches new integers and sends them again until the peer acks them.
+
+use std::{
+    cmp,
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A set of integers stored as disjoint inclusive ranges, which serializes as a list of
+/// `[start, end]` pairs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(from = "Vec<(u64, u64)>", into = "Vec<(u64, u64)>")]
+pub struct RangeSet {
+    /// The end of every range by its start. Ranges neither overlap nor touch.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl From<Vec<(u64, u64)>> for RangeSet {
+    fn from(ranges: Vec<(u64, u64)>) -> Self {
+        let mut set = Self::new();
+        for (start, end) in ranges {
+            set.insert_range(start, end);
+        }
+        set
+    }
+}
+
+impl From<RangeSet> for Vec<(u64, u64)> {
+    fn from(set: RangeSet) -> Self {
+        set.ranges.into_iter().collect()
+    }
+}
+
+impl FromIterator<u64> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> Self {
+        let mut set = Self::new();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl RangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `value` and returns whether it was new.
+    pub fn insert(&mut self, value: u64) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+        self.insert_range(value, value);
+        true
+    }
+
+    /// Adds every value from `start` to `end`, both included. Does nothing if `start`
+    /// is after `end`.
+    pub fn insert_range(&mut self, mut start: u64, mut end: u64) {
+        if start > end {
+            return;
+        }
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..start).next_back() {
+            if prev_end.saturating_add(1) >= start {
+                start = prev_start;
+                end = cmp::max(end, prev_end);
+            }
+        }
+        while let Some((&next_start, &next_end)) = self.ranges.range(start..).next() {
+            if next_start > end.saturating_add(1) {
+                break;
+            }
+            end = cmp::max(end, next_end);
+            self.ranges.remove(&next_start);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Adds every value of `other`.
+    pub fn extend(&mut self, other: &RangeSet) {
+        for (start, end) in other.ranges() {
+            self.insert_range(start, end);
+        }
+    }
+
+    /// Removes every value of `other`.
+    pub fn remove_all(&mut self, other: &RangeSet) {
+        for (start, end) in other.ranges() {
+            self.remove_range(start, end);
+        }
+    }
+
+    fn remove_range(&mut self, start: u64, end: u64) {
+        let first = match self.ranges.range(..start).next_back() {
+            Some((&prev_start, &prev_end)) if prev_end >= start => prev_start,
+            _ => start,
+        };
+        let overlapping: Vec<(u64, u64)> = self
+            .ranges
+            .range(first..=end)
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (range_start, range_end) in overlapping {
+            self.ranges.remove(&range_start);
+            if range_start < start {
+                self.ranges.insert(range_start, start - 1);
+            }
+            if range_end > end {
+                self.ranges.insert(end + 1, range_end);
+            }
+        }
+    }
+
+    /// Returns the values of this set that are not in `other`.
+    pub fn difference(&self, other: &RangeSet) -> RangeSet {
+        let mut difference = self.clone();
+        difference.remove_all(other);
+        difference
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= value)
+    }
+
+    /// Returns the number of values.
+    pub fn len(&self) -> u64 {
+        self.ranges().map(|(start, end)| end - start + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Iterates over the ranges in order, as inclusive `(start, end)` pairs.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(start, end)| (*start, *end))
+    }
+
+    /// Iterates over the values in order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges().flat_map(|(start, end)| start..=end)
+    }
+}
+
+/// When an outbox sends.
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    /// How long new values wait for more to join them before they are sent.
+    pub debounce: Duration,
+    /// How long sent values wait for an ack before they are sent again. Doubles with
+    /// every send that goes unacked, and drops back once an ack comes in.
+    pub min_retry: Duration,
+    pub max_retry: Duration,
+}
+
+/// The values a peer has not acked yet.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    timing: Timing,
+    /// Values never sent, and when they are sent.
+    fresh: RangeSet,
+    fresh_due: Option<Instant>,
+    /// Values sent but not acked yet, and when they are sent again.
+    sent: RangeSet,
+    retry_due: Option<Instant>,
+    retry: Duration,
+}
+
+impl Outbox {
+    pub fn new(timing: Timing) -> Self {
+        Self {
+            timing,
+            fresh: RangeSet::new(),
+            fresh_due: None,
+            sent: RangeSet::new(),
+            retry_due: None,
+            retry: timing.min_retry,
+        }
+    }
+
+    /// Queues `values` for the peer. They go out one debounce after the oldest queued
+    /// value that has not been sent yet.
+    pub fn push(&mut self, values: &RangeSet, now: Instant) {
+        let values = values.difference(&self.sent);
+        if values.is_empty() {
+            return;
+        }
+        self.fresh.extend(&values);
+        self.fresh_due.get_or_insert(now + self.timing.debounce);
+    }
+
+    /// Drops `values`, which the peer has, and returns whether any were waiting for it.
+    /// An ack that makes progress resets the retry interval.
+    pub fn ack(&mut self, values: &RangeSet) -> bool {
+        let before = self.fresh.len() + self.sent.len();
+        self.fresh.remove_all(values);
+        self.sent.remove_all(values);
+        if self.fresh.is_empty() {
+            self.fresh_due = None;
+        }
+        if self.sent.is_empty() {
+            self.retry_due = None;
+        }
+        let progress = self.fresh.len() + self.sent.len() < before;
+        if progress {
+            self.retry = self.timing.min_retry;
+        }
+        progress
+    }
+
+    /// Returns the values to send to the peer now, if any: the new ones once their
+    /// debounce is over, together with the unacked ones once their retry is due.
+    pub fn poll(&mut self, now: Instant) -> Option<RangeSet> {
+        let fresh_ready = self.fresh_due.is_some_and(|due| due <= now);
+        let retry_ready = self.retry_due.is_some_and(|due| due <= now);
+        if !fresh_ready && !retry_ready {
+            return None;
+        }
+        let mut batch = std::mem::take(&mut self.fresh);
+        self.fresh_due = None;
+        if retry_ready {
+            batch.extend(&self.sent);
+            self.retry = cmp::min(self.retry * 2, self.timing.max_retry);
+            self.retry_due = None;
+        }
+        self.sent.extend(&batch);
+        self.retry_due.get_or_insert(now + self.retry);
+        Some(batch)
+    }
+
+    /// Returns whether no value is waiting for the peer.
+    pub fn is_empty(&self) -> bool {
+        self.fresh.is_empty() && self.sent.is_empty()
+    }
+}
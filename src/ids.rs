@@ -0,0 +1,214 @@
+//! Id formats of the unique-ids workload. Turning a number into an id never locks
+//! or waits, so a node can hand ids out as fast as it can count.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Bits of the random section of a UUIDv7 after its variant, taken by the counter. The
+/// node index takes the UUID_NODE_BITS above them.
+pub const UUID_COUNTER_BITS: u32 = 46;
+
+pub const UUID_NODE_BITS: u32 = 16;
+
+/// Formats number `n` of the node with index `index` as `{index}-{n}`.
+pub fn node_seq(index: usize, n: u64) -> String {
+    format!("{}-{}", index, n)
+}
+
+/// Builds UUIDv7s. Ids of a node are unique as long as it hands out fewer than
+/// 2^46 of them, and those of different nodes as long as there are fewer than 2^16
+/// nodes, whatever the clock does.
+#[derive(Debug)]
+pub struct UuidV7<C = SystemClock> {
+    /// Index of the node in the cluster.
+    node: u64,
+    /// Scrambles the counter, which keeps it unique as XOR is a bijection.
+    mask: u64,
+    /// Unix time in milliseconds of the last id.
+    last_millis: AtomicU64,
+    /// Unix time in milliseconds the wall clock read for the last id, which may be
+    /// behind `last_millis` once the clock went back.
+    last_reading: AtomicU64,
+    /// State of the splitmix64 generator filling the random bits.
+    rng: AtomicU64,
+    wall: C,
+}
+
+impl UuidV7 {
+    pub fn new(node: u64) -> Self {
+        Self::with_clock(node, SystemClock)
+    }
+}
+
+impl<C: Clock> UuidV7<C> {
+    /// Returns a builder stamping ids with the time `wall` reads.
+    pub fn with_clock(node: u64, wall: C) -> Self {
+        let seed = wall.unix_time().as_nanos() as u64 ^ node.rotate_left(32);
+        let rng = AtomicU64::new(seed);
+        let mask = splitmix64(&rng) & ((1 << UUID_COUNTER_BITS) - 1);
+        Self {
+            node,
+            mask,
+            last_millis: AtomicU64::new(0),
+            last_reading: AtomicU64::new(0),
+            rng,
+            wall,
+        }
+    }
+
+    /// Formats the id with number `n` of this node.
+    pub fn format(&self, n: u64) -> String {
+        let (millis, went_back) = self.millis();
+        if let Some(went_back) = went_back {
+            eprintln!(
+                "uuidv7: wall clock went back by {}ms, ids keep the time of the last one",
+                went_back
+            );
+        }
+        let random = splitmix64(&self.rng);
+        let high = (millis & 0xffff_ffff_ffff) << 16 | 0x7000 | (random & 0xfff);
+        let low = 0b10 << 62
+            | (self.node & 0xffff) << UUID_COUNTER_BITS
+            | (n ^ self.mask) & ((1 << UUID_COUNTER_BITS) - 1);
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0xffff,
+            low >> 48,
+            low & 0xffff_ffff_ffff
+        )
+    }
+
+    /// Returns the time in milliseconds to stamp the next id with, and how far the wall
+    /// clock went back since the last id, if it did.
+    fn millis(&self) -> (u64, Option<u64>) {
+        let now = self.wall.unix_time().as_millis() as u64;
+        let previous = self.last_reading.swap(now, Ordering::Relaxed);
+        // Uniqueness rests on the counter alone; the time only has to never go back.
+        let millis = self.last_millis.fetch_max(now, Ordering::Relaxed).max(now);
+        (millis, (now < previous).then(|| previous - now))
+    }
+}
+
+/// Returns the next number of the splitmix64 generator with state `state`.
+fn splitmix64(state: &AtomicU64) -> u64 {
+    let mut z = state
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc, time::Duration};
+
+    use super::*;
+
+    /// A wall clock the test sets, in milliseconds.
+    #[derive(Debug, Clone, Default)]
+    struct MockClock(Arc<AtomicU64>);
+
+    impl MockClock {
+        fn set(&self, millis: u64) {
+            self.0.store(millis, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn unix_time(&self) -> Duration {
+            Duration::from_millis(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    /// The Unix time in milliseconds in the first 48 bits of `id`.
+    fn time_of(id: &str) -> u64 {
+        u64::from_str_radix(&id.replace('-', "")[..12], 16).expect("hex time")
+    }
+
+    #[test]
+    fn ids_never_go_back_when_the_clock_does() {
+        let clock = MockClock::default();
+        let uuid = UuidV7::with_clock(3, clock.clone());
+        clock.set(1_700_000_000_000);
+        assert_eq!(time_of(&uuid.format(0)), 1_700_000_000_000);
+
+        // A small step back, as NTP makes.
+        clock.set(1_699_999_999_995);
+        assert_eq!(uuid.millis(), (1_700_000_000_000, Some(5)));
+        assert_eq!(time_of(&uuid.format(1)), 1_700_000_000_000);
+        clock.set(1_700_000_000_010);
+        assert_eq!(uuid.millis(), (1_700_000_000_010, None));
+
+        // A large one, as restoring a VM snapshot makes.
+        clock.set(1_699_996_400_010);
+        assert_eq!(uuid.millis(), (1_700_000_000_010, Some(3_600_000)));
+        for n in 2..10 {
+            assert_eq!(time_of(&uuid.format(n)), 1_700_000_000_010);
+        }
+        clock.set(1_700_000_000_011);
+        assert_eq!(time_of(&uuid.format(10)), 1_700_000_000_011);
+    }
+
+    #[test]
+    fn ids_are_rfc_4122_strings() {
+        let uuid = UuidV7::new(7);
+        for n in 0..100 {
+            let id = uuid.format(n);
+            let groups: Vec<_> = id.split('-').map(str::len).collect();
+            assert_eq!(groups, [8, 4, 4, 4, 12], "{}", id);
+            assert!(
+                id.chars()
+                    .all(|c| c == '-' || c.is_ascii_digit() || ('a'..='f').contains(&c)),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn ids_have_version_7_and_the_rfc_4122_variant() {
+        let uuid = UuidV7::new(7);
+        for n in 0..100 {
+            let id = uuid.format(n).replace('-', "");
+            assert_eq!(&id[12..13], "7", "{}", id);
+            let variant = u8::from_str_radix(&id[16..17], 16).expect("hex digit");
+            assert_eq!(variant >> 2, 0b10, "{}", id);
+        }
+    }
+
+    #[test]
+    fn the_time_prefix_never_goes_back() {
+        let clock = MockClock::default();
+        let uuid = UuidV7::with_clock(0, clock.clone());
+        let mut last = 0;
+        for (n, millis) in [5_000, 5_000, 5_007, 4_000, 5_006, 6_000, 6_001]
+            .into_iter()
+            .enumerate()
+        {
+            clock.set(millis);
+            let time = time_of(&uuid.format(n as u64));
+            assert!(time >= last, "{} after {}", time, last);
+            assert!(time >= millis, "{} for a clock at {}", time, millis);
+            last = time;
+        }
+    }
+
+    #[test]
+    fn ids_of_several_nodes_are_unique() {
+        // All in the same millisecond, so only the counter and node tell them apart.
+        let clock = MockClock::default();
+        clock.set(1_700_000_000_000);
+        let mut ids = HashSet::new();
+        for node in 0..4 {
+            let uuid = UuidV7::with_clock(node, clock.clone());
+            for n in 0..25_000 {
+                assert!(ids.insert(uuid.format(n)), "duplicate id");
+            }
+        }
+        assert_eq!(ids.len(), 100_000);
+    }
+}
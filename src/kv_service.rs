@@ -0,0 +1,172 @@
+//! The requests of Maelstrom's key/value services and how they apply to a store, for
+//! the nodes that stand in for lin-kv and seq-kv when testing locally and for the
+//! testkit's MockKvService.
+
+use std::{cmp, collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::ErrorCode;
+
+/// The read, write and cas requests of KVPayload and their replies. Keys are any JSON
+/// value rather than strings, as the lin-kv workload uses integers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+impl Payload {
+    pub fn is_reply(&self) -> bool {
+        matches!(
+            self,
+            Payload::ReadOk { .. } | Payload::WriteOk | Payload::CasOk | Payload::Error { .. }
+        )
+    }
+}
+
+/// Values by the JSON encoding of their key, which tells `1` and `"1"` apart.
+pub type Store = HashMap<String, Value>;
+
+/// Applies a request to `store` and returns the reply, or an error payload if it
+/// cannot be applied.
+pub fn apply(store: &mut Store, request: Payload) -> Payload {
+    match request {
+        Payload::Read { key } => match store.get(&key.to_string()) {
+            Some(value) => Payload::ReadOk {
+                value: value.clone(),
+            },
+            None => key_does_not_exist(&key),
+        },
+        Payload::Write { key, value } => {
+            store.insert(key.to_string(), value);
+            Payload::WriteOk
+        }
+        Payload::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        } => match store.get_mut(&key.to_string()) {
+            // Values compare deeply and by type, so 1, 1.0 and "1" all differ.
+            Some(current) if *current == from => {
+                *current = to;
+                Payload::CasOk
+            }
+            Some(current) => Payload::Error {
+                code: ErrorCode::PreconditionFailed.code(),
+                text: format!("expected {}, but had {}", from, current),
+            },
+            None if create_if_not_exists => {
+                store.insert(key.to_string(), to);
+                Payload::CasOk
+            }
+            None => key_does_not_exist(&key),
+        },
+        request => Payload::Error {
+            code: ErrorCode::NotSupported.code(),
+            text: format!("unsupported request {:?}", request),
+        },
+    }
+}
+
+pub fn key_does_not_exist(key: &Value) -> Payload {
+    Payload::Error {
+        code: ErrorCode::KeyDoesNotExist.code(),
+        text: format!("key {} does not exist", key),
+    }
+}
+
+/// Every version of the store, numbered by the writes that made them: version `v` is
+/// the store after the first `v` writes.
+#[derive(Default)]
+pub struct History {
+    latest: Store,
+    /// When every write took effect, the one making version `v` at `v - 1`.
+    times: Vec<Instant>,
+    /// The values of every key, oldest first, with the version that wrote them.
+    values: HashMap<String, Vec<(usize, Value)>>,
+    /// The latest version every client has seen, which its reads may not go below.
+    sessions: HashMap<String, usize>,
+}
+
+impl History {
+    /// The latest value of every key.
+    pub fn latest(&self) -> &Store {
+        &self.latest
+    }
+
+    /// Reads `key` from the oldest version this client may still see: the last one
+    /// older than the staleness window, or the last one it saw if that is newer.
+    pub fn read(
+        &mut self,
+        client: &str,
+        key: &Value,
+        now: Instant,
+        staleness: Duration,
+    ) -> Payload {
+        let stale = match now.checked_sub(staleness) {
+            Some(cutoff) => self.times.partition_point(|time| *time <= cutoff),
+            None => 0,
+        };
+        let seen = self.sessions.entry(client.to_string()).or_default();
+        let version = cmp::max(*seen, stale);
+        *seen = version;
+        let value = self
+            .values
+            .get(&key.to_string())
+            .and_then(|values| values.iter().rev().find(|(written, _)| *written <= version));
+        match value {
+            Some((_, value)) => Payload::ReadOk {
+                value: value.clone(),
+            },
+            None => key_does_not_exist(key),
+        }
+    }
+
+    /// Applies a write or cas to the latest version, which the client sees from then
+    /// on.
+    pub fn update(&mut self, client: &str, request: Payload, now: Instant) -> Payload {
+        let key = match &request {
+            Payload::Write { key, .. } | Payload::Cas { key, .. } => Some(key.to_string()),
+            _ => None,
+        };
+        let reply = apply(&mut self.latest, request);
+        if let (Some(key), Payload::WriteOk | Payload::CasOk) = (key, &reply) {
+            self.times.push(now);
+            let value = self.latest[&key].clone();
+            self.values
+                .entry(key)
+                .or_default()
+                .push((self.times.len(), value));
+        }
+        // A failed cas compared against the latest version, so it saw it as well.
+        self.sessions.insert(client.to_string(), self.times.len());
+        reply
+    }
+}
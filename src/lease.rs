@@ -0,0 +1,356 @@
+//! A lease on a name, kept as a record in lin-kv that only ever changes by cas. Only
+//! one node holds the lease at a time, for as long as it keeps renewing it.
+//!
+//! Nodes do not share a clock, so the record holds no expiry time. The holder bumps
+//! the term of the record on every renewal, and a node waiting for the lease only
+//! takes it over once it has seen the same term for a whole ttl on its own clock. The
+//! holder in turn trusts the lease for a ttl from when it sent its last successful
+//! renewal, which is before any other node could have seen that term. Both sides only
+//! compare durations measured on their own clock, so clocks may be set arbitrarily
+//! apart as long as they run at about the same rate.
+//!
+//! That guarantee has limits. A holder that checks `is_held` and then stalls, in a long
+//! pause or a slow write, can act after its lease went to another node, so two nodes
+//! both act as the holder. Clocks that run apart by more than the renewal slack of two
+//! thirds of the ttl do the same. Writes that must not come from a former holder should
+//! carry the `term` they were made under, as a fencing token the storage checks.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{ErrorCode, KV};
+
+const STORAGE: &str = "lin-kv";
+
+/// The record of a lease in storage.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The holder, or None once the last one released the lease.
+    pub owner: Option<String>,
+    /// Grows with every acquisition and renewal.
+    pub term: u64,
+}
+
+#[derive(Debug)]
+struct Held {
+    record: Record,
+    valid_until: Instant,
+}
+
+#[derive(Debug)]
+pub struct Lease {
+    key: String,
+    node: String,
+    ttl: Duration,
+    /// The record last written by this node, or None once the lease is lost.
+    held: Mutex<Option<Held>>,
+}
+
+impl Lease {
+    /// Waits until this node holds the lease on `name`, then returns it. A lease nobody
+    /// holds is taken at once, one held by another node once its record has not
+    /// changed for `ttl`. The caller must call `renew` every `renew_interval` from then
+    /// on to keep the lease.
+    pub async fn acquire<K: KV<Value>>(
+        kv: &K,
+        name: &str,
+        node: &str,
+        ttl: Duration,
+    ) -> anyhow::Result<Self> {
+        let key = format!("lease:{}", name);
+        // The record of another holder, and when it was first seen.
+        let mut watched: Option<(Record, Instant)> = None;
+        loop {
+            let current = match kv.read(STORAGE, key.clone()).await {
+                Ok(value) => Some(
+                    serde_json::from_value::<Record>(value).context("deserialize lease record")?,
+                ),
+                Err(err) if ErrorCode::of(&err) == Some(ErrorCode::KeyDoesNotExist) => None,
+                Err(err) => return Err(err).context("read lease"),
+            };
+            let now = Instant::now();
+            let takeover = match &current {
+                None => true,
+                Some(record) if record.owner.is_none() => true,
+                // Renewals always change the term, so an unchanged record is one its
+                // holder has not renewed since it was first seen.
+                Some(record) => match &watched {
+                    Some((seen, since)) if seen == record => now.duration_since(*since) >= ttl,
+                    _ => {
+                        watched = Some((record.clone(), now));
+                        false
+                    }
+                },
+            };
+            if takeover {
+                let record = Record {
+                    owner: Some(node.to_string()),
+                    term: current.as_ref().map_or(0, |record| record.term) + 1,
+                };
+                if let Some(valid_until) = cas(kv, &key, current.as_ref(), &record, ttl).await? {
+                    return Ok(Self {
+                        key,
+                        node: node.to_string(),
+                        ttl,
+                        held: Mutex::new(Some(Held {
+                            record,
+                            valid_until,
+                        })),
+                    });
+                }
+                // Someone else got there first, watch their record from now on.
+                watched = None;
+            }
+            tokio::time::sleep(ttl / 3).await;
+        }
+    }
+
+    /// How often `renew` must be called. Renewing three times per ttl leaves room for
+    /// a renewal or two to fail before the lease runs out.
+    pub fn renew_interval(&self) -> Duration {
+        self.ttl / 3
+    }
+
+    /// Extends the lease by another ttl. Returns whether it is still held, which it is
+    /// not once another node took it over. A renewal that fails for other reasons
+    /// leaves the lease held until it runs out.
+    pub async fn renew<K: KV<Value>>(&self, kv: &K) -> anyhow::Result<bool> {
+        let mut held = self.held.lock().await;
+        let Some(current) = held.as_ref() else {
+            return Ok(false);
+        };
+        let record = Record {
+            owner: Some(self.node.clone()),
+            term: current.record.term + 1,
+        };
+        match cas(kv, &self.key, Some(&current.record), &record, self.ttl).await {
+            Ok(Some(valid_until)) => {
+                *held = Some(Held {
+                    record,
+                    valid_until,
+                });
+                Ok(true)
+            }
+            Ok(None) => {
+                *held = None;
+                Ok(false)
+            }
+            // The renewal may have gone through, in which case only the record in
+            // storage knows the new term and the next renewal finds the lease lost.
+            Err(err) => Err(err).context("renew lease"),
+        }
+    }
+
+    /// Gives the lease up, so the next node takes it without waiting for it to run out.
+    pub async fn release<K: KV<Value>>(&self, kv: &K) -> anyhow::Result<()> {
+        let Some(current) = self.held.lock().await.take() else {
+            return Ok(());
+        };
+        let record = Record {
+            owner: None,
+            term: current.record.term + 1,
+        };
+        cas(kv, &self.key, Some(&current.record), &record, self.ttl)
+            .await
+            .context("release lease")?;
+        Ok(())
+    }
+
+    /// Returns whether the lease is held right now. It may run out right after.
+    pub async fn is_held(&self) -> bool {
+        self.held
+            .lock()
+            .await
+            .as_ref()
+            .is_some_and(|held| Instant::now() < held.valid_until)
+    }
+
+    /// Returns the term the lease is held under, which grows with every renewal and
+    /// serves as a fencing token, or None once it is lost.
+    pub async fn term(&self) -> Option<u64> {
+        self.held.lock().await.as_ref().map(|held| held.record.term)
+    }
+}
+
+/// Swaps the lease record from `from`, None if there is none yet, to `to`. Returns
+/// until when the new record may be trusted, or None if the record was not `from`.
+async fn cas<K: KV<Value>>(
+    kv: &K,
+    key: &str,
+    from: Option<&Record>,
+    to: &Record,
+    ttl: Duration,
+) -> anyhow::Result<Option<Instant>> {
+    // No one could have seen the new record before the request was sent.
+    let sent = Instant::now();
+    let from = match from {
+        Some(from) => serde_json::to_value(from).context("serialize lease record")?,
+        None => Value::Null,
+    };
+    let to = serde_json::to_value(to).context("serialize lease record")?;
+    match kv.cas(STORAGE, key.to_string(), from, to, true).await {
+        Ok(()) => Ok(Some(sent + ttl)),
+        Err(err) if ErrorCode::of(&err) == Some(ErrorCode::PreconditionFailed) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use async_trait::async_trait;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::MaelstromError;
+
+    const TTL: Duration = Duration::from_millis(300);
+
+    /// lin-kv in memory, with the error codes Maelstrom replies with.
+    #[derive(Default)]
+    struct MemoryKv {
+        values: std::sync::Mutex<HashMap<String, Value>>,
+    }
+
+    #[async_trait]
+    impl KV<Value> for MemoryKv {
+        async fn read(&self, _storage: &str, key: String) -> anyhow::Result<Value>
+        where
+            Value: Deserialize<'static> + Send,
+        {
+            let values = self.values.lock().expect("kv poisoned");
+            values.get(&key).cloned().ok_or_else(|| {
+                MaelstromError::new(ErrorCode::KeyDoesNotExist, "key does not exist").into()
+            })
+        }
+
+        async fn write(&self, _storage: &str, key: String, val: Value) -> anyhow::Result<()>
+        where
+            Value: Serialize + Send,
+        {
+            self.values.lock().expect("kv poisoned").insert(key, val);
+            Ok(())
+        }
+
+        async fn cas(
+            &self,
+            _storage: &str,
+            key: String,
+            from: Value,
+            to: Value,
+            put: bool,
+        ) -> anyhow::Result<()>
+        where
+            Value: Serialize + Deserialize<'static> + Send,
+        {
+            let mut values = self.values.lock().expect("kv poisoned");
+            match values.get(&key) {
+                None if !put => Err(MaelstromError::new(
+                    ErrorCode::KeyDoesNotExist,
+                    "key does not exist",
+                )
+                .into()),
+                Some(current) if *current != from => Err(MaelstromError::new(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {}, found {}", from, current),
+                )
+                .into()),
+                _ => {
+                    values.insert(key, to);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    async fn acquire(kv: &MemoryKv, node: &str) -> Lease {
+        Lease::acquire(kv, "leader", node, TTL)
+            .await
+            .expect("acquire lease")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_free_lease_is_taken_at_once() {
+        let kv = MemoryKv::default();
+        let started = Instant::now();
+        let lease = acquire(&kv, "n0").await;
+        assert_eq!(Instant::now(), started);
+        assert!(lease.is_held().await);
+        assert_eq!(lease.term().await, Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn takeover_waits_until_the_record_went_unchanged_for_a_ttl() {
+        let kv = MemoryKv::default();
+        let held = acquire(&kv, "n0").await;
+        let started = Instant::now();
+        let lease = acquire(&kv, "n1").await;
+        let waited = Instant::now() - started;
+        assert!(waited >= TTL, "took over after {:?}", waited);
+        assert!(waited < TTL * 2, "took over after {:?}", waited);
+        assert!(lease.is_held().await);
+        assert_eq!(
+            lease.term().await,
+            Some(held.term().await.expect("term") + 1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_renewed_lease_is_not_taken_over() {
+        let kv = Arc::new(MemoryKv::default());
+        let held = Arc::new(acquire(&kv, "n0").await);
+        let renewer = {
+            let (kv, held) = (kv.clone(), held.clone());
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(held.renew_interval()).await;
+                    assert!(held.renew(&*kv).await.expect("renew lease"));
+                }
+            })
+        };
+        let waiting = tokio::time::timeout(TTL * 10, acquire(&kv, "n1")).await;
+        assert!(waiting.is_err(), "took over a renewed lease");
+        assert!(held.is_held().await);
+        renewer.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn renew_fails_after_a_takeover() {
+        let kv = MemoryKv::default();
+        let old = acquire(&kv, "n0").await;
+        let new = acquire(&kv, "n1").await;
+        assert!(!old.renew(&kv).await.expect("renew lease"));
+        assert!(!old.is_held().await);
+        assert_eq!(old.term().await, None);
+        // Losing the lease leaves the record of the new holder alone.
+        assert!(new.renew(&kv).await.expect("renew lease"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn release_lets_the_next_node_take_over_at_once() {
+        let kv = MemoryKv::default();
+        let old = acquire(&kv, "n0").await;
+        old.release(&kv).await.expect("release lease");
+        assert!(!old.is_held().await);
+        let started = Instant::now();
+        let new = acquire(&kv, "n1").await;
+        assert_eq!(Instant::now(), started);
+        assert_eq!(new.term().await, Some(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lease_not_renewed_runs_out() {
+        let kv = MemoryKv::default();
+        let lease = acquire(&kv, "n0").await;
+        tokio::time::sleep(TTL).await;
+        assert!(!lease.is_held().await);
+        // Nobody took it over, so renewing still works.
+        assert!(lease.renew(&kv).await.expect("renew lease"));
+        assert!(lease.is_held().await);
+    }
+}
@@ -1,16 +1,40 @@
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
 use tokio::task::JoinSet;
 
+pub mod clock;
+pub mod crdt;
+pub mod echo;
+pub mod error;
+pub mod gossip;
+pub mod ids;
+pub mod kv_service;
+pub mod lease;
+pub mod merkle;
+pub mod raft;
+pub mod rpc;
+pub mod sharding;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod topology;
+
+pub use clock::{HybridClock, LamportClock, VectorClock};
+pub use error::{ErrorCode, MaelstromError};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<Payload> {
     pub src: String,
@@ -28,21 +52,37 @@ pub struct Body<Payload> {
 }
 
 impl<Payload> Message<Payload> {
+    /// Returns the same message with its payload turned into another type.
+    pub fn map_payload<P>(self, f: impl FnOnce(Payload) -> P) -> Message<P> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload: f(self.body.payload),
+            },
+        }
+    }
+
     pub fn into_reply(self, id: Option<&AtomicUsize>) -> Self {
         Self {
             src: self.dest,
             dest: self.src,
             body: Body {
-                id: id.map(|id| id.fetch_add(1, Ordering::SeqCst)),
+                // Message ids only have to be unique, which needs no ordering.
+                id: id.map(|id| id.fetch_add(1, Ordering::Relaxed)),
                 in_reply_to: self.body.id,
                 payload: self.body.payload,
             },
         }
     }
 
-    pub async fn send(&self, out: &Mutex<tokio::io::Stdout>) -> anyhow::Result<()>
+    /// Writes the message as one line to `out`, which is stdout for a running node.
+    pub async fn send<W>(&self, out: &Mutex<W>) -> anyhow::Result<()>
     where
         Payload: Serialize,
+        W: tokio::io::AsyncWrite + Unpin,
     {
         let raw_msg = serde_json::to_string(self).context("deserialize message")?;
         let mut out = out.lock().await;
@@ -70,29 +110,64 @@ pub struct Init {
     pub node_ids: Vec<String>,
 }
 
+impl Init {
+    /// Returns the position of this node among the sorted node ids, which every node
+    /// computes alike, whatever order Maelstrom lists the nodes in.
+    pub fn node_index(&self) -> anyhow::Result<usize> {
+        let mut node_ids: Vec<&str> = self.node_ids.iter().map(String::as_str).collect();
+        node_ids.sort_unstable();
+        node_ids.dedup();
+        node_ids.binary_search(&self.node_id.as_str()).map_err(|_| {
+            anyhow::anyhow!(
+                "node {} is not among the node ids {:?}",
+                self.node_id,
+                self.node_ids
+            )
+        })
+    }
+}
+
+/// Where a node writes its messages: stdout under `event_loop`, an in-memory pipe when
+/// it is run through `run` by the testkit or the benches.
+pub type Output = Box<dyn AsyncWrite + Send + Unpin>;
+
 #[async_trait]
 pub trait Node<Payload, InjectedPayload = ()>: Sync + Send {
     fn from_init(
         init: Init,
         tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Mutex<Output>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized;
 
     async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()>;
+
+    /// Called with a message whose payload could not be deserialized, as raw JSON, and
+    /// the reason. Nodes can reply to shapes they recognize, by default it is dropped.
+    async fn malformed(
+        &self,
+        message: Message<serde_json::Value>,
+        err: serde_json::Error,
+    ) -> anyhow::Result<()> {
+        eprintln!(
+            "dropping message that could not be deserialized ({}): {:?}",
+            err, message
+        );
+        Ok(())
+    }
 }
 
 #[async_trait]
 pub trait KV<T>: Send + Sync {
     /// Read returns the value for a given key in the key/value store.
     /// Returns an RPCError error with a KeyDoesNotExist code if the key does not exist.
-    async fn read(&self, storage: &String, key: String) -> anyhow::Result<T>
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T>
     where
         T: Deserialize<'static> + Send;
 
     /// Write overwrites the value for a given key in the key/value store.
-    async fn write(&self, storage: &String, key: String, val: T) -> anyhow::Result<()>
+    async fn write(&self, storage: &str, key: String, val: T) -> anyhow::Result<()>
     where
         T: Serialize + Send;
 
@@ -103,7 +178,7 @@ pub trait KV<T>: Send + Sync {
     /// does not match. Return a code of KeyDoesNotExist if the key did not exist.
     async fn cas(
         &self,
-        storage: &String,
+        storage: &str,
         key: String,
         from: T,
         to: T,
@@ -113,7 +188,7 @@ pub trait KV<T>: Send + Sync {
         T: Serialize + Deserialize<'static> + Send;
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum KVPayload<T> {
     /// KVReadMessageBody represents the body for the KV "read" message.
@@ -124,11 +199,13 @@ pub enum KVPayload<T> {
     ReadOk {
         value: T,
     },
-    /// KVWriteMessageBody represents the body for the KV "cas" message.
+    /// KVWriteMessageBody represents the body for the KV "write" message.
     Write {
         key: String,
         value: T,
     },
+    /// KVWriteOKMessageBody represents the response body for the KV "write_ok" message.
+    WriteOk {},
     /// KVCASMessageBody represents the body for the KV "cas" message.
     Cas {
         key: String,
@@ -144,6 +221,16 @@ pub enum KVPayload<T> {
     CasOk {},
 }
 
+/// Payload of a node that talks to a key/value store: either a message of its own
+/// workload or of the store, read from the same stdin stream. The workload is tried
+/// first, so `error` replies, which all of Maelstrom shares, parse as the workload's.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WithKV<Payload, T = serde_json::Value> {
+    Workload(Payload),
+    KV(KVPayload<T>),
+}
+
 #[allow(clippy::trivially_copy_pass_by_ref)]
 fn is_ref_false(b: &bool) -> bool {
     !*b
@@ -156,26 +243,109 @@ pub enum Event<Payload, InjectedPayload = ()> {
     EOF,
 }
 
+/// Injects `payload` into the event loop every `period`, starting one period from now.
+/// The timer stops once the event loop is gone and the channel is closed.
+pub fn spawn_timer<Payload, InjectedPayload>(
+    tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    period: Duration,
+    payload: InjectedPayload,
+) where
+    Payload: Send + 'static,
+    InjectedPayload: Clone + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if tx.send(Event::Injected(payload.clone())).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Returns the current time as the std Instant that raft and the gossip outboxes take.
+/// It is read from tokio's clock, so it stands still and jumps along with timers and
+/// sleeps under `tokio::time::pause`.
+pub fn now() -> std::time::Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// Reads `name` from the environment and parses it, falling back to `default` when unset.
+pub fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    match std::env::var(name) {
+        std::result::Result::Ok(raw) => raw
+            .parse()
+            .map_err(Into::into)
+            .with_context(|| format!("invalid value {:?} for {}", raw, name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Runs the node on stdin and stdout until stdin closes.
 pub async fn event_loop<N, P, IP>() -> anyhow::Result<()>
 where
     N: Node<P, IP> + 'static,
     P: std::fmt::Debug + DeserializeOwned + Send + 'static,
     IP: Send + 'static,
 {
-    let stdin = tokio::io::stdin();
-    let stdin = tokio::io::BufReader::new(stdin);
-    let stdout = Mutex::new(tokio::io::stdout());
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-
-    let init_msg: Message<InitPayload> = serde_json::from_str(
-        &stdin
-            .lines()
-            .next_line()
-            .await
-            .expect("read init")
-            .context("failed to read init message from stdin")?,
-    )
-    .context("init message could not be deserialized")?;
+    run::<N, P, IP>(tokio::io::stdin(), tokio::io::stdout()).await
+}
+
+/// Runs the node on `input` and `output` rather than stdin and stdout, until `input`
+/// ends. Everything a node does under Maelstrom goes through here, so an in-memory
+/// duplex runs it the same way without a process or a pipe.
+pub async fn run<N, P, IP>(
+    input: impl AsyncRead + Unpin + Send + 'static,
+    output: impl AsyncWrite + Unpin + Send + 'static,
+) -> anyhow::Result<()>
+where
+    N: Node<P, IP> + 'static,
+    P: std::fmt::Debug + DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+{
+    // One reader for init and everything after it, so lines read ahead along with init
+    // are not lost.
+    let mut input = tokio::io::BufReader::new(input).lines();
+    let init = input
+        .next_line()
+        .await
+        .context("read init message from input")?
+        .context("input closed before the init message")?;
+    let (node, tx, rx) = start::<N, P, IP>(&init, Box::new(output)).await?;
+
+    let reader_node = node.clone();
+    let reader = tokio::spawn(async move {
+        while let Some(line) = input.next_line().await.context("read line from input")? {
+            if !deliver(&*reader_node, &line, &tx).await {
+                return Ok(());
+            }
+        }
+        let _ = tx.send(Event::EOF).await;
+        Ok(())
+    });
+
+    dispatch(node, rx).await;
+    reader.await.context("join input reader")?
+}
+
+/// Sets up a node from the init message in `line` and answers it on `output`. Returns
+/// the node along with both ends of the channel its events go through.
+#[allow(clippy::type_complexity)]
+pub(crate) async fn start<N, P, IP>(
+    line: &str,
+    output: Output,
+) -> anyhow::Result<(Arc<N>, Sender<Event<P, IP>>, Receiver<Event<P, IP>>)>
+where
+    N: Node<P, IP> + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let init_msg: Message<InitPayload> =
+        serde_json::from_str(line).context("init message could not be deserialized")?;
 
     let InitPayload::Init(init) = init_msg.body.payload else {
         return Err(anyhow::anyhow!("expected init message"));
@@ -190,37 +360,422 @@ where
             payload: InitPayload::InitOk,
         },
     };
-    reply.send(&stdout).await.context("send response to init")?;
-
-    let node = Arc::new(N::from_init(init, tx.clone(), stdout)?);
+    let output = SharedOutput(Arc::new(std::sync::Mutex::new(output)));
+    let node = Arc::new(N::from_init(
+        init,
+        tx.clone(),
+        Mutex::new(Box::new(output.clone())),
+    )?);
+    // Only acknowledged once the node is set up, so a node that cannot run fails init.
+    // Nothing else writes to the output before events are dispatched, and the reply is
+    // flushed so it cannot be overtaken by writes through the node's handle.
+    let init_out = Mutex::new(output);
+    reply
+        .send(&init_out)
+        .await
+        .context("send response to init")?;
+    init_out
+        .lock()
+        .await
+        .flush()
+        .await
+        .context("flush response to init")?;
+    Ok((node, tx, rx))
+}
 
-    let mut join_set = JoinSet::new();
-    join_set.spawn(async move {
-        let stdin = tokio::io::stdin();
-        let mut stdin = tokio::io::BufReader::new(stdin).lines();
-        while let Some(line) = stdin.next_line().await.expect("read line") {
-            let input: Message<P> = serde_json::from_str(&line)
-                .context("input from Maelstrom on stdin could not be deserialized")?;
-            if let Err(_) = tx.send(Event::Message(input)).await {
-                return Ok(());
+/// Hands the message in `line` to the node through `tx`. Returns false once nobody
+/// takes events from `tx` any more.
+pub(crate) async fn deliver<N, P, IP>(node: &N, line: &str, tx: &Sender<Event<P, IP>>) -> bool
+where
+    N: Node<P, IP>,
+    P: DeserializeOwned,
+{
+    // A message we cannot make sense of does not stop the node, the next ones may be
+    // fine.
+    let input: Message<P> = match serde_json::from_str(line) {
+        std::result::Result::Ok(input) => input,
+        Err(err) => {
+            match serde_json::from_str(line) {
+                std::result::Result::Ok(message) => {
+                    if let Err(err) = node.malformed(message, err).await {
+                        eprintln!("failed to handle malformed message: {:#}", err);
+                    }
+                }
+                Err(_) => eprintln!(
+                    "dropping input that could not be deserialized ({}): {}",
+                    err, line
+                ),
             }
+            return true;
         }
-        let _ = tx.send(Event::EOF);
-        Ok(())
-    });
+    };
+    tx.send(Event::Message(input)).await.is_ok()
+}
 
+/// Handles every event from `rx` in a task of its own until the channel closes, then
+/// waits for the handlers still running.
+pub(crate) async fn dispatch<N, P, IP>(node: Arc<N>, mut rx: Receiver<Event<P, IP>>)
+where
+    N: Node<P, IP> + 'static,
+    P: Send + 'static,
+    IP: Send + 'static,
+{
+    let mut join_set = JoinSet::new();
     while let Some(event) = rx.recv().await {
         let node_clone = node.clone();
         join_set.spawn(async move {
-            node_clone
-                .handle(event)
-                .await
-                .context("failed to handle event")?;
-            Ok(())
+            // A failed event is logged and the node moves on to the next one.
+            if let Err(err) = node_clone.handle(event).await {
+                eprintln!("failed to handle event: {:#}", err);
+            }
         });
     }
 
-    while let Some(_) = join_set.join_next().await {}
+    while join_set.join_next().await.is_some() {}
+}
+
+/// An output shared by the node and `start`, which writes the init reply through it.
+/// Every write holds the lock only while it is polled; whole lines are kept together by
+/// the Mutex each writer sends through.
+#[derive(Clone)]
+struct SharedOutput(Arc<std::sync::Mutex<Output>>);
+
+impl SharedOutput {
+    fn poll_with<T>(
+        &self,
+        f: impl FnOnce(Pin<&mut Output>) -> Poll<std::io::Result<T>>,
+    ) -> Poll<std::io::Result<T>> {
+        let mut output = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        f(Pin::new(&mut *output))
+    }
+}
+
+impl AsyncWrite for SharedOutput {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_with(|output| output.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_with(|output| output.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_with(|output| output.poll_shutdown(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::testkit::{wire, Harness};
+
+    /// A workload of the shape every node has, for WithKV.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Workload {
+        Add { delta: u64 },
+        AddOk,
+        Error { code: usize, text: String },
+    }
+
+    /// Asserts that `golden` deserializes and serializes back to itself, and returns
+    /// what it deserialized to.
+    fn round_trip<P>(golden: Value) -> P
+    where
+        P: Serialize + DeserializeOwned,
+    {
+        let payload: P = serde_json::from_value(golden.clone())
+            .unwrap_or_else(|err| panic!("deserialize {}: {}", golden, err));
+        assert_eq!(serde_json::to_value(&payload).expect("serialize"), golden);
+        payload
+    }
+
+    #[test]
+    fn init_messages_round_trip() {
+        let line = r#"{"id":0,"src":"c0","dest":"n1","body":{"type":"init","node_id":"n1","node_ids":["n1","n2","n3"],"msg_id":1}}"#;
+        let message: Message<InitPayload> = serde_json::from_str(line).expect("deserialize init");
+        assert_eq!(message.body.id, Some(1));
+        let InitPayload::Init(init) = &message.body.payload else {
+            panic!("expected init, got {:?}", message.body.payload);
+        };
+        assert_eq!(init.node_id, "n1");
+        assert_eq!(init.node_ids, ["n1", "n2", "n3"]);
+        assert_eq!(init.node_index().expect("index"), 0);
+
+        round_trip::<Message<InitPayload>>(json!({
+            "src": "n1",
+            "dest": "c0",
+            "body": {"type": "init_ok", "msg_id": 0, "in_reply_to": 1},
+        }));
+    }
+
+    fn init(node_id: &str, node_ids: &[&str]) -> Init {
+        Init {
+            node_id: node_id.to_string(),
+            node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn node_index_is_the_position_among_the_sorted_ids() {
+        for node_ids in [
+            ["n0", "n1", "n2", "n3"],
+            ["n3", "n2", "n1", "n0"],
+            ["n2", "n0", "n3", "n1"],
+        ] {
+            for (expected, node_id) in ["n0", "n1", "n2", "n3"].into_iter().enumerate() {
+                let index = init(node_id, &node_ids).node_index().expect("index");
+                assert_eq!(index, expected, "{} in {:?}", node_id, node_ids);
+            }
+        }
+        // Ids sort as strings, as every node sorts them alike.
+        assert_eq!(init("n10", &["n9", "n10"]).node_index().unwrap(), 0);
+    }
+
+    #[test]
+    fn node_index_of_a_node_missing_from_node_ids_is_an_error() {
+        let err = init("n3", &["n0", "n1", "n2"]).node_index().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"node n3 is not among the node ids ["n0", "n1", "n2"]"#
+        );
+        assert!(init("n0", &[]).node_index().is_err());
+    }
+
+    #[test]
+    fn kv_payloads_round_trip() {
+        for golden in [
+            json!({"type": "read", "key": "counter"}),
+            json!({"type": "read_ok", "value": 3}),
+            json!({"type": "read_ok", "value": {"owner": "n1", "term": 2}}),
+            json!({"type": "write", "key": "counter", "value": [1, 2]}),
+            json!({"type": "write_ok"}),
+            json!({"type": "cas", "key": "counter", "from": 1, "to": 2}),
+            json!({"type": "cas", "key": "counter", "from": null, "to": 2, "create_if_not_exists": true}),
+            json!({"type": "cas_ok"}),
+        ] {
+            round_trip::<KVPayload<Value>>(golden);
+        }
+        let cas: KVPayload<u64> = serde_json::from_value(
+            json!({"type": "cas", "key": "k", "from": 1, "to": 2, "create_if_not_exists": false}),
+        )
+        .expect("deserialize cas");
+        assert!(matches!(cas, KVPayload::Cas { put: false, .. }));
+    }
+
+    #[test]
+    fn with_kv_tries_the_workload_first() {
+        let add: WithKV<Workload> = round_trip(json!({"type": "add", "delta": 5}));
+        assert!(matches!(add, WithKV::Workload(Workload::Add { delta: 5 })));
+        let read_ok: WithKV<Workload> = round_trip(json!({"type": "read_ok", "value": 5}));
+        assert!(matches!(read_ok, WithKV::KV(KVPayload::ReadOk { .. })));
+        // Storage errors come in the workload's error, which every payload has.
+        let error: WithKV<Workload> =
+            round_trip(json!({"type": "error", "code": 20, "text": "key does not exist"}));
+        assert!(matches!(
+            error,
+            WithKV::Workload(Workload::Error { code: 20, .. })
+        ));
+        assert!(serde_json::from_value::<WithKV<Workload>>(json!({"type": "nope"})).is_err());
+    }
+
+    /// Answers adds, and malformed requests with a malformed-request error.
+    struct AddNode {
+        id: AtomicUsize,
+        stdout: Mutex<Output>,
+    }
+
+    #[async_trait]
+    impl Node<Workload> for AddNode {
+        fn from_init(
+            _init: Init,
+            _tx: Sender<Event<Workload>>,
+            stdout: Mutex<Output>,
+        ) -> anyhow::Result<Self> {
+            Ok(Self {
+                id: AtomicUsize::new(1),
+                stdout,
+            })
+        }
+
+        async fn handle(&self, event: Event<Workload>) -> anyhow::Result<()> {
+            let Event::Message(message) = event else {
+                return Ok(());
+            };
+            message
+                .into_reply(Some(&self.id))
+                .map_payload(|_| Workload::AddOk)
+                .send(&self.stdout)
+                .await
+        }
+
+        async fn malformed(
+            &self,
+            message: Message<Value>,
+            err: serde_json::Error,
+        ) -> anyhow::Result<()> {
+            if message.body.id.is_none() {
+                return Ok(());
+            }
+            message
+                .into_reply(Some(&self.id))
+                .map_payload(|_| Workload::Error {
+                    code: ErrorCode::MalformedRequest.code(),
+                    text: err.to_string(),
+                })
+                .send(&self.stdout)
+                .await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn near_valid_lines_are_errors_not_panics() {
+        let valid = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":5}}"#;
+        let mut node = Harness::<AddNode, Workload>::new("n1", &["n1"]).await;
+
+        // Lines that are no message at all are skipped.
+        for line in [
+            "",
+            "{",
+            &valid[..valid.len() - 1],
+            "[]",
+            "42",
+            r#"{"src":"c1","dest":"n1"}"#,
+            r#"{"src":"c1","src":"c2","dest":"n1","body":{"type":"add","delta":5}}"#,
+            r#"{"src":1,"dest":"n1","body":{"type":"add","delta":5}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":-1,"delta":5}}"#,
+        ] {
+            node.send_line(line).await;
+            assert!(node.drain().await.is_empty(), "answered {:?}", line);
+        }
+
+        // Messages whose payload does not fit get an error if somebody waits for it.
+        for line in [
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":"five"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":5,"delta":6}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"subtract","msg_id":2,"delta":5}}"#,
+        ] {
+            node.send_line(line).await;
+            let replies = node.drain().await;
+            assert!(
+                matches!(
+                    replies.as_slice(),
+                    [Message {
+                        body: Body {
+                            in_reply_to: Some(2),
+                            payload: Workload::Error { code: 12, .. },
+                            ..
+                        },
+                        ..
+                    }]
+                ),
+                "{:?} answered with {:?}",
+                line,
+                replies
+            );
+        }
+        node.send_line(r#"{"src":"c1","dest":"n1","body":{"type":"add","delta":"five"}}"#)
+            .await;
+        assert!(node.drain().await.is_empty());
+
+        // The node still serves what comes after.
+        node.send_line(valid).await;
+        let reply = node.recv().await;
+        assert_eq!(reply.body.payload, Workload::AddOk);
+        assert_eq!(reply.body.in_reply_to, Some(2));
+    }
+
+    fn workload() -> impl Strategy<Value = Workload> {
+        prop_oneof![
+            any::<u64>().prop_map(|delta| Workload::Add { delta }),
+            Just(Workload::AddOk),
+            wire::error().prop_map(|(code, text)| Workload::Error { code, text }),
+        ]
+    }
 
-    Ok(())
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in wire::message(wire::with_kv(workload()))) {
+            wire::assert_round_trip(&message);
+        }
+
+        #[test]
+        fn service_messages_round_trip(message in wire::message(wire::service_payload())) {
+            wire::assert_round_trip(&message);
+        }
+
+        #[test]
+        fn raft_messages_round_trip(message in wire::message(wire::raft_payload(wire::json()))) {
+            wire::assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn maelstrom_lines_parse() {
+        wire::assert_fixture_parses::<InitPayload>("init");
+        wire::assert_fixture_parses::<kv_service::Payload>("lin_kv");
+        wire::assert_fixture_parses::<KVPayload<Value>>("kv_service");
+    }
+
+    #[test]
+    fn kv_requests_parse_as_the_services_do() {
+        for request in [
+            KVPayload::Read {
+                key: "k".to_string(),
+            },
+            KVPayload::Write {
+                key: "k".to_string(),
+                value: json!(1),
+            },
+            KVPayload::Cas {
+                key: "k".to_string(),
+                from: json!(1),
+                to: json!(2),
+                put: true,
+            },
+        ] {
+            let json = serde_json::to_value(&request).expect("serialize request");
+            let served: kv_service::Payload =
+                serde_json::from_value(json.clone()).expect("deserialize request");
+            assert!(!served.is_reply(), "{} parsed as a reply", json);
+            assert_eq!(serde_json::to_value(&served).expect("serialize"), json);
+        }
+        for reply in [
+            kv_service::Payload::ReadOk { value: json!(3) },
+            kv_service::Payload::WriteOk,
+            kv_service::Payload::CasOk,
+        ] {
+            let json = serde_json::to_value(&reply).expect("serialize reply");
+            round_trip::<KVPayload<Value>>(json);
+        }
+    }
+
+    #[test]
+    fn raft_payloads_round_trip() {
+        for golden in [
+            json!({"type": "request_vote", "term": 2, "candidate": "n1", "last_log_index": 4, "last_log_term": 1}),
+            json!({"type": "request_vote_reply", "term": 2, "vote_granted": true}),
+            json!({
+                "type": "append_entries",
+                "term": 2,
+                "leader": "n1",
+                "prev_log_index": 4,
+                "prev_log_term": 1,
+                "entries": [{"term": 2, "command": null}, {"term": 2, "command": 7}],
+                "leader_commit": 3,
+            }),
+            json!({"type": "append_entries_reply", "term": 2, "success": false, "match_index": 3}),
+        ] {
+            round_trip::<raft::RaftPayload<u64>>(golden);
+        }
+    }
 }
@@ -0,0 +1,264 @@
+//! Hash trees over sets, so two nodes find where their sets differ by exchanging a
+//! digest rather than the sets themselves.
+//!
+//! Elements go into one of `2^depth` buckets by the leading bits of their hash. A leaf
+//! hashes the sorted elements of its bucket and every inner node hashes its two
+//! children, so equal sets have equal trees whatever order their elements came in.
+//! The digest is the leaf level: the receiver rebuilds the inner levels from it and
+//! walks both trees from the root, skipping every subtree whose hashes match.
+
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+/// Deepest tree allowed, with 65536 buckets. Digests grow with `2^depth`.
+pub const MAX_DEPTH: u32 = 16;
+
+/// The leaf hashes of a tree, which is all a peer needs to diff against it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub depth: u32,
+    pub leaves: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MerkleTree<T> {
+    depth: u32,
+    /// The elements of every bucket, sorted.
+    buckets: Vec<Vec<T>>,
+    /// The hashes of every level, the root first and the leaves last.
+    levels: Vec<Vec<u64>>,
+}
+
+impl<T: Hash + Ord> MerkleTree<T> {
+    /// Builds the tree of `depth` over `elements`. Duplicates count once.
+    pub fn new(depth: u32, elements: impl IntoIterator<Item = T>) -> anyhow::Result<Self> {
+        check_depth(depth)?;
+        let mut buckets: Vec<Vec<T>> = (0..1usize << depth).map(|_| Vec::new()).collect();
+        for element in elements {
+            buckets[bucket_of(depth, hash(&element))].push(element);
+        }
+        for bucket in &mut buckets {
+            bucket.sort();
+            bucket.dedup();
+        }
+        let leaves = buckets
+            .iter()
+            .map(|bucket| {
+                bucket
+                    .iter()
+                    .fold(0, |acc, element| combine(acc, hash(element)))
+            })
+            .collect();
+        Ok(Self {
+            depth,
+            buckets,
+            levels: levels(leaves),
+        })
+    }
+
+    /// Returns the bucket `element` falls in, whether the tree holds it or not.
+    pub fn bucket_of(&self, element: &T) -> usize {
+        bucket_of(self.depth, hash(element))
+    }
+
+    /// Returns the elements of `bucket`, sorted, or none if there is no such bucket.
+    pub fn bucket(&self, bucket: usize) -> &[T] {
+        self.buckets.get(bucket).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the elements of all of `buckets`.
+    pub fn elements_in<'a>(&'a self, buckets: &'a [usize]) -> impl Iterator<Item = &'a T> {
+        buckets.iter().flat_map(|bucket| self.bucket(*bucket))
+    }
+}
+
+impl<T> MerkleTree<T> {
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Returns the hash of the root, which is equal for equal sets.
+    pub fn root(&self) -> u64 {
+        self.levels[0][0]
+    }
+
+    pub fn digest(&self) -> Digest {
+        Digest {
+            depth: self.depth,
+            leaves: self.levels[self.depth as usize].clone(),
+        }
+    }
+
+    /// Returns the buckets in which this tree and the one `digest` came from hold
+    /// different elements, in order. Fails if the digest is malformed or of a tree of
+    /// another depth, as those bucket the same elements differently.
+    pub fn diff(&self, digest: &Digest) -> anyhow::Result<Vec<usize>> {
+        check_depth(digest.depth)?;
+        if digest.depth != self.depth {
+            anyhow::bail!(
+                "digest of depth {} against tree of depth {}",
+                digest.depth,
+                self.depth
+            );
+        }
+        if digest.leaves.len() != 1 << digest.depth {
+            anyhow::bail!(
+                "digest of depth {} has {} leaves",
+                digest.depth,
+                digest.leaves.len()
+            );
+        }
+        let other = levels(digest.leaves.clone());
+        // Nodes that differ on the current level, starting at the root.
+        let mut differing = vec![0];
+        for (level, (ours, theirs)) in self.levels.iter().zip(&other).enumerate() {
+            differing.retain(|node| ours[*node] != theirs[*node]);
+            if level < self.depth as usize {
+                differing = differing
+                    .into_iter()
+                    .flat_map(|node| [2 * node, 2 * node + 1])
+                    .collect();
+            }
+        }
+        Ok(differing)
+    }
+}
+
+fn check_depth(depth: u32) -> anyhow::Result<()> {
+    if depth > MAX_DEPTH {
+        anyhow::bail!("depth {} is above {}", depth, MAX_DEPTH);
+    }
+    Ok(())
+}
+
+/// Builds the inner levels above `leaves`, the root first.
+fn levels(leaves: Vec<u64>) -> Vec<Vec<u64>> {
+    let mut levels = vec![leaves];
+    while levels[0].len() > 1 {
+        let parents = levels[0]
+            .chunks(2)
+            .map(|children| combine(children[0], children[1]))
+            .collect();
+        levels.insert(0, parents);
+    }
+    levels
+}
+
+fn bucket_of(depth: u32, hash: u64) -> usize {
+    hash.checked_shr(64 - depth).unwrap_or_default() as usize
+}
+
+/// Hashes `element` the same way on every node and in every build, which the std
+/// hasher does not promise.
+fn hash<T: Hash>(element: &T) -> u64 {
+    let mut hasher = Fnv1a::default();
+    element.hash(&mut hasher);
+    mix(hasher.finish())
+}
+
+fn combine(left: u64, right: u64) -> u64 {
+    mix(left ^ mix(right).rotate_left(32))
+}
+
+/// The splitmix64 finalizer, which spreads FNV's weak high bits over the whole hash.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    const DEPTH: u32 = 8;
+
+    fn tree(elements: impl IntoIterator<Item = u64>) -> MerkleTree<u64> {
+        MerkleTree::new(DEPTH, elements).expect("build tree")
+    }
+
+    #[test]
+    fn equal_sets_have_equal_roots() {
+        let forward = tree(0..1000);
+        let backward = tree((0..1000).rev().chain(0..10));
+        assert_eq!(forward.root(), backward.root());
+        assert_eq!(forward.digest(), backward.digest());
+        assert!(forward.diff(&backward.digest()).expect("diff").is_empty());
+        assert_ne!(forward.root(), tree(0..999).root());
+    }
+
+    #[test]
+    fn only_the_buckets_of_differing_elements_are_exchanged() {
+        for k in [1, 2, 5, 20, 100] {
+            // Each side holds elements the other lacks, k in all.
+            let ours: BTreeSet<u64> = (k / 2..2000).collect();
+            let theirs: BTreeSet<u64> = (0..2000 - (k - k / 2)).collect();
+            let differing: BTreeSet<u64> = ours.symmetric_difference(&theirs).copied().collect();
+            assert_eq!(differing.len() as u64, k);
+            let (our_tree, their_tree) = (tree(ours.clone()), tree(theirs.clone()));
+
+            let buckets = our_tree.diff(&their_tree.digest()).expect("diff");
+            let expected: BTreeSet<usize> =
+                differing.iter().map(|e| our_tree.bucket_of(e)).collect();
+            assert_eq!(buckets, expected.into_iter().collect::<Vec<_>>(), "k={}", k);
+            assert_eq!(buckets, their_tree.diff(&our_tree.digest()).expect("diff"));
+
+            // Trading the elements of those buckets is enough to end up equal.
+            let mut merged = ours.clone();
+            merged.extend(their_tree.elements_in(&buckets));
+            let mut their_merged = theirs.clone();
+            their_merged.extend(our_tree.elements_in(&buckets));
+            assert_eq!(tree(merged).root(), tree(their_merged).root());
+        }
+    }
+
+    #[test]
+    fn a_tree_of_depth_zero_has_one_bucket() {
+        let ours = MerkleTree::new(0, [1u64, 2, 3]).expect("build tree");
+        let theirs = MerkleTree::new(0, [1u64, 2]).expect("build tree");
+        assert_eq!(ours.digest().leaves.len(), 1);
+        assert_eq!(ours.diff(&theirs.digest()).expect("diff"), vec![0]);
+        assert_eq!(ours.bucket(0), &[1, 2, 3]);
+        assert!(ours.bucket(1).is_empty());
+    }
+
+    #[test]
+    fn mismatched_or_malformed_digests_are_rejected() {
+        let ours = tree(0..10);
+        let shallow = MerkleTree::new(DEPTH - 1, 0..10u64).expect("build tree");
+        assert!(ours.diff(&shallow.digest()).is_err());
+        let mut short = ours.digest();
+        short.leaves.pop();
+        assert!(ours.diff(&short).is_err());
+        let deep = Digest {
+            depth: MAX_DEPTH + 1,
+            leaves: Vec::new(),
+        };
+        assert!(ours.diff(&deep).is_err());
+        assert!(MerkleTree::new(MAX_DEPTH + 1, 0..1u64).is_err());
+    }
+}
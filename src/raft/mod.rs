@@ -0,0 +1,904 @@
+//! Raft: leader election and log replication. The state machine does no I/O and reads
+//! no clock: it is fed the messages of its peers and the ticks of a timer, each with
+//! the current time, and returns the messages to send. Nodes drive it from their event
+//! loop with spawn_timer, and a simulation can drive it with made-up times. Committed
+//! commands are drained with Raft::committed and applied by the node.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Most entries sent in a single AppendEntries, the rest follow once it is accepted.
+const MAX_APPEND_ENTRIES: usize = 64;
+
+/// Messages Raft nodes exchange, flattened into the body like any other payload.
+/// Commands in the log are of type `C`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RaftPayload<C> {
+    /// Asks for the vote of a node to become leader for `term`.
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteReply {
+        term: u64,
+        vote_granted: bool,
+    },
+    /// Sent by the leader of `term` with the entries following `prev_log_index`, none
+    /// for a heartbeat.
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry<C>>,
+        leader_commit: u64,
+    },
+    /// On success, `match_index` is the last entry known to match the leader's log. On
+    /// failure, it is a hint of where the logs may match.
+    AppendEntriesReply {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+impl<C> RaftPayload<C> {
+    pub fn term(&self) -> u64 {
+        match self {
+            Self::RequestVote { term, .. }
+            | Self::RequestVoteReply { term, .. }
+            | Self::AppendEntries { term, .. }
+            | Self::AppendEntriesReply { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry<C> {
+    pub term: u64,
+    /// None for the entry a new leader appends to commit the entries of earlier terms.
+    pub command: Option<C>,
+}
+
+/// State a node must not forget once it has acted on it, or it could vote twice in a
+/// term. Nodes that can store it somewhere hand it back to Raft::new after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PersistentState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    Follower { leader: Option<String> },
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Election timeouts are drawn uniformly from `[min, max)`, so nodes rarely time
+    /// out together and split the vote.
+    pub election_timeout: (Duration, Duration),
+    /// Interval between two heartbeats of the leader, well below the election timeout.
+    pub heartbeat_interval: Duration,
+    /// Seeds the timeouts, mixed with the node id so nodes draw different ones.
+    pub seed: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: (Duration::from_millis(300), Duration::from_millis(600)),
+            heartbeat_interval: Duration::from_millis(100),
+            seed: 0,
+        }
+    }
+}
+
+/// A message for `dest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outgoing<C> {
+    pub dest: String,
+    pub payload: RaftPayload<C>,
+}
+
+#[derive(Debug)]
+pub struct Raft<C> {
+    node: String,
+    /// The other nodes of the cluster.
+    peers: Vec<String>,
+    config: RaftConfig,
+    state: PersistentState,
+    role: Role,
+    /// Entry `i` of the log is at `log[i - 1]`, 0 stands for the empty prefix.
+    log: Vec<LogEntry<C>>,
+    /// Last entry known to be stored on a majority.
+    commit_index: u64,
+    /// Last entry handed out by Raft::committed.
+    last_applied: u64,
+    /// Nodes that voted for this one in the current term, while it is a candidate.
+    votes: HashSet<String>,
+    /// Next entry to send to each peer, while leading.
+    next_index: HashMap<String, u64>,
+    /// Last entry known to match on each peer, while leading.
+    match_index: HashMap<String, u64>,
+    /// Followers and candidates start an election when this passes.
+    election_deadline: Instant,
+    /// The leader sends heartbeats when this passes.
+    next_heartbeat: Instant,
+    /// State of the splitmix64 generator drawing election timeouts.
+    rng: u64,
+}
+
+impl<C: Clone> Raft<C> {
+    /// Starts a follower of the cluster `node_ids` at time `now`, from `state` if the
+    /// node ran before.
+    pub fn new(
+        node: String,
+        node_ids: &[String],
+        config: RaftConfig,
+        state: PersistentState,
+        now: Instant,
+    ) -> Self {
+        let rng = node
+            .bytes()
+            .fold(config.seed ^ 0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        let mut raft = Self {
+            peers: node_ids.iter().filter(|id| **id != node).cloned().collect(),
+            node,
+            config,
+            state,
+            role: Role::Follower { leader: None },
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: now,
+            next_heartbeat: now,
+            rng,
+        };
+        raft.reset_election_deadline(now);
+        raft
+    }
+
+    pub fn role(&self) -> &Role {
+        &self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    /// Returns the leader of the current term as far as this node knows.
+    pub fn leader(&self) -> Option<&str> {
+        match &self.role {
+            Role::Follower { leader } => leader.as_deref(),
+            Role::Candidate => None,
+            Role::Leader => Some(&self.node),
+        }
+    }
+
+    /// Returns the state to store before sending any of the messages Raft returned.
+    pub fn persistent_state(&self) -> &PersistentState {
+        &self.state
+    }
+
+    pub fn log(&self) -> &[LogEntry<C>] {
+        &self.log
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// Appends `command` to the log if this node leads, and returns its index with the
+    /// messages replicating it. The command is applied once it shows up at that index
+    /// in Raft::committed, which it may never do if leadership changes meanwhile.
+    pub fn propose(&mut self, command: C) -> Option<(u64, Vec<Outgoing<C>>)> {
+        if !self.is_leader() {
+            return None;
+        }
+        self.log.push(LogEntry {
+            term: self.state.term,
+            command: Some(command),
+        });
+        self.advance_commit();
+        let index = self.last_log_index();
+        let replicate = self
+            .peers
+            .iter()
+            .map(|peer| self.append_entries(peer))
+            .collect();
+        Some((index, replicate))
+    }
+
+    /// Returns the commands committed since the last call with their log indexes, in
+    /// log order. Every node gets the same command at the same index.
+    pub fn committed(&mut self) -> Vec<(u64, C)> {
+        let from = self.last_applied;
+        self.last_applied = self.commit_index;
+        (from + 1..=self.commit_index)
+            .filter_map(|index| {
+                let entry = &self.log[index as usize - 1];
+                entry.command.clone().map(|command| (index, command))
+            })
+            .collect()
+    }
+
+    /// Advances the timers to `now`: starts an election if no leader was heard from
+    /// in time, or sends heartbeats if this node leads.
+    pub fn tick(&mut self, now: Instant) -> Vec<Outgoing<C>> {
+        match self.role {
+            Role::Leader if now >= self.next_heartbeat => self.heartbeat(now),
+            Role::Leader => Vec::new(),
+            _ if now >= self.election_deadline => self.start_election(now),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Handles `payload` from `src` received at `now`.
+    pub fn receive(
+        &mut self,
+        now: Instant,
+        src: &str,
+        payload: RaftPayload<C>,
+    ) -> Vec<Outgoing<C>> {
+        // Any newer term makes this node a follower of it, whatever role it had.
+        if payload.term() > self.state.term {
+            self.state = PersistentState {
+                term: payload.term(),
+                voted_for: None,
+            };
+            // A leader had no election deadline running, so without a new one it would
+            // start an election right away and unseat the leader it just learned of.
+            if self.role == Role::Leader {
+                self.reset_election_deadline(now);
+            }
+            self.role = Role::Follower { leader: None };
+        }
+        let term = self.state.term;
+        let reply = match payload {
+            RaftPayload::RequestVote {
+                term: their_term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                // Only a candidate with every committed entry may lead, and committed
+                // entries are on a majority, so it needs a log as recent as the voters'.
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (self.last_log_term(), self.last_log_index());
+                let vote_granted = their_term == term
+                    && up_to_date
+                    && self
+                        .state
+                        .voted_for
+                        .as_ref()
+                        .is_none_or(|voted| *voted == candidate);
+                if vote_granted {
+                    self.state.voted_for = Some(candidate);
+                    self.reset_election_deadline(now);
+                }
+                RaftPayload::RequestVoteReply { term, vote_granted }
+            }
+            RaftPayload::RequestVoteReply {
+                term: their_term,
+                vote_granted,
+            } => {
+                if self.role == Role::Candidate && their_term == term && vote_granted {
+                    self.votes.insert(src.to_string());
+                    if self.votes.len() >= self.majority() {
+                        return self.become_leader(now);
+                    }
+                }
+                return Vec::new();
+            }
+            RaftPayload::AppendEntries {
+                term: their_term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if their_term < term {
+                    RaftPayload::AppendEntriesReply {
+                        term,
+                        success: false,
+                        match_index: 0,
+                    }
+                } else {
+                    self.role = Role::Follower {
+                        leader: Some(leader),
+                    };
+                    self.reset_election_deadline(now);
+                    self.append(prev_log_index, prev_log_term, entries, leader_commit)
+                }
+            }
+            RaftPayload::AppendEntriesReply {
+                term: their_term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || their_term != term {
+                    return Vec::new();
+                }
+                let next = self.next_index.entry(src.to_string()).or_default();
+                if success {
+                    let matched = self.match_index.entry(src.to_string()).or_default();
+                    // Replies can arrive out of order, an older one knows less.
+                    *matched = (*matched).max(match_index);
+                    *next = (*next).max(*matched + 1);
+                    self.advance_commit();
+                    if *self.next_index.get(src).unwrap_or(&1) > self.last_log_index() {
+                        return Vec::new();
+                    }
+                } else {
+                    *next = next.saturating_sub(1).min(match_index + 1).max(1);
+                }
+                return vec![self.append_entries(src)];
+            }
+        };
+        vec![Outgoing {
+            dest: src.to_string(),
+            payload: reply,
+        }]
+    }
+
+    /// Stores the entries of the leader after `prev_log_index` if the logs match up to
+    /// there, and returns the reply to it.
+    fn append(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry<C>>,
+        leader_commit: u64,
+    ) -> RaftPayload<C> {
+        let term = self.state.term;
+        if prev_log_index > self.last_log_index() {
+            return RaftPayload::AppendEntriesReply {
+                term,
+                success: false,
+                match_index: self.last_log_index(),
+            };
+        }
+        if self.term_at(prev_log_index) != prev_log_term {
+            // The whole term of the conflicting entry is likely wrong, skip past it.
+            let conflict = self.term_at(prev_log_index);
+            let mut hint = prev_log_index - 1;
+            while hint > self.commit_index && self.term_at(hint) == conflict {
+                hint -= 1;
+            }
+            return RaftPayload::AppendEntriesReply {
+                term,
+                success: false,
+                match_index: hint,
+            };
+        }
+        let last_new = prev_log_index + entries.len() as u64;
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            if index <= self.last_log_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                // Entries after a conflict were never committed, the leader's win.
+                self.log.truncate(index as usize - 1);
+            }
+            self.log.push(entry);
+        }
+        // Only what this message showed to match is known to be the leader's log.
+        self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+        RaftPayload::AppendEntriesReply {
+            term,
+            success: true,
+            match_index: last_new,
+        }
+    }
+
+    /// Commits the latest entry of this term stored on a majority, and every one
+    /// before it. Entries of earlier terms are only committed along with one of this
+    /// term, as they could still be overwritten on their own.
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_log_index()).rev() {
+            if self.term_at(index) != self.state.term {
+                break;
+            }
+            let stored = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if stored >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn start_election(&mut self, now: Instant) -> Vec<Outgoing<C>> {
+        self.state = PersistentState {
+            term: self.state.term + 1,
+            voted_for: Some(self.node.clone()),
+        };
+        self.role = Role::Candidate;
+        self.votes = HashSet::from([self.node.clone()]);
+        self.reset_election_deadline(now);
+        if self.votes.len() >= self.majority() {
+            return self.become_leader(now);
+        }
+        let request = RaftPayload::RequestVote {
+            term: self.state.term,
+            candidate: self.node.clone(),
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        self.peers
+            .iter()
+            .map(|peer| Outgoing {
+                dest: peer.clone(),
+                payload: request.clone(),
+            })
+            .collect()
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Vec<Outgoing<C>> {
+        self.role = Role::Leader;
+        self.votes.clear();
+        let next = self.last_log_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (peer.clone(), next)).collect();
+        self.match_index = self.peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        self.log.push(LogEntry {
+            term: self.state.term,
+            command: None,
+        });
+        self.advance_commit();
+        // Followers learn about the new leader right away rather than a tick later.
+        self.heartbeat(now)
+    }
+
+    fn heartbeat(&mut self, now: Instant) -> Vec<Outgoing<C>> {
+        self.next_heartbeat = now + self.config.heartbeat_interval;
+        self.peers
+            .iter()
+            .map(|peer| self.append_entries(peer))
+            .collect()
+    }
+
+    /// Returns the AppendEntries with the entries `peer` is missing, as far as this
+    /// leader knows.
+    fn append_entries(&self, peer: &str) -> Outgoing<C> {
+        let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = next - 1;
+        let entries = self
+            .log
+            .iter()
+            .skip(prev_log_index as usize)
+            .take(MAX_APPEND_ENTRIES)
+            .cloned()
+            .collect();
+        Outgoing {
+            dest: peer.to_string(),
+            payload: RaftPayload::AppendEntries {
+                term: self.state.term,
+                leader: self.node.clone(),
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index),
+                entries,
+                leader_commit: self.commit_index,
+            },
+        }
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index())
+    }
+
+    /// Returns the term of entry `index`, 0 for the empty prefix.
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log[index as usize - 1].term,
+        }
+    }
+
+    /// Number of votes that make a leader, this node's own included.
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        let (min, max) = self.config.election_timeout;
+        let spread = max.saturating_sub(min).as_nanos() as u64;
+        let jitter = match spread {
+            0 => 0,
+            spread => self.next_random() % spread,
+        };
+        self.election_deadline = now + min + Duration::from_nanos(jitter);
+    }
+
+    /// Returns the next number of the splitmix64 generator.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, VecDeque};
+
+    use super::*;
+
+    /// Time that passes between two rounds of ticks.
+    const STEP: Duration = Duration::from_millis(10);
+
+    /// Most rounds a cluster gets to reach a state, several election timeouts.
+    const MAX_STEPS: usize = 1000;
+
+    /// A cluster driven with made-up times, whose isolated nodes lose every message
+    /// they send or are sent.
+    struct Cluster {
+        nodes: BTreeMap<String, Raft<u64>>,
+        now: Instant,
+        isolated: HashSet<String>,
+        queue: VecDeque<(String, Outgoing<u64>)>,
+        /// Commands each node got from Raft::committed, in order.
+        applied: HashMap<String, Vec<(u64, u64)>>,
+    }
+
+    impl Cluster {
+        fn new(size: usize) -> Self {
+            let now = Instant::now();
+            let ids: Vec<String> = (0..size).map(|i| format!("n{}", i)).collect();
+            let nodes = ids
+                .iter()
+                .map(|id| {
+                    let raft = Raft::new(
+                        id.clone(),
+                        &ids,
+                        RaftConfig::default(),
+                        PersistentState::default(),
+                        now,
+                    );
+                    (id.clone(), raft)
+                })
+                .collect();
+            Self {
+                nodes,
+                now,
+                isolated: HashSet::new(),
+                queue: VecDeque::new(),
+                applied: HashMap::new(),
+            }
+        }
+
+        fn node(&self, id: &str) -> &Raft<u64> {
+            &self.nodes[id]
+        }
+
+        /// Advances the time by STEP, ticks every node and delivers messages until
+        /// there are none left.
+        fn step(&mut self) {
+            self.now += STEP;
+            let ids: Vec<String> = self.nodes.keys().cloned().collect();
+            for id in &ids {
+                let outgoing = self.nodes.get_mut(id).expect("node").tick(self.now);
+                self.send(id, outgoing);
+            }
+            while let Some((src, message)) = self.queue.pop_front() {
+                if self.isolated.contains(&src) || self.isolated.contains(&message.dest) {
+                    continue;
+                }
+                let node = self.nodes.get_mut(&message.dest).expect("node");
+                let replies = node.receive(self.now, &src, message.payload);
+                self.send(&message.dest, replies);
+            }
+            for (id, node) in &mut self.nodes {
+                self.applied
+                    .entry(id.clone())
+                    .or_default()
+                    .extend(node.committed());
+            }
+        }
+
+        fn send(&mut self, src: &str, outgoing: Vec<Outgoing<u64>>) {
+            self.queue.extend(
+                outgoing
+                    .into_iter()
+                    .map(|message| (src.to_string(), message)),
+            );
+        }
+
+        /// Steps until `done` holds, failing after MAX_STEPS.
+        fn run_until(&mut self, what: &str, done: impl Fn(&Self) -> bool) {
+            for _ in 0..MAX_STEPS {
+                if done(self) {
+                    return;
+                }
+                self.step();
+            }
+            panic!("no {} after {} steps", what, MAX_STEPS);
+        }
+
+        fn run_for(&mut self, steps: usize) {
+            for _ in 0..steps {
+                self.step();
+            }
+        }
+
+        /// Returns the leader reachable from the rest of the cluster, if there is one.
+        fn leader(&self) -> Option<String> {
+            let leaders: Vec<&String> = self
+                .nodes
+                .iter()
+                .filter(|(id, node)| node.is_leader() && !self.isolated.contains(*id))
+                .map(|(id, _)| id)
+                .collect();
+            match leaders.as_slice() {
+                [leader] => Some(leader.to_string()),
+                _ => None,
+            }
+        }
+
+        fn elect(&mut self) -> String {
+            self.run_until("leader", |cluster| cluster.leader().is_some());
+            self.leader().expect("leader")
+        }
+
+        fn propose(&mut self, id: &str, command: u64) -> u64 {
+            let (index, outgoing) = self
+                .nodes
+                .get_mut(id)
+                .expect("node")
+                .propose(command)
+                .expect("node leads");
+            self.send(id, outgoing);
+            index
+        }
+
+        fn commands(&self, id: &str) -> Vec<u64> {
+            self.applied
+                .get(id)
+                .map(|applied| applied.iter().map(|(_, command)| *command).collect())
+                .unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn elects_one_leader_that_everyone_follows() {
+        let mut cluster = Cluster::new(3);
+        let leader = cluster.elect();
+        cluster.run_for(20);
+        for (id, node) in &cluster.nodes {
+            assert_eq!(
+                node.leader(),
+                Some(leader.as_str()),
+                "leader seen by {}",
+                id
+            );
+            assert_eq!(node.term(), cluster.node(&leader).term());
+        }
+    }
+
+    #[test]
+    fn a_lost_leader_is_replaced_at_a_higher_term() {
+        let mut cluster = Cluster::new(5);
+        let old = cluster.elect();
+        let old_term = cluster.node(&old).term();
+        cluster.isolated.insert(old.clone());
+        let new = cluster.elect();
+        assert_ne!(new, old);
+        assert!(cluster.node(&new).term() > old_term);
+        cluster.run_for(20);
+        for (id, node) in cluster.nodes.iter().filter(|(id, _)| **id != old) {
+            assert_eq!(node.leader(), Some(new.as_str()), "leader seen by {}", id);
+        }
+
+        cluster.isolated.clear();
+        cluster.run_until("old leader following", |cluster| {
+            cluster.node(&old).leader() == Some(new.as_str())
+        });
+        assert_eq!(cluster.node(&old).term(), cluster.node(&new).term());
+    }
+
+    #[test]
+    fn no_leader_is_elected_without_a_majority() {
+        let mut cluster = Cluster::new(3);
+        let leader = cluster.elect();
+        let term = cluster.node(&leader).term();
+        let cut_off: Vec<String> = cluster
+            .nodes
+            .keys()
+            .filter(|id| **id != leader)
+            .cloned()
+            .collect();
+        cluster.isolated.extend(cut_off.iter().cloned());
+        cluster.run_for(MAX_STEPS);
+        for id in &cut_off {
+            // It keeps calling elections nobody answers.
+            assert!(!cluster.node(id).is_leader(), "{} leads alone", id);
+            assert!(cluster.node(id).term() > term);
+        }
+    }
+
+    #[test]
+    fn every_term_has_at_most_one_leader() {
+        let mut cluster = Cluster::new(5);
+        let mut leaders: HashMap<u64, String> = HashMap::new();
+        for _ in 0..5 {
+            let leader = cluster.elect();
+            cluster.isolated.clear();
+            cluster.isolated.insert(leader);
+            for _ in 0..100 {
+                cluster.step();
+                for (id, node) in cluster.nodes.iter().filter(|(_, node)| node.is_leader()) {
+                    let first = leaders.entry(node.term()).or_insert_with(|| id.clone());
+                    assert_eq!(first, id, "two leaders in term {}", node.term());
+                }
+            }
+        }
+        assert!(leaders.len() >= 5, "terms with a leader: {:?}", leaders);
+    }
+
+    #[test]
+    fn commands_commit_in_the_same_order_everywhere() {
+        let mut cluster = Cluster::new(5);
+        let leader = cluster.elect();
+        for command in 1..=10 {
+            cluster.propose(&leader, command);
+        }
+        cluster.run_until("commit everywhere", |cluster| {
+            cluster
+                .nodes
+                .keys()
+                .all(|id| cluster.commands(id).len() == 10)
+        });
+        for id in cluster.nodes.keys() {
+            assert_eq!(cluster.commands(id), (1..=10).collect::<Vec<_>>());
+            assert_eq!(cluster.applied[id], cluster.applied[&leader]);
+        }
+    }
+
+    #[test]
+    fn a_leader_cut_off_from_its_peers_commits_nothing_and_steps_down() {
+        let mut cluster = Cluster::new(3);
+        let old = cluster.elect();
+        let old_term = cluster.node(&old).term();
+        cluster.isolated.insert(old.clone());
+        let index = cluster.propose(&old, 1);
+        cluster.run_for(100);
+        // It never hears that it was replaced, but cannot commit on its own.
+        assert!(cluster.node(&old).is_leader());
+        assert_eq!(cluster.node(&old).term(), old_term);
+        assert!(cluster.node(&old).commit_index() < index);
+        assert!(cluster.commands(&old).is_empty());
+
+        let new = cluster.elect();
+        assert_ne!(new, old);
+        assert!(cluster.node(&new).term() > old_term);
+        cluster.propose(&new, 2);
+        cluster.run_until("commit on the majority", |cluster| {
+            cluster
+                .nodes
+                .keys()
+                .filter(|id| **id != old)
+                .all(|id| cluster.commands(id) == [2])
+        });
+
+        cluster.isolated.clear();
+        cluster.run_until("old leader following", |cluster| {
+            cluster.node(&old).leader() == Some(new.as_str())
+        });
+        assert!(!cluster.node(&old).is_leader());
+        cluster.run_until("old leader caught up", |cluster| {
+            cluster.commands(&old) == [2]
+        });
+    }
+
+    #[test]
+    fn divergent_logs_are_truncated_to_the_leaders() {
+        let mut cluster = Cluster::new(5);
+        let old = cluster.elect();
+        cluster.propose(&old, 1);
+        cluster.run_until("first commit", |cluster| {
+            cluster.nodes.keys().all(|id| cluster.commands(id) == [1])
+        });
+        // Entries only the cut-off leader stores, never committed.
+        cluster.isolated.insert(old.clone());
+        cluster.propose(&old, 100);
+        cluster.propose(&old, 101);
+        cluster.propose(&old, 102);
+        cluster.run_for(5);
+
+        let new = cluster.elect();
+        for command in [2, 3] {
+            cluster.propose(&new, command);
+        }
+        cluster.run_until("commit on the majority", |cluster| {
+            cluster.commands(&new) == [1, 2, 3]
+        });
+        // Both logs hold entries after the first commit, but different ones.
+        let has = |id: &str, command| {
+            cluster
+                .node(id)
+                .log()
+                .iter()
+                .any(|entry| entry.command == Some(command))
+        };
+        assert!(has(&old, 100) && !has(&new, 100));
+        assert!(has(&new, 2) && !has(&old, 2));
+
+        cluster.isolated.clear();
+        cluster.run_until("logs matching", |cluster| {
+            cluster
+                .nodes
+                .values()
+                .all(|node| node.log() == cluster.node(&new).log())
+        });
+        cluster.run_until("old leader caught up", |cluster| {
+            cluster.commands(&old) == [1, 2, 3]
+        });
+        for node in cluster.nodes.values() {
+            assert!(node
+                .log()
+                .iter()
+                .all(|entry| !matches!(entry.command, Some(100..=102))));
+        }
+    }
+
+    #[test]
+    fn a_vote_goes_to_one_candidate_per_term() {
+        let now = Instant::now();
+        let ids = ["n0", "n1", "n2"].map(String::from);
+        let mut raft: Raft<u64> = Raft::new(
+            "n0".into(),
+            &ids,
+            RaftConfig::default(),
+            PersistentState::default(),
+            now,
+        );
+        let request = |candidate: &str| RaftPayload::RequestVote {
+            term: 1,
+            candidate: candidate.to_string(),
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+        let granted = |outgoing: Vec<Outgoing<u64>>| match outgoing.as_slice() {
+            [Outgoing {
+                payload: RaftPayload::RequestVoteReply { vote_granted, .. },
+                ..
+            }] => *vote_granted,
+            other => panic!("expected a vote reply, got {:?}", other),
+        };
+        assert!(granted(raft.receive(now, "n1", request("n1"))));
+        assert!(!granted(raft.receive(now, "n2", request("n2"))));
+        // Asking again, as after a lost reply, gets the same answer.
+        assert!(granted(raft.receive(now, "n1", request("n1"))));
+        assert_eq!(raft.persistent_state().voted_for.as_deref(), Some("n1"));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinSet;
+
+use crate::{Body, ErrorCode, MaelstromError, Message};
+
+/// Keeps track of outstanding requests sent by a node and routes their replies
+/// back to the waiting callers.
+pub struct Rpc<Payload> {
+    pending: Mutex<HashMap<usize, oneshot::Sender<Message<Payload>>>>,
+}
+
+impl<Payload> Default for Rpc<Payload> {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<Payload> Rpc<Payload>
+where
+    Payload: Serialize + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `msg` and waits for its reply for at most `timeout`.
+    /// The message must carry a msg_id, which is what the reply is matched on.
+    pub async fn call<W: AsyncWrite + Unpin>(
+        &self,
+        msg: Message<Payload>,
+        timeout: Duration,
+        out: &Mutex<W>,
+    ) -> anyhow::Result<Message<Payload>> {
+        let id = msg.body.id.context("rpc message without msg_id")?;
+        let rx = self.register(id).await;
+        if let Err(err) = msg.send(out).await {
+            self.pending.lock().await.remove(&id);
+            return Err(err.context("send rpc message"));
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(reply) => reply.context("rpc reply channel closed"),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                Err(MaelstromError::new(
+                    ErrorCode::Timeout,
+                    format!("rpc to {} timed out after {:?}", msg.dest, timeout),
+                )
+                .into())
+            }
+        }
+    }
+
+    /// Sends all `msgs` and waits until `needed` of them have been answered or
+    /// `timeout` expires, whichever comes first. Returns the replies received so far,
+    /// so callers decide themselves whether a short answer is good enough.
+    pub async fn quorum<W: AsyncWrite + Unpin>(
+        &self,
+        msgs: Vec<Message<Payload>>,
+        needed: usize,
+        timeout: Duration,
+        out: &Mutex<W>,
+    ) -> anyhow::Result<Vec<Message<Payload>>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        // Every message is checked before any is registered, so a bad one leaves
+        // nothing behind in pending.
+        let ids = msgs
+            .iter()
+            .map(|msg| msg.body.id.context("rpc message without msg_id"))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut waiting = JoinSet::new();
+        for (msg, id) in msgs.into_iter().zip(&ids) {
+            waiting.spawn(self.register(*id).await);
+            if let Err(err) = msg.send(out).await {
+                self.forget(&ids).await;
+                return Err(err.context("send quorum message"));
+            }
+        }
+
+        let mut replies = Vec::with_capacity(needed);
+        while replies.len() < needed {
+            match tokio::time::timeout_at(deadline, waiting.join_next()).await {
+                Ok(Some(Ok(Ok(reply)))) => replies.push(reply),
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        self.forget(&ids).await;
+        Ok(replies)
+    }
+
+    /// Forwards `request` to `to` under the new msg_id `id` and waits for the answer.
+    /// The answer is returned re-addressed as a reply to the original request, ready
+    /// to be sent back to whoever sent it to us.
+    pub async fn forward<W: AsyncWrite + Unpin>(
+        &self,
+        request: Message<Payload>,
+        to: &str,
+        id: usize,
+        timeout: Duration,
+        out: &Mutex<W>,
+    ) -> anyhow::Result<Message<Payload>> {
+        let msg = Message {
+            src: request.dest.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload: request.body.payload,
+            },
+        };
+        let answer = self.call(msg, timeout, out).await.context("forward")?;
+        Ok(Message {
+            src: request.dest,
+            dest: request.src,
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                payload: answer.body.payload,
+            },
+        })
+    }
+
+    /// Hands `msg` to the caller waiting for it. Returns the message back if it
+    /// is not a reply to any outstanding request.
+    pub async fn resolve(&self, msg: Message<Payload>) -> Option<Message<Payload>> {
+        let Some(id) = msg.body.in_reply_to else {
+            return Some(msg);
+        };
+        let Some(tx) = self.pending.lock().await.remove(&id) else {
+            return Some(msg);
+        };
+        // The caller may have given up already, in which case the reply is dropped.
+        let _ = tx.send(msg);
+        None
+    }
+
+    /// Stops waiting for replies to `ids`, dropping their channels.
+    async fn forget(&self, ids: &[usize]) {
+        let mut pending = self.pending.lock().await;
+        for id in ids {
+            pending.remove(id);
+        }
+    }
+
+    async fn register(&self, id: usize) -> oneshot::Receiver<Message<Payload>> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(id, tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(id: Option<usize>) -> Message<serde_json::Value> {
+        Message {
+            src: "n0".to_string(),
+            dest: "n1".to_string(),
+            body: Body {
+                id,
+                in_reply_to: None,
+                payload: serde_json::json!({"type": "read"}),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn quorum_registers_nothing_if_a_message_has_no_msg_id() {
+        let rpc = Rpc::new();
+        let out = Mutex::new(tokio::io::sink());
+        let msgs = vec![request(Some(1)), request(Some(2)), request(None)];
+        let result = rpc.quorum(msgs, 2, Duration::from_secs(1), &out).await;
+        assert!(result.is_err());
+        assert!(rpc.pending.lock().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quorum_forgets_the_requests_it_stopped_waiting_for() {
+        let rpc = Rpc::new();
+        let out = Mutex::new(tokio::io::sink());
+        let msgs = vec![request(Some(1)), request(Some(2))];
+        let replies = rpc.quorum(msgs, 2, Duration::from_secs(1), &out).await;
+        assert!(replies.expect("quorum").is_empty());
+        assert!(rpc.pending.lock().await.is_empty());
+    }
+}
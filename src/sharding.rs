@@ -0,0 +1,133 @@
+//! Key ownership by rendezvous hashing: every node gets a score for a key and the
+//! highest one wins. Owners only depend on the key and the set of node ids, not on
+//! their order, so all nodes agree on them without talking to each other. Adding or
+//! removing a node only moves the keys that node wins or owned.
+
+/// Picks the node owning `key` among `node_ids`, or "" if there are none.
+pub fn owner<'a>(key: &str, node_ids: &'a [String]) -> &'a str {
+    node_ids
+        .iter()
+        .max_by_key(|node| (rendezvous_score(key, node), *node))
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+/// Orders `node_ids` by how strongly they claim `key`, the owner first. The first `n`
+/// are the preferred replicas of the key, and the rest the order in which others stand
+/// in for them.
+pub fn preference_list<'a>(key: &str, node_ids: &'a [String]) -> Vec<&'a str> {
+    let mut nodes: Vec<&str> = node_ids.iter().map(String::as_str).collect();
+    nodes.sort_by_cached_key(|node| std::cmp::Reverse((rendezvous_score(key, node), *node)));
+    nodes
+}
+
+/// Returns the keys out of `keys` that `node` owns.
+pub fn owned_keys<'a, K>(
+    node: &'a str,
+    node_ids: &'a [String],
+    keys: impl IntoIterator<Item = K> + 'a,
+) -> impl Iterator<Item = K> + 'a
+where
+    K: AsRef<str>,
+{
+    keys.into_iter()
+        .filter(move |key| owner(key.as_ref(), node_ids) == node)
+}
+
+/// FNV-1a over `key` and `node`, followed by a final avalanche so that similar
+/// inputs do not get similar scores.
+fn rendezvous_score(key: &str, node: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes().chain([0xff]).chain(node.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("n{}", i)).collect()
+    }
+
+    fn keys() -> Vec<String> {
+        (0..1000).map(|i| format!("key-{}", i)).collect()
+    }
+
+    #[test]
+    fn owner_does_not_depend_on_node_order() {
+        let node_ids = nodes(5);
+        let mut reversed = node_ids.clone();
+        reversed.reverse();
+        let mut rotated = node_ids.clone();
+        rotated.rotate_left(2);
+        for key in keys() {
+            let expected = owner(&key, &node_ids);
+            assert_eq!(owner(&key, &reversed), expected);
+            assert_eq!(owner(&key, &rotated), expected);
+            assert_eq!(preference_list(&key, &node_ids)[0], expected);
+            assert_eq!(
+                preference_list(&key, &reversed),
+                preference_list(&key, &node_ids)
+            );
+        }
+    }
+
+    #[test]
+    fn every_node_owns_some_keys() {
+        let node_ids = nodes(5);
+        for node in &node_ids {
+            let owned = owned_keys(node, &node_ids, keys()).count();
+            assert!(owned > 100, "{} owns only {} of 1000 keys", node, owned);
+        }
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let before = nodes(5);
+        let after = nodes(6);
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (owner(&key, &before), owner(&key, &after));
+            if old != new {
+                assert_eq!(new, "n5", "{} moved from {} to {}", key, old, new);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let before = nodes(5);
+        let after: Vec<String> = before.iter().filter(|n| *n != "n2").cloned().collect();
+        for key in keys() {
+            let (old, new) = (owner(&key, &before), owner(&key, &after));
+            if old != "n2" {
+                assert_eq!(new, old, "{} moved from {} to {}", key, old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn preference_list_keeps_order_when_a_node_is_removed() {
+        let before = nodes(5);
+        let after: Vec<String> = before.iter().filter(|n| *n != "n2").cloned().collect();
+        for key in keys() {
+            let mut expected = preference_list(&key, &before);
+            expected.retain(|node| *node != "n2");
+            assert_eq!(preference_list(&key, &after), expected);
+        }
+    }
+
+    #[test]
+    fn no_nodes_no_owner() {
+        assert_eq!(owner("key", &[]), "");
+        assert!(preference_list("key", &[]).is_empty());
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{future::BoxFuture, FutureExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use super::{init_line, parse, setting_up, Inbox, LineWriter, MockKvService, Rng};
+use crate::{Body, Event, Message, Node, Output};
+
+/// A message the cluster routed, from the node, service or client that sent it to the
+/// one it is addressed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub at: Instant,
+    pub from: String,
+    pub to: String,
+    pub line: String,
+    pub fate: Fate,
+}
+
+/// What the network did with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    /// Handed on, after the latency of its link.
+    Delivered,
+    Dropped,
+    /// Kept back by a partition until it heals, when it is routed again.
+    Held,
+}
+
+/// What the network does to the messages between nodes and services. Clients always
+/// reach the nodes, unless a partition names them.
+struct Nemesis {
+    /// Pairs that cannot reach each other, both ways round.
+    cut: HashSet<(String, String)>,
+    latency: HashMap<(String, String), Duration>,
+    /// Most extra latency of a message between nodes and services, drawn from `rng`.
+    jitter: Duration,
+    drop_rate: f64,
+    rng: Rng,
+    /// Whether messages across a partition are held until it heals instead of
+    /// dropped.
+    hold: bool,
+    held: Vec<(String, String)>,
+}
+
+impl Nemesis {
+    fn new(seed: u64) -> Self {
+        Self {
+            cut: HashSet::new(),
+            latency: HashMap::new(),
+            jitter: Duration::ZERO,
+            drop_rate: 0.0,
+            rng: Rng::new(seed),
+            hold: false,
+            held: Vec::new(),
+        }
+    }
+
+    /// Decides the fate of a message from `from` to `to` and, if it is delivered, how
+    /// long it takes to arrive. `internal` is whether it goes between nodes and
+    /// services, the only messages that are dropped or delayed at random.
+    fn fate(&mut self, from: &str, to: &str, internal: bool) -> (Fate, Duration) {
+        let link = (from.to_string(), to.to_string());
+        if self.cut.contains(&link) {
+            let fate = if self.hold { Fate::Held } else { Fate::Dropped };
+            return (fate, Duration::ZERO);
+        }
+        if internal && self.rng.chance(self.drop_rate) {
+            return (Fate::Dropped, Duration::ZERO);
+        }
+        let mut latency = self.latency.get(&link).copied().unwrap_or_default();
+        if internal && !self.jitter.is_zero() {
+            latency += Duration::from_nanos(self.rng.below(self.jitter.as_nanos() as u64));
+        }
+        (Fate::Delivered, latency)
+    }
+}
+
+/// A node of a cluster, whatever its types.
+#[async_trait]
+trait Member: Send + Sync {
+    /// Hands the message in `line` to the node. Returns false once it stopped taking
+    /// events.
+    async fn deliver(&self, line: &str) -> bool;
+}
+
+struct Running<N, P, IP> {
+    node: Arc<N>,
+    tx: Sender<Event<P, IP>>,
+}
+
+#[async_trait]
+impl<N, P, IP> Member for Running<N, P, IP>
+where
+    N: Node<P, IP> + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+{
+    async fn deliver(&self, line: &str) -> bool {
+        crate::deliver(&*self.node, line, &self.tx).await
+    }
+}
+
+/// A node set up, along with the future dispatching its events, which is only spawned
+/// once every node is set up.
+type Started = (Arc<dyn Member>, BoxFuture<'static, ()>);
+
+/// Sets a node up from its init message and output.
+type Starter =
+    Box<dyn FnOnce(String, Output) -> BoxFuture<'static, anyhow::Result<Started>> + Send>;
+
+/// Several nodes run in memory, possibly of different types, along with the
+/// key/value services they use. Every message a node sends goes to the node or
+/// service it is addressed to, and everything else to the test, which plays the
+/// clients:
+///
+/// ```ignore
+/// let mut cluster = Cluster::builder()
+///     .nodes::<CounterNode, Payload, InjectedPayload>(&["n0", "n1", "n2"])
+///     .start()
+///     .await;
+/// let id = cluster.send("c1", "n0", Payload::Add { delta: 1 });
+/// let reply: Message<Payload> = cluster.expect_reply_to(id).await;
+/// ```
+///
+/// Messages are routed one at a time in the order they were sent, and every one is
+/// kept in the trace. The network between nodes and services can be partitioned,
+/// slowed down and made to lose messages.
+///
+/// On a current-thread runtime with the clock paused, as `#[tokio::test(start_paused
+/// = true)]` sets up, a run only depends on its seed: the paused clock only advances
+/// once no task can make progress, so no timer fires while a message can still be
+/// routed, and every drop and jitter is drawn from the seeded generator. The same seed
+/// gives the same trace as long as the nodes do not draw on hash order or randomness
+/// of their own.
+pub struct Cluster {
+    node_ids: Vec<String>,
+    lines: UnboundedSender<(String, String)>,
+    output: Inbox,
+    trace: Arc<Mutex<Vec<Delivery>>>,
+    nemesis: Arc<Mutex<Nemesis>>,
+    next_msg_id: usize,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Cluster {
+    /// Sets up a cluster once the builder is started.
+    pub fn builder() -> ClusterBuilder {
+        ClusterBuilder {
+            nodes: Vec::new(),
+            services: Vec::new(),
+            vars: Vec::new(),
+            seed: 0,
+        }
+    }
+
+    /// The ids of the nodes, in the order they were added.
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Sends `line` from `src`, as the client or node it names, malformed or not.
+    pub fn send_line(&mut self, src: &str, line: &str) {
+        assert!(
+            self.lines.send((src.to_string(), line.to_string())).is_ok(),
+            "cluster stopped routing"
+        );
+    }
+
+    /// Sends a message from `src` to `dest` with the payload `body`. Returns the
+    /// msg_id it was sent with, given one here unless `body` has one.
+    pub fn send_json(&mut self, src: &str, dest: &str, mut body: Value) -> usize {
+        let id = match body.get("msg_id").and_then(Value::as_u64) {
+            Some(id) => id as usize,
+            None => {
+                body["msg_id"] = self.next_msg_id.into();
+                self.next_msg_id += 1;
+                self.next_msg_id - 1
+            }
+        };
+        let message = serde_json::json!({"src": src, "dest": dest, "body": body});
+        self.send_line(src, &message.to_string());
+        id
+    }
+
+    /// Sends a message from `src` to `dest` with `payload`, returning the msg_id it
+    /// was sent with.
+    pub fn send<P: Serialize>(&mut self, src: &str, dest: &str, payload: P) -> usize {
+        let id = self.next_msg_id;
+        self.next_msg_id += 1;
+        let message = Message {
+            src: src.to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        let line = serde_json::to_string(&message).expect("serialize message");
+        self.send_line(src, &line);
+        id
+    }
+
+    /// Returns the next message any node sends to a client, waiting up to
+    /// RECV_TIMEOUT for it.
+    pub async fn recv<P: DeserializeOwned>(&mut self) -> Message<P> {
+        parse(&self.output.recv_line().await)
+    }
+
+    /// Returns the reply to the message sent with `msg_id`, waiting up to RECV_TIMEOUT
+    /// for it. Messages sent to clients before it are left for `recv`.
+    pub async fn expect_reply_to<P: DeserializeOwned>(&mut self, msg_id: usize) -> Message<P> {
+        parse(&self.output.expect_reply_to(msg_id).await)
+    }
+
+    /// Returns the first message sent to a client that matches `want`, waiting up to
+    /// RECV_TIMEOUT for it. Messages that do not match are left for `recv`.
+    pub async fn expect<P: DeserializeOwned>(
+        &mut self,
+        want: impl Fn(&Message<Value>) -> bool,
+    ) -> Message<P> {
+        parse(&self.output.expect(want).await)
+    }
+
+    /// Lets the cluster run until it has nothing left to do, which costs a millisecond
+    /// of virtual time, and returns every message sent to a client that the test has
+    /// not taken.
+    pub async fn drain<P: DeserializeOwned>(&mut self) -> Vec<Message<P>> {
+        self.output
+            .drain()
+            .await
+            .iter()
+            .map(|line| parse(line))
+            .collect()
+    }
+
+    /// Lets `duration` of virtual time pass, firing every timer that comes due on the
+    /// way, then drains the cluster like `drain`.
+    pub async fn advance<P: DeserializeOwned>(&mut self, duration: Duration) -> Vec<Message<P>> {
+        self.output
+            .advance(duration)
+            .await
+            .iter()
+            .map(|line| parse(line))
+            .collect()
+    }
+
+    /// Every message routed so far, oldest first.
+    pub fn trace(&self) -> Vec<Delivery> {
+        lock(&self.trace).clone()
+    }
+
+    /// Cuts every link between the nodes, services or clients in `a` and those in
+    /// `b`, both ways round, replacing any earlier partition.
+    pub fn partition(&mut self, a: &[&str], b: &[&str]) {
+        let mut nemesis = lock(&self.nemesis);
+        nemesis.cut.clear();
+        for (x, y) in a.iter().flat_map(|x| b.iter().map(move |y| (x, y))) {
+            nemesis.cut.insert((x.to_string(), y.to_string()));
+            nemesis.cut.insert((y.to_string(), x.to_string()));
+        }
+    }
+
+    /// Lifts the partition, routing the messages it held again.
+    pub fn heal(&mut self) {
+        let held = {
+            let mut nemesis = lock(&self.nemesis);
+            nemesis.cut.clear();
+            std::mem::take(&mut nemesis.held)
+        };
+        for (from, line) in held {
+            self.send_line(&from, &line);
+        }
+    }
+
+    /// Whether messages across a partition are held until it heals rather than
+    /// dropped, as Maelstrom does.
+    pub fn set_hold_partitioned(&mut self, hold: bool) {
+        lock(&self.nemesis).hold = hold;
+    }
+
+    /// Delays every message from `from` to `to` by `latency`.
+    pub fn set_latency(&mut self, from: &str, to: &str, latency: Duration) {
+        lock(&self.nemesis)
+            .latency
+            .insert((from.to_string(), to.to_string()), latency);
+    }
+
+    /// Delays every message between nodes and services by up to `jitter` more than
+    /// its link's latency, which also reorders messages sent close together.
+    pub fn set_jitter(&mut self, jitter: Duration) {
+        lock(&self.nemesis).jitter = jitter;
+    }
+
+    /// Drops every message between nodes and services with probability `rate`.
+    pub fn set_drop_rate(&mut self, rate: f64) {
+        lock(&self.nemesis).drop_rate = rate;
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Sets up a Cluster, see `Cluster::builder`.
+pub struct ClusterBuilder {
+    nodes: Vec<(String, Starter)>,
+    services: Vec<MockKvService>,
+    vars: Vec<(String, Option<String>)>,
+    seed: u64,
+}
+
+impl ClusterBuilder {
+    /// Adds a node of type `N` for every id in `ids`. `P` is the payload of its
+    /// messages and `IP` that of its injected events.
+    pub fn nodes<N, P, IP>(mut self, ids: &[&str]) -> Self
+    where
+        N: Node<P, IP> + 'static,
+        P: DeserializeOwned + Send + 'static,
+        IP: Send + 'static,
+    {
+        for id in ids {
+            let start: Starter = Box::new(|init, output| start::<N, P, IP>(init, output).boxed());
+            self.nodes.push((id.to_string(), start));
+        }
+        self
+    }
+
+    /// Lets `kv` answer every message the nodes send to its name.
+    pub fn service(mut self, kv: &MockKvService) -> Self {
+        self.services.push(kv.clone());
+        self
+    }
+
+    /// Sets the variables `vars` while the nodes read their configuration, or unsets
+    /// them where their value is None.
+    pub fn env(mut self, vars: &[(&str, Option<&str>)]) -> Self {
+        self.vars.extend(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_string))),
+        );
+        self
+    }
+
+    /// Seeds the generator deciding which messages `set_drop_rate` drops and how long
+    /// `set_jitter` delays them.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sets every node up, answering their init messages, and starts routing.
+    pub async fn start(mut self) -> Cluster {
+        let vars = std::mem::take(&mut self.vars);
+        setting_up(&vars, self.init()).await
+    }
+
+    async fn init(self) -> Cluster {
+        let node_ids: Vec<String> = self.nodes.iter().map(|(id, _)| id.clone()).collect();
+        let (lines, mut written) = mpsc::unbounded_channel();
+        let mut members = HashMap::new();
+        let mut dispatches = Vec::new();
+        // Nodes only send once their events are dispatched, so the next line is the
+        // init_ok of the node just set up.
+        for (id, start) in self.nodes {
+            let writer = Box::new(LineWriter::new(&id, lines.clone()));
+            let (member, dispatch) = start(init_line(&id, &node_ids), writer)
+                .await
+                .unwrap_or_else(|err| panic!("init of {} failed: {:#}", id, err));
+            let (_, init_ok) = written.recv().await.expect("init_ok");
+            let _: Message<Value> = parse(&init_ok);
+            members.insert(id, member);
+            dispatches.push(dispatch);
+        }
+
+        let trace = Arc::default();
+        let nemesis = Arc::new(Mutex::new(Nemesis::new(self.seed)));
+        let (to_test, output) = mpsc::unbounded_channel();
+        let router = Router {
+            members,
+            services: self.services,
+            lines: lines.clone(),
+            to_test,
+            trace: Arc::clone(&trace),
+            nemesis: Arc::clone(&nemesis),
+        };
+        let mut tasks = vec![tokio::spawn(router.run(written))];
+        tasks.extend(dispatches.into_iter().map(tokio::spawn));
+        Cluster {
+            node_ids,
+            lines,
+            output: Inbox::new("cluster", output),
+            trace,
+            nemesis,
+            next_msg_id: 1,
+            tasks,
+        }
+    }
+}
+
+async fn start<N, P, IP>(init: String, output: Output) -> anyhow::Result<Started>
+where
+    N: Node<P, IP> + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+{
+    let (node, tx, rx) = crate::start::<N, P, IP>(&init, output).await?;
+    let dispatch = crate::dispatch(node.clone(), rx).boxed();
+    Ok((Arc::new(Running { node, tx }), dispatch))
+}
+
+/// Hands every line written in the cluster to where it is addressed, unless the
+/// nemesis has it otherwise.
+struct Router {
+    members: HashMap<String, Arc<dyn Member>>,
+    services: Vec<MockKvService>,
+    /// Where services send their replies, so they are routed like any other message.
+    lines: UnboundedSender<(String, String)>,
+    to_test: UnboundedSender<(String, String)>,
+    trace: Arc<Mutex<Vec<Delivery>>>,
+    nemesis: Arc<Mutex<Nemesis>>,
+}
+
+impl Router {
+    async fn run(self, mut written: UnboundedReceiver<(String, String)>) {
+        while let Some((from, line)) = written.recv().await {
+            let to = serde_json::from_str::<Message<Value>>(&line)
+                .map(|message| message.dest)
+                .unwrap_or_default();
+            let internal = self.is_internal(&from) && self.is_internal(&to);
+            let (fate, latency) = {
+                let mut nemesis = lock(&self.nemesis);
+                let (fate, latency) = nemesis.fate(&from, &to, internal);
+                if fate == Fate::Held {
+                    nemesis.held.push((from.clone(), line.clone()));
+                }
+                (fate, latency)
+            };
+            lock(&self.trace).push(Delivery {
+                at: Instant::now(),
+                from: from.clone(),
+                to: to.clone(),
+                line: line.clone(),
+                fate,
+            });
+            if fate != Fate::Delivered {
+                continue;
+            }
+            if let Some(member) = self.members.get(&to) {
+                self.deliver(member.clone(), line, latency).await;
+            } else if let Some(kv) = self.services.iter().find(|kv| kv.name() == to) {
+                self.answer(kv.clone(), line, latency);
+            } else if self.to_test.send((from, line)).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn is_internal(&self, name: &str) -> bool {
+        self.members.contains_key(name) || self.services.iter().any(|kv| kv.name() == name)
+    }
+
+    async fn deliver(&self, member: Arc<dyn Member>, line: String, latency: Duration) {
+        // A stopped node takes nothing any more, like a crashed process.
+        if latency.is_zero() {
+            member.deliver(&line).await;
+            return;
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            member.deliver(&line).await;
+        });
+    }
+
+    /// Serves the request in `line` once it reaches `kv`, and sends the reply back
+    /// through the cluster after the service's own latency.
+    fn answer(&self, kv: MockKvService, line: String, latency: Duration) {
+        let lines = self.lines.clone();
+        let serve = move || {
+            let Some((reply, latency)) = kv.answer(&line) else {
+                return;
+            };
+            let name = kv.name().to_string();
+            if latency.is_zero() {
+                let _ = lines.send((name, reply));
+                return;
+            }
+            tokio::spawn(async move {
+                tokio::time::sleep(latency).await;
+                let _ = lines.send((name, reply));
+            });
+        };
+        if latency.is_zero() {
+            serve();
+            return;
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            serve();
+        });
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
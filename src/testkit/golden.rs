@@ -0,0 +1,71 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde_json::Value;
+
+/// Regenerates the golden files rather than comparing against them when set.
+const UPDATE_VAR: &str = "UPDATE_GOLDEN";
+
+/// Compares `transcript`, as `Harness::transcript` returns it, with the golden file
+/// `tests/golden/{name}.jsonl`, or writes the file if UPDATE_GOLDEN is set. Messages
+/// are compared with their keys sorted and every msg_id replaced by a placeholder,
+/// which an in_reply_to answering it shares, so only what goes over the wire counts.
+pub fn assert_golden(name: &str, transcript: &[String]) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.jsonl", name));
+    let actual = normalize(transcript);
+    if std::env::var_os(UPDATE_VAR).is_some() {
+        std::fs::write(&path, actual.join("\n") + "\n")
+            .unwrap_or_else(|err| panic!("write {}: {}", path.display(), err));
+        return;
+    }
+    let golden = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "read {}: {}, run with {}=1 to create it",
+            path.display(),
+            err,
+            UPDATE_VAR
+        )
+    });
+    let golden: Vec<&str> = golden.lines().collect();
+    if let Some(i) = (0..golden.len().max(actual.len()))
+        .find(|&i| golden.get(i).copied() != actual.get(i).map(String::as_str))
+    {
+        panic!(
+            "{} differs at line {}:\n  golden: {}\n  actual: {}\nrun with {}=1 if the change is intended",
+            path.display(),
+            i + 1,
+            golden.get(i).unwrap_or(&"(none)"),
+            actual.get(i).map_or("(none)", String::as_str),
+            UPDATE_VAR
+        );
+    }
+}
+
+/// Replaces the msg_ids of the messages in `transcript` by `#1`, `#2`, ... in the
+/// order they appear, per sender. Lines that are not JSON are kept as they are.
+fn normalize(transcript: &[String]) -> Vec<String> {
+    let mut ids: HashMap<(String, u64), String> = HashMap::new();
+    transcript
+        .iter()
+        .map(|line| {
+            let (direction, message) = line.split_at(line.find(' ').map_or(0, |i| i + 1));
+            let Ok(mut message) = serde_json::from_str::<Value>(message) else {
+                return line.clone();
+            };
+            let src = message["src"].as_str().unwrap_or_default().to_string();
+            let dest = message["dest"].as_str().unwrap_or_default().to_string();
+            let body = &mut message["body"];
+            if let Some(id) = body.get("msg_id").and_then(Value::as_u64) {
+                let next = format!("#{}", ids.len() + 1);
+                body["msg_id"] = ids.entry((src, id)).or_insert(next).clone().into();
+            }
+            if let Some(id) = body.get("in_reply_to").and_then(Value::as_u64) {
+                if let Some(placeholder) = ids.get(&(dest, id)) {
+                    body["in_reply_to"] = placeholder.clone().into();
+                }
+            }
+            format!("{}{}", direction, message)
+        })
+        .collect()
+}
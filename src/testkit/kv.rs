@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicUsize, Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::time::Instant;
+
+use super::Rng;
+use crate::{
+    kv_service::{History, Payload},
+    ErrorCode, Message,
+};
+
+/// The requests a key/value service serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KvOp {
+    Read,
+    Write,
+    Cas,
+}
+
+impl KvOp {
+    /// The operation and key of `request`, None for replies.
+    fn of(request: &Payload) -> Option<(Self, &Value)> {
+        match request {
+            Payload::Read { key } => Some((Self::Read, key)),
+            Payload::Write { key, .. } => Some((Self::Write, key)),
+            Payload::Cas { key, .. } => Some((Self::Cas, key)),
+            _ => None,
+        }
+    }
+}
+
+/// A request a MockKvService got, with its reply, or None if it was dropped.
+#[derive(Debug, Clone)]
+pub struct KvCall {
+    pub client: String,
+    pub op: KvOp,
+    pub key: Value,
+    pub request: Payload,
+    pub reply: Option<Payload>,
+    pub at: Instant,
+}
+
+/// Faults injected into the requests of one operation.
+#[derive(Debug, Clone, Default)]
+struct Faults {
+    latency: Duration,
+    drop_rate: f64,
+    drop_next: usize,
+    fail_next: usize,
+}
+
+struct State {
+    history: History,
+    staleness: Duration,
+    faults: HashMap<KvOp, Faults>,
+    rng: Rng,
+    log: Vec<KvCall>,
+}
+
+/// A key/value service answering the nodes of a harness in memory, as lin-kv or, with
+/// a staleness window, as seq-kv does. Requests can be delayed, dropped or failed per
+/// operation, and every one is logged. Clones share everything, so a test keeps one to
+/// inspect what its nodes did.
+#[derive(Clone)]
+pub struct MockKvService {
+    name: String,
+    id: Arc<AtomicUsize>,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockKvService {
+    /// A linearizable service named `name`: every read sees every write answered
+    /// before it.
+    pub fn lin(name: &str) -> Self {
+        Self::seq(name, Duration::ZERO)
+    }
+
+    /// A sequentially consistent service named `name`, whose reads may miss the writes
+    /// of other clients up to `staleness` old, see `kv_service::History`.
+    pub fn seq(name: &str, staleness: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            id: Arc::new(1.into()),
+            state: Arc::new(Mutex::new(State {
+                history: History::default(),
+                staleness,
+                faults: HashMap::new(),
+                rng: Rng::new(0),
+                log: Vec::new(),
+            })),
+        }
+    }
+
+    /// The node id the service answers to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Seeds the generator deciding which requests `set_drop_rate` drops.
+    pub fn seed(&self, seed: u64) {
+        self.state().rng = Rng::new(seed);
+    }
+
+    /// Delays every reply to `op` by `latency`.
+    pub fn set_latency(&self, op: KvOp, latency: Duration) {
+        self.faults(op, |faults| faults.latency = latency);
+    }
+
+    /// Drops every `op` request with probability `rate`, before it is applied.
+    pub fn set_drop_rate(&self, op: KvOp, rate: f64) {
+        self.faults(op, |faults| faults.drop_rate = rate);
+    }
+
+    /// Drops the next `n` `op` requests before they are applied.
+    pub fn drop_next(&self, op: KvOp, n: usize) {
+        self.faults(op, |faults| faults.drop_next = n);
+    }
+
+    /// Answers the next `n` `op` requests with precondition_failed instead of applying
+    /// them.
+    pub fn fail_next(&self, op: KvOp, n: usize) {
+        self.faults(op, |faults| faults.fail_next = n);
+    }
+
+    /// Writes `value` to `key` as a client of its own. Like any write, seq reads of
+    /// other clients may miss it for the staleness window.
+    pub fn put(&self, key: impl Into<Value>, value: impl Into<Value>) {
+        let request = Payload::Write {
+            key: key.into(),
+            value: value.into(),
+        };
+        self.state()
+            .history
+            .update("testkit", request, Instant::now());
+    }
+
+    /// The latest value of `key`.
+    pub fn get(&self, key: impl Into<Value>) -> Option<Value> {
+        self.state()
+            .history
+            .latest()
+            .get(&key.into().to_string())
+            .cloned()
+    }
+
+    /// The number of keys with a value.
+    pub fn key_count(&self) -> usize {
+        self.state().history.latest().len()
+    }
+
+    /// Every request so far, oldest first.
+    pub fn log(&self) -> Vec<KvCall> {
+        self.state().log.clone()
+    }
+
+    /// The number of `op` requests so far, dropped ones included.
+    pub fn count(&self, op: KvOp) -> usize {
+        self.state().log.iter().filter(|call| call.op == op).count()
+    }
+
+    /// The number of `op` requests on `key` so far, dropped ones included.
+    pub fn count_key(&self, op: KvOp, key: impl Into<Value>) -> usize {
+        let key = key.into();
+        self.state()
+            .log
+            .iter()
+            .filter(|call| call.op == op && call.key == key)
+            .count()
+    }
+
+    /// Serves `request` from `client`. Returns the reply and how long it takes to
+    /// arrive, or None if the request is dropped or is not a request at all.
+    pub fn call(&self, client: &str, request: Payload) -> Option<(Payload, Duration)> {
+        let (op, key) = KvOp::of(&request)?;
+        let key = key.clone();
+        let now = Instant::now();
+        let mut state = self.state();
+        let State {
+            history,
+            staleness,
+            faults,
+            rng,
+            log,
+        } = &mut *state;
+        let faults = faults.entry(op).or_default();
+        let reply = if faults.drop_next > 0 {
+            faults.drop_next -= 1;
+            None
+        } else if rng.chance(faults.drop_rate) {
+            None
+        } else if faults.fail_next > 0 {
+            faults.fail_next -= 1;
+            Some(Payload::Error {
+                code: ErrorCode::PreconditionFailed.code(),
+                text: format!("injected failure of {:?} on {}", op, key),
+            })
+        } else {
+            Some(match request.clone() {
+                Payload::Read { key } => history.read(client, &key, now, *staleness),
+                request => history.update(client, request, now),
+            })
+        };
+        let latency = faults.latency;
+        log.push(KvCall {
+            client: client.to_string(),
+            op,
+            key,
+            request,
+            reply: reply.clone(),
+            at: now,
+        });
+        reply.map(|reply| (reply, latency))
+    }
+
+    /// Serves the request in `line`, as a node sent it. Returns the line of the reply
+    /// and how long it takes to arrive, or None if there is nothing to answer.
+    pub(crate) fn answer(&self, line: &str) -> Option<(String, Duration)> {
+        let request: Message<Payload> = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => {
+                eprintln!("{} dropping unknown request ({}): {}", self.name, err, line);
+                return None;
+            }
+        };
+        let (payload, latency) = self.call(&request.src, request.body.payload.clone())?;
+        let reply = request.into_reply(Some(&self.id)).map_payload(|_| payload);
+        let line = serde_json::to_string(&reply).expect("serialize reply");
+        Some((line, latency))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn faults(&self, op: KvOp, f: impl FnOnce(&mut Faults)) {
+        f(self.state().faults.entry(op).or_default());
+    }
+}
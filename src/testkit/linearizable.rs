@@ -0,0 +1,472 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+};
+
+use serde_json::Value;
+use tokio::time::Instant;
+
+use super::{Delivery, Fate};
+use crate::{kv_service::Payload, ErrorCode, Message};
+
+/// An operation of a history, from when a client invoked it until its response
+/// arrived. One never answered may take effect at any time after it was invoked, or
+/// never.
+#[derive(Debug, Clone)]
+pub struct Operation<I, O> {
+    pub client: String,
+    pub input: I,
+    pub output: Option<O>,
+    pub invoked: Instant,
+    pub returned: Option<Instant>,
+}
+
+impl<I, O> Operation<I, O> {
+    /// Whether the response to `self` arrived before `other` was invoked, so it has to
+    /// take effect first. Operations at the same instant are taken as concurrent.
+    fn precedes<J, P>(&self, other: &Operation<J, P>) -> bool {
+        self.returned
+            .is_some_and(|returned| returned < other.invoked)
+    }
+}
+
+/// Pairs every request a client sent in `trace` with the reply it got, if any. Clients
+/// are named `c...` as in Maelstrom, and requests and replies are their message
+/// bodies.
+pub fn client_operations(trace: &[Delivery]) -> Vec<Operation<Value, Value>> {
+    let mut operations = Vec::new();
+    let mut pending = HashMap::new();
+    for delivery in trace {
+        if delivery.fate != Fate::Delivered {
+            continue;
+        }
+        let Ok(message) = serde_json::from_str::<Message<Value>>(&delivery.line) else {
+            continue;
+        };
+        if message.src.starts_with('c') {
+            if let Some(id) = message.body.id {
+                pending.insert((message.src.clone(), id), operations.len());
+                operations.push(Operation {
+                    client: message.src,
+                    input: message.body.payload,
+                    output: None,
+                    invoked: delivery.at,
+                    returned: None,
+                });
+            }
+        } else if let Some(id) = message.body.in_reply_to {
+            if let Some(i) = pending.remove(&(message.dest, id)) {
+                let operation: &mut Operation<_, _> = &mut operations[i];
+                operation.output = Some(message.body.payload);
+                operation.returned = Some(delivery.at);
+            }
+        }
+    }
+    operations
+}
+
+/// A sequential specification that histories are checked against.
+pub trait Model: Clone + PartialEq {
+    type Input: Debug;
+    type Output: Debug;
+
+    /// The state after `input` is answered with `output`, or None if the model never
+    /// answers like that. An output of None stands for no answer, which allows any.
+    fn step(&self, input: &Self::Input, output: Option<&Self::Output>) -> Option<Self>;
+}
+
+/// Checks that `history` is linearizable from `init`: that every operation can take
+/// effect at a single instant between its invocation and response, in an order `init`
+/// accepts. A Wing & Gong search, exponential in the number of concurrent operations,
+/// so histories are kept to at most 64 operations.
+pub fn check_linearizable<M: Model>(
+    init: M,
+    history: &[Operation<M::Input, M::Output>],
+) -> Result<(), String> {
+    assert!(
+        history.len() <= 64,
+        "history of {} operations",
+        history.len()
+    );
+    let mut search = Search {
+        history,
+        seen: HashMap::new(),
+        deepest: Vec::new(),
+    };
+    if search.run(&init, 0, &mut Vec::new()) {
+        return Ok(());
+    }
+    let order: Vec<_> = search
+        .deepest
+        .iter()
+        .map(|i| (&history[*i].input, &history[*i].output))
+        .collect();
+    Err(format!(
+        "history of {} operations is not linearizable, at most these {} are: {:?}",
+        history.len(),
+        order.len(),
+        order
+    ))
+}
+
+struct Search<'a, M: Model> {
+    history: &'a [Operation<M::Input, M::Output>],
+    /// The states reached with every set of operations taken, which need not be
+    /// searched again.
+    seen: HashMap<u64, Vec<M>>,
+    /// The longest order of operations taken so far, for reporting.
+    deepest: Vec<usize>,
+}
+
+impl<M: Model> Search<'_, M> {
+    fn run(&mut self, model: &M, taken: u64, order: &mut Vec<usize>) -> bool {
+        let left: Vec<usize> = (0..self.history.len())
+            .filter(|i| taken & (1 << i) == 0)
+            .collect();
+        // Operations never answered need not take effect at all.
+        if left.iter().all(|i| self.history[*i].output.is_none()) {
+            return true;
+        }
+        let states = self.seen.entry(taken).or_default();
+        if states.contains(model) {
+            return false;
+        }
+        states.push(model.clone());
+        if order.len() > self.deepest.len() {
+            self.deepest = order.clone();
+        }
+
+        for &i in &left {
+            let operation = &self.history[i];
+            // Only an operation no other one left has to precede can go next.
+            if left.iter().any(|j| self.history[*j].precedes(operation)) {
+                continue;
+            }
+            let Some(next) = model.step(&operation.input, operation.output.as_ref()) else {
+                continue;
+            };
+            order.push(i);
+            if self.run(&next, taken | (1 << i), order) {
+                return true;
+            }
+            order.pop();
+        }
+        false
+    }
+}
+
+/// The value of a single key of lin-kv, which the requests of `kv_service::Payload`
+/// read, write and cas.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Register(pub Option<Value>);
+
+impl Model for Register {
+    type Input = Payload;
+    type Output = Payload;
+
+    fn step(&self, input: &Payload, output: Option<&Payload>) -> Option<Self> {
+        let failed_with = |code: ErrorCode| match output {
+            None => true,
+            Some(Payload::Error { code: got, .. }) => *got == code.code(),
+            Some(_) => false,
+        };
+        match input {
+            Payload::Read { .. } => {
+                let matches = match (output, &self.0) {
+                    (None, _) => true,
+                    (Some(Payload::ReadOk { value }), Some(current)) => value == current,
+                    (_, None) => failed_with(ErrorCode::KeyDoesNotExist),
+                    _ => false,
+                };
+                matches.then(|| self.clone())
+            }
+            Payload::Write { value, .. } => {
+                matches!(output, None | Some(Payload::WriteOk)).then(|| Self(Some(value.clone())))
+            }
+            Payload::Cas {
+                from,
+                to,
+                create_if_not_exists,
+                ..
+            } => match &self.0 {
+                Some(current) if current == from => {
+                    matches!(output, None | Some(Payload::CasOk)).then(|| Self(Some(to.clone())))
+                }
+                Some(_) => failed_with(ErrorCode::PreconditionFailed).then(|| self.clone()),
+                None if *create_if_not_exists => {
+                    matches!(output, None | Some(Payload::CasOk)).then(|| Self(Some(to.clone())))
+                }
+                None => failed_with(ErrorCode::KeyDoesNotExist).then(|| self.clone()),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Checks a history of lin-kv requests key by key, which is enough as linearizability
+/// is local: the history is linearizable if the history of every key is.
+pub fn check_kv(history: &[Operation<Payload, Payload>]) -> Result<(), String> {
+    let mut keys: HashMap<String, Vec<Operation<Payload, Payload>>> = HashMap::new();
+    for operation in history {
+        let key = match &operation.input {
+            Payload::Read { key } | Payload::Write { key, .. } | Payload::Cas { key, .. } => key,
+            input => return Err(format!("{:?} is not a request", input)),
+        };
+        keys.entry(key.to_string())
+            .or_default()
+            .push(operation.clone());
+    }
+    for (key, history) in keys {
+        check_linearizable(Register::default(), &history)
+            .map_err(|err| format!("key {}: {}", key, err))?;
+    }
+    Ok(())
+}
+
+/// The requests of a grow-only counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterOp {
+    Add(u64),
+    Read,
+}
+
+/// The replies of a grow-only counter: an add is acknowledged, a read has a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterOk {
+    Added,
+    Value(u64),
+}
+
+/// Checks the reads of a grow-only counter, which need not be linearizable as a
+/// counter reads its parts one by one, against some serialization of the adds: every
+/// read sees every add acknowledged before it was invoked, plus any of the adds
+/// invoked before it returned. A read after every acknowledged add, the final read,
+/// therefore sees their sum plus any of the adds that were never answered.
+pub fn check_counter(history: &[Operation<CounterOp, CounterOk>]) -> Result<(), String> {
+    for read in history {
+        let (CounterOp::Read, Some(CounterOk::Value(value))) = (read.input, read.output) else {
+            continue;
+        };
+        let mut seen = 0;
+        let mut may_see = Vec::new();
+        for add in history {
+            let CounterOp::Add(delta) = add.input else {
+                continue;
+            };
+            if add.output.is_some() && add.precedes(read) {
+                seen += delta;
+            } else if !read.precedes(add) {
+                may_see.push(delta);
+            }
+        }
+        let extra = value.checked_sub(seen);
+        if !extra.is_some_and(|extra| subset_sums(&may_see).contains(&extra)) {
+            return Err(format!(
+                "read of {} by {} invoked at {:?} is not {} plus any of {:?}",
+                value, read.client, read.invoked, seen, may_see
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Every sum of a subset of `values`.
+fn subset_sums(values: &[u64]) -> BTreeSet<u64> {
+    let mut sums = BTreeSet::from([0]);
+    for value in values {
+        let more: Vec<u64> = sums.iter().map(|sum| sum + value).collect();
+        sums.extend(more);
+    }
+    sums
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        // Every test measures from the same origin, so times compare across operations.
+        thread_local!(static ORIGIN: Instant = Instant::now());
+        ORIGIN.with(|origin| *origin + Duration::from_millis(ms))
+    }
+
+    fn op<I, O>(
+        input: I,
+        output: Option<O>,
+        invoked: u64,
+        returned: Option<u64>,
+    ) -> Operation<I, O> {
+        Operation {
+            client: "c1".to_string(),
+            input,
+            output,
+            invoked: at(invoked),
+            returned: returned.map(at),
+        }
+    }
+
+    fn write(value: i64) -> Payload {
+        Payload::Write {
+            key: json!(1),
+            value: json!(value),
+        }
+    }
+
+    fn read() -> Payload {
+        Payload::Read { key: json!(1) }
+    }
+
+    fn read_ok(value: i64) -> Option<Payload> {
+        Some(Payload::ReadOk {
+            value: json!(value),
+        })
+    }
+
+    fn cas(from: i64, to: i64) -> Payload {
+        Payload::Cas {
+            key: json!(1),
+            from: json!(from),
+            to: json!(to),
+            create_if_not_exists: false,
+        }
+    }
+
+    fn error(code: ErrorCode) -> Option<Payload> {
+        Some(Payload::Error {
+            code: code.code(),
+            text: String::new(),
+        })
+    }
+
+    #[test]
+    fn concurrent_writes_may_take_effect_in_either_order() {
+        let history = [
+            op(write(1), Some(Payload::WriteOk), 0, Some(10)),
+            op(write(2), Some(Payload::WriteOk), 1, Some(9)),
+            op(read(), read_ok(1), 11, Some(12)),
+            op(read(), read_ok(1), 13, Some(14)),
+        ];
+        assert_eq!(check_kv(&history), Ok(()));
+    }
+
+    #[test]
+    fn a_read_of_an_overwritten_value_is_not_linearizable() {
+        let history = [
+            op(write(1), Some(Payload::WriteOk), 0, Some(1)),
+            op(write(2), Some(Payload::WriteOk), 2, Some(3)),
+            op(read(), read_ok(1), 4, Some(5)),
+        ];
+        assert!(check_kv(&history).is_err());
+    }
+
+    #[test]
+    fn reads_must_not_go_back_between_concurrent_writes() {
+        let history = [
+            op(write(1), Some(Payload::WriteOk), 0, Some(1)),
+            op(write(2), Some(Payload::WriteOk), 2, Some(20)),
+            op(read(), read_ok(2), 3, Some(4)),
+            op(read(), read_ok(1), 5, Some(6)),
+        ];
+        assert!(check_kv(&history).is_err());
+    }
+
+    #[test]
+    fn cas_follows_the_value_it_compared_against() {
+        let valid = [
+            op(write(1), Some(Payload::WriteOk), 0, Some(1)),
+            op(cas(1, 2), Some(Payload::CasOk), 2, Some(3)),
+            op(cas(1, 3), error(ErrorCode::PreconditionFailed), 4, Some(5)),
+            op(read(), read_ok(2), 6, Some(7)),
+        ];
+        assert_eq!(check_kv(&valid), Ok(()));
+        let invalid = [
+            op(write(1), Some(Payload::WriteOk), 0, Some(1)),
+            op(cas(1, 2), Some(Payload::CasOk), 2, Some(3)),
+            op(cas(1, 3), Some(Payload::CasOk), 4, Some(5)),
+        ];
+        assert!(check_kv(&invalid).is_err());
+    }
+
+    #[test]
+    fn unanswered_writes_may_or_may_not_take_effect() {
+        for seen in [1, 2] {
+            let history = [
+                op(write(1), Some(Payload::WriteOk), 0, Some(1)),
+                op(write(2), None, 2, None),
+                op(read(), read_ok(seen), 10, Some(11)),
+            ];
+            assert_eq!(check_kv(&history), Ok(()), "read of {}", seen);
+        }
+    }
+
+    #[test]
+    fn missing_keys_read_as_key_does_not_exist() {
+        let history = [
+            op(read(), error(ErrorCode::KeyDoesNotExist), 0, Some(1)),
+            op(write(1), Some(Payload::WriteOk), 2, Some(3)),
+            op(read(), error(ErrorCode::KeyDoesNotExist), 4, Some(5)),
+        ];
+        assert!(check_kv(&history).is_err());
+        assert_eq!(check_kv(&history[..2]), Ok(()));
+    }
+
+    fn add(
+        delta: u64,
+        acked: bool,
+        invoked: u64,
+        returned: u64,
+    ) -> Operation<CounterOp, CounterOk> {
+        let (output, returned) = if acked {
+            (Some(CounterOk::Added), Some(returned))
+        } else {
+            (None, None)
+        };
+        op(CounterOp::Add(delta), output, invoked, returned)
+    }
+
+    fn counter_read(value: u64, invoked: u64, returned: u64) -> Operation<CounterOp, CounterOk> {
+        op(
+            CounterOp::Read,
+            Some(CounterOk::Value(value)),
+            invoked,
+            Some(returned),
+        )
+    }
+
+    #[test]
+    fn final_counter_reads_sum_the_acknowledged_adds() {
+        let adds = [
+            add(1, true, 0, 1),
+            add(10, true, 2, 3),
+            add(100, false, 4, 0),
+        ];
+        for (value, valid) in [(11, true), (111, true), (1, false), (12, false)] {
+            let mut history = adds.to_vec();
+            history.push(counter_read(value, 10, 11));
+            assert_eq!(check_counter(&history).is_ok(), valid, "read of {}", value);
+        }
+    }
+
+    #[test]
+    fn intermediate_counter_reads_see_some_of_the_concurrent_adds() {
+        let history = [
+            add(1, true, 0, 1),
+            add(10, true, 2, 20),
+            add(100, true, 3, 20),
+            counter_read(101, 4, 5),
+            counter_read(11, 6, 7),
+        ];
+        assert_eq!(check_counter(&history), Ok(()));
+        // An add invoked after the read returned cannot be seen.
+        let history = [
+            add(1, true, 0, 1),
+            counter_read(11, 2, 3),
+            add(10, true, 4, 5),
+        ];
+        assert!(check_counter(&history).is_err());
+    }
+}
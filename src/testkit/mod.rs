@@ -0,0 +1,578 @@
+//! Runs nodes in memory for tests, behind the `testkit` feature that the crate's own
+//! dev-dependency turns on, so the tests of every binary can use it.
+//!
+//! A `Harness` sets up one node through the same init handshake as `event_loop`, feeds
+//! it scripted messages and collects what it sends. Every binary tests its node like
+//! this:
+//!
+//! ```ignore
+//! #[tokio::test(start_paused = true)]
+//! async fn echoes() {
+//!     let mut node = Harness::<EchoNode, Payload>::new("n1", &["n1"]).await;
+//!     let id = node.send_json("c1", json!({"type": "echo", "echo": 1})).await;
+//!     let reply = node.expect_reply_to(id).await;
+//!     assert!(matches!(reply.body.payload, Payload::EchoOk { .. }));
+//! }
+//! ```
+//!
+//! Harness tests run on paused time. Waiting for a message lets the clock jump to the
+//! node's next timer whenever nothing else can run, so a node ticking every few
+//! milliseconds gets through minutes of ticks in milliseconds of real time. `advance`
+//! moves the clock on by a set time and returns what the node sent meanwhile. Nodes
+//! read the time from tokio's clock, through `now` or a `TokioClock`, never from std.
+//!
+//! Nodes that talk to Maelstrom's key/value services get a `MockKvService` for each, which
+//! answers their requests in memory and can be told to delay, drop or fail them:
+//!
+//! ```ignore
+//! let kv = MockKvService::lin("lin-kv");
+//! let mut node = Harness::<KafkaNode, Payload, InjectedPayload>::builder("n0", &["n0"])
+//!     .service(&kv)
+//!     .start()
+//!     .await;
+//! ```
+//!
+//! `assert_golden` pins the wire format: it compares everything a harness exchanged
+//! with its node against a file in `tests/golden`, which `UPDATE_GOLDEN=1 cargo test`
+//! regenerates when a change to the protocol is intended.
+//!
+//! `wire` holds the proptest strategies for the payloads of the library, which the
+//! binaries build the strategies of their own payloads from to check that every
+//! message they send or take round trips through JSON, along with the fixtures in
+//! `tests/fixtures` of lines in the shapes Maelstrom uses for each workload.
+//!
+//! A `Cluster` runs several nodes, possibly of different types, routing what they send
+//! to each other and to their services, and keeps a trace of every message. The test
+//! plays the clients of any node.
+//!
+//! Nodes read their configuration from the environment in `from_init`, which is shared
+//! by all tests of a binary. Tests that need other settings go through
+//! `Harness::with_env` or `with_env`, which never overlap with setting up any other
+//! harness.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::AsyncWrite,
+    sync::{
+        mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+        RwLock,
+    },
+    task::JoinHandle,
+};
+
+use crate::{Body, Event, Message, Node};
+
+mod cluster;
+mod golden;
+mod kv;
+mod linearizable;
+mod rng;
+pub mod wire;
+
+pub use cluster::{Cluster, ClusterBuilder, Delivery, Fate};
+pub use golden::assert_golden;
+pub use kv::{KvCall, KvOp, MockKvService};
+pub use linearizable::{
+    check_counter, check_kv, check_linearizable, client_operations, CounterOk, CounterOp, Model,
+    Operation, Register,
+};
+pub use rng::Rng;
+
+/// How long a harness waits for a message the test expects, in virtual time.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Taken for writing while a test changes the environment, and for reading while a
+/// harness sets its node up.
+static ENV: RwLock<()> = RwLock::const_new(());
+
+/// One node run in memory. `P` is the payload of its messages and `IP` that of its
+/// injected events.
+pub struct Harness<N, P, IP = ()> {
+    node: Arc<N>,
+    node_id: String,
+    tx: Sender<Event<P, IP>>,
+    output: Inbox,
+    init_ok: Message<Value>,
+    /// Every line exchanged with the node, see `transcript`.
+    transcript: Vec<String>,
+    next_msg_id: usize,
+    dispatch: JoinHandle<()>,
+    router: JoinHandle<()>,
+    _payload: PhantomData<P>,
+}
+
+impl<N, P, IP> Harness<N, P, IP>
+where
+    N: Node<P, IP> + 'static,
+    P: Serialize + DeserializeOwned + Debug + Send + 'static,
+    IP: Send + 'static,
+{
+    /// Sets up node `node_id` of the cluster `node_ids`, answering its init message.
+    pub async fn new(node_id: &str, node_ids: &[&str]) -> Self {
+        Self::builder(node_id, node_ids).start().await
+    }
+
+    /// Sets up the node like `new`, with the variables `vars` set while `from_init`
+    /// reads its configuration, or unset where their value is None.
+    pub async fn with_env(node_id: &str, node_ids: &[&str], vars: &[(&str, Option<&str>)]) -> Self {
+        Self::builder(node_id, node_ids).env(vars).start().await
+    }
+
+    /// Sets up node `node_id` of the cluster `node_ids` once the builder is started,
+    /// for nodes that need more than `new` gives them.
+    pub fn builder(node_id: &str, node_ids: &[&str]) -> HarnessBuilder<N, P, IP> {
+        HarnessBuilder {
+            node_id: node_id.to_string(),
+            node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+            vars: Vec::new(),
+            services: Vec::new(),
+            _harness: PhantomData,
+        }
+    }
+
+    /// The node, for assertions on its state.
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    /// The node's answer to its init message.
+    pub fn init_ok(&self) -> &Message<Value> {
+        &self.init_ok
+    }
+
+    /// Every line exchanged with the node so far, from its init message on, in the
+    /// order the test sent or took them: `> ` before the lines sent to the node and
+    /// `< ` before those it sent. Lines it sent to a service are left out.
+    pub fn transcript(&self) -> &[String] {
+        &self.transcript
+    }
+
+    /// Delivers `line` to the node as if read from stdin, malformed or not.
+    pub async fn send_line(&mut self, line: &str) {
+        self.transcript.push(format!("> {}", line));
+        assert!(
+            crate::deliver(&*self.node, line, &self.tx).await,
+            "node stopped taking events"
+        );
+    }
+
+    /// Delivers a message from `src` with the payload `body`. Returns the msg_id it
+    /// was sent with, given one here unless `body` has one.
+    pub async fn send_json(&mut self, src: &str, mut body: Value) -> usize {
+        let id = match body.get("msg_id").and_then(Value::as_u64) {
+            Some(id) => id as usize,
+            None => {
+                body["msg_id"] = self.next_msg_id.into();
+                self.next_msg_id += 1;
+                self.next_msg_id - 1
+            }
+        };
+        let message = serde_json::json!({"src": src, "dest": self.node_id, "body": body});
+        self.send_line(&message.to_string()).await;
+        id
+    }
+
+    /// Delivers a message from `src` with `payload`, returning the msg_id it was sent
+    /// with.
+    pub async fn send(&mut self, src: &str, payload: P) -> usize {
+        let id = self.next_msg_id;
+        self.next_msg_id += 1;
+        let message = Message {
+            src: src.to_string(),
+            dest: self.node_id.clone(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        let line = serde_json::to_string(&message).expect("serialize message");
+        self.send_line(&line).await;
+        id
+    }
+
+    /// Hands `payload` to the node as an injected event.
+    pub async fn inject(&mut self, payload: IP) {
+        assert!(
+            self.tx.send(Event::Injected(payload)).await.is_ok(),
+            "node stopped taking events"
+        );
+    }
+
+    /// Tells the node that stdin closed.
+    pub async fn eof(&mut self) {
+        assert!(
+            self.tx.send(Event::EOF).await.is_ok(),
+            "node stopped taking events"
+        );
+    }
+
+    /// Returns the next line the node sends, waiting up to RECV_TIMEOUT for it.
+    pub async fn recv_line(&mut self) -> String {
+        let line = self.output.recv_line().await;
+        self.taken(line)
+    }
+
+    /// Returns the next message the node sends, waiting up to RECV_TIMEOUT for it.
+    pub async fn recv(&mut self) -> Message<P> {
+        parse(&self.recv_line().await)
+    }
+
+    /// Returns the reply to the message sent with `msg_id`, waiting up to RECV_TIMEOUT
+    /// for it. Messages sent before it are left for `recv`.
+    pub async fn expect_reply_to(&mut self, msg_id: usize) -> Message<P> {
+        let line = self.output.expect_reply_to(msg_id).await;
+        parse(&self.taken(line))
+    }
+
+    /// Returns the first message the node sends that matches `want`, waiting up to
+    /// RECV_TIMEOUT for it. Messages that do not match are left for `recv`.
+    pub async fn expect(&mut self, want: impl Fn(&Message<Value>) -> bool) -> Message<P> {
+        let line = self.output.expect(want).await;
+        parse(&self.taken(line))
+    }
+
+    /// Lets the node run until it has nothing left to do, which costs a millisecond of
+    /// virtual time, and returns every message it sent that the test has not taken.
+    pub async fn drain(&mut self) -> Vec<Message<P>> {
+        let lines = self.output.drain().await;
+        lines
+            .into_iter()
+            .map(|line| parse(&self.taken(line)))
+            .collect()
+    }
+
+    /// Lets `duration` of virtual time pass, firing every timer of the node that comes
+    /// due on the way, then drains it like `drain`.
+    pub async fn advance(&mut self, duration: Duration) -> Vec<Message<P>> {
+        let lines = self.output.advance(duration).await;
+        lines
+            .into_iter()
+            .map(|line| parse(&self.taken(line)))
+            .collect()
+    }
+
+    /// Adds `line`, which the test took from the node, to the transcript.
+    fn taken(&mut self, line: String) -> String {
+        self.transcript.push(format!("< {}", line));
+        line
+    }
+}
+
+impl<N, P, IP> Drop for Harness<N, P, IP> {
+    fn drop(&mut self) {
+        self.dispatch.abort();
+        self.router.abort();
+    }
+}
+
+/// Sets up a Harness, see `Harness::builder`.
+pub struct HarnessBuilder<N, P, IP = ()> {
+    node_id: String,
+    node_ids: Vec<String>,
+    vars: Vec<(String, Option<String>)>,
+    services: Vec<MockKvService>,
+    _harness: PhantomData<Harness<N, P, IP>>,
+}
+
+impl<N, P, IP> HarnessBuilder<N, P, IP>
+where
+    N: Node<P, IP> + 'static,
+    P: Serialize + DeserializeOwned + Debug + Send + 'static,
+    IP: Send + 'static,
+{
+    /// Sets the variables `vars` while `from_init` reads its configuration, or unsets
+    /// them where their value is None.
+    pub fn env(mut self, vars: &[(&str, Option<&str>)]) -> Self {
+        self.vars.extend(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_string))),
+        );
+        self
+    }
+
+    /// Lets `kv` answer every message the node sends to its name.
+    pub fn service(mut self, kv: &MockKvService) -> Self {
+        self.services.push(kv.clone());
+        self
+    }
+
+    /// Sets the node up, answering its init message.
+    pub async fn start(mut self) -> Harness<N, P, IP> {
+        let vars = std::mem::take(&mut self.vars);
+        setting_up(&vars, self.init()).await
+    }
+
+    async fn init(self) -> Harness<N, P, IP> {
+        let node_id = self.node_id;
+        let (lines, mut written) = mpsc::unbounded_channel();
+        let writer = Box::new(LineWriter::new(&node_id, lines));
+        let init = init_line(&node_id, &self.node_ids);
+        let (node, tx, rx) = crate::start::<N, P, IP>(&init, writer)
+            .await
+            .unwrap_or_else(|err| panic!("init of {} failed: {:#}", node_id, err));
+        let (_, init_ok_line) = written.recv().await.expect("init_ok");
+        let init_ok: Message<Value> = serde_json::from_str(&init_ok_line).expect("parse init_ok");
+        let (to_test, output) = mpsc::unbounded_channel();
+        let router = tokio::spawn(route(
+            node.clone(),
+            tx.clone(),
+            written,
+            self.services,
+            to_test,
+        ));
+        let dispatch = tokio::spawn(crate::dispatch(node.clone(), rx));
+        Harness {
+            node,
+            output: Inbox::new(&node_id, output),
+            node_id,
+            tx,
+            init_ok,
+            transcript: vec![format!("> {}", init), format!("< {}", init_ok_line)],
+            next_msg_id: 1,
+            dispatch,
+            router,
+            _payload: PhantomData,
+        }
+    }
+}
+
+/// Hands what the node writes to the service it is addressed to, delivering the
+/// replies back to the node, and everything else to the test.
+async fn route<N, P, IP>(
+    node: Arc<N>,
+    tx: Sender<Event<P, IP>>,
+    mut written: UnboundedReceiver<(String, String)>,
+    services: Vec<MockKvService>,
+    to_test: UnboundedSender<(String, String)>,
+) where
+    N: Node<P, IP> + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+{
+    while let Some((from, line)) = written.recv().await {
+        let dest = serde_json::from_str::<Message<Value>>(&line)
+            .ok()
+            .map(|message| message.dest);
+        let Some(kv) = services
+            .iter()
+            .find(|kv| Some(kv.name()) == dest.as_deref())
+        else {
+            if to_test.send((from, line)).is_err() {
+                return;
+            }
+            continue;
+        };
+        let Some((reply, latency)) = kv.answer(&line) else {
+            continue;
+        };
+        let (node, tx) = (node.clone(), tx.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            crate::deliver(&*node, &reply, &tx).await;
+        });
+    }
+}
+
+/// The messages sent to the test, by a node or a cluster named `owner`.
+struct Inbox {
+    owner: String,
+    output: UnboundedReceiver<(String, String)>,
+    /// Lines sent that the test has not taken yet.
+    unread: VecDeque<String>,
+}
+
+impl Inbox {
+    fn new(owner: &str, output: UnboundedReceiver<(String, String)>) -> Self {
+        Self {
+            owner: owner.to_string(),
+            output,
+            unread: VecDeque::new(),
+        }
+    }
+
+    async fn recv_line(&mut self) -> String {
+        if let Some(line) = self.unread.pop_front() {
+            return line;
+        }
+        match tokio::time::timeout(RECV_TIMEOUT, self.output.recv()).await {
+            Ok(Some((_, line))) => line,
+            Ok(None) => panic!("{} closed its output", self.owner),
+            Err(_) => panic!("{} sent nothing for {:?}", self.owner, RECV_TIMEOUT),
+        }
+    }
+
+    async fn expect_reply_to(&mut self, msg_id: usize) -> String {
+        self.expect(|message: &Message<Value>| message.body.in_reply_to == Some(msg_id))
+            .await
+    }
+
+    async fn expect(&mut self, want: impl Fn(&Message<Value>) -> bool) -> String {
+        if let Some(i) = self.unread.iter().position(|line| want(&parse(line))) {
+            return self.unread.remove(i).expect("unread line");
+        }
+        let deadline = tokio::time::Instant::now() + RECV_TIMEOUT;
+        loop {
+            let line = match tokio::time::timeout_at(deadline, self.output.recv()).await {
+                Ok(Some((_, line))) => line,
+                Ok(None) => panic!("{} closed its output", self.owner),
+                Err(_) => panic!(
+                    "{} sent nothing expected for {:?}, only {:?}",
+                    self.owner, RECV_TIMEOUT, self.unread
+                ),
+            };
+            if want(&parse(&line)) {
+                return line;
+            }
+            self.unread.push_back(line);
+        }
+    }
+
+    async fn drain(&mut self) -> Vec<String> {
+        settle().await;
+        while let Ok((_, line)) = self.output.try_recv() {
+            self.unread.push_back(line);
+        }
+        self.unread.drain(..).collect()
+    }
+
+    async fn advance(&mut self, duration: Duration) -> Vec<String> {
+        // Sleeping rather than calling `tokio::time::advance` lets the clock stop at
+        // each timer on the way, so every task sees the time its timer fired at.
+        tokio::time::sleep(duration).await;
+        self.drain().await
+    }
+}
+
+/// Lets every task run until none can make progress without time passing, then lets a
+/// millisecond of virtual time pass.
+pub async fn settle() {
+    tokio::time::sleep(Duration::from_millis(1)).await;
+}
+
+/// Runs `setup` with the variables `vars` set, or unset where their value is None, and
+/// restores them after. Without variables, it only waits for no test to be changing
+/// the environment.
+async fn setting_up<T>(vars: &[(String, Option<String>)], setup: impl Future<Output = T>) -> T {
+    if vars.is_empty() {
+        let _env = ENV.read().await;
+        return setup.await;
+    }
+    let _env = ENV.write().await;
+    let vars: Vec<_> = vars
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_deref()))
+        .collect();
+    let saved = set_vars(&vars);
+    let result = setup.await;
+    restore_vars(saved);
+    result
+}
+
+/// The init message of node `node_id` of the cluster `node_ids`.
+fn init_line(node_id: &str, node_ids: &[String]) -> String {
+    serde_json::json!({
+        "src": "c0",
+        "dest": node_id,
+        "body": {
+            "type": "init",
+            "msg_id": 0,
+            "node_id": node_id,
+            "node_ids": node_ids,
+        },
+    })
+    .to_string()
+}
+
+/// Runs `f` with the variables `vars` set, or unset where their value is None, and
+/// restores them after. Never overlaps with another harness being set up.
+pub fn with_env<T>(vars: &[(&str, Option<&str>)], f: impl FnOnce() -> T) -> T {
+    let _env = ENV.blocking_write();
+    let saved = set_vars(vars);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    restore_vars(saved);
+    result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+fn set_vars(vars: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+    vars.iter()
+        .map(|(name, value)| {
+            let saved = std::env::var(name).ok();
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+            (name.to_string(), saved)
+        })
+        .collect()
+}
+
+fn restore_vars(saved: Vec<(String, Option<String>)>) {
+    for (name, value) in saved {
+        match value {
+            Some(value) => std::env::set_var(name, value),
+            None => std::env::remove_var(name),
+        }
+    }
+}
+
+fn parse<P: DeserializeOwned>(line: &str) -> Message<P> {
+    serde_json::from_str(line).unwrap_or_else(|err| panic!("unexpected message {}: {}", line, err))
+}
+
+/// An output that hands every whole line written to it to a channel, along with the
+/// node that wrote it.
+pub(crate) struct LineWriter {
+    node: String,
+    partial: Vec<u8>,
+    lines: UnboundedSender<(String, String)>,
+}
+
+impl LineWriter {
+    pub(crate) fn new(node: &str, lines: UnboundedSender<(String, String)>) -> Self {
+        Self {
+            node: node.to_string(),
+            partial: Vec::new(),
+            lines,
+        }
+    }
+}
+
+impl AsyncWrite for LineWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).into_owned();
+            // Nobody reading any more is the same as a closed pipe.
+            if self.lines.send((self.node.clone(), line)).is_err() {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
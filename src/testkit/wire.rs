@@ -0,0 +1,235 @@
+use std::{fmt::Debug, path::PathBuf};
+
+use proptest::{collection, prelude::*};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::VectorClock,
+    crdt::{LwwEntry, LwwMap, Stamp},
+    gossip::RangeSet,
+    kv_service,
+    merkle::Digest,
+    raft::{LogEntry, RaftPayload},
+    Body, KVPayload, Message, WithKV,
+};
+
+/// Asserts that `message` serializes to JSON that deserializes to a message of the
+/// same type serializing to the same JSON, so no field is lost or read back as
+/// another.
+pub fn assert_round_trip<P: Serialize + DeserializeOwned>(message: &Message<P>) {
+    let json = serde_json::to_value(message).expect("serialize message");
+    let parsed: Message<P> = serde_json::from_value(json.clone())
+        .unwrap_or_else(|err| panic!("deserialize {}: {}", json, err));
+    assert_eq!(
+        serde_json::to_value(&parsed).expect("serialize message"),
+        json
+    );
+}
+
+/// The lines of `tests/fixtures/{name}.jsonl`, which hold messages in the shapes
+/// Maelstrom sends and expects for a workload, one per line.
+pub fn fixture(name: &str) -> Vec<String> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(format!("{}.jsonl", name));
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("read {}: {}", path.display(), err))
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Asserts that every line of the fixture `name` deserializes to a `Message<P>`,
+/// which round trips from there.
+pub fn assert_fixture_parses<P: Serialize + DeserializeOwned>(name: &str) {
+    for line in fixture(name) {
+        let message: Message<P> = serde_json::from_str(&line)
+            .unwrap_or_else(|err| panic!("{}: deserialize {}: {}", name, line, err));
+        assert_round_trip(&message);
+    }
+}
+
+/// Messages with `payload` between clients and nodes, with or without msg_id and
+/// in_reply_to.
+pub fn message<P: Debug>(payload: impl Strategy<Value = P>) -> impl Strategy<Value = Message<P>> {
+    (
+        node_id(),
+        node_id(),
+        any::<Option<usize>>(),
+        any::<Option<usize>>(),
+        payload,
+    )
+        .prop_map(|(src, dest, id, in_reply_to, payload)| Message {
+            src,
+            dest,
+            body: Body {
+                id,
+                in_reply_to,
+                payload,
+            },
+        })
+}
+
+/// Ids of nodes and clients as Maelstrom names them.
+pub fn node_id() -> impl Strategy<Value = String> {
+    "[nc][0-9]{1,2}"
+}
+
+/// Any JSON value without floats, nested a few levels deep.
+pub fn json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        any::<String>().prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 16, 4, |inner| {
+        prop_oneof![
+            collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+            collection::btree_map(any::<String>(), inner, 0..4)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+/// Maelstrom errors, as the code and text of the `error` variant every workload has.
+pub fn error() -> impl Strategy<Value = (usize, String)> {
+    (any::<usize>(), any::<String>())
+}
+
+/// Requests of the key/value workloads and their replies.
+pub fn service_payload() -> impl Strategy<Value = kv_service::Payload> {
+    use kv_service::Payload;
+    prop_oneof![
+        json().prop_map(|key| Payload::Read { key }),
+        json().prop_map(|value| Payload::ReadOk { value }),
+        (json(), json()).prop_map(|(key, value)| Payload::Write { key, value }),
+        Just(Payload::WriteOk),
+        (json(), json(), json(), any::<bool>()).prop_map(
+            |(key, from, to, create_if_not_exists)| Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            }
+        ),
+        Just(Payload::CasOk),
+        error().prop_map(|(code, text)| Payload::Error { code, text }),
+    ]
+}
+
+/// What a node sends to and gets back from Maelstrom's key/value services.
+pub fn kv_payload() -> impl Strategy<Value = KVPayload<Value>> {
+    prop_oneof![
+        any::<String>().prop_map(|key| KVPayload::Read { key }),
+        json().prop_map(|value| KVPayload::ReadOk { value }),
+        (any::<String>(), json()).prop_map(|(key, value)| KVPayload::Write { key, value }),
+        Just(KVPayload::WriteOk {}),
+        (any::<String>(), json(), json(), any::<bool>())
+            .prop_map(|(key, from, to, put)| KVPayload::Cas { key, from, to, put }),
+        Just(KVPayload::CasOk {}),
+    ]
+}
+
+/// The payloads of a node with the `workload` of its own that talks to a key/value
+/// service.
+pub fn with_kv<P: Debug>(workload: impl Strategy<Value = P>) -> impl Strategy<Value = WithKV<P>> {
+    prop_oneof![
+        workload.prop_map(WithKV::Workload),
+        kv_payload().prop_map(WithKV::KV),
+    ]
+}
+
+/// Raft messages with log entries of `command`.
+pub fn raft_payload<C: Clone + Debug>(
+    command: impl Strategy<Value = C>,
+) -> impl Strategy<Value = RaftPayload<C>> {
+    let entry = (any::<u64>(), proptest::option::of(command))
+        .prop_map(|(term, command)| LogEntry { term, command });
+    prop_oneof![
+        (any::<u64>(), node_id(), any::<u64>(), any::<u64>()).prop_map(
+            |(term, candidate, last_log_index, last_log_term)| RaftPayload::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            }
+        ),
+        (any::<u64>(), any::<bool>())
+            .prop_map(|(term, vote_granted)| RaftPayload::RequestVoteReply { term, vote_granted }),
+        (
+            (any::<u64>(), node_id(), any::<u64>(), any::<u64>()),
+            collection::vec(entry, 0..4),
+            any::<u64>(),
+        )
+            .prop_map(
+                |((term, leader, prev_log_index, prev_log_term), entries, leader_commit)| {
+                    RaftPayload::AppendEntries {
+                        term,
+                        leader,
+                        prev_log_index,
+                        prev_log_term,
+                        entries,
+                        leader_commit,
+                    }
+                }
+            ),
+        (any::<u64>(), any::<bool>(), any::<u64>()).prop_map(|(term, success, match_index)| {
+            RaftPayload::AppendEntriesReply {
+                term,
+                success,
+                match_index,
+            }
+        }),
+    ]
+}
+
+pub fn stamp() -> impl Strategy<Value = Stamp> {
+    (any::<u64>(), node_id()).prop_map(|(time, node)| Stamp { time, node })
+}
+
+/// Writes of JSON values, or deletes.
+pub fn lww_entry() -> impl Strategy<Value = LwwEntry<Value>> {
+    (stamp(), proptest::option::of(json())).prop_map(|(stamp, value)| LwwEntry { stamp, value })
+}
+
+pub fn lww_map() -> impl Strategy<Value = LwwMap<String, Value>> {
+    collection::vec((any::<String>(), lww_entry()), 0..4).prop_map(|entries| {
+        let mut map = LwwMap::new();
+        for (key, entry) in entries {
+            map.apply(key, entry);
+        }
+        map
+    })
+}
+
+pub fn range_set() -> impl Strategy<Value = RangeSet> {
+    collection::vec((any::<u64>(), 0..100u64), 0..4).prop_map(|ranges| {
+        ranges
+            .into_iter()
+            .map(|(start, len)| (start, start.saturating_add(len)))
+            .collect::<Vec<_>>()
+            .into()
+    })
+}
+
+pub fn digest() -> impl Strategy<Value = Digest> {
+    (any::<u32>(), collection::vec(any::<u64>(), 0..8))
+        .prop_map(|(depth, leaves)| Digest { depth, leaves })
+}
+
+/// Clocks of clusters of up to eight nodes.
+pub fn vector_clock() -> impl Strategy<Value = VectorClock> {
+    collection::vec(0..4usize, 0..8).prop_map(|events| {
+        let mut clock = VectorClock::new();
+        for (index, count) in events.into_iter().enumerate() {
+            for _ in 0..count {
+                clock.increment(index);
+            }
+        }
+        clock
+    })
+}
@@ -0,0 +1,34 @@
+//! Topologies nodes compute for themselves instead of taking the one Maelstrom sends.
+
+use std::collections::HashMap;
+
+/// Makes the first `hubs` nodes hubs that are all connected to each other, and attaches
+/// every other node to one hub, round robin. A message crosses at most three links,
+/// and a leaf only ever talks to its hub. `hubs` is clamped to between one and the
+/// number of nodes. Every node computes the same topology from the same node ids.
+pub fn hubs(nodes: &[String], hubs: usize) -> HashMap<String, Vec<String>> {
+    let hubs = hubs.clamp(1, nodes.len().max(1));
+    let mut topology: HashMap<String, Vec<String>> = nodes
+        .iter()
+        .map(|node| (node.clone(), Vec::new()))
+        .collect();
+    for (i, hub) in nodes.iter().take(hubs).enumerate() {
+        for other in nodes.iter().take(hubs).filter(|other| *other != hub) {
+            topology
+                .get_mut(hub)
+                .expect("hub is a node")
+                .push(other.clone());
+        }
+        for leaf in nodes.iter().skip(hubs).skip(i).step_by(hubs) {
+            topology
+                .get_mut(hub)
+                .expect("hub is a node")
+                .push(leaf.clone());
+            topology
+                .get_mut(leaf)
+                .expect("leaf is a node")
+                .push(hub.clone());
+        }
+    }
+    topology
+}